@@ -0,0 +1,62 @@
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rshmem::Memory;
+
+const SIZE: usize = 1 << 20;
+
+/// Allocator configurations to compare, selected with a filter like `cargo bench -- journal`.
+const CONFIGS: [(&str, bool); 2] = [("plain", false), ("journal", true)];
+
+fn memory(name: &str, journal: bool) -> Memory {
+    Memory::builder(name, SIZE)
+        .journal(journal)
+        .track_sites(false)
+        .build()
+        .unwrap()
+}
+
+fn allocate_free(c: &mut Criterion) {
+    let mut group = c.benchmark_group("allocate_free");
+    group.throughput(Throughput::Elements(1));
+    for (config, journal) in CONFIGS {
+        let memory = memory(&format!("rshmem_bench_alloc_{config}"), journal);
+        for size in [16, 256, 4096] {
+            group.bench_with_input(BenchmarkId::new(config, size), &size, |b, &size| {
+                b.iter(|| {
+                    let data = memory.allocate(black_box(size)).unwrap();
+                    memory.deallocate(data.as_ptr());
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// Fills the heap with blocks of mixed sizes, then frees every other one and refills the
+/// holes, which makes the first-fit search walk a long block list.
+fn fragmented(c: &mut Criterion) {
+    const BLOCKS: usize = 512;
+
+    let mut group = c.benchmark_group("fragmented");
+    group.throughput(Throughput::Elements(BLOCKS as u64));
+    for (config, journal) in CONFIGS {
+        let memory = memory(&format!("rshmem_bench_fragmented_{config}"), journal);
+        group.bench_function(config, |b| {
+            b.iter(|| {
+                let blocks: Vec<_> = (0..BLOCKS)
+                    .map(|i| memory.allocate(16 + i % 7 * 24).unwrap().as_ptr())
+                    .collect();
+                for &data in blocks.iter().step_by(2) {
+                    memory.deallocate(data);
+                }
+                for _ in (0..BLOCKS).step_by(2) {
+                    black_box(memory.allocate(16).unwrap());
+                }
+                memory.reset(false);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, allocate_free, fragmented);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! Allocations while other processes hammer the same heap, to see how the lock scales.
+//!
+//! The heap links blocks with raw pointers, so contending processes map it at the same base
+//! address. The bench spawns its own executable as the workers.
+
+use std::{
+    env,
+    process::{Child, Command},
+    sync::atomic::{AtomicBool, Ordering::SeqCst},
+};
+
+use criterion::{black_box, criterion_group, BenchmarkId, Criterion, Throughput};
+use rshmem::{Memory, Offset};
+
+const NAME: &str = "rshmem_bench_contention";
+const SIZE: usize = 1 << 20;
+const BASE: usize = 0x6BC0_0000;
+
+/// Set in workers to the offset of the flag that stops them.
+const WORKER: &str = "RSHMEM_BENCH_WORKER";
+
+fn open() -> Memory {
+    Memory::builder(NAME, SIZE)
+        .base_address(BASE)
+        .track_sites(false)
+        .build()
+        .unwrap()
+}
+
+fn stop_flag(memory: &Memory, offset: Offset) -> &AtomicBool {
+    // SAFETY: The flag was allocated by the bench and outlives the workers.
+    unsafe { &*(memory.pointer(offset).unwrap() as *const AtomicBool) }
+}
+
+/// Allocates and frees until the bench sets the stop flag.
+fn work(offset: Offset) {
+    let memory = open();
+    let stop = stop_flag(&memory, offset);
+    while !stop.load(SeqCst) {
+        let data = memory.allocate(64).unwrap();
+        memory.deallocate(data.as_ptr());
+    }
+}
+
+fn contention(c: &mut Criterion) {
+    let memory = open();
+    let flag = memory.allocate(8).unwrap().as_ptr();
+    let offset = memory.offset_of(flag).unwrap();
+    let stop = stop_flag(&memory, offset);
+
+    let mut group = c.benchmark_group("contention");
+    group.throughput(Throughput::Elements(1));
+    for workers in [0, 1, 3, 7] {
+        stop.store(false, SeqCst);
+        let mut children: Vec<Child> = (0..workers)
+            .map(|_| {
+                Command::new(env::current_exe().unwrap())
+                    .env(WORKER, offset.get().to_string())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+
+        group.bench_with_input(BenchmarkId::from_parameter(workers), &workers, |b, _| {
+            b.iter(|| {
+                let data = memory.allocate(black_box(64)).unwrap();
+                memory.deallocate(data.as_ptr());
+            })
+        });
+
+        stop.store(true, SeqCst);
+        for child in &mut children {
+            child.wait().unwrap();
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, contention);
+
+fn main() {
+    if let Ok(offset) = env::var(WORKER) {
+        work(Offset::new(offset.parse().unwrap()));
+        return;
+    }
+    benches();
+    Criterion::default().configure_from_args().final_summary();
+}
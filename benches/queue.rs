@@ -0,0 +1,37 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rshmem::{Memory, ShmMpscQueue};
+
+fn push_pop(c: &mut Criterion) {
+    let memory = Memory::builder("rshmem_bench_queue", 1 << 20)
+        .track_sites(false)
+        .build()
+        .unwrap();
+    let queue = ShmMpscQueue::<u64>::create(&memory).unwrap();
+    let mut consumer = queue.consumer().unwrap();
+
+    let mut group = c.benchmark_group("mpsc");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("push_pop", |b| {
+        b.iter(|| {
+            assert!(queue.push(black_box(7)));
+            black_box(consumer.pop().unwrap());
+        })
+    });
+
+    const BATCH: u64 = 256;
+    group.throughput(Throughput::Elements(BATCH));
+    group.bench_function("batch", |b| {
+        b.iter(|| {
+            for value in 0..BATCH {
+                assert!(queue.push(value));
+            }
+            while let Some(value) = consumer.pop() {
+                black_box(value);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, push_pop);
+criterion_main!(benches);
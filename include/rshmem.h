@@ -0,0 +1,76 @@
+/*
+ * Layout of the blocks of an rshmem heap, for C and C++ components that work with blocks a
+ * Rust process allocated.
+ *
+ * Rust hands out blocks as offsets from the start of the memory (rshmem::Offset). The data
+ * of a block is at that offset, and its header directly before the data. The header is the
+ * one of 64-bit processes and of 32-bit processes built with the `wide-layout` feature.
+ *
+ * Only read and write the data of blocks here. Allocating, freeing and changing headers
+ * takes the lock of the heap, so leave that to the Rust side.
+ *
+ * The layout changes only together with RSHMEM_LAYOUT_VERSION, which a process attaching
+ * to the memory checks; see Memory::peers for the versions of the attached processes.
+ */
+#ifndef RSHMEM_H
+#define RSHMEM_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define RSHMEM_LAYOUT_VERSION 10
+
+/* Mixed with the size of a block into its canary. */
+#define RSHMEM_CANARY 0x5a17c0de0b10c4edULL
+
+/* Tags of blocks the heap uses itself. */
+#define RSHMEM_QUARANTINE_TAG 0xffffffffU
+#define RSHMEM_BUMP_TAG 0xfffffffeU
+#define RSHMEM_PENDING_TAG 0xfffffffdU
+
+#define RSHMEM_BLOCK_HEADER_SIZE 64
+
+struct rshmem_block_header {
+    /* Bytes of data, which may be more than were requested. */
+    uint64_t size;
+    /* Addresses in the mapping of the creator, zero for none. */
+    uint64_t next;
+    uint64_t parent;
+    /* Unix time in milliseconds after which the block may be reaped, zero for never. */
+    uint64_t expires;
+    /* Tag chosen by the application, zero for untagged. */
+    uint32_t tag;
+    /* Id of the process that allocated the block. */
+    uint32_t owner;
+    /* Alignment of the data. */
+    uint64_t align;
+    /* RSHMEM_CANARY ^ size, unless the header was written over. */
+    uint64_t canary;
+    /* Unix time in milliseconds at which the block was allocated, zero if not recorded. */
+    uint64_t created;
+};
+
+#if defined(__cplusplus)
+static_assert(sizeof(struct rshmem_block_header) == RSHMEM_BLOCK_HEADER_SIZE,
+              "rshmem_block_header is 64 bytes");
+#else
+_Static_assert(sizeof(struct rshmem_block_header) == RSHMEM_BLOCK_HEADER_SIZE,
+               "rshmem_block_header is 64 bytes");
+#endif
+
+/* Returns the data of the block at `offset` of the memory mapped at `base`. */
+static inline void *rshmem_data(void *base, uint64_t offset) {
+    return (unsigned char *)base + offset;
+}
+
+/* Returns the header of the block whose data is at `data`. */
+static inline struct rshmem_block_header *rshmem_header(void *data) {
+    return (struct rshmem_block_header *)((unsigned char *)data - RSHMEM_BLOCK_HEADER_SIZE);
+}
+
+/* Returns nonzero if the canary of the header matches its size. */
+static inline int rshmem_header_intact(const struct rshmem_block_header *header) {
+    return header->canary == (RSHMEM_CANARY ^ header->size);
+}
+
+#endif /* RSHMEM_H */
@@ -0,0 +1,130 @@
+//! `#[derive(ShmSafe)]` for rshmem, enabled by its `derive` feature.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{
+    parse_macro_input, parse_quote, spanned::Spanned, Data, DeriveInput, Error, Fields, Type,
+};
+
+/// Types that own memory of the process, which other processes cannot reach.
+const HEAP_TYPES: [&str; 8] = [
+    "Box", "Vec", "String", "Rc", "Arc", "HashMap", "BTreeMap", "VecDeque",
+];
+
+/// Implements `rshmem::ShmSafe` for a `#[repr(C)]` struct whose fields are all `ShmSafe`.
+///
+/// References, raw pointers, function pointers and heap types are rejected with an error at
+/// the field; other field types must implement `ShmSafe` themselves. Type parameters get a
+/// `ShmSafe` bound.
+#[proc_macro_derive(ShmSafe)]
+pub fn derive_shm_safe(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new(
+            input.ident.span(),
+            "ShmSafe can only be derived for structs",
+        ));
+    };
+    if !has_stable_repr(&input)? {
+        return Err(Error::new(
+            input.ident.span(),
+            "ShmSafe types must be #[repr(C)] or #[repr(transparent)], so every process lays them out the same way",
+        ));
+    }
+
+    let fields: Vec<_> = match &data.fields {
+        Fields::Named(fields) => fields.named.iter().collect(),
+        Fields::Unnamed(fields) => fields.unnamed.iter().collect(),
+        Fields::Unit => Vec::new(),
+    };
+    for field in &fields {
+        check_type(&field.ty)?;
+    }
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::rshmem::ShmSafe));
+    }
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut where_clause = where_clause.cloned().unwrap_or_else(|| parse_quote!(where));
+    for field in &fields {
+        let ty = &field.ty;
+        where_clause
+            .predicates
+            .push(parse_quote!(#ty: ::rshmem::ShmSafe));
+    }
+    Ok(quote! {
+        // SAFETY: The struct has a stable layout and every field is `ShmSafe`.
+        unsafe impl #impl_generics ::rshmem::ShmSafe for #name #ty_generics #where_clause {}
+    })
+}
+
+fn has_stable_repr(input: &DeriveInput) -> Result<bool, Error> {
+    let mut stable = false;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("repr"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("C") || meta.path.is_ident("transparent") {
+                stable = true;
+            } else if meta.input.peek(syn::token::Paren) {
+                // e.g. align(8), whose argument is not a repr of its own.
+                let content;
+                syn::parenthesized!(content in meta.input);
+                content.parse::<proc_macro2::TokenStream>()?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(stable)
+}
+
+fn check_type(ty: &Type) -> Result<(), Error> {
+    match ty {
+        Type::Reference(_) => Err(Error::new(
+            ty.span(),
+            "references point into one process; store an rshmem::Offset instead",
+        )),
+        Type::Ptr(_) => Err(Error::new(
+            ty.span(),
+            "pointers are only valid in one process; store an rshmem::Offset instead",
+        )),
+        Type::BareFn(_) => Err(Error::new(
+            ty.span(),
+            "function pointers are only valid in one process",
+        )),
+        Type::Array(array) => check_type(&array.elem),
+        Type::Tuple(tuple) if !tuple.elems.is_empty() => Err(Error::new(
+            ty.span(),
+            "tuples have no stable layout; use a #[repr(C)] struct",
+        )),
+        Type::Paren(inner) => check_type(&inner.elem),
+        Type::Group(inner) => check_type(&inner.elem),
+        Type::Path(path) => {
+            let heap = path
+                .path
+                .segments
+                .last()
+                .filter(|segment| HEAP_TYPES.iter().any(|name| segment.ident == name));
+            match heap {
+                Some(segment) => Err(Error::new(
+                    ty.span(),
+                    format!(
+                        "{} owns memory of this process; other processes cannot read it",
+                        segment.ident
+                    ),
+                )),
+                None => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
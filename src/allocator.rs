@@ -1,16 +1,303 @@
-use std::ptr;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    path::Path,
+    ptr,
+};
 
-use crate::mutex::MemoryGuard;
+use crate::{
+    bump::BumpHeader,
+    clock,
+    error::AllocError,
+    handshake::Handshake,
+    hooks::{Usage, UsageBreakdown},
+    journal::{self, Batch, Journal},
+    mutex::{MemoryGuard, MemoryMutex},
+    oplog::{self, LogOp, LogRecord, OpLog},
+    priority::Priority,
+    snapshot,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    MemoryBuilder, Offset,
+};
+
+/// Marks a heap whose header has been initialized.
+const MAGIC: usize = 0x7273_686d;
+
+/// Heap flag: metadata updates go through the journal.
+const JOURNAL: usize = 1;
+
+/// Heap flag: the memory is overwritten on reset and when the last handle closes.
+const SECURE_WIPE: usize = 2;
+
+/// Heap flag: blocks record when they were allocated.
+const TIMESTAMPS: usize = 4;
+
+/// Block sizes are rounded up to a multiple of this.
+///
+/// The wide layout rounds to 64 bits in every process, so blocks are placed the same way.
+const WORD: usize = if cfg!(feature = "wide-layout") {
+    8
+} else {
+    std::mem::size_of::<usize>()
+};
+
+/// Upper half of a word field of the shared headers in the wide layout of a 32-bit process,
+/// so every field is at the offset it has in a 64-bit process. Empty otherwise.
+///
+/// Pointers written by a 64-bit process fit in the lower half as long as the memory is
+/// mapped below 4 GiB, which a 32-bit process needs anyway to map it at the same address.
+#[cfg(all(feature = "wide-layout", target_pointer_width = "32"))]
+pub(crate) type High = u32;
+#[cfg(not(all(feature = "wide-layout", target_pointer_width = "32")))]
+pub(crate) type High = ();
+
+/// Size and alignment of the blocks of `allocate_padded`.
+pub(crate) const CACHE_LINE: usize = 64;
+
+/// Default of `MemoryBuilder::min_split`: a free remainder smaller than this could not even
+/// hold a header and a word of data.
+pub(crate) const MIN_SPLIT: usize = BlockHeader::SIZE + WORD;
+
+/// Mixed with the size of a block into its canary.
+const CANARY: u64 = 0x5a17_c0de_0b10_c4ed;
+
+/// Tag of the blocks that `Memory::scan` puts over corrupted regions to fence them off.
+pub const QUARANTINE_TAG: u32 = u32::MAX;
+
+/// Tag of the block that holds the bump region of `Memory::allocate_bump`.
+pub const BUMP_TAG: u32 = u32::MAX - 1;
+
+/// Tag of the blocks of a `Transaction` that was not committed yet.
+pub const PENDING_TAG: u32 = u32::MAX - 2;
+
+/// Bytes of the header before the data of every block.
+pub const BLOCK_HEADER_SIZE: usize = BlockHeader::SIZE;
+
+/// Number of processes that can have a quota at once.
+pub(crate) const OWNER_QUOTAS: usize = 16;
+
+/// The quota of one process, a free slot if `owner` is zero.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct OwnerQuota {
+    owner: u32,
+    _reserved: u32,
+    /// Bytes the blocks of the process may take, counting their headers and padding.
+    limit: u64,
+}
+
+#[repr(C)]
+struct HeapHeader {
+    magic: usize,
+    _magic: High,
+    handshake: Handshake,
+    flags: usize,
+    _flags: High,
+    /// Offset of the directory of named objects, zero until the first one is created.
+    directory: usize,
+    _directory: High,
+    /// Bytes taken by allocated blocks, counting their headers and padding.
+    used: usize,
+    _used: High,
+    /// Number of allocated blocks.
+    blocks: usize,
+    _blocks: High,
+    /// Highest `used` and `blocks` since creation or the last `reset_peak`.
+    peak_used: usize,
+    _peak_used: High,
+    peak_blocks: usize,
+    _peak_blocks: High,
+    /// Number of records in the operation log at the end of the memory, zero for none.
+    op_log: usize,
+    _op_log: High,
+    /// Smallest free remainder a block is split from, see `MemoryBuilder::min_split`.
+    min_split: usize,
+    _min_split: High,
+    /// Offset of the bump region, zero if there is none, and the bytes it hands out.
+    bump: usize,
+    _bump: High,
+    bump_len: usize,
+    _bump_len: High,
+    /// Number of writable handles open in all processes.
+    handles: usize,
+    _handles: High,
+    /// Twice the number of shutdowns that ended, plus one while one is requested.
+    shutdown: usize,
+    _shutdown: High,
+    /// Offset of the last block queued by `Memory::deallocate_deferred`, zero for none. The
+    /// first word of each queued block holds the offset of the one queued before it.
+    deferred: usize,
+    _deferred: High,
+    /// Bytes of the application header between this header and the heap.
+    user_header: usize,
+    _user_header: High,
+    /// Free bytes only allocations of `Priority::High` may take.
+    high_reserve: usize,
+    _high_reserve: High,
+    /// Free bytes only `Memory::allocate_critical` may take.
+    emergency_reserve: usize,
+    _emergency_reserve: High,
+    /// Number of `Memory::pause_allocations` in effect.
+    paused: usize,
+    _paused: High,
+    quotas: [OwnerQuota; OWNER_QUOTAS],
+    journal: Journal,
+}
+
+impl HeapHeader {
+    const SIZE: usize = std::mem::size_of::<HeapHeader>();
+}
+
+/// Returns a hash of the sizes and field offsets of the shared headers, so processes whose
+/// compilers laid them out differently refuse to share a memory.
+pub(crate) fn layout_hash() -> u64 {
+    use std::mem::{align_of, offset_of, size_of};
+
+    let layout = [
+        HeapHeader::SIZE,
+        align_of::<HeapHeader>(),
+        offset_of!(HeapHeader, handshake),
+        offset_of!(HeapHeader, flags),
+        offset_of!(HeapHeader, handles),
+        offset_of!(HeapHeader, quotas),
+        offset_of!(HeapHeader, journal),
+        size_of::<Journal>(),
+        BlockHeader::SIZE,
+        BlockHeader::ALIGN,
+        offset_of!(BlockHeader, next),
+        offset_of!(BlockHeader, parent),
+        offset_of!(BlockHeader, expires),
+        offset_of!(BlockHeader, tag),
+        offset_of!(BlockHeader, owner),
+        offset_of!(BlockHeader, align),
+        offset_of!(BlockHeader, canary),
+        offset_of!(BlockHeader, created),
+    ];
+    let bytes: Vec<u8> = layout
+        .iter()
+        .flat_map(|&value| (value as u64).to_le_bytes())
+        .collect();
+    crate::kv::hash(&bytes)
+}
 
 #[repr(C)]
 struct BlockHeader {
     pub size: usize,
+    _size: High,
     pub next: *mut u8,
+    _next: High,
     pub parent: *mut u8,
+    _parent: High,
+    /// Unix time in milliseconds after which the block may be reaped. Zero means never.
+    pub expires: u64,
+    /// Tag chosen by the application, inherited by children. Zero means untagged.
+    pub tag: u32,
+    /// Id of the process that allocated the block.
+    pub owner: u32,
+    /// Alignment of the data, which `compact` keeps when it moves the block.
+    pub align: usize,
+    _align: High,
+    /// Derived from the size, so a header that was written over is recognized.
+    pub canary: u64,
+    /// Unix time in milliseconds at which the block was allocated, zero if the heap does
+    /// not record it.
+    pub created: u64,
 }
 
 impl BlockHeader {
     const SIZE: usize = std::mem::size_of::<BlockHeader>();
+    const ALIGN: usize = std::mem::align_of::<BlockHeader>();
+
+    fn next_word(&mut self) -> *mut usize {
+        &mut self.next as *mut *mut u8 as *mut usize
+    }
+
+    /// Returns true for the bump region and quarantine blocks, which belong to the heap.
+    fn is_reserved(&self) -> bool {
+        matches!(self.tag, BUMP_TAG | QUARANTINE_TAG)
+    }
+
+    /// Schedules the size of the block and its canary to be set for `size` bytes.
+    fn resize(&mut self, batch: &mut Batch, size: usize) {
+        batch.set(&mut self.size, size);
+        let words = &mut self.canary as *mut u64 as *mut usize;
+        for (i, word) in canary(size).to_ne_bytes().chunks(WORD).enumerate() {
+            let value = usize::from_ne_bytes(word.try_into().expect("Chunks are words"));
+            // SAFETY: The canary spans whole words.
+            batch.set(unsafe { words.add(i) }, value);
+        }
+    }
+}
+
+/// Returns the canary of a block of `size` bytes.
+fn canary(size: usize) -> u64 {
+    CANARY ^ size as u64
+}
+
+/// What `Allocator::check_and_repair` found and fixed.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of blocks that passed validation.
+    pub blocks_checked: usize,
+    /// Whether the lock was taken over from a process that died holding it.
+    pub lock_broken: bool,
+    /// Whether an interrupted journal record was replayed.
+    pub journal_replayed: bool,
+    /// Number of links that pointed outside the heap or backwards and were cut.
+    pub links_truncated: usize,
+    /// Number of blocks removed because their header was invalid.
+    pub blocks_dropped: usize,
+    /// Number of blocks freed because their parent was no longer allocated.
+    pub orphans_freed: usize,
+}
+
+impl RepairReport {
+    /// Returns true if nothing had to be fixed.
+    pub fn is_clean(&self) -> bool {
+        !self.lock_broken
+            && !self.journal_replayed
+            && self.links_truncated == 0
+            && self.blocks_dropped == 0
+            && self.orphans_freed == 0
+    }
+}
+
+/// What `Memory::scan` found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScanReport {
+    /// Number of blocks whose header is intact.
+    pub blocks_checked: usize,
+    /// Offsets and lengths of the regions between intact blocks that were written over.
+    pub corrupted: Vec<(Offset, usize)>,
+    /// Number of corrupted regions fenced off by quarantine blocks.
+    pub quarantined: usize,
+}
+
+impl ScanReport {
+    /// Returns true if no corruption was found.
+    pub fn is_clean(&self) -> bool {
+        self.corrupted.is_empty()
+    }
+}
+
+/// An allocated block, as listed by `Memory::blocks`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Offset of the data of the block.
+    pub offset: Offset,
+    /// Size of the block, which is rounded up from the size it was allocated with.
+    pub size: usize,
+    /// Offset of the block it is linked to, if it was allocated with `allocate_more`.
+    pub parent: Option<Offset>,
+    /// Tag given to `allocate_tagged` or taken from the parent. Zero means untagged.
+    pub tag: u32,
+    /// Id of the process that allocated the block.
+    pub owner: u32,
+    /// Unix time in milliseconds after which `reap_expired` frees the block.
+    pub expires: Option<u64>,
+    /// Unix time in milliseconds at which the block was allocated, if the memory was
+    /// created with `MemoryBuilder::timestamps`.
+    pub created: Option<u64>,
 }
 
 pub struct Allocator<'a> {
@@ -18,51 +305,1322 @@ pub struct Allocator<'a> {
 }
 
 impl<'a> Allocator<'a> {
-    pub const MIN_SIZE: usize = BlockHeader::SIZE;
+    pub const MIN_SIZE: usize = HeapHeader::SIZE + BlockHeader::SIZE;
 
     pub fn new(memory: MemoryGuard<'a>) -> Self {
         Self { memory }
     }
 
+    /// Initializes the heap if no process did yet, or checks that this process can use it.
+    ///
+    /// Returns true if this process initialized it, or why it cannot use it.
+    pub fn attach(&self, options: &MemoryBuilder) -> Result<bool, String> {
+        let header = self.header();
+        if header.magic != MAGIC {
+            if !options.create {
+                return Err(format!("{} is not initialized", options.name));
+            }
+            header.handshake.init(options.protocol);
+            header.user_header = user_header_footprint(options.user_header);
+            header.flags = if options.journal { JOURNAL } else { 0 };
+            if options.secure_wipe {
+                header.flags |= SECURE_WIPE;
+            }
+            if options.timestamps {
+                header.flags |= TIMESTAMPS;
+            }
+            header.handles = 1;
+            header.op_log = options.op_log;
+            header.min_split = options.min_split;
+            header.high_reserve = options.high_priority_reserve;
+            header.emergency_reserve = options.emergency_reserve;
+            if let Some(log) = self.log() {
+                log.init(options.op_log);
+            }
+            self.create_bump(options.bump_region);
+            if let Some(log) = self.log() {
+                log.append(LogOp::Attach, 0, Offset(0), None);
+            }
+            header.magic = MAGIC;
+            return Ok(true);
+        }
+        // A different layout would misread the journal, so nothing is touched before this.
+        header.handshake.check(options.protocol)?;
+        if header.flags & JOURNAL != 0 {
+            header.journal.replay();
+        }
+        header.handshake.register(options.protocol);
+        header.handles += 1;
+        if let Some(log) = self.log() {
+            log.append(LogOp::Attach, 0, Offset(0), None);
+        }
+        Ok(false)
+    }
+
+    /// Closes a handle of `attach`, overwriting the whole memory after the lock if it was the
+    /// last one and the memory was created with `secure_wipe`.
+    pub fn detach(&self) {
+        if let Some(log) = self.log() {
+            log.append(LogOp::Detach, 0, Offset(0), None);
+        }
+        let header = self.header();
+        header.handles = header.handles.saturating_sub(1);
+        if header.handles == 0 && header.flags & SECURE_WIPE != 0 {
+            let (buffer, size) = (self.memory.buffer(), self.memory.size());
+            unsafe { buffer.write_bytes(0, size) };
+            // The bytes must be gone before the view is unmapped, even if nobody reads them.
+            std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.header().handshake
+    }
+
+    /// Releases the allocator, keeping the heap locked until the guard is dropped.
+    #[cfg(feature = "fault-injection")]
+    pub fn into_guard(self) -> MemoryGuard<'a> {
+        self.memory
+    }
+
+    /// Removes the block owning `data` from the list, leaving its children and the counters
+    /// alone, as a process dying halfway through freeing it would.
+    #[cfg(feature = "fault-injection")]
+    pub fn unlink_only(&self, data: *mut u8) -> bool {
+        let mut prev = self.head();
+        loop {
+            let block = unsafe { &mut *(prev as *mut BlockHeader) };
+            if block.next.is_null() {
+                return false;
+            }
+            if unsafe { block.next.add(BlockHeader::SIZE) } == data {
+                block.next = unsafe { &*(block.next as *mut BlockHeader) }.next;
+                return true;
+            }
+            prev = block.next;
+        }
+    }
+
+    /// Replaces the blocks with those of a snapshot of this memory at `path`.
+    ///
+    /// Only the application header, the blocks and their counters come from the snapshot.
+    /// The rest of the heap header belongs to the attached processes and stays, like the
+    /// handshake with its peers, the handle count, the shutdown state, deferred frees,
+    /// pauses, quotas and reserves, and so does the operation log.
+    ///
+    /// Fails if other handles are attached, whose blocks would change under them, or if the
+    /// snapshot is of a heap with another layout.
+    pub fn restore(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let header = self.header();
+        if header.handles > 1 {
+            return Err(format!(
+                "Cannot restore while {} other handles are attached",
+                header.handles - 1
+            )
+            .into());
+        }
+        let image = snapshot::read(&self.memory, path)?;
+        // SAFETY: The image is as large as the memory, which holds a heap header.
+        let saved = unsafe { ptr::read_unaligned(image.as_ptr() as *const HeapHeader) };
+        if saved.magic != MAGIC
+            || saved.flags != header.flags
+            || saved.user_header != header.user_header
+            || saved.op_log != header.op_log
+            || saved.bump != header.bump
+        {
+            return Err(format!("{} is a snapshot of another layout", path.display()).into());
+        }
+
+        let start = HeapHeader::SIZE;
+        let end = self.end() - self.memory.buffer() as usize;
+        // SAFETY: Both ranges lie within the memory and the image, which have the same size.
+        unsafe {
+            self.memory
+                .buffer()
+                .add(start)
+                .copy_from_nonoverlapping(image[start..end].as_ptr(), end - start)
+        };
+        header.used = saved.used;
+        header.blocks = saved.blocks;
+        header.peak_used = saved.peak_used;
+        header.peak_blocks = saved.peak_blocks;
+        Ok(())
+    }
+
+    /// Returns true if some process has written the heap header.
+    pub fn is_attached(&self) -> bool {
+        self.header().magic == MAGIC
+    }
+
     pub fn allocate(&self, size: usize) -> Option<*mut u8> {
-        let parent = ptr::null_mut();
-        allocate(self.memory.buffer(), self.memory.size(), size, parent)
+        self.allocate_block(size, ptr::null_mut(), 0, 0, WORD)
     }
 
+    /// Allocates a block whose data starts on a cache line and takes whole lines.
+    pub fn allocate_padded(&self, size: usize) -> Option<*mut u8> {
+        let size = align_up(size, CACHE_LINE);
+        self.allocate_block(size, ptr::null_mut(), 0, 0, CACHE_LINE)
+    }
+
+    /// Allocates a block whose data is aligned to `align`, a power of two.
+    #[cfg(feature = "rkyv")]
+    pub fn allocate_aligned(&self, size: usize, align: usize) -> Option<*mut u8> {
+        self.allocate_block(size, ptr::null_mut(), 0, 0, align)
+    }
+
+    /// Allocates a block that takes the tag of its parent.
     pub fn allocate_more(&self, size: usize, parent: *mut u8) -> Option<*mut u8> {
-        allocate(self.memory.buffer(), self.memory.size(), size, parent)
+        let tag = find_block(self.head(), parent)
+            .map_or(0, |block| unsafe { &*(block as *mut BlockHeader) }.tag);
+        self.allocate_block(size, parent, 0, tag, WORD)
+    }
+
+    /// Allocates a block that `reap_expired` frees once `expires` (unix millis) has passed.
+    pub fn allocate_with_ttl(&self, size: usize, expires: u64) -> Option<*mut u8> {
+        self.allocate_block(size, ptr::null_mut(), expires, 0, WORD)
+    }
+
+    /// Allocates a block of the given priority, which may take the reserve of the classes
+    /// below it.
+    pub fn allocate_with_priority(&self, size: usize, priority: Priority) -> Option<*mut u8> {
+        if self.is_paused() || self.quota_error(size).is_some() {
+            return None;
+        }
+        self.place_block(size, ptr::null_mut(), 0, 0, WORD, self.floor(priority))
+    }
+
+    /// Allocates a block that may take the emergency reserve, even while allocations are
+    /// paused.
+    pub fn allocate_critical(&self, size: usize) -> Option<*mut u8> {
+        if self.quota_error(size).is_some() {
+            return None;
+        }
+        self.place_block(size, ptr::null_mut(), 0, 0, WORD, 0)
+    }
+
+    /// Allocates a block whose bytes are accounted to `tag`.
+    pub fn allocate_tagged(&self, size: usize, tag: u32) -> Option<*mut u8> {
+        self.allocate_block(size, ptr::null_mut(), 0, tag, WORD)
+    }
+
+    /// Allocates a block of a transaction, tagged `PENDING_TAG` until `publish`.
+    pub fn allocate_pending(&self, size: usize, parent: *mut u8) -> Option<*mut u8> {
+        self.allocate_block(size, parent, 0, PENDING_TAG, WORD)
+    }
+
+    /// Gives the blocks of a committed transaction the tags they would have had without
+    /// it: the tag of their parent, or none. Parents come before their children.
+    pub fn publish(&self, blocks: &[*mut u8]) {
+        for &data in blocks {
+            let Some(current) = find_block(self.head(), data) else {
+                continue;
+            };
+            let block = unsafe { &mut *(current as *mut BlockHeader) };
+            block.tag = find_block(self.head(), block.parent)
+                .map_or(0, |parent| unsafe { &*(parent as *mut BlockHeader) }.tag);
+        }
     }
 
     pub fn deallocate(&self, buffer: *mut u8) -> bool {
-        let prev = self.memory.buffer();
-        let current = unsafe { prev.add(BlockHeader::SIZE) };
-        deallocate(prev, current, buffer, 0) > 0
+        self.deallocate_block(buffer) > 0
     }
-}
 
-fn allocate(buffer: *mut u8, buffer_len: usize, size: usize, parent: *mut u8) -> Option<*mut u8> {
-    let block = unsafe { &mut *(buffer as *mut BlockHeader) };
-    let block_size = BlockHeader::SIZE + block.size;
+    /// Frees each of the blocks with the blocks linked to them, as `deallocate` one after
+    /// the other would, in one pass over the list.
+    ///
+    /// Returns for each block whether it was freed; blocks that were not allocated, or were
+    /// freed with an earlier block of the list, are not.
+    pub fn deallocate_all(&self, buffers: &[*mut u8]) -> Vec<bool> {
+        let (allocated, children) = self.links();
+        let mut doomed = HashSet::new();
+        let freed = buffers
+            .iter()
+            .map(|&buffer| {
+                if !allocated.contains(&buffer) || !doomed.insert(buffer) {
+                    return false;
+                }
+                doomed.extend(children.get(&buffer).into_iter().flatten());
+                true
+            })
+            .collect();
+        self.deallocate_set(&doomed);
+        freed
+    }
 
-    // check the free space between this block and the next block or the end of the memory
-    let free_space = if block.next.is_null() {
-        buffer_len - block_size
-    } else {
-        block.next as usize - buffer as usize - block_size
-    };
+    /// Frees the block with every block linked to it, directly or through other blocks.
+    ///
+    /// Returns the number of blocks freed, zero if the block was not allocated.
+    pub fn deallocate_tree(&self, root: *mut u8) -> usize {
+        let (allocated, children) = self.links();
+        if !allocated.contains(&root) {
+            return 0;
+        }
+        let mut doomed = HashSet::from([root]);
+        let mut pending = vec![root];
+        while let Some(parent) = pending.pop() {
+            for &child in children.get(&parent).into_iter().flatten() {
+                if doomed.insert(child) {
+                    pending.push(child);
+                }
+            }
+        }
+        self.deallocate_set(&doomed)
+    }
 
-    // Initialize the new block and update the links.
-    if free_space >= BlockHeader::SIZE + size {
-        let new_buffer = unsafe { buffer.add(block_size) };
+    /// Trims the block at `data` to `size` bytes, rounded up to a word, so the rest of it is
+    /// free space again.
+    ///
+    /// Returns false if the block is not allocated, is not larger than that, or is the bump
+    /// region or a quarantine block.
+    pub fn shrink(&self, data: *mut u8, size: usize) -> bool {
+        let Some(current) = find_block(self.head(), data) else {
+            return false;
+        };
+        let block = unsafe { &mut *(current as *mut BlockHeader) };
+        let size = align_up(size.max(1), WORD);
+        if block.is_reserved() || size >= block.size {
+            return false;
+        }
+        let header = self.header();
+        let used = header.used - (footprint(block.size) - footprint(size));
+        let mut batch = Batch::default();
+        block.resize(&mut batch, size);
+        batch.set(&mut header.used, used);
+        self.commit(&batch);
+
+        if let Some(log) = self.log() {
+            let parent = (!block.parent.is_null()).then(|| self.offset_of(block.parent));
+            log.append(LogOp::Shrink, size, self.offset_of(data), parent);
+        }
+        true
+    }
+
+    /// Splits the block at `data` after `at` bytes, rounded up to a word, into two blocks.
+    /// The header of the second block goes right after the first, so the second block is
+    /// `BLOCK_HEADER_SIZE` bytes shorter than the rest.
+    ///
+    /// The second block takes the parent, tag, owner and expiry of the block; blocks linked
+    /// to the block stay with the first. Returns the data of the second block, or None if
+    /// the block is not allocated, is the bump region or a quarantine block, or the rest
+    /// would not hold a header and a word.
+    pub fn split(&self, data: *mut u8, at: usize) -> Option<*mut u8> {
+        let current = find_block(self.head(), data)?;
+        let block = unsafe { &mut *(current as *mut BlockHeader) };
+        let first = align_up(at.max(1), WORD);
+        let end = data as usize + block.size;
+        let second_header = current as usize + footprint(first);
+        let second_data = second_header + BlockHeader::SIZE;
+        if block.is_reserved() || second_data + WORD > end {
+            return None;
+        }
+
+        let second = second_header as *mut u8;
+        let size = end - second_data;
+        unsafe {
+            (second as *mut BlockHeader).write(BlockHeader {
+                size,
+                _size: High::default(),
+                next: block.next,
+                _next: High::default(),
+                parent: block.parent,
+                _parent: High::default(),
+                expires: block.expires,
+                tag: block.tag,
+                owner: block.owner,
+                align: WORD,
+                _align: High::default(),
+                canary: canary(size),
+                created: block.created,
+            })
+        };
+        // The headers take the padding of the block, so the used bytes stay the same.
+        let header = self.header();
+        let blocks = header.blocks + 1;
+        let mut batch = Batch::default();
+        block.resize(&mut batch, first);
+        batch.set(block.next_word(), second as usize);
+        batch.set(&mut header.blocks, blocks);
+        self.commit(&batch);
+        header.peak_blocks = header.peak_blocks.max(header.blocks);
+
+        let second_data = second_data as *mut u8;
+        if let Some(log) = self.log() {
+            let parent = (!block.parent.is_null()).then(|| self.offset_of(block.parent));
+            log.append(LogOp::Split, size, self.offset_of(second_data), parent);
+        }
+        Some(second_data)
+    }
+
+    /// Frees the blocks queued by `Memory::deallocate_deferred`, with their children.
+    ///
+    /// Returns the number of queued blocks that were freed.
+    pub fn free_deferred(&self) -> usize {
+        // SAFETY: The heap is attached while the allocator lives.
+        let word = unsafe { deferred_word(self.memory.buffer()) };
+        if word.load(SeqCst) == 0 {
+            return 0;
+        }
+        let mut offset = word.swap(0, SeqCst);
+        let mut freed = 0;
+        // A block queued twice would link the list into a loop.
+        let mut left = self.header().blocks;
+        while offset != 0 && left > 0 {
+            let data = unsafe { self.memory.buffer().add(offset - MemoryMutex::SIZE) };
+            let next = unsafe { (data as *const usize).read() };
+            if self.deallocate(data) {
+                freed += 1;
+            }
+            offset = next;
+            left -= 1;
+        }
+        freed
+    }
+
+    /// Returns the size of the allocated block that starts at `data`.
+    pub fn size_of(&self, data: *mut u8) -> Option<usize> {
+        let block = find_block(self.head(), data)?;
+        Some(unsafe { &*(block as *mut BlockHeader) }.size)
+    }
+
+    /// Returns the size of the block whose data starts at `data`, without searching for it.
+    ///
+    /// The block must be allocated.
+    pub fn block_size(&self, data: *mut u8) -> usize {
+        unsafe { &*(data.sub(BlockHeader::SIZE) as *const BlockHeader) }.size
+    }
+
+    /// Frees every block whose expiry is at or before `now` (unix millis), along with its
+    /// children. Returns the number of blocks freed.
+    pub fn reap_expired(&self, now: u64) -> usize {
+        let mut reaped = 0;
+        while let Some(data) = find_expired(self.head(), now) {
+            reaped += self.deallocate_block(data);
+        }
+        reaped
+    }
+
+    /// Returns the bytes taken by allocated blocks, counting their headers and padding.
+    pub fn used(&self) -> usize {
+        self.header().used
+    }
+
+    /// Returns the current and peak use of the heap.
+    pub fn usage(&self) -> Usage {
+        let header = self.header();
+        Usage {
+            used: header.used,
+            capacity: self.capacity(),
+            blocks: header.blocks,
+            peak_used: header.peak_used,
+            peak_blocks: header.peak_blocks,
+        }
+    }
+
+    /// Lists all allocated blocks in heap order.
+    pub fn blocks(&self) -> Vec<BlockInfo> {
+        let mut blocks = Vec::new();
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            blocks.push(BlockInfo {
+                offset: self.offset_of(unsafe { current.add(BlockHeader::SIZE) }),
+                size: block.size,
+                parent: (!block.parent.is_null()).then(|| self.offset_of(block.parent)),
+                tag: block.tag,
+                owner: block.owner,
+                expires: (block.expires != 0).then_some(block.expires),
+                created: (block.created != 0).then_some(block.created),
+            });
+            current = block.next;
+        }
+        blocks
+    }
+
+    /// Lists the free ranges between blocks in heap order, as their offsets and byte lengths.
+    ///
+    /// A range has to hold a block header besides the data of a block that goes into it.
+    pub fn gaps(&self) -> Vec<(Offset, usize)> {
+        let end = self.end();
+        let mut gaps = Vec::new();
+        let mut current = self.head();
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            let start = current as usize + footprint(block.size);
+            let next = if block.next.is_null() {
+                end
+            } else {
+                block.next as usize
+            };
+            if next > start {
+                gaps.push((self.offset_of(start as *mut u8), next - start));
+            }
+            current = block.next;
+        }
+        gaps
+    }
+
+    /// Sums up the bytes taken by blocks, by tag and by owning process.
+    pub fn breakdown(&self) -> UsageBreakdown {
+        let mut by_tag = BTreeMap::new();
+        let mut by_owner = BTreeMap::new();
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            *by_tag.entry(block.tag).or_insert(0) += footprint(block.size);
+            *by_owner.entry(block.owner).or_insert(0) += footprint(block.size);
+            current = block.next;
+        }
+        UsageBreakdown { by_tag, by_owner }
+    }
+
+    /// Starts tracking the peaks afresh from the current use.
+    pub fn reset_peak(&self) {
+        let header = self.header();
+        header.peak_used = header.used;
+        header.peak_blocks = header.blocks;
+    }
+
+    /// Returns the bytes that blocks can take, counting their headers and padding.
+    pub fn capacity(&self) -> usize {
+        self.len() - BlockHeader::SIZE
+    }
+
+    /// Returns the quota of the process `owner`, if it has one.
+    pub fn owner_quota(&self, owner: u32) -> Option<usize> {
+        self.header()
+            .quotas
+            .iter()
+            .find(|quota| quota.owner == owner)
+            .map(|quota| quota.limit as usize)
+    }
+
+    /// Sets or clears the quota of the process `owner`.
+    ///
+    /// Returns false if `OWNER_QUOTAS` other processes have one already.
+    pub fn set_owner_quota(&self, owner: u32, limit: Option<usize>) -> bool {
+        let quotas = &mut self.header().quotas;
+        let slot = quotas
+            .iter()
+            .position(|quota| quota.owner == owner)
+            .or_else(|| quotas.iter().position(|quota| quota.owner == 0));
+        match (slot, limit) {
+            (Some(slot), Some(limit)) => {
+                quotas[slot].owner = owner;
+                quotas[slot].limit = limit as u64;
+                true
+            }
+            (Some(slot), None) => {
+                if quotas[slot].owner == owner {
+                    quotas[slot].owner = 0;
+                }
+                true
+            }
+            (None, limit) => limit.is_none(),
+        }
+    }
+
+    /// Returns the bytes taken by the blocks of the process `owner`, counting their headers
+    /// and padding.
+    pub fn owner_used(&self, owner: u32) -> usize {
+        let mut used = 0;
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            if block.owner == owner {
+                used += footprint(block.size);
+            }
+            current = block.next;
+        }
+        used
+    }
+
+    /// Checks that another block of `size` bytes linked to `parent` keeps the sizes of its
+    /// blocks within `quota`.
+    pub fn children_quota(
+        &self,
+        parent: *mut u8,
+        size: usize,
+        quota: usize,
+    ) -> Result<(), AllocError> {
+        let mut used = 0;
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            if block.parent == parent {
+                used += block.size;
+            }
+            current = block.next;
+        }
+        let left = quota.saturating_sub(used);
+        if align_up(size.max(1), WORD) <= left {
+            return Ok(());
+        }
+        Err(AllocError::QuotaExceeded {
+            requested: size,
+            quota,
+            used,
+            largest_hole: (left / WORD * WORD).min(largest_hole(self.head(), self.len())),
+        })
+    }
+
+    /// Returns the error of an allocation of `size` bytes that would take this process over
+    /// its quota, or None if it has no quota or stays within it.
+    fn quota_error(&self, size: usize) -> Option<AllocError> {
+        let quota = self.owner_quota(std::process::id())?;
+        let used = self.owner_used(std::process::id());
+        if used.saturating_add(footprint(align_up(size.max(1), WORD))) <= quota {
+            return None;
+        }
+        Some(AllocError::QuotaExceeded {
+            requested: size,
+            quota,
+            used,
+            largest_hole: fitting(quota.saturating_sub(used))
+                .min(largest_hole(self.head(), self.len())),
+        })
+    }
+
+    /// Explains why an allocation of `size` bytes failed.
+    pub fn alloc_error(&self, size: usize) -> AllocError {
+        self.alloc_error_with_priority(size, Priority::Normal)
+    }
+
+    /// Explains why an allocation of `size` bytes and the given priority failed, counting
+    /// the reserves it may not take as used.
+    pub fn alloc_error_with_priority(&self, size: usize, priority: Priority) -> AllocError {
+        if self.is_paused() {
+            return AllocError::Paused { requested: size };
+        }
+        self.space_error(size, self.floor(priority))
+    }
+
+    /// Explains why an allocation of `allocate_critical` failed.
+    pub fn critical_alloc_error(&self, size: usize) -> AllocError {
+        self.space_error(size, 0)
+    }
+
+    /// Explains why an allocation of `size` bytes that has to leave `floor` bytes free
+    /// failed.
+    fn space_error(&self, size: usize, floor: usize) -> AllocError {
+        if let Some(error) = self.quota_error(size) {
+            return error;
+        }
+        let free = (self.capacity() - self.used()).saturating_sub(floor);
+        let largest_hole = largest_hole(self.head(), self.len()).min(fitting(free));
+        if footprint(size) <= free {
+            AllocError::Fragmented {
+                requested: size,
+                free,
+                largest_hole,
+            }
+        } else {
+            AllocError::Exhausted {
+                requested: size,
+                free,
+                largest_hole,
+            }
+        }
+    }
+
+    /// Returns the free bytes kept for `Priority::High`.
+    pub fn high_priority_reserve(&self) -> usize {
+        self.header().high_reserve
+    }
+
+    /// Changes the reserve for `Priority::High`, leaving allocated blocks alone.
+    pub fn set_high_priority_reserve(&self, bytes: usize) {
+        self.header().high_reserve = bytes;
+    }
+
+    /// Returns the free bytes kept for `allocate_critical`.
+    pub fn emergency_reserve(&self) -> usize {
+        self.header().emergency_reserve
+    }
+
+    /// Changes the emergency reserve, leaving allocated blocks alone.
+    pub fn set_emergency_reserve(&self, bytes: usize) {
+        self.header().emergency_reserve = bytes;
+    }
+
+    /// Makes allocations other than `allocate_critical` fail until as many `resume` calls.
+    pub fn pause(&self) {
+        self.header().paused += 1;
+    }
+
+    /// Ends a `pause`, or every pause if `all` is set.
+    pub fn resume(&self, all: bool) {
+        let header = self.header();
+        header.paused = if all {
+            0
+        } else {
+            header.paused.saturating_sub(1)
+        };
+    }
+
+    /// Returns true while allocations other than `allocate_critical` are paused.
+    pub fn is_paused(&self) -> bool {
+        self.header().paused != 0
+    }
+
+    /// Returns the free bytes an allocation of `priority` has to leave.
+    fn floor(&self, priority: Priority) -> usize {
+        let header = self.header();
+        match priority {
+            Priority::Normal => header.high_reserve.saturating_add(header.emergency_reserve),
+            Priority::High => header.emergency_reserve,
+        }
+    }
+
+    /// Returns the offset of the directory of named objects, zero if there is none.
+    pub fn directory(&self) -> usize {
+        self.header().directory
+    }
+
+    /// Records the offset of the directory unless another process did first.
+    ///
+    /// Returns the offset of the directory in use.
+    pub fn claim_directory(&self, offset: usize) -> usize {
+        let header = self.header();
+        if header.directory == 0 {
+            header.directory = offset;
+        }
+        header.directory
+    }
+
+    /// Slides blocks towards the start of the heap, so the free space is in one piece.
+    ///
+    /// The block at `pinned` and its children stay where they are, unless it is null. Moving
+    /// a block rewrites the parent links of its children, but not what is stored in blocks.
+    /// The copies are not journaled, so a process dying midway leaves the heap corrupted.
+    ///
+    /// Returns where the data of each moved block was and is now, in heap order.
+    pub fn compact(&self, pinned: *mut u8) -> Vec<(*mut u8, *mut u8)> {
+        let mut moves = Vec::new();
+        let mut prev = self.head();
+        loop {
+            let block = unsafe { &mut *(prev as *mut BlockHeader) };
+            if block.next.is_null() {
+                break;
+            }
+
+            let current = block.next;
+            let (size, parent, align) = {
+                let current = unsafe { &*(current as *mut BlockHeader) };
+                (current.size, current.parent, current.align)
+            };
+            let data = unsafe { current.add(BlockHeader::SIZE) };
+            let target = placement(prev as usize + footprint(block.size), align) as *mut u8;
+            let movable = pinned.is_null() || (data != pinned && parent != pinned);
+            if target == current || !movable {
+                prev = current;
+                continue;
+            }
+
+            // The target lies before the block, so copying forwards never reads what it wrote.
+            unsafe { ptr::copy(current, target, BlockHeader::SIZE + size) };
+            block.next = target;
+            moves.push((data, unsafe { target.add(BlockHeader::SIZE) }));
+            prev = target;
+        }
+
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &mut *(current as *mut BlockHeader) };
+            if let Ok(index) = moves.binary_search_by_key(&block.parent, |&(old, _)| old) {
+                block.parent = moves[index].1;
+            }
+            current = block.next;
+        }
+
+        // A move is logged as freeing the block where it was and allocating it where it is.
+        if let Some(log) = self.log() {
+            for &(old, new) in &moves {
+                let block = unsafe { &*(new.sub(BlockHeader::SIZE) as *mut BlockHeader) };
+                let parent = (!block.parent.is_null()).then(|| self.offset_of(block.parent));
+                log.append(LogOp::Deallocate, block.size, self.offset_of(old), None);
+                log.append(LogOp::Allocate, block.size, self.offset_of(new), parent);
+            }
+        }
+        moves
+    }
+
+    /// Frees every block at once and forgets the directory, keeping the heap flags.
+    ///
+    /// The data area is zeroed as well if `zero` is set, otherwise it keeps its old bytes.
+    pub fn reset(&self, zero: bool) {
+        let header = self.header();
+        let (magic, flags) = (header.magic, header.flags);
+        let (op_log, min_split) = (header.op_log, header.min_split);
+        let (bump_len, handshake) = (header.bump_len, header.handshake);
+        let (handles, shutdown) = (header.handles, header.shutdown);
+        let (user_header, quotas) = (header.user_header, header.quotas);
+        let (high_reserve, emergency_reserve) = (header.high_reserve, header.emergency_reserve);
+        let paused = header.paused;
+        unsafe { (header as *mut HeapHeader).write_bytes(0, 1) };
+        header.magic = magic;
+        header.handshake = handshake;
+        header.handles = handles;
+        header.shutdown = shutdown;
+        header.user_header = user_header;
+        header.quotas = quotas;
+        header.high_reserve = high_reserve;
+        header.emergency_reserve = emergency_reserve;
+        header.paused = paused;
+        header.flags = flags;
+        header.op_log = op_log;
+        header.min_split = min_split;
+
+        let zero = zero || flags & SECURE_WIPE != 0;
+        let len = if zero { self.len() } else { BlockHeader::SIZE };
+        unsafe { self.head().write_bytes(0, len) };
+        if let Some(log) = self.log() {
+            log.append(LogOp::Reset, 0, Offset(0), None);
+        }
+        // The empty heap puts the region back where it was.
+        self.create_bump(bump_len);
+    }
+
+    /// Allocates the bump region as the first block, where it never moves.
+    fn create_bump(&self, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let size = BumpHeader::SIZE + len;
+        // The region is part of the layout, so it may take the reserves and counts for no
+        // quota.
+        let data = self
+            .place_block(size, ptr::null_mut(), 0, BUMP_TAG, WORD, 0)
+            .expect("The memory was checked to fit the bump region");
+        unsafe {
+            // The region belongs to the heap rather than to the process that created it.
+            (*(data.sub(BlockHeader::SIZE) as *mut BlockHeader)).owner = 0;
+            (data as *mut BumpHeader).write(BumpHeader::new(len));
+        }
+        let header = self.header();
+        header.bump = self.offset_of(data).0;
+        header.bump_len = len;
+    }
+
+    /// Validates the header and canary of every block.
+    ///
+    /// A block is corrupted if its link, size or canary is off. The region from the end of
+    /// the last intact block up to the next intact header found in memory is reported, and
+    /// with `quarantine` it is covered by a block tagged `QUARANTINE_TAG`, which keeps it
+    /// from being handed out again. The blocks inside the region are lost.
+    pub fn scan(&self, quarantine: bool) -> ScanReport {
+        let mut report = ScanReport::default();
+        let end = self.end();
+        let mut prev = self.head();
+        loop {
+            let block = unsafe { &mut *(prev as *mut BlockHeader) };
+            if block.next.is_null() {
+                break;
+            }
+            let start = prev as usize + footprint(block.size);
+            if block.next as usize >= start && is_intact(block.next as usize, end) {
+                report.blocks_checked += 1;
+                prev = block.next;
+                continue;
+            }
+
+            let resume = (start..end.saturating_sub(BlockHeader::SIZE))
+                .step_by(BlockHeader::ALIGN)
+                .find(|&address| is_intact(address, end));
+            let region_end = resume.unwrap_or(end);
+            report
+                .corrupted
+                .push((self.offset_of(start as *mut u8), region_end - start));
+            if !quarantine {
+                match resume {
+                    Some(resume) => {
+                        report.blocks_checked += 1;
+                        prev = resume as *mut u8;
+                    }
+                    None => break,
+                }
+                continue;
+            }
+
+            let next = resume.map_or(ptr::null_mut(), |resume| resume as *mut u8);
+            let size = (region_end - start).saturating_sub(BlockHeader::SIZE);
+            if size == 0 {
+                // Too small to hold a block, so nothing can be allocated there anyway.
+                block.next = next;
+                continue;
+            }
+            let fence = start as *mut u8;
+            unsafe {
+                (fence as *mut BlockHeader).write(BlockHeader {
+                    size,
+                    _size: High::default(),
+                    next,
+                    _next: High::default(),
+                    parent: ptr::null_mut(),
+                    _parent: High::default(),
+                    expires: 0,
+                    tag: QUARANTINE_TAG,
+                    owner: 0,
+                    align: WORD,
+                    _align: High::default(),
+                    canary: canary(size),
+                    created: 0,
+                })
+            };
+            block.next = fence;
+            report.quarantined += 1;
+            prev = fence;
+        }
+
+        if report.quarantined > 0 {
+            self.recount();
+        }
+        report
+    }
+
+    /// Validates every block header and link, fixing what it can.
+    ///
+    /// A link that points outside the heap or into the previous block is cut, losing the
+    /// blocks after it. A block whose size does not fit its slot is removed. Children whose
+    /// parent is no longer allocated are freed, as deallocating the parent would have done.
+    pub fn check_and_repair(&self) -> RepairReport {
+        let mut report = RepairReport::default();
+        let header = self.header();
+        if header.magic == MAGIC && header.flags & JOURNAL != 0 {
+            report.journal_replayed = header.journal.replay();
+        }
+
+        let end = self.end();
+        let mut live = Vec::new();
+        let mut used = 0;
+        let mut prev = self.head();
+        // The sentinel never holds data.
+        unsafe { &mut *(prev as *mut BlockHeader) }.size = 0;
+
+        loop {
+            let block = unsafe { &mut *(prev as *mut BlockHeader) };
+            if block.next.is_null() {
+                break;
+            }
+
+            let block_end =
+                prev as usize + align_up(BlockHeader::SIZE + block.size, BlockHeader::ALIGN);
+            let next = block.next as usize;
+            if next < block_end
+                || !next.is_multiple_of(BlockHeader::ALIGN)
+                || next > end - BlockHeader::SIZE
+            {
+                block.next = ptr::null_mut();
+                report.links_truncated += 1;
+                break;
+            }
+
+            let next_block = unsafe { &*(block.next as *mut BlockHeader) };
+            let limit = if next_block.next.is_null() {
+                end
+            } else {
+                (next_block.next as usize).min(end)
+            };
+            let fits = next_block.size > 0
+                && next_block.size <= limit.saturating_sub(next + BlockHeader::SIZE);
+            if !fits {
+                block.next = next_block.next;
+                report.blocks_dropped += 1;
+                continue;
+            }
+
+            report.blocks_checked += 1;
+            used += footprint(next_block.size);
+            live.push(unsafe { block.next.add(BlockHeader::SIZE) });
+            prev = block.next;
+        }
+
+        // Blocks that were dropped or cut off above are still counted.
+        header.used = used;
+        header.blocks = live.len();
+        header.peak_used = header.peak_used.max(used);
+        header.peak_blocks = header.peak_blocks.max(live.len());
+
+        let orphans: Vec<*mut u8> = live
+            .iter()
+            .map(|data| unsafe { &*(data.sub(BlockHeader::SIZE) as *mut BlockHeader) }.parent)
+            .filter(|parent| !parent.is_null() && !live.contains(parent))
+            .collect();
+        for parent in orphans {
+            report.orphans_freed += self.deallocate_block(parent);
+        }
+
+        report
+    }
+
+    fn allocate_block(
+        &self,
+        size: usize,
+        parent: *mut u8,
+        expires: u64,
+        tag: u32,
+        align: usize,
+    ) -> Option<*mut u8> {
+        if self.is_paused() || self.quota_error(size).is_some() {
+            return None;
+        }
+        let floor = self.floor(Priority::Normal);
+        self.place_block(size, parent, expires, tag, align, floor)
+    }
+
+    /// Allocates a block that leaves at least `floor` bytes of the heap free, ignoring the
+    /// quotas.
+    fn place_block(
+        &self,
+        size: usize,
+        parent: *mut u8,
+        expires: u64,
+        tag: u32,
+        align: usize,
+        floor: usize,
+    ) -> Option<*mut u8> {
+        let len = self.len();
+        let mut size = align_up(size.max(1), WORD);
+        if (self.capacity() - self.used()).saturating_sub(footprint(size)) < floor {
+            return None;
+        }
+        let (prev, new_buffer) = find_free(self.head(), len, size, align)?;
+        let block = unsafe { &mut *(prev as *mut BlockHeader) };
         let new_block = unsafe { &mut *(new_buffer as *mut BlockHeader) };
-        let new_block_data = unsafe { new_buffer.add(BlockHeader::SIZE) };
 
+        // A hole left between the new block and the next one that is too small to be useful
+        // is given to the new block instead. The free space at the end stays in one piece.
+        if !block.next.is_null() {
+            let hole = block.next as usize - (new_buffer as usize + footprint(size));
+            if hole < self.header().min_split {
+                size += hole;
+            }
+        }
+
+        // The new header lies in free space, so it can be written before the block is linked.
         new_block.size = size;
         new_block.next = block.next;
         new_block.parent = parent;
+        new_block.expires = expires;
+        new_block.tag = tag;
+        new_block.owner = std::process::id();
+        new_block.align = align;
+        new_block.canary = canary(size);
+        new_block.created = if self.header().flags & TIMESTAMPS != 0 {
+            clock::unix_millis()
+        } else {
+            0
+        };
+
+        // The counters are committed along with the link, so they stay right if the process
+        // dies in between.
+        let header = self.header();
+        let (used, blocks) = (header.used + footprint(size), header.blocks + 1);
+        let mut batch = Batch::default();
+        batch.set(block.next_word(), new_buffer as usize);
+        batch.set(&mut header.used, used);
+        batch.set(&mut header.blocks, blocks);
+        self.commit(&batch);
+        header.peak_used = header.peak_used.max(header.used);
+        header.peak_blocks = header.peak_blocks.max(header.blocks);
 
-        block.next = new_buffer;
-        return Some(new_block_data);
+        let data = unsafe { new_buffer.add(BlockHeader::SIZE) };
+        if let Some(log) = self.log() {
+            let parent = (!parent.is_null()).then(|| self.offset_of(parent));
+            log.append(LogOp::Allocate, size, self.offset_of(data), parent);
+        }
+        Some(data)
+    }
+
+    fn deallocate_block(&self, data: *mut u8) -> usize {
+        let prev = self.head();
+        let current = unsafe { &*(prev as *mut BlockHeader) }.next;
+        let mut batch = Batch::default();
+        let mut freed = Vec::new();
+        unlink(prev, current, data, &mut batch, &mut freed);
+        self.release(batch, &freed)
+    }
+
+    /// Frees the blocks whose data is in `doomed` in one pass over the list.
+    ///
+    /// Returns the number of blocks freed.
+    fn deallocate_set(&self, doomed: &HashSet<*mut u8>) -> usize {
+        let mut batch = Batch::default();
+        let mut freed = Vec::new();
+        let mut prev = self.head();
+        let mut current = unsafe { &*(prev as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            if doomed.contains(&unsafe { current.add(BlockHeader::SIZE) }) {
+                let prev_block = unsafe { &mut *(prev as *mut BlockHeader) };
+                batch.set(prev_block.next_word(), block.next as usize);
+                freed.push(current);
+            } else {
+                prev = current;
+            }
+            current = block.next;
+        }
+        self.release(batch, &freed)
+    }
+
+    /// Commits the unlinking of the `freed` headers along with the counters.
+    ///
+    /// With the journal, an unlinking too large for one record is committed in steps of
+    /// whole blocks, each after the blocks linked to it, so a process dying in between
+    /// leaves the heap as a few separate deallocations would have, without orphans.
+    ///
+    /// Returns the number of blocks freed.
+    fn release(&self, batch: Batch, freed: &[*mut u8]) -> usize {
+        if freed.is_empty() {
+            return 0;
+        }
+        // Each step also writes the two counters.
+        let step = journal::CAPACITY - 2;
+        if self.header().flags & JOURNAL == 0 || batch.len() <= step {
+            self.release_step(batch, freed);
+        } else {
+            for blocks in children_first(freed).chunks(step) {
+                let batch = self.unlink_batch(&blocks.iter().copied().collect());
+                self.release_step(batch, blocks);
+            }
+        }
+        freed.len()
+    }
+
+    /// Commits `batch`, which unlinks the `freed` headers, and the counters without them.
+    fn release_step(&self, mut batch: Batch, freed: &[*mut u8]) {
+        let header = self.header();
+        let sizes: Vec<usize> = freed
+            .iter()
+            .map(|block| unsafe { &*(*block as *mut BlockHeader) }.size)
+            .collect();
+        let used = header.used - sizes.iter().map(|&size| footprint(size)).sum::<usize>();
+        let blocks = header.blocks - freed.len();
+        batch.set(&mut header.used, used);
+        batch.set(&mut header.blocks, blocks);
+        self.commit(&batch);
+
+        let log = self.log();
+        for (block, size) in freed.iter().zip(sizes) {
+            // Only the header is cleared while the lock is held, the data is cleared by the
+            // process that allocates it next, after releasing the lock.
+            unsafe { block.write_bytes(0, BlockHeader::SIZE) };
+            if let Some(log) = &log {
+                let data = unsafe { block.add(BlockHeader::SIZE) };
+                log.append(LogOp::Deallocate, size, self.offset_of(data), None);
+            }
+        }
+    }
+
+    /// Returns the link writes that take the `blocks` headers out of the list.
+    fn unlink_batch(&self, blocks: &HashSet<*mut u8>) -> Batch {
+        let mut batch = Batch::default();
+        let mut prev = self.head();
+        let mut current = unsafe { &*(prev as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            if blocks.contains(&current) {
+                let prev_block = unsafe { &mut *(prev as *mut BlockHeader) };
+                batch.set(prev_block.next_word(), block.next as usize);
+            } else {
+                prev = current;
+            }
+            current = block.next;
+        }
+        batch
+    }
+
+    /// Returns the data of the allocated blocks, and the data of the blocks linked to each
+    /// block by the data of the parent.
+    fn links(&self) -> (HashSet<*mut u8>, HashMap<*mut u8, Vec<*mut u8>>) {
+        let mut allocated = HashSet::new();
+        let mut children: HashMap<_, Vec<_>> = HashMap::new();
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            let data = unsafe { current.add(BlockHeader::SIZE) };
+            allocated.insert(data);
+            if !block.parent.is_null() {
+                children.entry(block.parent).or_default().push(data);
+            }
+            current = block.next;
+        }
+        (allocated, children)
+    }
+
+    /// Recomputes the used bytes and the block count from the block list.
+    fn recount(&self) {
+        let (mut used, mut blocks) = (0, 0);
+        let mut current = unsafe { &*(self.head() as *mut BlockHeader) }.next;
+        while !current.is_null() {
+            let block = unsafe { &*(current as *mut BlockHeader) };
+            used += footprint(block.size);
+            blocks += 1;
+            current = block.next;
+        }
+        let header = self.header();
+        header.used = used;
+        header.blocks = blocks;
+        header.peak_used = header.peak_used.max(used);
+        header.peak_blocks = header.peak_blocks.max(blocks);
+    }
+
+    fn commit(&self, batch: &Batch) {
+        let header = self.header();
+        if header.flags & JOURNAL != 0 {
+            header.journal.commit(batch);
+        } else {
+            batch.apply();
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn header(&self) -> &mut HeapHeader {
+        unsafe { &mut *(self.memory.buffer() as *mut HeapHeader) }
+    }
+
+    /// Returns the records of the operation log, oldest first.
+    pub fn op_log(&self) -> Vec<LogRecord> {
+        self.log().map_or_else(Vec::new, |log| log.read())
+    }
+
+    /// Returns the operation log, if the heap has one.
+    fn log(&self) -> Option<OpLog> {
+        let records = self.header().op_log;
+        (records > 0).then(|| unsafe { OpLog::new(self.end() as *mut u8) })
+    }
+
+    /// Returns the bytes from the sentinel block to the end of the heap, which stops short
+    /// of the operation log.
+    fn len(&self) -> usize {
+        self.end() - self.head() as usize
+    }
+
+    /// Returns the address just past the heap, where the operation log starts if there is one.
+    fn end(&self) -> usize {
+        let end = self.memory.buffer() as usize + self.memory.size();
+        match self.header().op_log {
+            0 => end,
+            records => (end - oplog::bytes(records)) & !(BlockHeader::ALIGN - 1),
+        }
+    }
+
+    /// Returns the offset of a pointer into the heap, counted from the lock word before it.
+    fn offset_of(&self, pointer: *mut u8) -> Offset {
+        Offset(pointer as usize - self.memory.buffer() as usize + MemoryMutex::SIZE)
+    }
+
+    /// The sentinel block that starts the block list.
+    fn head(&self) -> *mut u8 {
+        unsafe { self.user_header().0.add(self.header().user_header) }
+    }
+
+    /// Returns the start and length of the application header, which comes right after the
+    /// heap header and is not touched by resets.
+    pub fn user_header(&self) -> (*mut u8, usize) {
+        let start = unsafe { self.memory.buffer().add(HeapHeader::SIZE) };
+        (start, self.header().user_header)
+    }
+}
+
+/// Returns the bytes an application header of `len` bytes takes before the heap.
+pub(crate) const fn user_header_footprint(len: usize) -> usize {
+    align_up(len, BlockHeader::ALIGN)
+}
+
+/// Returns the bytes a bump region of `len` bytes takes out of the heap.
+pub(crate) fn bump_footprint(len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    footprint(BumpHeader::SIZE + len)
+}
+
+/// Returns the offset of the bump region of the heap at `heap`, without taking the lock.
+///
+/// # Safety
+/// `heap` must point to an attached heap, past its lock word.
+pub(crate) unsafe fn bump_offset(heap: *mut u8) -> Option<Offset> {
+    let offset = (*(heap as *const HeapHeader)).bump;
+    (offset != 0).then_some(Offset(offset))
+}
+
+/// Returns the shutdown word of the heap, which is read and written without the lock.
+///
+/// # Safety
+/// `heap` must point to an attached heap, past its lock word, that outlives `'a`.
+pub(crate) unsafe fn shutdown_word<'a>(heap: *mut u8) -> &'a AtomicUsize {
+    &*(ptr::addr_of!((*(heap as *const HeapHeader)).shutdown) as *const AtomicUsize)
+}
+
+/// Returns the head of the queue of `Memory::deallocate_deferred`, which is pushed to
+/// without the lock.
+///
+/// # Safety
+/// `heap` must point to an attached heap, past its lock word, that outlives `'a`.
+pub(crate) unsafe fn deferred_word<'a>(heap: *mut u8) -> &'a AtomicUsize {
+    &*(ptr::addr_of!((*(heap as *const HeapHeader)).deferred) as *const AtomicUsize)
+}
+
+/// Returns the bytes a block of `size` bytes takes, counting its header and padding.
+fn footprint(size: usize) -> usize {
+    align_up(BlockHeader::SIZE + size, BlockHeader::ALIGN)
+}
+
+/// Returns the largest block whose header and padding fit in `bytes`.
+fn fitting(bytes: usize) -> usize {
+    let bytes = bytes / BlockHeader::ALIGN * BlockHeader::ALIGN;
+    bytes.saturating_sub(BlockHeader::SIZE) / WORD * WORD
+}
+
+/// Returns the first address at or after `address` where a header can go so that the data
+/// after it is aligned to `align`.
+fn placement(address: usize, align: usize) -> usize {
+    align_up(address + BlockHeader::SIZE, align.max(BlockHeader::ALIGN)) - BlockHeader::SIZE
+}
+
+/// Returns true if a valid block header starts at `address`, judged by its size, canary and
+/// link, without trusting anything else in the heap.
+fn is_intact(address: usize, end: usize) -> bool {
+    if !address.is_multiple_of(BlockHeader::ALIGN) || address > end - BlockHeader::SIZE {
+        return false;
+    }
+    let block = unsafe { &*(address as *const BlockHeader) };
+    let limit = if block.next.is_null() {
+        end
+    } else {
+        block.next as usize
+    };
+    let next_valid = block.next.is_null()
+        || (limit.is_multiple_of(BlockHeader::ALIGN)
+            && limit >= address + footprint(block.size)
+            && limit <= end - BlockHeader::SIZE);
+    block.size > 0
+        && block.canary == canary(block.size)
+        && block.size <= end - address - BlockHeader::SIZE
+        && next_valid
+}
+
+/// Finds the first block followed by enough free space and returns it with the address at
+/// which the new block fits, so that its data is aligned to `align`.
+fn find_free(
+    buffer: *mut u8,
+    buffer_len: usize,
+    size: usize,
+    align: usize,
+) -> Option<(*mut u8, *mut u8)> {
+    let block = unsafe { &*(buffer as *mut BlockHeader) };
+    // The new header goes after this block, where the data behind it ends up aligned.
+    let block_size = placement(buffer as usize + footprint(block.size), align) - buffer as usize;
+
+    // check the free space between this block and the next block or the end of the memory
+    let free_space = if block.next.is_null() {
+        buffer_len.saturating_sub(block_size)
+    } else {
+        (block.next as usize - buffer as usize).saturating_sub(block_size)
+    };
+
+    if free_space >= BlockHeader::SIZE + size {
+        return Some((buffer, unsafe { buffer.add(block_size) }));
     }
 
     if block.next.is_null() {
@@ -70,38 +1628,200 @@ fn allocate(buffer: *mut u8, buffer_len: usize, size: usize, parent: *mut u8) ->
     }
 
     let distance = block.next as usize - buffer as usize;
-    allocate(block.next, buffer_len - distance, size, parent)
+    find_free(block.next, buffer_len - distance, size, align)
+}
+
+/// Returns the size of the largest block that fits between the blocks.
+fn largest_hole(mut buffer: *mut u8, mut buffer_len: usize) -> usize {
+    let mut largest = 0;
+    loop {
+        let block = unsafe { &*(buffer as *mut BlockHeader) };
+        let block_size = align_up(BlockHeader::SIZE + block.size, BlockHeader::ALIGN);
+        let free_space = if block.next.is_null() {
+            buffer_len.saturating_sub(block_size)
+        } else {
+            (block.next as usize - buffer as usize).saturating_sub(block_size)
+        };
+        largest = largest.max(free_space.saturating_sub(BlockHeader::SIZE));
+
+        if block.next.is_null() {
+            return largest;
+        }
+        buffer_len -= block.next as usize - buffer as usize;
+        buffer = block.next;
+    }
 }
 
-fn deallocate(prev: *mut u8, current: *mut u8, data: *mut u8, deallocated: usize) -> usize {
+fn find_block(current: *mut u8, data: *mut u8) -> Option<*mut u8> {
     if current.is_null() {
-        return deallocated;
+        return None;
+    }
+
+    let block = unsafe { &*(current as *mut BlockHeader) };
+
+    if block.size > 0 && unsafe { current.add(BlockHeader::SIZE) } == data {
+        Some(current)
+    } else {
+        find_block(block.next, data)
+    }
+}
+
+fn find_expired(current: *mut u8, now: u64) -> Option<*mut u8> {
+    if current.is_null() {
+        return None;
+    }
+
+    let block = unsafe { &*(current as *mut BlockHeader) };
+
+    if block.size > 0 && block.expires != 0 && block.expires <= now {
+        Some(unsafe { current.add(BlockHeader::SIZE) })
+    } else {
+        find_expired(block.next, now)
+    }
+}
+
+pub(crate) const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Orders the `freed` headers so that every block comes after the blocks linked to it
+/// among them.
+fn children_first(freed: &[*mut u8]) -> Vec<*mut u8> {
+    let parents: HashMap<*mut u8, *mut u8> = freed
+        .iter()
+        .map(|&block| {
+            let data = unsafe { block.add(BlockHeader::SIZE) };
+            (data, unsafe { &*(block as *mut BlockHeader) }.parent)
+        })
+        .collect();
+    // The number of ancestors among the freed blocks, bounded in case the links loop.
+    let depth = |block: *mut u8| {
+        let mut parent = unsafe { &*(block as *mut BlockHeader) }.parent;
+        let mut depth = 0;
+        while let Some(&next) = parents.get(&parent) {
+            if depth == freed.len() {
+                break;
+            }
+            depth += 1;
+            parent = next;
+        }
+        depth
+    };
+    let mut ordered = freed.to_vec();
+    ordered.sort_by_cached_key(|&block| std::cmp::Reverse(depth(block)));
+    ordered
+}
+
+/// Plans the link updates that remove the block owning `data` and all of its children.
+///
+/// `prev` is the last block that stays in the list. The freed blocks are collected so they
+/// can be cleared once the new links are committed.
+fn unlink(
+    prev: *mut u8,
+    current: *mut u8,
+    data: *mut u8,
+    batch: &mut Batch,
+    freed: &mut Vec<*mut u8>,
+) {
+    if current.is_null() {
+        return;
     }
 
     let block = unsafe { &*(current as *mut BlockHeader) };
     let block_data = unsafe { current.add(BlockHeader::SIZE) };
 
     if block.size > 0 && (block_data == data || block.parent == data) {
-        let next = block.next;
-        unsafe { &mut *(prev as *mut BlockHeader) }.next = block.next;
-        unsafe { current.write_bytes(0, BlockHeader::SIZE + block.size) };
+        let prev_block = unsafe { &mut *(prev as *mut BlockHeader) };
+        batch.set(prev_block.next_word(), block.next as usize);
+        freed.push(current);
 
-        deallocate(prev, next, data, deallocated + 1)
+        unlink(prev, block.next, data, batch, freed)
     } else {
-        deallocate(current, block.next, data, deallocated)
+        unlink(current, block.next, data, batch, freed)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use crate::mutex::MemoryMutex;
 
     use super::*;
     use std::alloc::{alloc_zeroed, Layout};
 
+    #[cfg(feature = "wide-layout")]
+    #[test]
+    fn test_wide_layout() {
+        use std::mem::offset_of;
+
+        assert_eq!(offset_of!(HeapHeader, handshake), 8, "The magic is 64-bit");
+        assert_eq!(
+            offset_of!(BlockHeader, expires),
+            24,
+            "The pointers are 64-bit"
+        );
+        assert_eq!(
+            offset_of!(BlockHeader, canary),
+            48,
+            "The alignment is 64-bit"
+        );
+        assert_eq!(BlockHeader::SIZE, 64, "Headers are as large as in 64-bit");
+    }
+
+    #[cfg(any(target_pointer_width = "64", feature = "wide-layout"))]
+    #[test]
+    fn test_c_header() {
+        let header = include_str!("../include/rshmem.h");
+        let define = |name: &str| {
+            let value = header
+                .lines()
+                .find_map(|line| line.strip_prefix("#define ")?.strip_prefix(name))
+                .unwrap()
+                .trim()
+                .trim_end_matches(['U', 'L']);
+            match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).unwrap(),
+                None => value.parse().unwrap(),
+            }
+        };
+
+        assert_eq!(
+            define("RSHMEM_LAYOUT_VERSION"),
+            crate::handshake::LAYOUT_VERSION as u64,
+            "The C header has the layout version"
+        );
+        assert_eq!(
+            define("RSHMEM_CANARY"),
+            CANARY,
+            "The C header has the canary"
+        );
+        assert_eq!(
+            define("RSHMEM_BLOCK_HEADER_SIZE"),
+            BlockHeader::SIZE as u64,
+            "The C header has the header size"
+        );
+        assert_eq!(
+            define("RSHMEM_QUARANTINE_TAG"),
+            QUARANTINE_TAG as u64,
+            "The C header has the quarantine tag"
+        );
+        assert_eq!(
+            define("RSHMEM_BUMP_TAG"),
+            BUMP_TAG as u64,
+            "The C header has the bump tag"
+        );
+        assert_eq!(
+            define("RSHMEM_PENDING_TAG"),
+            PENDING_TAG as u64,
+            "The C header has the pending tag"
+        );
+    }
+
+    /// Creates an allocator over a zeroed buffer with room for five block headers.
     fn create_allocator<'a>() -> Allocator<'a> {
-        let buffer = unsafe { alloc_zeroed(Layout::array::<u8>(100).unwrap()) };
-        let mutex = unsafe { MemoryMutex::new(buffer, 100) };
+        let size = MemoryMutex::SIZE + Allocator::MIN_SIZE + 5 * BlockHeader::SIZE;
+        let buffer = unsafe { alloc_zeroed(Layout::array::<u8>(size).unwrap()) };
+        let mutex = unsafe { MemoryMutex::new(buffer, size) };
         let lock = mutex.lock();
         Allocator::new(lock)
     }
@@ -114,10 +1834,24 @@ mod tests {
         assert_eq!(data.is_some(), true, "The result should be Some(*mut u8)");
         assert_eq!(data.unwrap().is_null(), false, "Pointer must not be null");
 
-        let data = allocator.allocate(100);
+        let data = allocator.allocate(4 * BlockHeader::SIZE);
         assert!(data.is_none(), "Result should be None");
     }
 
+    #[test]
+    fn test_allocate_zero() {
+        let allocator = create_allocator();
+
+        let data = allocator.allocate(0).unwrap();
+        let next = allocator.allocate(8).unwrap();
+        assert_eq!(allocator.blocks()[0].size, WORD, "Empty blocks take a word");
+        assert!(allocator.scan(false).is_clean(), "The block is intact");
+
+        assert!(allocator.deallocate(data), "The block can be freed");
+        assert_eq!(allocator.usage().blocks, 1, "Only the next block is left");
+        assert!(allocator.deallocate(next), "The next block is intact");
+    }
+
     #[test]
     fn test_allocate_more() {
         let allocator = create_allocator();
@@ -204,4 +1938,405 @@ mod tests {
             "Result should be false because the parent was deallocated"
         );
     }
+
+    #[test]
+    fn test_deallocate_after_first_block() {
+        let allocator = create_allocator();
+
+        let first = allocator.allocate(4).unwrap();
+        let second = allocator.allocate(4).unwrap();
+
+        assert!(allocator.deallocate(first), "The first block is allocated");
+        assert!(
+            allocator.deallocate(second),
+            "Result should be true because the second block is still allocated"
+        );
+    }
+
+    #[test]
+    fn test_reap_expired() {
+        let allocator = create_allocator();
+
+        let expired = allocator.allocate_with_ttl(4, 10).unwrap();
+        let child = allocator.allocate_more(4, expired).unwrap();
+        let alive = allocator.allocate_with_ttl(4, 30).unwrap();
+
+        assert_eq!(
+            allocator.reap_expired(20),
+            2,
+            "The expired block and its child should be reaped"
+        );
+        assert!(!allocator.deallocate(child), "The child was reaped");
+        assert!(allocator.deallocate(alive), "The block has not expired yet");
+    }
+
+    #[test]
+    fn test_journaled_deallocate() {
+        let allocator = create_allocator();
+        allocator
+            .attach(&MemoryBuilder::new("", 0).journal(true))
+            .unwrap();
+
+        let parent = allocator.allocate(4).unwrap();
+        let child = allocator.allocate_more(4, parent).unwrap();
+        let other = allocator.allocate(4).unwrap();
+
+        assert!(allocator.deallocate(parent), "The parent is allocated");
+        assert!(
+            !allocator.deallocate(child),
+            "The child was freed with the parent"
+        );
+        assert!(
+            allocator.deallocate(other),
+            "Unrelated blocks stay allocated"
+        );
+    }
+
+    #[test]
+    fn test_journaled_shrink() {
+        let allocator = create_allocator();
+        allocator
+            .attach(&MemoryBuilder::new("", 0).journal(true))
+            .unwrap();
+
+        let data = allocator.allocate(64).unwrap();
+        let used = allocator.used();
+        assert!(allocator.shrink(data, 20), "The block is trimmed");
+        assert_eq!(allocator.used(), used - 40, "The tail is free again");
+        assert!(allocator.scan(false).is_clean(), "The canary was updated");
+
+        for tag in [BUMP_TAG, QUARANTINE_TAG] {
+            unsafe { (*(data.sub(BlockHeader::SIZE) as *mut BlockHeader)).tag = tag };
+            assert!(
+                !allocator.shrink(data, 8),
+                "Blocks of the heap are not trimmed"
+            );
+        }
+        assert_eq!(allocator.used(), used - 40, "Nothing else was freed");
+    }
+
+    #[test]
+    fn test_journaled_split() {
+        let size = MemoryMutex::SIZE + Allocator::MIN_SIZE + 8 * BlockHeader::SIZE;
+        let buffer = unsafe { alloc_zeroed(Layout::array::<u8>(size).unwrap()) };
+        let mutex = unsafe { MemoryMutex::new(buffer, size) };
+        let allocator = Allocator::new(mutex.lock());
+        allocator
+            .attach(&MemoryBuilder::new("", 0).journal(true))
+            .unwrap();
+
+        let data = allocator.allocate(16 + 4 * BlockHeader::SIZE).unwrap();
+        unsafe { (*(data.sub(BlockHeader::SIZE) as *mut BlockHeader)).tag = 7 };
+        let second = allocator.split(data, 16).unwrap();
+        assert!(allocator.scan(false).is_clean(), "Both canaries are valid");
+        let blocks = allocator.blocks();
+        assert_eq!(blocks.len(), 2, "The block was split in two");
+        assert_eq!(blocks[1].tag, 7, "The second block takes the tag");
+        assert_eq!(blocks[1].owner, blocks[0].owner, "And the owner");
+
+        for tag in [BUMP_TAG, QUARANTINE_TAG] {
+            unsafe { (*(second.sub(BlockHeader::SIZE) as *mut BlockHeader)).tag = tag };
+            assert!(
+                allocator.split(second, 8).is_none(),
+                "Blocks of the heap are not split"
+            );
+        }
+    }
+
+    #[test]
+    fn test_journaled_deallocate_in_steps() {
+        let size = MemoryMutex::SIZE + Allocator::MIN_SIZE + 32 * BlockHeader::SIZE;
+        let buffer = unsafe { alloc_zeroed(Layout::array::<u8>(size).unwrap()) };
+        let mutex = unsafe { MemoryMutex::new(buffer, size) };
+        let allocator = Allocator::new(mutex.lock());
+        allocator
+            .attach(&MemoryBuilder::new("", 0).journal(true))
+            .unwrap();
+
+        // Every child is followed by an unrelated block, so each takes its own link write.
+        let parent = allocator.allocate(8).unwrap();
+        let mut others = Vec::new();
+        let mut first_child = ptr::null_mut();
+        for _ in 0..12 {
+            first_child = allocator.allocate_more(8, parent).unwrap();
+            others.push(allocator.allocate(8).unwrap());
+        }
+        let grandchild = allocator.allocate_more(8, first_child).unwrap();
+        assert_eq!(
+            allocator.deallocate_tree(parent),
+            14,
+            "The tree takes more than one journal record"
+        );
+
+        let usage = allocator.usage();
+        assert_eq!(
+            usage.blocks, 12,
+            "The counters were committed with each step"
+        );
+        assert_eq!(usage.used, 12 * footprint(8), "Only the others are counted");
+        assert!(
+            !allocator.deallocate(grandchild),
+            "The grandchild went with the tree"
+        );
+        assert!(
+            allocator.check_and_repair().is_clean(),
+            "No orphans are left"
+        );
+        for other in others {
+            assert!(
+                allocator.deallocate(other),
+                "Unrelated blocks stay allocated"
+            );
+        }
+    }
+
+    #[test]
+    fn test_min_split() {
+        let allocator = create_allocator();
+        allocator.attach(&MemoryBuilder::new("", 0)).unwrap();
+
+        let first = allocator.allocate(5).unwrap();
+        assert_eq!(
+            allocator.size_of(first),
+            Some(8),
+            "Sizes are rounded to words"
+        );
+
+        let hole = allocator.allocate(64).unwrap();
+        allocator.allocate(8).unwrap();
+        allocator.deallocate(hole);
+
+        let filler = allocator.allocate(56).unwrap();
+        assert_eq!(filler, hole, "The block goes into the hole");
+        assert_eq!(
+            allocator.size_of(filler),
+            Some(64),
+            "The sliver left over goes to the block"
+        );
+    }
+
+    #[test]
+    fn test_allocate_padded() {
+        let size = MemoryMutex::SIZE + Allocator::MIN_SIZE + 8 * BlockHeader::SIZE;
+        let buffer = unsafe { alloc_zeroed(Layout::from_size_align(size, 64).unwrap()) };
+        let mutex = unsafe { MemoryMutex::new(buffer, size) };
+        let allocator = Allocator::new(mutex.lock());
+
+        let first = allocator.allocate(8).unwrap();
+        let padded = allocator.allocate_padded(4).unwrap();
+        assert_eq!(padded as usize % CACHE_LINE, 0, "The data starts a line");
+        assert_eq!(
+            allocator.size_of(padded),
+            Some(CACHE_LINE),
+            "It takes a line"
+        );
+
+        allocator.deallocate(first);
+        let moves = allocator.compact(ptr::null_mut());
+        let (_, moved) = moves[0];
+        assert_eq!(
+            moved as usize % CACHE_LINE,
+            0,
+            "Compaction keeps it aligned"
+        );
+    }
+
+    #[test]
+    fn test_alloc_error() {
+        let allocator = create_allocator();
+        let first = allocator.allocate(16).unwrap();
+        let _second = allocator.allocate(16).unwrap();
+        allocator.deallocate(first);
+
+        let hole = allocator.alloc_error(usize::MAX / 2);
+        assert!(!hole.is_fragmented(), "No compaction makes room for that");
+        let largest = hole.largest_hole();
+        assert!(
+            allocator.allocate(largest + 1).is_none(),
+            "Nothing larger fits"
+        );
+
+        // The freed gap and the tail together would fit a block that neither fits alone.
+        let free = match hole {
+            AllocError::Exhausted { free, .. } => free,
+            _ => unreachable!(),
+        };
+        let size = free - BlockHeader::SIZE - BlockHeader::ALIGN;
+        assert!(size > largest, "The free bytes are split up");
+        assert!(
+            allocator.alloc_error(size).is_fragmented(),
+            "The gaps are too small, but not the free bytes"
+        );
+        assert!(
+            allocator.allocate(largest).is_some(),
+            "The largest hole fits"
+        );
+    }
+
+    #[test]
+    fn test_peak_usage() {
+        let allocator = create_allocator();
+
+        let first = allocator.allocate(16).unwrap();
+        let second = allocator.allocate(16).unwrap();
+        allocator.deallocate(first);
+        let usage = allocator.usage();
+        assert_eq!(usage.blocks, 1, "One block is left");
+        assert_eq!(usage.peak_blocks, 2, "Two blocks were allocated at once");
+        assert_eq!(usage.peak_used, 2 * footprint(16), "Both blocks count");
+
+        allocator.reset_peak();
+        allocator.deallocate(second);
+        let usage = allocator.usage();
+        assert_eq!(usage.peak_blocks, 1, "The peak starts from the reset");
+        assert_eq!(
+            usage.peak_used,
+            footprint(16),
+            "The peak starts from the reset"
+        );
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let allocator = create_allocator();
+
+        let parent = allocator.allocate_tagged(8, 7).unwrap();
+        allocator.allocate_more(8, parent).unwrap();
+        allocator.allocate(8).unwrap();
+
+        let breakdown = allocator.breakdown();
+        assert_eq!(
+            breakdown.by_tag.get(&7),
+            Some(&(2 * footprint(8))),
+            "The child takes the tag of its parent"
+        );
+        assert_eq!(breakdown.by_tag.get(&0), Some(&footprint(8)), "Untagged");
+        assert_eq!(
+            breakdown.by_owner.get(&std::process::id()),
+            Some(&allocator.used()),
+            "This process allocated everything"
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let allocator = create_allocator();
+
+        let parent = allocator.allocate(8).unwrap();
+        allocator.allocate_more(8, parent).unwrap();
+        unsafe { parent.write_bytes(7, 8) };
+        allocator.reset(true);
+
+        assert_eq!(allocator.usage().blocks, 0, "Every block is gone");
+        assert_eq!(allocator.used(), 0, "Nothing is used");
+        assert_eq!(unsafe { *parent }, 0, "The data was zeroed");
+        assert!(
+            allocator.allocate(100).is_some(),
+            "The whole heap is free again"
+        );
+    }
+
+    #[test]
+    fn test_scan_quarantine() {
+        let allocator = create_allocator();
+
+        let first = allocator.allocate(8).unwrap();
+        let second = allocator.allocate(8).unwrap();
+        let third = allocator.allocate(8).unwrap();
+        assert!(allocator.scan(false).is_clean(), "The heap is intact");
+
+        // Scribble over the header of the second block.
+        unsafe {
+            second
+                .sub(BlockHeader::SIZE)
+                .write_bytes(0xAB, BlockHeader::SIZE)
+        };
+        let report = allocator.scan(false);
+        assert_eq!(report.corrupted.len(), 1, "The second block is corrupted");
+        assert_eq!(report.blocks_checked, 2, "The others are intact");
+        assert_eq!(report.quarantined, 0, "Nothing is fenced off yet");
+
+        let report = allocator.scan(true);
+        assert_eq!(report.quarantined, 1, "The region is fenced off");
+        assert!(
+            allocator.scan(false).is_clean(),
+            "The quarantine block is intact"
+        );
+        let fence = allocator
+            .blocks()
+            .into_iter()
+            .find(|block| block.tag == QUARANTINE_TAG)
+            .unwrap();
+        assert_eq!(fence.size, 8, "It covers the old block");
+        assert!(allocator.deallocate(first), "The first block is usable");
+        assert!(allocator.deallocate(third), "The third block is usable");
+    }
+
+    #[test]
+    fn test_compact() {
+        let allocator = create_allocator();
+
+        let first = allocator.allocate(16).unwrap();
+        let parent = allocator.allocate(8).unwrap();
+        let child = allocator.allocate_more(8, parent).unwrap();
+        unsafe { parent.write_bytes(7, 8) };
+        allocator.deallocate(first);
+        assert!(
+            allocator.allocate(40).is_none(),
+            "The free space is split up"
+        );
+
+        let moves = allocator.compact(ptr::null_mut());
+        assert_eq!(moves.len(), 2, "Both remaining blocks slide down");
+        assert_eq!(moves[0].0, parent, "The parent comes first");
+        let parent = moves[0].1;
+        let child = moves
+            .iter()
+            .find(|&&(old, _)| old == child)
+            .map(|&(_, new)| new)
+            .unwrap();
+        assert_eq!(unsafe { *parent.add(7) }, 7, "The data moves along");
+        assert!(allocator.allocate(40).is_some(), "The free space is whole");
+
+        assert!(allocator.deallocate(parent), "The parent moved");
+        assert!(
+            !allocator.deallocate(child),
+            "The child still belongs to the parent"
+        );
+    }
+
+    #[test]
+    fn test_check_and_repair() {
+        let allocator = create_allocator();
+
+        let first = allocator.allocate(4).unwrap();
+        let parent = allocator.allocate(4).unwrap();
+        let child = allocator.allocate_more(4, parent).unwrap();
+        assert!(
+            allocator.check_and_repair().is_clean(),
+            "The heap is intact"
+        );
+
+        // Free the parent without its child, like a process dying midway would.
+        let parent_block = unsafe { &mut *(parent.sub(BlockHeader::SIZE) as *mut BlockHeader) };
+        let first_block = unsafe { &mut *(first.sub(BlockHeader::SIZE) as *mut BlockHeader) };
+        first_block.next = parent_block.next;
+
+        let report = allocator.check_and_repair();
+        assert_eq!(
+            report.orphans_freed, 1,
+            "The child of the freed parent should be freed"
+        );
+        assert!(!allocator.deallocate(child), "The orphan was already freed");
+
+        // Point the last block outside of the heap.
+        first_block.next = usize::MAX as *mut u8;
+        let report = allocator.check_and_repair();
+        assert_eq!(report.links_truncated, 1, "The broken link should be cut");
+        assert_eq!(report.blocks_checked, 1, "Only the first block is left");
+        assert!(
+            allocator.allocate(4).is_some(),
+            "The heap should be usable again"
+        );
+    }
 }
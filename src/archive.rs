@@ -0,0 +1,198 @@
+use std::{error::Error, slice};
+
+use rkyv::{
+    ser::{
+        serializers::{
+            AllocScratch, BufferSerializerError, CompositeSerializer, FallbackScratch, HeapScratch,
+            SharedSerializeMap,
+        },
+        Serializer,
+    },
+    validation::validators::DefaultValidator,
+    Archive, Archived, CheckBytes, Fallible,
+};
+
+use crate::{allocator::align_up, Memory, Offset};
+
+/// Alignment of archives, which rkyv needs for its widest primitives.
+const ALIGN: usize = 16;
+
+/// Bytes in front of an archive that hold its length.
+const HEADER: usize = align_up(std::mem::size_of::<usize>(), ALIGN);
+
+/// The serializer of `Memory::archive`.
+pub type ArchiveSerializer<'a> = CompositeSerializer<
+    BlockWriter<'a>,
+    FallbackScratch<HeapScratch<256>, AllocScratch>,
+    SharedSerializeMap,
+>;
+
+/// Writes an archive into a block, or only measures it if there is no block yet.
+#[derive(Default)]
+pub struct BlockWriter<'a> {
+    block: Option<&'a mut [u8]>,
+    pos: usize,
+}
+
+impl Fallible for BlockWriter<'_> {
+    type Error = BufferSerializerError;
+}
+
+impl Serializer for BlockWriter<'_> {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error> {
+        let end = self.pos + bytes.len();
+        if let Some(block) = &mut self.block {
+            let archive_len = block.len();
+            block
+                .get_mut(self.pos..end)
+                .ok_or(BufferSerializerError::Overflow {
+                    pos: self.pos,
+                    bytes_needed: bytes.len(),
+                    archive_len,
+                })?
+                .copy_from_slice(bytes);
+        }
+        self.pos = end;
+        Ok(())
+    }
+}
+
+impl Memory {
+    /// Archives a value with rkyv into a new allocation.
+    ///
+    /// Consumers access the archived value in place with `view_archived`, without
+    /// deserializing or copying it.
+    pub fn archive<T>(&self, value: &T) -> Result<Offset, Box<dyn Error>>
+    where
+        T: for<'a> rkyv::Serialize<ArchiveSerializer<'a>>,
+    {
+        // The value is measured first, so that it can be written straight into its block.
+        let len = serialize(value, None)?;
+
+        // Blocks may be larger than requested, and rkyv finds the root from the end of the
+        // bytes, so the length goes in front of them.
+        let data = self
+            .observe(HEADER + len, |allocator| {
+                allocator.allocate_aligned(HEADER + len, ALIGN)
+            })?
+            .as_ptr();
+        // SAFETY: The block was just allocated with room for the length and the bytes.
+        let block = unsafe { slice::from_raw_parts_mut(data.add(HEADER), len) };
+        let len = match serialize(value, Some(block)) {
+            Ok(len) => len,
+            Err(error) => {
+                self.deallocate(data);
+                return Err(error);
+            }
+        };
+        // SAFETY: See above.
+        unsafe { (data as *mut usize).write(len) };
+
+        Ok(self
+            .offset_of(data)
+            .expect("Allocations are inside the memory"))
+    }
+
+    /// Validates and returns an archived value written by `archive`.
+    ///
+    /// Archives are aligned to 16 bytes, so values whose archived form needs a larger
+    /// alignment fail validation.
+    ///
+    /// # Safety
+    /// The allocation must not be freed or written to while the returned reference is used,
+    /// by this or any other process.
+    pub unsafe fn view_archived<T>(&self, offset: Offset) -> Result<&Archived<T>, Box<dyn Error>>
+    where
+        T: Archive,
+        T::Archived: for<'a> CheckBytes<DefaultValidator<'a>>,
+    {
+        let data = self.pointer(offset).ok_or("Offset is out of bounds")?;
+        let size = self
+            .allocation_size(data)
+            .ok_or("There is no allocation at the offset")?;
+        let len = (data as *const usize).read();
+        if len > size - HEADER {
+            return Err("The allocation is not an archive".into());
+        }
+
+        let slice = std::slice::from_raw_parts(data.add(HEADER), len);
+        rkyv::check_archived_root::<T>(slice).map_err(|e| e.to_string().into())
+    }
+}
+
+/// Serializes a value into `block`, and returns the length of its archive.
+fn serialize<T>(value: &T, block: Option<&mut [u8]>) -> Result<usize, Box<dyn Error>>
+where
+    T: for<'a> rkyv::Serialize<ArchiveSerializer<'a>>,
+{
+    let writer = BlockWriter { block, pos: 0 };
+    let mut serializer = ArchiveSerializer::new(writer, Default::default(), Default::default());
+    serializer.serialize_value(value)?;
+    Ok(serializer.pos())
+}
+
+#[cfg(test)]
+mod tests {
+    use rkyv::{Archive, Serialize};
+
+    use super::{ALIGN, HEADER};
+    use crate::Memory;
+
+    #[derive(Archive, Serialize)]
+    #[archive(check_bytes)]
+    struct Message {
+        id: u32,
+        text: String,
+    }
+
+    #[test]
+    fn test_archive_and_view() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let message = Message {
+            id: 7,
+            text: "hello".to_owned(),
+        };
+
+        let offset = memory.archive(&message).unwrap();
+        let archived = unsafe { memory.view_archived::<Message>(offset) }.unwrap();
+        assert_eq!(archived.id, 7, "The archived id should match");
+        assert_eq!(archived.text, "hello", "The archived text should match");
+    }
+
+    #[derive(Archive, Serialize)]
+    #[archive(check_bytes)]
+    struct Wide {
+        value: u128,
+        text: String,
+    }
+
+    #[test]
+    fn test_archive_is_aligned() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        // Moves the next block off a 16-byte boundary.
+        memory.allocate(8).unwrap();
+        let wide = Wide {
+            value: u128::MAX - 1,
+            text: "wide".to_owned(),
+        };
+
+        let offset = memory.archive(&wide).unwrap();
+        let data = memory.pointer(offset).unwrap();
+        assert_eq!(
+            (data as usize + HEADER) % ALIGN,
+            0,
+            "The archive should be aligned"
+        );
+        let archived = unsafe { memory.view_archived::<Wide>(offset) }.unwrap();
+        assert_eq!(
+            archived.value,
+            u128::MAX - 1,
+            "The archived value should match"
+        );
+        assert_eq!(archived.text, "wide", "The archived text should match");
+    }
+}
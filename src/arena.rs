@@ -0,0 +1,179 @@
+use std::ptr::NonNull;
+
+use crate::{
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    AllocError, BlockInfo, Memory, Offset,
+};
+
+/// A named group of allocations that is freed as a whole, e.g. everything provisioned for
+/// one connected client.
+///
+/// The arena is a small root block registered in the directory of the memory. Its
+/// allocations are linked to the root like blocks of `Memory::allocate_more`, so
+/// `Memory::destroy_arena` frees them along with it. Blocks allocated with `allocate_more`
+/// under an allocation of the arena are not freed with it.
+///
+/// The root block holds the quota of the arena, zero for none.
+pub struct ShmArena<'a> {
+    memory: &'a Memory,
+    root: *mut u8,
+}
+
+impl<'a> ShmArena<'a> {
+    /// Allocates a zeroed block in the arena.
+    ///
+    /// Fails with `AllocError::QuotaExceeded` if the block would take `used` over the quota
+    /// of the arena.
+    pub fn allocate(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        let quota = self.quota();
+        self.memory.uncache(self.root);
+        self.memory.observe_with(size, |allocator| {
+            if let Some(quota) = quota {
+                allocator.children_quota(self.root, size, quota)?;
+            }
+            allocator
+                .allocate_more(size, self.root)
+                .ok_or_else(|| allocator.alloc_error(size))
+        })
+    }
+
+    /// Limits the bytes the blocks of the arena may take, as counted by `used`, or lifts the
+    /// limit if `quota` is None. Blocks the arena already has stay.
+    pub fn set_quota(&self, quota: Option<usize>) {
+        self.quota_word().store(quota.unwrap_or(0), SeqCst);
+    }
+
+    /// Returns the quota of the arena, or None if it has none.
+    pub fn quota(&self) -> Option<usize> {
+        Some(self.quota_word().load(SeqCst)).filter(|&quota| quota != 0)
+    }
+
+    /// Frees a block of the arena before the arena is destroyed.
+    ///
+    /// Returns false if the block is not one of the arena.
+    pub fn deallocate(&self, buffer: *mut u8) -> bool {
+        if !self.contains(buffer) {
+            return false;
+        }
+        self.memory.deallocate(buffer)
+    }
+
+    /// Returns true if the block was allocated in the arena.
+    pub fn contains(&self, buffer: *mut u8) -> bool {
+        self.memory
+            .offset_of(buffer)
+            .is_some_and(|offset| self.blocks().iter().any(|block| block.offset == offset))
+    }
+
+    /// Returns the blocks of the arena, in heap order.
+    pub fn blocks(&self) -> Vec<BlockInfo> {
+        let root = self.offset();
+        self.memory
+            .blocks()
+            .into_iter()
+            .filter(|block| block.parent == Some(root))
+            .collect()
+    }
+
+    /// Returns the bytes taken by the blocks of the arena.
+    pub fn used(&self) -> usize {
+        self.blocks().iter().map(|block| block.size).sum()
+    }
+
+    /// Returns the offset of the root block of the arena.
+    pub fn offset(&self) -> Offset {
+        self.memory
+            .offset_of(self.root)
+            .expect("The arena is inside the memory")
+    }
+
+    fn quota_word(&self) -> &AtomicUsize {
+        // SAFETY: The root block holds the quota.
+        unsafe { &*(self.root as *const AtomicUsize) }
+    }
+}
+
+impl Memory {
+    /// Returns the arena registered under `name`, creating it if there is none.
+    ///
+    /// Every process that asks for the same name gets the same arena. Returns None if not
+    /// enough memory.
+    pub fn arena(&self, name: &str) -> Option<ShmArena<'_>> {
+        let root = self.named("arena", name, || {
+            Some(self.allocate(std::mem::size_of::<usize>()).ok()?.as_ptr())
+        })?;
+        Some(ShmArena { memory: self, root })
+    }
+
+    /// Returns the arena registered under `name`, or None if there is none.
+    pub fn open_arena(&self, name: &str) -> Option<ShmArena<'_>> {
+        self.arenas()
+            .iter()
+            .any(|arena| arena == name)
+            .then(|| self.arena(name))
+            .flatten()
+    }
+
+    /// Frees the arena registered under `name` with all of its blocks, and removes its name.
+    ///
+    /// Handles of the arena must not be used afterwards, in any process. Returns false if
+    /// there is no such arena.
+    pub fn destroy_arena(&self, name: &str) -> bool {
+        match self.unname("arena", name) {
+            Some(root) => self.deallocate(root),
+            None => false,
+        }
+    }
+
+    /// Returns the names of the arenas, in no particular order.
+    pub fn arenas(&self) -> Vec<String> {
+        self.names("arena")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destroy_frees_everything() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let before = memory.usage().blocks;
+        let client = memory.arena("client-1").unwrap();
+        let first = client.allocate(64).unwrap().as_ptr();
+        client.allocate(128).unwrap();
+        let other = memory.arena("client-2").unwrap();
+        other.allocate(32).unwrap();
+
+        assert_eq!(client.blocks().len(), 2, "Both blocks are in the arena");
+        assert!(client.contains(first), "The block is in the arena");
+        assert!(!other.contains(first), "Arenas are separate");
+        let mut names = memory.arenas();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["client-1", "client-2"],
+            "Both arenas are listed"
+        );
+        assert!(memory.open_arena("client-3").is_none(), "No such arena");
+
+        client.set_quota(Some(client.used() + 64));
+        let error = client.allocate(128).unwrap_err();
+        assert!(
+            matches!(error, AllocError::QuotaExceeded { .. }),
+            "The arena is over its quota: {error}"
+        );
+        assert_eq!(error.largest_hole(), 64, "The rest of the quota fits");
+        assert!(other.allocate(128).is_ok(), "Other arenas are not limited");
+
+        assert!(memory.destroy_arena("client-1"), "The arena exists");
+        assert!(!memory.destroy_arena("client-1"), "The arena is gone");
+        assert_eq!(memory.arenas(), vec!["client-2"], "The name was removed");
+        assert!(memory.destroy_arena("client-2"), "The arena exists");
+        assert_eq!(
+            memory.usage().blocks,
+            before + 1,
+            "Only the directory is left"
+        );
+    }
+}
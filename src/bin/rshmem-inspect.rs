@@ -0,0 +1,112 @@
+//! Prints the state of a live shared memory without changing it.
+//!
+//! Usage: `rshmem-inspect <name> <size> [--base <address>] [--json | --dot]`
+//!
+//! The memory is opened read-only, so inspecting it never takes its lock. Sizes and addresses
+//! may be given in decimal or as hex with a `0x` prefix.
+
+use std::{error::Error, io, process::ExitCode};
+
+use rshmem::Memory;
+
+enum Format {
+    Table,
+    Json,
+    Dot,
+}
+
+struct Args {
+    name: String,
+    size: usize,
+    base: usize,
+    format: Format,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}");
+            eprintln!("Usage: rshmem-inspect <name> <size> [--base <address>] [--json | --dot]");
+            return ExitCode::from(2);
+        }
+    };
+
+    match inspect(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn inspect(args: &Args) -> Result<(), Box<dyn Error>> {
+    let memory = Memory::builder(&args.name, args.size)
+        .base_address(args.base)
+        .read_only(true)
+        .build()?;
+
+    match args.format {
+        Format::Json => println!("{}", memory.export_layout()),
+        Format::Dot => print!("{}", memory.export_dot()),
+        Format::Table => {
+            let usage = memory.usage();
+            println!("memory   {} ({} bytes)", args.name, args.size);
+            println!(
+                "lock     {}",
+                if memory.is_locked() { "held" } else { "free" }
+            );
+            println!(
+                "used     {} of {} bytes ({:.1}%), {} blocks",
+                usage.used,
+                usage.capacity,
+                usage.fraction() * 100.0,
+                usage.blocks
+            );
+            println!(
+                "peak     {} bytes, {} blocks",
+                usage.peak_used, usage.peak_blocks
+            );
+
+            let breakdown = memory.usage_breakdown();
+            for (owner, bytes) in &breakdown.by_owner {
+                println!("owner    {owner:>10} {bytes:>12} bytes");
+            }
+            for (tag, bytes) in &breakdown.by_tag {
+                println!("tag      {tag:>10} {bytes:>12} bytes");
+            }
+            println!();
+            memory.dump(&mut io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, Box<dyn Error>> {
+    let name = args.next().ok_or("Missing the memory name")?;
+    let size = parse_number(&args.next().ok_or("Missing the memory size")?)?;
+    let mut base = 0;
+    let mut format = Format::Table;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => base = parse_number(&args.next().ok_or("Missing the base address")?)?,
+            "--json" => format = Format::Json,
+            "--dot" => format = Format::Dot,
+            _ => return Err(format!("Unknown argument {arg}").into()),
+        }
+    }
+    Ok(Args {
+        name,
+        size,
+        base,
+        format,
+    })
+}
+
+fn parse_number(value: &str) -> Result<usize, Box<dyn Error>> {
+    match value.strip_prefix("0x") {
+        Some(hex) => Ok(usize::from_str_radix(hex, 16)?),
+        None => Ok(value.parse()?),
+    }
+}
@@ -0,0 +1,182 @@
+use crate::{
+    shared::SharedBlock,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+/// A fixed size set of bits in shared memory, updated with atomic operations.
+///
+/// No lock is taken, so it suits dirty-page tracking or claiming work items between
+/// processes: whoever flips a bit from 0 to 1 owns the item behind it.
+pub struct ShmBitset<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmBitset<'a> {
+    /// Creates a bitset of `bits` cleared bits.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, bits: usize) -> Option<Self> {
+        let words = bits.div_ceil(WORD_BITS);
+        let size = (1 + words) * std::mem::size_of::<usize>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (block.ptr as *mut usize).write(bits);
+        }
+        Some(Self { block })
+    }
+
+    /// Opens a bitset created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a bitset that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the bitset.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the number of bits.
+    pub fn len(&self) -> usize {
+        // SAFETY: The block starts with the number of bits, which never changes.
+        unsafe { *(self.block.ptr as *const usize) }
+    }
+
+    /// Returns true if the bitset has no bits.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sets a bit and returns its previous value.
+    pub fn set(&self, bit: usize) -> bool {
+        let (word, mask) = self.locate(bit);
+        word.fetch_or(mask, SeqCst) & mask != 0
+    }
+
+    /// Clears a bit and returns its previous value.
+    pub fn clear(&self, bit: usize) -> bool {
+        let (word, mask) = self.locate(bit);
+        word.fetch_and(!mask, SeqCst) & mask != 0
+    }
+
+    /// Returns the value of a bit.
+    pub fn test(&self, bit: usize) -> bool {
+        let (word, mask) = self.locate(bit);
+        word.load(SeqCst) & mask != 0
+    }
+
+    /// Sets a bit if it is clear.
+    ///
+    /// Returns true if this call set it, meaning the caller owns whatever the bit stands for.
+    pub fn try_claim(&self, bit: usize) -> bool {
+        !self.set(bit)
+    }
+
+    /// Sets the first clear bit and returns its index, or None if all bits are set.
+    pub fn claim_next(&self) -> Option<usize> {
+        let len = self.len();
+        for (index, word) in self.words().iter().enumerate() {
+            let mut value = word.load(SeqCst);
+            loop {
+                let bit = (!value).trailing_zeros() as usize;
+                if bit == WORD_BITS || index * WORD_BITS + bit >= len {
+                    break;
+                }
+                let mask = 1 << bit;
+                match word.compare_exchange(value, value | mask, SeqCst, SeqCst) {
+                    Ok(_) => return Some(index * WORD_BITS + bit),
+                    Err(current) => value = current,
+                }
+            }
+        }
+        None
+    }
+
+    /// Clears all bits.
+    pub fn clear_all(&self) {
+        for word in self.words() {
+            word.store(0, SeqCst);
+        }
+    }
+
+    /// Returns the number of set bits.
+    pub fn count_ones(&self) -> usize {
+        self.words()
+            .iter()
+            .map(|word| word.load(SeqCst).count_ones() as usize)
+            .sum()
+    }
+
+    /// Iterates over the indices of the set bits.
+    ///
+    /// Each word is read once, so bits changed during the iteration may or may not be seen.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words().iter().enumerate().flat_map(|(index, word)| {
+            let mut value = word.load(SeqCst);
+            std::iter::from_fn(move || {
+                if value == 0 {
+                    return None;
+                }
+                let bit = value.trailing_zeros() as usize;
+                value &= value - 1;
+                Some(index * WORD_BITS + bit)
+            })
+        })
+    }
+
+    /// Frees the bitset.
+    ///
+    /// Other processes must not use the bitset afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn words(&self) -> &[AtomicUsize] {
+        let words = self.len().div_ceil(WORD_BITS);
+        // SAFETY: The words directly follow the number of bits.
+        unsafe { std::slice::from_raw_parts((self.block.ptr as *const AtomicUsize).add(1), words) }
+    }
+
+    fn locate(&self, bit: usize) -> (&AtomicUsize, usize) {
+        assert!(bit < self.len(), "The bit index is out of bounds");
+        (&self.words()[bit / WORD_BITS], 1 << (bit % WORD_BITS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_clear_and_iterate() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let bitset = ShmBitset::create(&memory, 70).unwrap();
+
+        assert!(!bitset.set(3), "The bit was clear");
+        assert!(bitset.set(3), "The bit was already set");
+        assert!(bitset.try_claim(69), "The bit was clear");
+        assert!(!bitset.try_claim(69), "The bit was already claimed");
+        assert_eq!(
+            bitset.iter().collect::<Vec<_>>(),
+            vec![3, 69],
+            "Both bits are set"
+        );
+
+        assert!(bitset.clear(3), "The bit was set");
+        assert_eq!(
+            bitset.claim_next(),
+            Some(0),
+            "The first clear bit is claimed"
+        );
+        assert_eq!(bitset.count_ones(), 2, "Two bits are set");
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{
+    kv::hash,
+    shared::SharedBlock,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FilterHeader {
+    bits: usize,
+    hashes: usize,
+}
+
+/// A Bloom filter in shared memory, updated with atomic operations.
+///
+/// Producers record keys with `insert` and consumers query them with `contains` without
+/// taking any lock. A query may wrongly report a key that was never inserted, but never
+/// misses one that was. Keys cannot be removed, only all of them at once with `clear`.
+pub struct ShmBloomFilter<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmBloomFilter<'a> {
+    /// Creates an empty filter of `bits` bits, setting `hashes` bits per key.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, bits: usize, hashes: usize) -> Option<Self> {
+        assert!(bits > 0, "The filter needs at least one bit");
+        assert!(hashes > 0, "Keys need at least one hash");
+
+        let words = bits.div_ceil(WORD_BITS);
+        let size = std::mem::size_of::<FilterHeader>() + words * std::mem::size_of::<usize>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (block.ptr as *mut FilterHeader).write(FilterHeader { bits, hashes });
+        }
+        Some(Self { block })
+    }
+
+    /// Creates an empty filter sized for `keys` keys with about `rate` false positives.
+    ///
+    /// Returns None if not enough memory.
+    pub fn with_rate(memory: &'a Memory, keys: usize, rate: f64) -> Option<Self> {
+        assert!(rate > 0.0 && rate < 1.0, "The rate must be between 0 and 1");
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-(keys.max(1) as f64) * rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let hashes = ((bits as f64 / keys.max(1) as f64) * ln2).round() as usize;
+        Self::create(memory, bits, hashes.max(1))
+    }
+
+    /// Opens a filter created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a filter that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the filter.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Records a key.
+    ///
+    /// Returns true if the key may have been recorded before, false if it is certainly new.
+    pub fn insert(&self, key: &[u8]) -> bool {
+        let mut seen = true;
+        for bit in self.positions(key) {
+            let (word, mask) = self.locate(bit);
+            seen &= word.fetch_or(mask, SeqCst) & mask != 0;
+        }
+        seen
+    }
+
+    /// Returns true if the key may have been recorded, false if it certainly was not.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key).all(|bit| {
+            let (word, mask) = self.locate(bit);
+            word.load(SeqCst) & mask != 0
+        })
+    }
+
+    /// Forgets all keys.
+    pub fn clear(&self) {
+        for word in self.words() {
+            word.store(0, SeqCst);
+        }
+    }
+
+    /// Returns the number of bits.
+    pub fn bits(&self) -> usize {
+        self.header().bits
+    }
+
+    /// Returns the number of bits set per key.
+    pub fn hashes(&self) -> usize {
+        self.header().hashes
+    }
+
+    /// Frees the filter.
+    ///
+    /// Other processes must not use the filter afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    /// Returns the bits of a key, derived from two hashes as in Kirsch and Mitzenmacher.
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let FilterHeader { bits, hashes } = *self.header();
+        let first = hash(key);
+        // A different seed gives an independent second hash; it is odd so steps never repeat.
+        let second = key.iter().fold(0x8422_2325_cbf2_9ce4, |hash: u64, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+        }) | 1;
+        (0..hashes as u64)
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % bits as u64) as usize)
+    }
+
+    fn header(&self) -> &FilterHeader {
+        // SAFETY: The block starts with the header, which never changes.
+        unsafe { &*(self.block.ptr as *const FilterHeader) }
+    }
+
+    fn words(&self) -> &[AtomicUsize] {
+        let words = self.bits().div_ceil(WORD_BITS);
+        // SAFETY: The words directly follow the header.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.block.ptr.add(std::mem::size_of::<FilterHeader>()) as *const AtomicUsize,
+                words,
+            )
+        }
+    }
+
+    fn locate(&self, bit: usize) -> (&AtomicUsize, usize) {
+        (&self.words()[bit / WORD_BITS], 1 << (bit % WORD_BITS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_contains() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let filter = ShmBloomFilter::with_rate(&memory, 100, 0.01).unwrap();
+
+        assert!(!filter.insert(b"apple"), "The key is new");
+        assert!(filter.insert(b"apple"), "The key was recorded");
+        assert!(filter.contains(b"apple"), "Recorded keys are never missed");
+
+        let false_positives = (0..100)
+            .filter(|i| filter.contains(format!("pear{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 5, "Few keys are wrongly reported");
+
+        filter.clear();
+        assert!(!filter.contains(b"apple"), "Cleared keys are forgotten");
+    }
+}
@@ -0,0 +1,405 @@
+use std::{
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::SharedBlock,
+    shm_safe::check_align,
+    Memory, Offset, ShmSafe,
+};
+
+/// Maximum number of keys in a node before it is split.
+const MAX_KEYS: usize = 16;
+
+#[repr(C)]
+struct MapHeader {
+    /// Offset of the root node, zero until the first insert.
+    root: usize,
+    len: usize,
+}
+
+#[repr(C)]
+struct Node<K, V> {
+    leaf: bool,
+    len: usize,
+    /// Offset of the next leaf, zero for the last one. Unused in inner nodes.
+    next: usize,
+    /// One more than the maximum, so a node can overflow before it is split.
+    keys: [MaybeUninit<K>; MAX_KEYS + 1],
+    /// Only used in leaves.
+    values: [MaybeUninit<V>; MAX_KEYS + 1],
+    /// Only used in inner nodes. Child `i` holds the keys below key `i`.
+    children: [usize; MAX_KEYS + 2],
+}
+
+impl<K: ShmSafe + Ord, V: ShmSafe> Node<K, V> {
+    fn keys(&self) -> &[K] {
+        // SAFETY: The first `len` keys are initialized.
+        unsafe { std::slice::from_raw_parts(self.keys.as_ptr() as *const K, self.len) }
+    }
+
+    fn key(&self, index: usize) -> K {
+        self.keys()[index]
+    }
+
+    fn value(&self, index: usize) -> V {
+        assert!(index < self.len, "The value index is out of bounds");
+        // SAFETY: The first `len` values of a leaf are initialized.
+        unsafe { self.values[index].assume_init() }
+    }
+
+    /// Returns the index of the child whose keys include `key`.
+    fn child_index(&self, key: &K) -> usize {
+        self.keys().partition_point(|k| k <= key)
+    }
+
+    fn insert_entry(&mut self, index: usize, key: K, value: V) {
+        self.keys.copy_within(index..self.len, index + 1);
+        self.values.copy_within(index..self.len, index + 1);
+        self.keys[index] = MaybeUninit::new(key);
+        self.values[index] = MaybeUninit::new(value);
+        self.len += 1;
+    }
+
+    /// Inserts the separator and right half of the split child at `index`.
+    fn insert_child(&mut self, index: usize, separator: K, right: usize) {
+        self.keys.copy_within(index..self.len, index + 1);
+        self.children.copy_within(index + 1..=self.len, index + 2);
+        self.keys[index] = MaybeUninit::new(separator);
+        self.children[index + 1] = right;
+        self.len += 1;
+    }
+}
+
+/// An ordered map in shared memory, kept as a B+ tree of nodes allocated from the memory.
+///
+/// Values live in the leaves, which are linked in key order, so range queries only descend
+/// the tree once. It suits an index shared between processes, e.g. from timestamps to the
+/// offsets of records. Nodes are allocated as children of the map, which lets `destroy` free
+/// everything at once. Removing keys does not merge nodes, so the tree keeps its size until
+/// the map is destroyed.
+///
+/// `K` must order the same way in every process.
+pub struct ShmBTreeMap<'a, K: ShmSafe + Ord, V: ShmSafe> {
+    block: SharedBlock<'a>,
+    _entry: PhantomData<(K, V)>,
+}
+
+impl<'a, K: ShmSafe + Ord, V: ShmSafe> ShmBTreeMap<'a, K, V> {
+    const SIZE: usize = MemoryMutex::SIZE + std::mem::size_of::<MapHeader>();
+
+    /// Creates an empty map.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let block = SharedBlock::create(memory, Self::SIZE)?;
+        Some(Self {
+            block,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Opens a map created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a map of the same `K` and `V` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the map.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Inserts a value, replacing the previous value of the key.
+    ///
+    /// Returns false if there was not enough memory for the nodes.
+    pub fn insert(&self, key: K, value: V) -> bool {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.root == 0 {
+            let Some(leaf) = self.allocate_node() else {
+                return false;
+            };
+            self.node(leaf).leaf = true;
+            header.root = leaf;
+        }
+
+        // Nodes from the root to the leaf, with the index of the child taken from each.
+        let mut path = Vec::new();
+        let mut current = header.root;
+        loop {
+            let node = self.node(current);
+            if node.leaf {
+                break;
+            }
+            let index = node.child_index(&key);
+            path.push((current, index));
+            current = node.children[index];
+        }
+
+        let leaf = self.node(current);
+        let index = match leaf.keys().binary_search(&key) {
+            Ok(index) => {
+                leaf.values[index] = MaybeUninit::new(value);
+                return true;
+            }
+            Err(index) => index,
+        };
+
+        // Every full node from the leaf up splits, and a full root also needs a new root.
+        // Allocate them all first, so running out of memory leaves the tree untouched.
+        let splits = std::iter::once(current)
+            .chain(path.iter().rev().map(|&(offset, _)| offset))
+            .take_while(|&offset| self.node(offset).len == MAX_KEYS)
+            .count();
+        let needed = splits + usize::from(splits == path.len() + 1);
+        let mut spare = Vec::with_capacity(needed);
+        for _ in 0..needed {
+            match self.allocate_node() {
+                Some(node) => spare.push(node),
+                None => {
+                    for node in spare {
+                        self.block.memory.deallocate(self.pointer(node));
+                    }
+                    return false;
+                }
+            }
+        }
+
+        let leaf = self.node(current);
+        leaf.insert_entry(index, key, value);
+        header.len += 1;
+
+        let mut split = None;
+        if leaf.len > MAX_KEYS {
+            split = Some(self.split(current, spare.pop().expect("Nodes were allocated")));
+        }
+        while let Some((separator, right)) = split.take() {
+            let Some((parent, index)) = path.pop() else {
+                let root = spare.pop().expect("Nodes were allocated");
+                let node = self.node(root);
+                node.len = 1;
+                node.keys[0] = MaybeUninit::new(separator);
+                node.children[0] = header.root;
+                node.children[1] = right;
+                header.root = root;
+                break;
+            };
+
+            let node = self.node(parent);
+            node.insert_child(index, separator, right);
+            if node.len > MAX_KEYS {
+                split = Some(self.split(parent, spare.pop().expect("Nodes were allocated")));
+            }
+        }
+        true
+    }
+
+    /// Returns a copy of the value of the key.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let guard = self.lock();
+        let leaf = self.node(self.find_leaf(header(&guard), Bound::Included(key))?);
+        let index = leaf.keys().binary_search(key).ok()?;
+        Some(leaf.value(index))
+    }
+
+    /// Returns true if the map has a value for the key.
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Removes the value of the key and returns it.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let guard = self.lock();
+        let header = header(&guard);
+        let leaf = self.node(self.find_leaf(header, Bound::Included(key))?);
+        let index = leaf.keys().binary_search(key).ok()?;
+
+        let value = leaf.value(index);
+        leaf.keys.copy_within(index + 1..leaf.len, index);
+        leaf.values.copy_within(index + 1..leaf.len, index);
+        leaf.len -= 1;
+        header.len -= 1;
+        Some(value)
+    }
+
+    /// Returns copies of the entries with keys in the range, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Vec<(K, V)> {
+        let guard = self.lock();
+        let mut entries = Vec::new();
+        let Some(mut current) = self.find_leaf(header(&guard), range.start_bound()) else {
+            return entries;
+        };
+
+        while current != 0 {
+            let node = self.node(current);
+            for index in 0..node.len {
+                let key = node.key(index);
+                let started = match range.start_bound() {
+                    Bound::Included(start) => key >= *start,
+                    Bound::Excluded(start) => key > *start,
+                    Bound::Unbounded => true,
+                };
+                if !started {
+                    continue;
+                }
+                if !range.contains(&key) {
+                    return entries;
+                }
+                entries.push((key, node.value(index)));
+            }
+            current = node.next;
+        }
+        entries
+    }
+
+    /// Returns the number of values in the map.
+    pub fn len(&self) -> usize {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the map and all of its nodes.
+    ///
+    /// Other processes must not use the map afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::SIZE)
+    }
+
+    /// Returns the leaf where keys from the bound on are found, or None if the map is empty.
+    ///
+    /// Must be called with the lock held.
+    fn find_leaf(&self, header: &MapHeader, bound: Bound<&K>) -> Option<usize> {
+        let mut current = header.root;
+        if current == 0 {
+            return None;
+        }
+        loop {
+            let node = self.node(current);
+            if node.leaf {
+                return Some(current);
+            }
+            current = match bound {
+                Bound::Included(key) | Bound::Excluded(key) => node.children[node.child_index(key)],
+                Bound::Unbounded => node.children[0],
+            };
+        }
+    }
+
+    /// Moves the upper half of an overflowing node to `right`, a fresh node.
+    ///
+    /// Returns the key separating the halves and the offset of the right half.
+    fn split(&self, offset: usize, right: usize) -> (K, usize) {
+        let node = self.node(offset);
+        let new = self.node(right);
+        let mid = node.len / 2;
+
+        if node.leaf {
+            let count = node.len - mid;
+            new.leaf = true;
+            new.keys[..count].copy_from_slice(&node.keys[mid..node.len]);
+            new.values[..count].copy_from_slice(&node.values[mid..node.len]);
+            new.len = count;
+            new.next = node.next;
+            node.next = right;
+            node.len = mid;
+            (new.key(0), right)
+        } else {
+            // The middle key moves up, so it is in neither half.
+            let separator = node.key(mid);
+            let count = node.len - mid - 1;
+            new.keys[..count].copy_from_slice(&node.keys[mid + 1..node.len]);
+            new.children[..=count].copy_from_slice(&node.children[mid + 1..=node.len]);
+            new.len = count;
+            node.len = mid;
+            (separator, right)
+        }
+    }
+
+    /// Allocates a zeroed inner node and returns its offset.
+    fn allocate_node(&self) -> Option<usize> {
+        check_align::<Node<K, V>>();
+        let size = std::mem::size_of::<Node<K, V>>();
+        let node = self
+            .block
+            .memory
+            .allocate_more(size, self.block.ptr)
+            .ok()?
+            .as_ptr();
+        // SAFETY: The block was just allocated with `size` bytes.
+        unsafe { node.write_bytes(0, size) };
+        self.block.memory.offset_of(node).map(|offset| offset.0)
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn node(&self, offset: usize) -> &mut Node<K, V> {
+        // SAFETY: Node offsets come from `allocate_node`.
+        unsafe { &mut *(self.pointer(offset) as *mut Node<K, V>) }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Nodes are inside the memory")
+    }
+}
+
+/// Returns the map header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut MapHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut MapHeader) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_range() {
+        let memory = Memory::new_in_process(65536).unwrap();
+        let map = ShmBTreeMap::<u64, u64>::create(&memory).unwrap();
+
+        // Spread the keys so that leaves and inner nodes split in the middle.
+        for i in 0..200 {
+            let key = i * 7 % 200;
+            assert!(map.insert(key, key * 10), "The nodes should fit");
+        }
+        assert!(map.insert(5, 5), "Replacing needs no memory");
+        assert_eq!(map.len(), 200, "Replacing does not add a value");
+        assert_eq!(map.get(&5), Some(5), "The value was replaced");
+        assert_eq!(map.get(&199), Some(1990), "The last key is found");
+
+        assert_eq!(map.remove(&11), Some(110), "The value is in the map");
+        assert_eq!(map.remove(&11), None, "The value was removed");
+        assert_eq!(
+            map.range(9..14),
+            vec![(9, 90), (10, 100), (12, 120), (13, 130)],
+            "The range is ordered and skips removed keys"
+        );
+        assert_eq!(map.range(198..).len(), 2, "The range reaches the end");
+        assert_eq!(map.range(..).len(), 199, "The full range has every value");
+    }
+}
@@ -0,0 +1,152 @@
+use std::marker::PhantomData;
+
+use bytes::{buf::UninitSlice, Buf, BufMut};
+
+use crate::Memory;
+
+/// Reads a shared memory region through `bytes::Buf`.
+pub struct ShmBuf<'a> {
+    data: *const u8,
+    len: usize,
+    pos: usize,
+    _memory: PhantomData<&'a Memory>,
+}
+
+impl<'a> ShmBuf<'a> {
+    /// Creates a buffer over `len` bytes starting at `data`, e.g. a sub-region of a block.
+    ///
+    /// # Safety
+    /// The region must stay allocated and must not be written to while the buffer is used.
+    pub unsafe fn from_raw_parts(data: *const u8, len: usize) -> Self {
+        Self {
+            data,
+            len,
+            pos: 0,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> Buf for ShmBuf<'a> {
+    fn remaining(&self) -> usize {
+        self.len - self.pos
+    }
+
+    fn chunk(&self) -> &[u8] {
+        // SAFETY: The region is valid for `len` bytes, see `from_raw_parts`.
+        unsafe { std::slice::from_raw_parts(self.data.add(self.pos), self.len - self.pos) }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        assert!(
+            cnt <= self.remaining(),
+            "Cannot advance past the end of the buffer"
+        );
+        self.pos += cnt;
+    }
+}
+
+/// Writes into a shared memory region through `bytes::BufMut`.
+pub struct ShmBufMut<'a> {
+    data: *mut u8,
+    len: usize,
+    pos: usize,
+    _memory: PhantomData<&'a Memory>,
+}
+
+impl<'a> ShmBufMut<'a> {
+    /// Creates a buffer over `len` bytes starting at `data`, e.g. a sub-region of a block.
+    ///
+    /// # Safety
+    /// The region must stay allocated and must not be accessed by anyone else while the
+    /// buffer is used.
+    pub unsafe fn from_raw_parts(data: *mut u8, len: usize) -> Self {
+        Self {
+            data,
+            len,
+            pos: 0,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> usize {
+        self.pos
+    }
+}
+
+// SAFETY: `chunk_mut` only hands out the unwritten part of the region and `advance_mut`
+// never moves past its end.
+unsafe impl<'a> BufMut for ShmBufMut<'a> {
+    fn remaining_mut(&self) -> usize {
+        self.len - self.pos
+    }
+
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        assert!(
+            cnt <= self.remaining_mut(),
+            "Cannot advance past the end of the buffer"
+        );
+        self.pos += cnt;
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        // SAFETY: The region is valid for `len` bytes, see `from_raw_parts`.
+        unsafe { UninitSlice::from_raw_parts_mut(self.data.add(self.pos), self.len - self.pos) }
+    }
+}
+
+impl Memory {
+    /// Returns a `bytes::Buf` over the allocated block at `buffer`.
+    ///
+    /// Returns None if the block is not allocated.
+    ///
+    /// # Safety
+    /// The block must not be freed or written to while the buffer is used.
+    pub unsafe fn buf(&self, buffer: *mut u8) -> Option<ShmBuf<'_>> {
+        let size = self.allocation_size(buffer)?;
+        Some(ShmBuf::from_raw_parts(buffer, size))
+    }
+
+    /// Returns a `bytes::BufMut` over the allocated block at `buffer`.
+    ///
+    /// Returns None if the block is not allocated.
+    ///
+    /// # Safety
+    /// The block must not be freed or accessed by anyone else while the buffer is used.
+    pub unsafe fn buf_mut(&self, buffer: *mut u8) -> Option<ShmBufMut<'_>> {
+        let size = self.allocation_size(buffer)?;
+        Some(ShmBufMut::from_raw_parts(buffer, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, BufMut};
+
+    use crate::Memory;
+
+    #[test]
+    fn test_write_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let data = memory.allocate(16).unwrap().as_ptr();
+
+        let mut writer = unsafe { memory.buf_mut(data) }.unwrap();
+        writer.put_u32(7);
+        writer.put_slice(b"hello");
+        assert_eq!(writer.written(), 9, "Nine bytes should be written");
+
+        let mut reader = unsafe { memory.buf(data) }.unwrap();
+        assert_eq!(reader.get_u32(), 7, "The number should be read back");
+        assert_eq!(
+            &reader.chunk()[..5],
+            b"hello",
+            "The text should be read back"
+        );
+    }
+}
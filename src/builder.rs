@@ -0,0 +1,270 @@
+use std::error::Error;
+
+use crate::{allocator::MIN_SPLIT, Memory, PressureThresholds};
+
+/// Options for creating or opening a shared memory.
+///
+/// Options that change the layout of the heap only take effect for the process that
+/// initializes the memory. Processes attaching later use whatever the creator chose.
+pub struct MemoryBuilder<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) size: usize,
+    pub(crate) base_address: usize,
+    pub(crate) journal: bool,
+    pub(crate) op_log: usize,
+    pub(crate) min_split: usize,
+    pub(crate) bump_region: usize,
+    pub(crate) local_cache: usize,
+    pub(crate) read_only: bool,
+    pub(crate) track_sites: bool,
+    pub(crate) create: bool,
+    pub(crate) in_process: bool,
+    pub(crate) protocol: u32,
+    pub(crate) secure_wipe: bool,
+    pub(crate) timestamps: bool,
+    pub(crate) pressure_thresholds: PressureThresholds,
+    pub(crate) user_header: usize,
+    pub(crate) high_priority_reserve: usize,
+    pub(crate) emergency_reserve: usize,
+    pub(crate) security: Option<&'a str>,
+    #[cfg(all(unix, feature = "sysv"))]
+    pub(crate) sysv_key: Option<libc::key_t>,
+}
+
+impl<'a> MemoryBuilder<'a> {
+    /// Name is the file mapping name. Size is the size of the memory in bytes.
+    pub fn new(name: &'a str, size: usize) -> Self {
+        Self {
+            name,
+            size,
+            base_address: 0,
+            journal: false,
+            op_log: 0,
+            min_split: MIN_SPLIT,
+            bump_region: 0,
+            local_cache: 0,
+            read_only: false,
+            track_sites: cfg!(debug_assertions),
+            create: true,
+            in_process: false,
+            protocol: 0,
+            secure_wipe: false,
+            timestamps: false,
+            pressure_thresholds: PressureThresholds::default(),
+            user_header: 0,
+            high_priority_reserve: 0,
+            emergency_reserve: 0,
+            security: None,
+            #[cfg(all(unix, feature = "sysv"))]
+            sysv_key: None,
+        }
+    }
+
+    /// Address at which the memory is mapped. All processes must use the same address,
+    /// because the heap links blocks with raw pointers.
+    pub fn base_address(mut self, base_address: usize) -> Self {
+        self.base_address = base_address;
+        self
+    }
+
+    /// Records allocator metadata updates in a write-ahead journal before applying them.
+    ///
+    /// If a process dies in the middle of an allocation or deallocation, the next process
+    /// that attaches takes the lock over from it and replays the journal instead of
+    /// inheriting a half-updated block list.
+    pub fn journal(mut self, enabled: bool) -> Self {
+        self.journal = enabled;
+        self
+    }
+
+    /// Keeps a log of the last `records` allocations and frees of all processes at the end
+    /// of the memory, for `Memory::op_log` and `replay_log`, along with every process that
+    /// attached to or detached from it, for auditing who touched the shared state.
+    ///
+    /// Each record holds the operation, the size and offset of the block, the process id and
+    /// the time, and takes 40 bytes out of the heap. Zero, the default, keeps no log.
+    pub fn op_log(mut self, records: usize) -> Self {
+        self.op_log = records;
+        self
+    }
+
+    /// Gives a block the whole of a hole it goes into if less than `bytes` would be left
+    /// over, rather than leaving a sliver no allocation fits into.
+    ///
+    /// Block sizes are rounded up to a multiple of the pointer size either way, so a block
+    /// may be larger than requested. The default is the size of a block header plus a word.
+    pub fn min_split(mut self, bytes: usize) -> Self {
+        self.min_split = bytes;
+        self
+    }
+
+    /// Keeps the last `bytes` of free space for allocations of `Priority::High`, so control
+    /// messages still fit when bulk data has nearly filled the heap. The default is zero.
+    ///
+    /// The reserve counts block headers and padding. It can be changed later with
+    /// `Memory::set_high_priority_reserve`.
+    pub fn high_priority_reserve(mut self, bytes: usize) -> Self {
+        self.high_priority_reserve = bytes;
+        self
+    }
+
+    /// Keeps the last `bytes` of free space for `Memory::allocate_critical`, below the
+    /// high-priority reserve, e.g. for the bookkeeping of a recovery. The default is zero.
+    pub fn emergency_reserve(mut self, bytes: usize) -> Self {
+        self.emergency_reserve = bytes;
+        self
+    }
+
+    /// Reserves `bytes` at the start of the heap for `Memory::allocate_bump`, which hands
+    /// them out without taking the lock until `reset`.
+    pub fn bump_region(mut self, bytes: usize) -> Self {
+        self.bump_region = bytes;
+        self
+    }
+
+    /// Reserves `bytes` between the heap header and the heap for a header of the
+    /// application, see `Memory::read_user_header`, rather than taking the first allocation
+    /// for it.
+    ///
+    /// The region is zeroed on creation, keeps its contents across `Memory::reset`, and is
+    /// rounded up to a multiple of the word size.
+    pub fn user_header(mut self, bytes: usize) -> Self {
+        self.user_header = bytes;
+        self
+    }
+
+    /// Remembers the source location of every allocation of this process, for
+    /// `Memory::allocation_site` and leak reports.
+    ///
+    /// Locations are kept in the memory of the process, not the shared memory. It is on by
+    /// default in debug builds.
+    pub fn track_sites(mut self, enabled: bool) -> Self {
+        self.track_sites = enabled;
+        self
+    }
+
+    /// Keeps blocks of up to 4096 bytes that this process frees allocated, sorted by size
+    /// class, and hands them out again without taking the lock.
+    ///
+    /// Each class is refilled with `batch` blocks under one lock when it runs empty, and
+    /// gives `batch` blocks back when it holds more than twice that. Cached requests are
+    /// rounded up to their class, and the allocation hooks do not run for them. The cache
+    /// belongs to this `Memory`, so each thread with its own handle has its own. Zero, the
+    /// default, disables it.
+    pub fn local_cache(mut self, batch: usize) -> Self {
+        self.local_cache = batch;
+        self
+    }
+
+    /// Overwrites the heap with zeros on every `Memory::reset`, and the whole memory when the
+    /// last handle of any process is dropped, so no data outlives it in the paging file.
+    ///
+    /// Handles are counted in the memory, so a process that dies without dropping its handle
+    /// keeps the memory from being wiped. Read-only handles are not counted.
+    pub fn secure_wipe(mut self, enabled: bool) -> Self {
+        self.secure_wipe = enabled;
+        self
+    }
+
+    /// Records when each block was allocated, for `Memory::allocations_older_than`, at the
+    /// cost of reading the clock on every allocation.
+    pub fn timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Thresholds of `Memory::pressure` in this process. The default has medium pressure
+    /// from half of the capacity, high from three quarters and critical from 90%.
+    pub fn pressure_thresholds(mut self, thresholds: PressureThresholds) -> Self {
+        self.pressure_thresholds = thresholds;
+        self
+    }
+
+    /// Security descriptor of the file mapping in SDDL, e.g. to add SIDs that may open it.
+    ///
+    /// It only applies when this process creates the mapping; by default the mapping gets the
+    /// default security of the process.
+    pub fn security_descriptor(mut self, sddl: &'a str) -> Self {
+        self.security = Some(sddl);
+        self
+    }
+
+    /// Lets AppContainer (UWP) and low-integrity processes of the same machine open the
+    /// mapping, e.g. a sandboxed renderer.
+    ///
+    /// This grants all application packages read and write access and lowers the integrity
+    /// label of the mapping, replacing any `security_descriptor`. Sandboxed processes see the
+    /// name inside their own namespace, so they usually have to open it by its full path
+    /// under `\Sessions\<id>\BaseNamedObjects`.
+    #[cfg(windows)]
+    pub fn sandbox_access(mut self, enabled: bool) -> Self {
+        self.security = enabled.then_some(crate::platform::SANDBOX_SDDL);
+        self
+    }
+
+    /// Uses the System V segment of `key` instead of a named file mapping, to share the
+    /// memory with programs that identify segments by `key_t`. The name is then only used in
+    /// errors.
+    ///
+    /// The segment is created unless `create` is off, and a segment this process created is
+    /// removed when its handle is dropped. Security descriptors do not apply to it; it is
+    /// created readable and writable by its owner only.
+    #[cfg(all(unix, feature = "sysv"))]
+    pub fn sysv_key(mut self, key: libc::key_t) -> Self {
+        self.sysv_key = Some(key);
+        self
+    }
+
+    /// Lets the processes of every signed-in user open the mapping for reading and writing,
+    /// e.g. a .NET service and the desktop application of a user, which the default security
+    /// keeps apart. Combine it with a `Namespace::Global` name. This replaces any
+    /// `security_descriptor`.
+    #[cfg(windows)]
+    pub fn user_access(mut self, enabled: bool) -> Self {
+        self.security = enabled.then_some(crate::platform::USERS_SDDL);
+        self
+    }
+
+    /// Opens an existing memory without write access, to inspect it without disturbing it.
+    ///
+    /// The heap is read without taking its lock, so a stuck lock does not block inspection.
+    /// Only reading methods like `usage`, `blocks`, `dump` and `snapshot` work; allocating,
+    /// freeing and other methods that lock the heap panic, and the shared data structures
+    /// cannot be used, because their locks cannot be taken either. Opening fails if no
+    /// process created the memory yet.
+    pub fn read_only(mut self, enabled: bool) -> Self {
+        self.read_only = enabled;
+        self
+    }
+
+    /// Creates the memory if no process did yet, the default. Otherwise opening fails if it
+    /// does not exist, like with `Memory::open`.
+    pub fn create(mut self, enabled: bool) -> Self {
+        self.create = enabled;
+        self
+    }
+
+    /// Puts the memory into a zeroed buffer on the heap of this process instead of a named
+    /// mapping, like `Memory::new_in_process`, so no other process can open it.
+    ///
+    /// The name, the base address and the options of the mapping are then ignored; all other
+    /// options apply as usual.
+    pub fn in_process(mut self, enabled: bool) -> Self {
+        self.in_process = enabled;
+        self
+    }
+
+    /// Version of the protocol the application speaks through the memory, zero by default.
+    ///
+    /// The creator records it, and processes attaching with another version are rejected.
+    /// Bump it whenever the types the application puts into the memory change.
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.protocol = version;
+        self
+    }
+
+    /// Creates or opens the shared memory.
+    pub fn build(self) -> Result<Memory, Box<dyn Error>> {
+        Memory::from_builder(&self)
+    }
+}
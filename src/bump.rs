@@ -0,0 +1,105 @@
+use std::ptr::NonNull;
+
+use crate::{
+    allocator::align_up,
+    error::AllocError,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory,
+};
+
+/// Start of the bump region, followed by the bytes it hands out.
+#[repr(C)]
+pub(crate) struct BumpHeader {
+    /// Bytes handed out so far.
+    cursor: AtomicUsize,
+    len: usize,
+}
+
+impl BumpHeader {
+    pub(crate) const SIZE: usize = std::mem::size_of::<BumpHeader>();
+
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            cursor: AtomicUsize::new(0),
+            len,
+        }
+    }
+}
+
+impl Memory {
+    /// Allocates from the bump region without taking the lock, or from the heap like
+    /// `allocate` once the region is used up or if the memory has none.
+    ///
+    /// Blocks from the region cannot be freed on their own: `deallocate` ignores them, and
+    /// they are only given back all at once by `reset`. They take no header, so
+    /// `allocation_size` knows nothing about them, and the allocation hooks do not run for
+    /// them. This suits data that is only appended to until the whole memory is reset.
+    #[track_caller]
+    pub fn allocate_bump(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        if let Some(region) = self.bump_region() {
+            // SAFETY: The region starts with its header, written when the heap was created.
+            let header = unsafe { &*(region as *const BumpHeader) };
+            let size = align_up(size.max(1), std::mem::size_of::<usize>());
+            let claimed = header.cursor.fetch_update(SeqCst, SeqCst, |cursor| {
+                (cursor + size <= header.len).then_some(cursor + size)
+            });
+            if let Ok(cursor) = claimed {
+                // SAFETY: The claimed bytes lie within the region.
+                let data = unsafe { region.add(BumpHeader::SIZE + cursor) };
+                return Ok(NonNull::new(data).expect("The region is not null"));
+            }
+        }
+        self.allocate(size)
+    }
+
+    /// Returns the bytes left in the bump region, zero if the memory has none.
+    pub fn bump_available(&self) -> usize {
+        self.bump_region().map_or(0, |region| {
+            // SAFETY: The region starts with its header, written when the heap was created.
+            let header = unsafe { &*(region as *const BumpHeader) };
+            header.len - header.cursor.load(SeqCst).min(header.len)
+        })
+    }
+
+    fn bump_region(&self) -> Option<*mut u8> {
+        // SAFETY: The offset of the region is only written while the heap is created or
+        // reset, and never changes its value.
+        let offset = unsafe { self.heap_bump_offset() }?;
+        self.pointer(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bump_then_fallback() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .bump_region(64)
+            .build()
+            .unwrap();
+        let blocks = memory.usage().blocks;
+
+        let first = memory.allocate_bump(40).unwrap().as_ptr();
+        let second = memory.allocate_bump(24).unwrap().as_ptr();
+        assert_eq!(second as usize - first as usize, 40, "Blocks are packed");
+        assert_eq!(memory.bump_available(), 0, "The region is used up");
+        assert_eq!(memory.usage().blocks, blocks, "The heap was not touched");
+
+        let fallback = memory.allocate_bump(8).unwrap().as_ptr();
+        assert!(
+            memory.allocation_size(fallback).is_some(),
+            "The heap takes over"
+        );
+
+        memory.reset(false);
+        assert_eq!(memory.bump_available(), 64, "Reset rewinds the region");
+        assert_eq!(
+            memory.allocate_bump(8).unwrap().as_ptr(),
+            first,
+            "The region starts over"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::{collections::HashMap, panic::Location, ptr::NonNull};
+
+use crate::{Memory, Offset};
+
+/// Block sizes the cache serves, each request going to the smallest class that fits it.
+const CLASSES: [usize; 9] = [16, 32, 64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Blocks of this process kept allocated in the heap after they were freed, to be handed out
+/// again without taking the lock.
+pub(crate) struct LocalCache {
+    /// Blocks taken from or given back to the heap under one lock.
+    batch: usize,
+    /// Offsets and sizes of the freed blocks of each class.
+    free: [Vec<(Offset, usize)>; CLASSES.len()],
+    /// Blocks handed out from the cache, with their class and size. Blocks that got children
+    /// are dropped from it, so freeing them frees their children in the heap.
+    handed_out: HashMap<Offset, (usize, usize)>,
+}
+
+impl LocalCache {
+    pub(crate) fn new(batch: usize) -> Self {
+        Self {
+            batch,
+            free: Default::default(),
+            handed_out: HashMap::new(),
+        }
+    }
+}
+
+impl Memory {
+    /// Hands out a block of the class of `size` from the cache, refilling the class from the
+    /// heap if it is empty.
+    ///
+    /// Returns None if the memory has no cache, the size is too large for it, or the heap is
+    /// out of memory, so that the caller takes the usual path.
+    #[track_caller]
+    pub(crate) fn allocate_cached(&self, size: usize) -> Option<NonNull<u8>> {
+        let mut cache = self.cache.as_ref()?.borrow_mut();
+        let class = CLASSES.iter().position(|&class| class >= size)?;
+        if cache.free[class].is_empty() {
+            let allocator = self.allocator();
+            let refill: Vec<_> = (0..cache.batch)
+                .map_while(|_| allocator.allocate(CLASSES[class]))
+                .map(|data| (data, allocator.block_size(data)))
+                .collect();
+            drop(allocator);
+            cache.free[class] = refill
+                .into_iter()
+                .map(|(data, len)| {
+                    let offset = self.offset_of(data).expect("Blocks are inside the memory");
+                    (offset, len)
+                })
+                .collect();
+        }
+
+        let (offset, len) = cache.free[class].pop()?;
+        cache.handed_out.insert(offset, (class, len));
+        if let Some(sites) = &self.sites {
+            sites.borrow_mut().insert(offset.0, Location::caller());
+        }
+        let data = self.pointer(offset).expect("Blocks are inside the memory");
+        // Freed blocks keep their data, and new blocks start out zeroed like in the heap.
+        // SAFETY: The block is allocated with `len` bytes and owned by this process.
+        unsafe { data.write_bytes(0, len) };
+        NonNull::new(data)
+    }
+
+    /// Takes back a block the cache handed out, giving a batch back to the heap if its class
+    /// holds too many.
+    ///
+    /// Returns false if the block did not come from the cache.
+    pub(crate) fn deallocate_cached(&self, buffer: *mut u8) -> bool {
+        let (Some(cache), Some(offset)) = (&self.cache, self.offset_of(buffer)) else {
+            return false;
+        };
+        let mut cache = cache.borrow_mut();
+        let Some((class, len)) = cache.handed_out.remove(&offset) else {
+            return false;
+        };
+        if let Some(sites) = &self.sites {
+            sites.borrow_mut().remove(&offset.0);
+        }
+
+        cache.free[class].push((offset, len));
+        if cache.free[class].len() > 2 * cache.batch {
+            let keep = cache.batch;
+            let surplus = cache.free[class].split_off(keep);
+            self.release(&surplus);
+        }
+        true
+    }
+
+    /// Stops caching a block that is about to get a child, so freeing it frees the child.
+    pub(crate) fn uncache(&self, parent: *mut u8) {
+        if let (Some(cache), Some(offset)) = (&self.cache, self.offset_of(parent)) {
+            cache.borrow_mut().handed_out.remove(&offset);
+        }
+    }
+
+    /// Gives every freed block in the cache back to the heap under one lock.
+    ///
+    /// Blocks held by the cache count as allocated in `usage` until then. Dropping the memory
+    /// flushes the cache as well.
+    pub fn flush_cache(&self) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let free: Vec<_> = cache
+            .borrow_mut()
+            .free
+            .iter_mut()
+            .flat_map(std::mem::take)
+            .collect();
+        self.release(&free);
+    }
+
+    /// Updates the cache after blocks moved or the heap was reset, where `moves` is None.
+    pub(crate) fn relocate_cache(&self, moves: Option<&[(Offset, Offset)]>) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let mut cache = cache.borrow_mut();
+        let Some(moves) = moves else {
+            cache.free.iter_mut().for_each(Vec::clear);
+            cache.handed_out.clear();
+            return;
+        };
+        let moved = |offset: Offset| {
+            moves
+                .binary_search_by_key(&offset, |&(old, _)| old)
+                .map_or(offset, |index| moves[index].1)
+        };
+        for (offset, _) in cache.free.iter_mut().flatten() {
+            *offset = moved(*offset);
+        }
+        cache.handed_out = cache
+            .handed_out
+            .drain()
+            .map(|(offset, entry)| (moved(offset), entry))
+            .collect();
+    }
+
+    fn release(&self, blocks: &[(Offset, usize)]) {
+        if blocks.is_empty() {
+            return;
+        }
+        let allocator = self.allocator();
+        for &(offset, _) in blocks {
+            allocator.deallocate(self.pointer(offset).expect("Blocks are inside the memory"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_blocks_are_reused() {
+        let memory = Memory::builder("", 8192)
+            .in_process(true)
+            .local_cache(4)
+            .build()
+            .unwrap();
+
+        let first = memory.allocate(20).unwrap().as_ptr();
+        assert_eq!(memory.usage().blocks, 4, "A batch of the class was taken");
+        assert_eq!(
+            memory.allocation_size(first),
+            Some(32),
+            "The class size is used"
+        );
+
+        unsafe { first.write_bytes(0xab, 32) };
+        assert!(memory.deallocate(first), "The block was handed out");
+        assert_eq!(memory.usage().blocks, 4, "The block stays in the cache");
+        let reused = memory.allocate(30).unwrap().as_ptr();
+        assert_eq!(reused, first, "The freed block is reused");
+        let bytes = unsafe { std::slice::from_raw_parts(reused, 32) };
+        assert!(
+            bytes.iter().all(|&byte| byte == 0),
+            "The reused block is zeroed"
+        );
+
+        let parent = memory.allocate(16).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+        memory.deallocate(parent);
+        assert_eq!(memory.usage().blocks, 7, "A parent is freed with its child");
+
+        memory.flush_cache();
+        assert_eq!(memory.usage().blocks, 1, "Only the block in use is left");
+    }
+}
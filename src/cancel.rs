@@ -0,0 +1,215 @@
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    platform::{self, c_void},
+    ready::SPINS,
+    shared::SharedBlock,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset,
+};
+
+/// Longest a waiter sleeps on the event before it looks at the flag again, so it also
+/// notices controllers that did not attach the event.
+const EVENT_SLICE: Duration = Duration::from_millis(50);
+
+/// A named event of this process, closed on drop.
+struct WakeEvent(*mut c_void);
+
+impl Drop for WakeEvent {
+    fn drop(&mut self) {
+        // SAFETY: The handle came from `open_event` and is closed once.
+        unsafe { platform::close_event(self.0) };
+    }
+}
+
+/// A flag in shared memory that a controller process trips to ask workers to abort a
+/// long-running computation.
+///
+/// Workers poll `is_cancelled` between steps or block in `wait_timeout`. The word holds the
+/// flag in its lowest bit, and counts cancellations like `Memory::request_shutdown`, so a
+/// worker that remembers the `generation` it started in can tell whether it was cancelled
+/// since, even if the token was reset meanwhile.
+///
+/// On Windows, processes can attach a named event with `with_event`, which the token sets
+/// on cancellation, so that waiters sleep on the event instead of polling.
+pub struct ShmCancellationToken<'a> {
+    block: SharedBlock<'a>,
+    event: Option<WakeEvent>,
+}
+
+impl<'a> ShmCancellationToken<'a> {
+    /// Creates a token that is not cancelled.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let block = SharedBlock::create(memory, std::mem::size_of::<AtomicU64>())?;
+        Some(Self { block, event: None })
+    }
+
+    /// Opens a token created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a token that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block, event: None })
+    }
+
+    /// Returns the offset other processes use to open the token.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Attaches the named event that wakes waiters, creating it if no process did yet.
+    ///
+    /// Every process using the token should attach the same name; waiters still look at the
+    /// flag every 50 ms in case the controller did not. Only available on Windows.
+    pub fn with_event(mut self, name: &str) -> Result<Self, Box<dyn Error>> {
+        // SAFETY: The handle is owned by the token from here on.
+        let event = WakeEvent(unsafe { platform::open_event(name)? });
+        if self.is_cancelled() {
+            // SAFETY: The handle is open.
+            unsafe { platform::set_event(event.0) };
+        }
+        self.event = Some(event);
+        Ok(self)
+    }
+
+    /// Trips the token and wakes the waiters.
+    ///
+    /// Returns the generation of the cancellation, which stays the same if the token was
+    /// already cancelled.
+    pub fn cancel(&self) -> u64 {
+        let word = self.word();
+        let _ = word.fetch_update(SeqCst, SeqCst, |word| (word % 2 == 0).then_some(word + 1));
+        if let Some(event) = &self.event {
+            // SAFETY: The handle is open while the token lives.
+            unsafe { platform::set_event(event.0) };
+        }
+        generation(word.load(SeqCst))
+    }
+
+    /// Returns true while the token is cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.word().load(SeqCst) % 2 == 1
+    }
+
+    /// Returns the number of cancellations since the token was created, counting the
+    /// current one.
+    pub fn generation(&self) -> u64 {
+        generation(self.word().load(SeqCst))
+    }
+
+    /// Clears the flag, e.g. before the next computation. Does nothing if the token is not
+    /// cancelled.
+    pub fn reset(&self) {
+        let _ = self
+            .word()
+            .fetch_update(SeqCst, SeqCst, |word| (word % 2 == 1).then_some(word + 1));
+        if let Some(event) = &self.event {
+            // SAFETY: The handle is open while the token lives.
+            unsafe { platform::reset_event(event.0) };
+        }
+    }
+
+    /// Blocks until the token is cancelled or `timeout` passed.
+    ///
+    /// Returns true if it was cancelled. Without an event, the wait polls the flag like
+    /// `Memory::wait_for_peer`.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let started = Instant::now();
+        let mut polls = 0;
+        loop {
+            if self.is_cancelled() {
+                return true;
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= timeout {
+                return false;
+            }
+            match &self.event {
+                // SAFETY: The handle is open while the token lives.
+                Some(event) => unsafe {
+                    platform::wait_event(event.0, (timeout - elapsed).min(EVENT_SLICE));
+                },
+                None if polls < SPINS => hint::spin_loop(),
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+            polls += 1;
+        }
+    }
+
+    /// Frees the token.
+    ///
+    /// Other processes must not use the token afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn word(&self) -> &AtomicU64 {
+        // SAFETY: The block holds the word.
+        unsafe { &*(self.block.ptr as *const AtomicU64) }
+    }
+}
+
+impl Memory {
+    /// Returns the cancellation token registered under `name`, creating it if there is none.
+    ///
+    /// Every process that asks for the same name gets the same token. Returns None if not
+    /// enough memory.
+    pub fn cancellation_token(&self, name: &str) -> Option<ShmCancellationToken<'_>> {
+        let block = self.named("cancellation_token", name, || {
+            ShmCancellationToken::create(self).map(|token| token.block.ptr)
+        })?;
+        Some(ShmCancellationToken {
+            block: SharedBlock {
+                memory: self,
+                ptr: block,
+            },
+            event: None,
+        })
+    }
+}
+
+fn generation(word: u64) -> u64 {
+    word.div_ceil(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_and_reset() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let controller = memory.cancellation_token("build").unwrap();
+        let worker = memory.cancellation_token("build").unwrap();
+        let timeout = Duration::from_millis(5);
+
+        let started = worker.generation();
+        assert!(!worker.wait_timeout(timeout), "Nobody cancelled yet");
+        assert_eq!(controller.cancel(), 1, "The first cancellation");
+        assert_eq!(controller.cancel(), 1, "Cancelling again changes nothing");
+        assert!(
+            worker.wait_timeout(timeout),
+            "The worker sees the cancellation"
+        );
+
+        controller.reset();
+        assert!(!worker.is_cancelled(), "The token was reset");
+        assert_ne!(
+            worker.generation(),
+            started,
+            "The worker can tell it was cancelled since it started"
+        );
+        assert_eq!(controller.cancel(), 2, "The second cancellation");
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Returns the current unix time in milliseconds.
+///
+/// Wall-clock time is used because it is the only clock all processes on the machine agree on.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the current unix time in nanoseconds.
+pub fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,234 @@
+use std::{marker::PhantomData, ops::Deref};
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::{values_start, SharedBlock},
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct Control {
+    /// Offset of the current version block.
+    current: usize,
+    version: u64,
+}
+
+#[repr(C)]
+struct Version {
+    /// Readers holding the version, plus one while it is the current version.
+    refs: AtomicUsize,
+    version: u64,
+}
+
+/// A configuration value that writers replace as a whole and readers see consistently.
+///
+/// Every version lives in its own block. Publishing allocates and fills a new block, then
+/// swaps it in as the current one; readers hold a reference count on the version they read,
+/// and the last one to let go of a replaced version frees it.
+pub struct ShmConfig<'a, T: ShmSafe> {
+    control: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmConfig<'a, T> {
+    const DATA: usize = values_start::<T>(std::mem::size_of::<Version>());
+
+    /// Creates a configuration with an initial value as version 1.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, value: &T) -> Option<Self> {
+        let size = MemoryMutex::SIZE + std::mem::size_of::<Control>();
+        let control = SharedBlock::create(memory, size)?;
+
+        let config = Self {
+            control,
+            _value: PhantomData,
+        };
+        if config.publish(value).is_none() {
+            memory.deallocate(config.control.ptr);
+            return None;
+        }
+        Some(config)
+    }
+
+    /// Opens a configuration created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a configuration of the same `T` that has not
+    /// been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let control = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            control,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the configuration.
+    pub fn offset(&self) -> Offset {
+        self.control.offset()
+    }
+
+    /// Publishes a new version of the value.
+    ///
+    /// Returns the new version number, or None if not enough memory.
+    pub fn publish(&self, value: &T) -> Option<u64> {
+        let size = Self::DATA + std::mem::size_of::<T>();
+        let block = self
+            .control
+            .memory
+            .allocate_more(size, self.control.ptr)
+            .ok()?
+            .as_ptr();
+
+        // SAFETY: The block was just allocated with room for the header and the value, and
+        // nobody else can see it yet.
+        unsafe {
+            (block.add(Self::DATA) as *mut T).write(*value);
+        }
+
+        let (previous, version) = {
+            let guard = self.lock();
+            let control = control(&guard);
+            control.version += 1;
+            unsafe {
+                (block as *mut Version).write(Version {
+                    refs: AtomicUsize::new(1),
+                    version: control.version,
+                })
+            };
+            let previous = std::mem::replace(&mut control.current, self.offset_of(block));
+            (previous, control.version)
+        };
+
+        if previous != 0 {
+            self.release(self.pointer(previous));
+        }
+        Some(version)
+    }
+
+    /// Returns the current version, which stays valid while the snapshot is alive even if
+    /// a newer version is published meanwhile.
+    pub fn read(&self) -> ConfigSnapshot<'_, 'a, T> {
+        let block = {
+            let guard = self.lock();
+            let block = self.pointer(control(&guard).current);
+            unsafe { &*(block as *const Version) }
+                .refs
+                .fetch_add(1, SeqCst);
+            block
+        };
+        ConfigSnapshot {
+            config: self,
+            block,
+        }
+    }
+
+    /// Returns a copy of the current value.
+    pub fn get(&self) -> T {
+        *self.read()
+    }
+
+    /// Returns the number of the current version.
+    pub fn version(&self) -> u64 {
+        control(&self.lock()).version
+    }
+
+    /// Frees the configuration and all versions.
+    ///
+    /// Other processes must not use the configuration afterwards.
+    pub fn destroy(self) {
+        self.control.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        let size = MemoryMutex::SIZE + std::mem::size_of::<Control>();
+        self.control.lock(size)
+    }
+
+    /// Drops a reference to a version and frees it if it was the last one.
+    fn release(&self, block: *mut u8) {
+        let version = unsafe { &*(block as *const Version) };
+        if version.refs.fetch_sub(1, SeqCst) == 1 {
+            self.control.memory.deallocate(block);
+        }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.control
+            .memory
+            .pointer(Offset(offset))
+            .expect("Versions are inside the memory")
+    }
+
+    fn offset_of(&self, block: *mut u8) -> usize {
+        self.control
+            .memory
+            .offset_of(block)
+            .expect("Versions are inside the memory")
+            .0
+    }
+}
+
+/// A version of a `ShmConfig` value, which is not freed while the snapshot is alive.
+pub struct ConfigSnapshot<'c, 'a, T: ShmSafe> {
+    config: &'c ShmConfig<'a, T>,
+    block: *mut u8,
+}
+
+impl<'c, 'a, T: ShmSafe> ConfigSnapshot<'c, 'a, T> {
+    /// Returns the version number of the snapshot.
+    pub fn version(&self) -> u64 {
+        unsafe { &*(self.block as *const Version) }.version
+    }
+}
+
+impl<'c, 'a, T: ShmSafe> Deref for ConfigSnapshot<'c, 'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: Versions are never written after they are published.
+        unsafe { &*(self.block.add(ShmConfig::<T>::DATA) as *const T) }
+    }
+}
+
+impl<'c, 'a, T: ShmSafe> Drop for ConfigSnapshot<'c, 'a, T> {
+    fn drop(&mut self) {
+        self.config.release(self.block);
+    }
+}
+
+/// Returns the control header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn control<'g>(guard: &'g MemoryGuard) -> &'g mut Control {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut Control) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let config = ShmConfig::create(&memory, &[1u64, 10]).unwrap();
+
+        let old = config.read();
+        assert_eq!(config.publish(&[2, 20]), Some(2), "The second version is 2");
+        assert_eq!(*old, [1, 10], "Old snapshots keep their value");
+        assert_eq!(old.version(), 1, "Old snapshots keep their version");
+
+        let old_block = old.block;
+        drop(old);
+        assert_eq!(
+            memory.allocation_size(old_block),
+            None,
+            "The replaced version is freed with its last reader"
+        );
+        assert_eq!(config.get(), [2, 20], "Readers see the new version");
+    }
+}
@@ -0,0 +1,111 @@
+use std::ptr::NonNull;
+
+use chacha20poly1305::{
+    aead::{AeadCore, AeadInPlace, KeyInit, OsRng},
+    Tag, XChaCha20Poly1305, XNonce,
+};
+
+use crate::{error::AllocError, Memory};
+
+const LEN: usize = std::mem::size_of::<usize>();
+const NONCE: usize = 24;
+const TAG: usize = 16;
+/// Bytes in front of the ciphertext: its length, the nonce and the tag.
+const HEADER: usize = LEN + NONCE + TAG;
+
+/// Stores payloads in a memory encrypted with XChaCha20-Poly1305, so processes that can open
+/// the memory by its name but do not have the key cannot read or undetectably change them.
+///
+/// Each payload is a block of its own with a random nonce, so the same key can seal any
+/// number of them. How processes agree on the key is up to the application.
+pub struct EncryptedMemory<'a> {
+    memory: &'a Memory,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<'a> EncryptedMemory<'a> {
+    pub fn new(memory: &'a Memory, key: &[u8; 32]) -> Self {
+        Self {
+            memory,
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Encrypts `plaintext` into a new block.
+    ///
+    /// The block takes 48 bytes more than the plaintext, which is encrypted before it is
+    /// copied in, so other processes never see it.
+    ///
+    /// Returns the pointer to the block, or why there was not enough memory.
+    pub fn store(&self, plaintext: &[u8]) -> Result<NonNull<u8>, AllocError> {
+        let block = self.memory.allocate(HEADER + plaintext.len())?;
+        let data = block.as_ptr();
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut payload = plaintext.to_vec();
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &[], &mut payload)
+            .expect("Payloads that fit the memory are not too long");
+        // SAFETY: The block was just allocated with room for the header and the payload.
+        unsafe {
+            std::ptr::copy_nonoverlapping(payload.as_ptr(), data.add(HEADER), payload.len());
+            (data as *mut usize).write(plaintext.len());
+            std::ptr::copy_nonoverlapping(nonce.as_ptr(), data.add(LEN), NONCE);
+            std::ptr::copy_nonoverlapping(tag.as_ptr(), data.add(LEN + NONCE), TAG);
+        }
+        Ok(block)
+    }
+
+    /// Decrypts the payload of a block written by `store`.
+    ///
+    /// Returns None if the block is not allocated, is too small, was stored with another key
+    /// or was changed since.
+    pub fn load(&self, buffer: *mut u8) -> Option<Vec<u8>> {
+        let size = self.memory.allocation_size(buffer)?;
+        let buffer = self.memory.pointer(self.memory.offset_of(buffer)?)?;
+        if size < HEADER {
+            return None;
+        }
+        // SAFETY: The block is allocated with at least the header.
+        let len = unsafe { (buffer as *const usize).read() };
+        if len > size - HEADER {
+            return None;
+        }
+        // SAFETY: The header and `len` bytes of ciphertext are inside the block.
+        let (nonce, tag, mut payload) = unsafe {
+            let nonce = *XNonce::from_slice(std::slice::from_raw_parts(buffer.add(LEN), NONCE));
+            let tag = *Tag::from_slice(std::slice::from_raw_parts(buffer.add(LEN + NONCE), TAG));
+            let payload = std::slice::from_raw_parts(buffer.add(HEADER), len).to_vec();
+            (nonce, tag, payload)
+        };
+        self.cipher
+            .decrypt_in_place_detached(&nonce, &[], &mut payload, &tag)
+            .ok()?;
+        Some(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_and_load() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let encrypted = EncryptedMemory::new(&memory, &[7; 32]);
+
+        let block = encrypted.store(b"secret payload").unwrap().as_ptr();
+        let raw = unsafe { std::slice::from_raw_parts(block.add(HEADER), 14) };
+        assert_ne!(raw, b"secret payload", "The memory holds ciphertext");
+        assert_eq!(
+            encrypted.load(block).as_deref(),
+            Some(&b"secret payload"[..]),
+            "The key decrypts it"
+        );
+
+        let other = EncryptedMemory::new(&memory, &[8; 32]);
+        assert_eq!(other.load(block), None, "Another key does not");
+        unsafe { *block.add(HEADER) ^= 1 };
+        assert_eq!(encrypted.load(block), None, "Changes are detected");
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+};
+
+use crate::Memory;
+
+/// A `std::io` cursor over a shared memory region.
+///
+/// It behaves like `std::io::Cursor<&mut [u8]>`: writes past the end of the region are cut
+/// short instead of growing it.
+pub struct ShmCursor<'a> {
+    data: *mut u8,
+    len: usize,
+    pos: u64,
+    _memory: PhantomData<&'a Memory>,
+}
+
+impl<'a> ShmCursor<'a> {
+    /// Creates a cursor over `len` bytes starting at `data`, e.g. a sub-region of a block.
+    ///
+    /// # Safety
+    /// The region must stay allocated and must not be accessed by anyone else while the
+    /// cursor is used.
+    pub unsafe fn from_raw_parts(data: *mut u8, len: usize) -> Self {
+        Self {
+            data,
+            len,
+            pos: 0,
+            _memory: PhantomData,
+        }
+    }
+
+    /// Returns the current position of the cursor.
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    /// Returns the length of the region.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the region is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn remaining(&mut self) -> &mut [u8] {
+        let start = (self.pos as usize).min(self.len);
+        // SAFETY: The region is valid for `len` bytes, see `from_raw_parts`.
+        unsafe { std::slice::from_raw_parts_mut(self.data.add(start), self.len - start) }
+    }
+}
+
+impl<'a> Read for ShmCursor<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining();
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<'a> Write for ShmCursor<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.remaining();
+        let n = remaining.len().min(buf.len());
+        remaining[..n].copy_from_slice(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Seek for ShmCursor<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.len as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        match base.checked_add_signed(offset) {
+            Some(n) => {
+                self.pos = n;
+                Ok(n)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+impl Memory {
+    /// Returns a cursor implementing `Read`, `Write` and `Seek` over the allocated block
+    /// at `buffer`.
+    ///
+    /// Returns None if the block is not allocated.
+    ///
+    /// # Safety
+    /// The block must not be freed or accessed by anyone else while the cursor is used.
+    pub unsafe fn cursor(&self, buffer: *mut u8) -> Option<ShmCursor<'_>> {
+        let size = self.allocation_size(buffer)?;
+        Some(ShmCursor::from_raw_parts(buffer, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use crate::Memory;
+
+    #[test]
+    fn test_write_seek_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let data = memory.allocate(8).unwrap().as_ptr();
+        let mut cursor = unsafe { memory.cursor(data) }.unwrap();
+
+        assert_eq!(
+            cursor.write(b"hello world").unwrap(),
+            8,
+            "Writes stop at the end"
+        );
+
+        cursor.seek(SeekFrom::Start(6)).unwrap();
+        let mut text = String::new();
+        cursor.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "wo", "The tail of the block should be read back");
+    }
+}
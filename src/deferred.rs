@@ -0,0 +1,90 @@
+use crate::{
+    allocator::{self, Allocator},
+    mutex::MemoryMutex,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory,
+};
+
+impl Memory {
+    /// Queues a block to be freed with the blocks linked to it, without taking the lock.
+    ///
+    /// The queue is in the heap and shared by all processes. It is emptied by the next
+    /// allocator call of any process that takes the lock, or by `sweep_deferred`, e.g. from
+    /// a background thread with its own handle, so latency-critical threads do not stall
+    /// freeing large trees. The block must not be used or queued again afterwards.
+    ///
+    /// Returns false if the block is not inside the memory.
+    ///
+    /// # Panics
+    /// If the memory is read-only.
+    pub fn deallocate_deferred(&self, buffer: *mut u8) -> bool {
+        assert!(!self.is_read_only(), "The memory is read-only");
+        if self.deallocate_cached(buffer) {
+            return true;
+        }
+        let Some(offset) = self.offset_of(buffer) else {
+            return false;
+        };
+        let word = self.deferred_word();
+        let mut head = word.load(SeqCst);
+        loop {
+            // SAFETY: The block belongs to the caller until the sweep frees it, and holds at
+            // least a word.
+            unsafe { (buffer as *mut usize).write(head) };
+            match word.compare_exchange_weak(head, offset.0, SeqCst, SeqCst) {
+                Ok(_) => return true,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Frees the blocks queued by `deallocate_deferred` under a single lock.
+    ///
+    /// Returns the number of queued blocks that were freed.
+    pub fn sweep_deferred(&self) -> usize {
+        Allocator::new(self.lock()).free_deferred()
+    }
+
+    /// Returns true if blocks are queued by `deallocate_deferred`.
+    pub fn has_deferred(&self) -> bool {
+        self.deferred_word().load(SeqCst) != 0
+    }
+
+    fn deferred_word(&self) -> &AtomicUsize {
+        // SAFETY: The heap was attached when the memory was opened and lives as long as it.
+        unsafe { allocator::deferred_word(self.buffer().add(MemoryMutex::SIZE)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deferred_frees_are_applied_later() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let before = memory.usage().blocks;
+        let root = memory.allocate(64).unwrap().as_ptr();
+        memory.allocate_more(128, root).unwrap();
+        let other = memory.allocate(32).unwrap().as_ptr();
+
+        assert!(memory.deallocate_deferred(root), "The root is queued");
+        assert!(memory.deallocate_deferred(other), "Another block is queued");
+        assert!(memory.has_deferred(), "The frees are pending");
+        assert_eq!(memory.sweep_deferred(), 2, "Both blocks are freed");
+        assert_eq!(
+            memory.usage().blocks,
+            before,
+            "The child went with the root"
+        );
+
+        let block = memory.allocate(16).unwrap().as_ptr();
+        memory.deallocate_deferred(block);
+        memory.allocate(16).unwrap();
+        assert!(
+            !memory.has_deferred(),
+            "The next allocation emptied the queue"
+        );
+        assert_eq!(memory.usage().blocks, before + 1, "The block was freed");
+    }
+}
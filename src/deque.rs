@@ -0,0 +1,236 @@
+use std::marker::PhantomData;
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::{values_start, SharedBlock},
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct DequeHeader {
+    capacity: usize,
+    /// Index of the slot of the front value.
+    head: usize,
+    len: usize,
+}
+
+/// A fixed capacity double-ended queue in shared memory, behind a lock.
+///
+/// The values live in a ring of slots like a `VecDeque` that never grows. Every operation
+/// takes the lock of the deque, which is simpler to reason about than the lock-free
+/// structures and fast enough when the deque is not contended.
+pub struct ShmDeque<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmDeque<'a, T> {
+    const HEADER: usize = MemoryMutex::SIZE + std::mem::size_of::<DequeHeader>();
+    const SLOTS: usize = values_start::<T>(Self::HEADER);
+
+    /// Creates an empty deque with room for `capacity` values.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: usize) -> Option<Self> {
+        let size = Self::SLOTS + capacity * std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr.add(MemoryMutex::SIZE) as *mut DequeHeader)).capacity = capacity;
+        }
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a deque created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a deque of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the deque.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Appends a value at the back.
+    ///
+    /// Returns the value back if the deque is full.
+    pub fn push_back(&self, value: T) -> Result<(), T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == header.capacity {
+            return Err(value);
+        }
+        let index = (header.head + header.len) % header.capacity;
+        // SAFETY: The slot is free and the lock is held.
+        unsafe { self.slot(index).write(value) };
+        header.len += 1;
+        Ok(())
+    }
+
+    /// Prepends a value at the front.
+    ///
+    /// Returns the value back if the deque is full.
+    pub fn push_front(&self, value: T) -> Result<(), T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == header.capacity {
+            return Err(value);
+        }
+        header.head = (header.head + header.capacity - 1) % header.capacity;
+        // SAFETY: The slot is free and the lock is held.
+        unsafe { self.slot(header.head).write(value) };
+        header.len += 1;
+        Ok(())
+    }
+
+    /// Removes the value at the back.
+    pub fn pop_back(&self) -> Option<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == 0 {
+            return None;
+        }
+        header.len -= 1;
+        let index = (header.head + header.len) % header.capacity;
+        // SAFETY: The slot held the back value and the lock is held.
+        Some(unsafe { self.slot(index).read() })
+    }
+
+    /// Removes the value at the front.
+    pub fn pop_front(&self) -> Option<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == 0 {
+            return None;
+        }
+        // SAFETY: The slot held the front value and the lock is held.
+        let value = unsafe { self.slot(header.head).read() };
+        header.head = (header.head + 1) % header.capacity;
+        header.len -= 1;
+        Some(value)
+    }
+
+    /// Returns a copy of the value at `index` from the front.
+    pub fn get(&self, index: usize) -> Option<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if index >= header.len {
+            return None;
+        }
+        let index = (header.head + index) % header.capacity;
+        // SAFETY: The slot holds a value and the lock is held.
+        Some(unsafe { self.slot(index).read() })
+    }
+
+    /// Returns a copy of the value at the front.
+    pub fn front(&self) -> Option<T> {
+        self.get(0)
+    }
+
+    /// Returns a copy of the value at the back.
+    pub fn back(&self) -> Option<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        let last = header.len.checked_sub(1)?;
+        let index = (header.head + last) % header.capacity;
+        // SAFETY: The slot holds a value and the lock is held.
+        Some(unsafe { self.slot(index).read() })
+    }
+
+    /// Returns copies of all values, front to back.
+    pub fn to_vec(&self) -> Vec<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        (0..header.len)
+            .map(|index| {
+                let index = (header.head + index) % header.capacity;
+                // SAFETY: The slot holds a value and the lock is held.
+                unsafe { self.slot(index).read() }
+            })
+            .collect()
+    }
+
+    /// Removes all values.
+    pub fn clear(&self) {
+        let guard = self.lock();
+        let header = header(&guard);
+        header.head = 0;
+        header.len = 0;
+    }
+
+    /// Returns the number of values in the deque.
+    pub fn len(&self) -> usize {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the deque is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of values.
+    pub fn capacity(&self) -> usize {
+        header(&self.lock()).capacity
+    }
+
+    /// Frees the deque.
+    ///
+    /// Other processes must not use the deque afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::HEADER)
+    }
+
+    fn slot(&self, index: usize) -> *mut T {
+        // SAFETY: The caller took the index modulo the capacity.
+        unsafe { (self.block.ptr.add(Self::SLOTS) as *mut T).add(index) }
+    }
+}
+
+/// Returns the deque header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut DequeHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut DequeHeader) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop_both_ends() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let deque = ShmDeque::<u32>::create(&memory, 3).unwrap();
+
+        assert_eq!(deque.push_back(2), Ok(()), "The deque has room");
+        assert_eq!(deque.push_front(1), Ok(()), "The front wraps around");
+        assert_eq!(deque.push_back(3), Ok(()), "The deque has room");
+        assert_eq!(deque.push_front(0), Err(0), "The deque is full");
+        assert_eq!(deque.to_vec(), vec![1, 2, 3], "Values are in order");
+
+        assert_eq!(deque.pop_front(), Some(1), "The front comes out");
+        assert_eq!(deque.pop_back(), Some(3), "The back comes out");
+        assert_eq!(deque.back(), Some(2), "One value is left");
+        assert_eq!(deque.front(), Some(2), "One value is left");
+
+        deque.clear();
+        assert_eq!(deque.pop_back(), None, "The deque is empty");
+    }
+}
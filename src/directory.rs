@@ -0,0 +1,104 @@
+//! Objects registered under a name, so processes can find them without passing offsets.
+//!
+//! The directory is a `ShmKvStore` from names to offsets, created when the first name is
+//! registered. Its offset is kept in the heap header.
+
+use crate::{Memory, Offset, ShmKvStore};
+
+impl Memory {
+    /// Returns the block registered under `kind` and `name`, registering a block made by
+    /// `create` if there is none.
+    ///
+    /// If two processes register the same name at once, one block wins and the other is
+    /// freed again. Returns None if not enough memory.
+    pub(crate) fn named(
+        &self,
+        kind: &str,
+        name: &str,
+        create: impl FnOnce() -> Option<*mut u8>,
+    ) -> Option<*mut u8> {
+        let directory = self.directory()?;
+        let key = format!("{kind}:{name}");
+        if let Some(value) = directory.get(&key) {
+            return self.pointer(decode(&value));
+        }
+
+        let block = create()?;
+        let offset = self.offset_of(block)?;
+        let Some(value) = directory.get_or_insert(&key, &offset.0.to_ne_bytes()) else {
+            self.deallocate(block);
+            return None;
+        };
+
+        let winner = decode(&value);
+        if winner != offset {
+            self.deallocate(block);
+        }
+        self.pointer(winner)
+    }
+
+    /// Removes the registration of `kind` and `name` and returns its block, which stays
+    /// allocated.
+    pub(crate) fn unname(&self, kind: &str, name: &str) -> Option<*mut u8> {
+        let directory = self.directory()?;
+        let key = format!("{kind}:{name}");
+        let block = self.pointer(decode(&directory.get(&key)?))?;
+        // Of two processes removing the name at once, only one gets the block.
+        directory.remove(&key).then_some(block)
+    }
+
+    /// Returns the names registered under `kind`, in no particular order.
+    pub(crate) fn names(&self, kind: &str) -> Vec<String> {
+        let Some(directory) = self.directory() else {
+            return Vec::new();
+        };
+        let prefix = format!("{kind}:");
+        directory
+            .keys()
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&prefix).map(str::to_owned))
+            .collect()
+    }
+
+    /// Points names of moved blocks to their new offsets.
+    ///
+    /// `moves` holds the old and new offsets of each moved block, ordered by old offset.
+    pub(crate) fn relocate_names(&self, moves: &[(Offset, Offset)]) {
+        let offset = self.allocator().directory();
+        if offset == 0 {
+            return;
+        }
+        // SAFETY: Only directory offsets are stored in the heap header.
+        let Some(directory) = (unsafe { ShmKvStore::open(self, Offset(offset)) }) else {
+            return;
+        };
+        directory.update_values(|value| {
+            let old = decode(value);
+            if let Ok(index) = moves.binary_search_by_key(&old, |&(old, _)| old) {
+                value.copy_from_slice(&moves[index].1 .0.to_ne_bytes());
+            }
+        });
+    }
+
+    fn directory(&self) -> Option<ShmKvStore<'_>> {
+        let offset = self.allocator().directory();
+        if offset != 0 {
+            // SAFETY: Only directory offsets are stored in the heap header.
+            return unsafe { ShmKvStore::open(self, Offset(offset)) };
+        }
+
+        let store = ShmKvStore::create(self, 16)?;
+        let offset = self.allocator().claim_directory(store.offset().0);
+        if offset == store.offset().0 {
+            return Some(store);
+        }
+        store.destroy();
+        // SAFETY: Another process created the directory in the meantime.
+        unsafe { ShmKvStore::open(self, Offset(offset)) }
+    }
+}
+
+fn decode(value: &[u8]) -> Offset {
+    let bytes = value.try_into().expect("Directory values are offsets");
+    Offset(usize::from_ne_bytes(bytes))
+}
@@ -0,0 +1,191 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use crate::Memory;
+
+impl Memory {
+    /// Writes a table of every allocated block, with its offset, size, parent, tag, owner and
+    /// expiry, followed by the usage of the heap.
+    ///
+    /// The blocks are listed under one lock, so the table is consistent even if other
+    /// processes allocate while it is written.
+    pub fn dump(&self, out: &mut impl Write) -> io::Result<()> {
+        let (blocks, usage) = {
+            let allocator = self.inspector();
+            (allocator.blocks(), allocator.usage())
+        };
+
+        writeln!(
+            out,
+            "{:>12} {:>12} {:>12} {:>10} {:>10} {:>16}",
+            "offset", "size", "parent", "tag", "owner", "expires"
+        )?;
+        for block in &blocks {
+            let parent = block
+                .parent
+                .map_or_else(|| "-".to_string(), |parent| format!("{:#x}", parent.get()));
+            let expires = block
+                .expires
+                .map_or_else(|| "-".to_string(), |expires| expires.to_string());
+            writeln!(
+                out,
+                "{:>12} {:>12} {:>12} {:>10} {:>10} {:>16}",
+                format!("{:#x}", block.offset.get()),
+                block.size,
+                parent,
+                block.tag,
+                block.owner,
+                expires
+            )?;
+        }
+        writeln!(
+            out,
+            "{} blocks, {} of {} bytes used, peak {} bytes",
+            usage.blocks, usage.used, usage.capacity, usage.peak_used
+        )
+    }
+
+    /// Describes the blocks and the free ranges between them as JSON, for visualization tools.
+    ///
+    /// The object has the `capacity` and `used` bytes of the heap, a `blocks` array with the
+    /// fields of `BlockInfo`, null where they are None, and a `gaps` array of free ranges
+    /// with their `offset` and `size`. Offsets are plain numbers.
+    pub fn export_layout(&self) -> String {
+        let (blocks, gaps, usage) = {
+            let allocator = self.inspector();
+            (allocator.blocks(), allocator.gaps(), allocator.usage())
+        };
+
+        let mut json = format!(
+            "{{\"capacity\":{},\"used\":{},\"blocks\":[",
+            usage.capacity, usage.used
+        );
+        for (index, block) in blocks.iter().enumerate() {
+            let separator = if index > 0 { "," } else { "" };
+            let parent = block
+                .parent
+                .map_or_else(|| "null".to_string(), |parent| parent.get().to_string());
+            let expires = block
+                .expires
+                .map_or_else(|| "null".to_string(), |expires| expires.to_string());
+            let created = block
+                .created
+                .map_or_else(|| "null".to_string(), |created| created.to_string());
+            let _ = write!(
+                json,
+                "{separator}{{\"offset\":{},\"size\":{},\"parent\":{parent},\"tag\":{},\"owner\":{},\"expires\":{expires},\"created\":{created}}}",
+                block.offset.get(),
+                block.size,
+                block.tag,
+                block.owner
+            );
+        }
+        json.push_str("],\"gaps\":[");
+        for (index, (offset, size)) in gaps.iter().enumerate() {
+            let separator = if index > 0 { "," } else { "" };
+            let _ = write!(
+                json,
+                "{separator}{{\"offset\":{},\"size\":{size}}}",
+                offset.get()
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+
+    /// Describes the blocks as a Graphviz graph, with an edge from every child to its parent.
+    pub fn export_dot(&self) -> String {
+        let mut dot = String::from("digraph heap {\n    node [shape=box];\n");
+        for block in self.blocks() {
+            let offset = block.offset.get();
+            let _ = writeln!(
+                dot,
+                "    b{offset} [label=\"{offset:#x}\\n{} bytes\\ntag {}\"];",
+                block.size, block.tag
+            );
+            if let Some(parent) = block.parent {
+                let _ = writeln!(dot, "    b{offset} -> b{};", parent.get());
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    #[test]
+    fn test_dump() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let parent = memory.allocate_tagged(16, 3).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+
+        let mut out = Vec::new();
+        memory.dump(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        assert_eq!(lines.len(), 4, "A header, two blocks and a summary");
+        let parent = format!("{:#x}", memory.offset_of(parent).unwrap().get());
+        assert!(lines[1].contains(&parent), "The parent is listed first");
+        assert!(lines[2].contains(&parent), "The child names its parent");
+        assert!(lines[3].starts_with("2 blocks"), "The summary counts both");
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_read_only() {
+        let memory = Memory::new("rshmem_test_read_only", 4096, 0).unwrap();
+        memory.allocate(16).unwrap();
+
+        let view = Memory::builder("rshmem_test_read_only", 4096)
+            .read_only(true)
+            .build()
+            .unwrap();
+        assert_eq!(view.blocks(), memory.blocks(), "The view sees the blocks");
+        assert!(!view.is_locked(), "Nobody holds the lock");
+        assert!(
+            Memory::builder("rshmem_test_read_only_missing", 4096)
+                .read_only(true)
+                .build()
+                .is_err(),
+            "Only existing memories can be opened"
+        );
+    }
+
+    #[test]
+    fn test_export_layout() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let first = memory.allocate(16).unwrap().as_ptr();
+        let parent = memory.allocate(16).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+        memory.deallocate(first);
+
+        let json = memory.export_layout();
+        assert_eq!(json.matches("\"owner\"").count(), 2, "Two blocks are left");
+        assert_eq!(
+            json.matches("\"parent\":null").count(),
+            1,
+            "Only the child has a parent"
+        );
+        let gaps = &json[json.find("\"gaps\"").unwrap()..];
+        assert_eq!(
+            gaps.matches("\"offset\"").count(),
+            2,
+            "The freed block and the tail are free"
+        );
+
+        let parent = memory.offset_of(parent).unwrap().get();
+        assert!(
+            memory.export_dot().contains(&format!("-> b{parent};")),
+            "The child points to its parent"
+        );
+    }
+}
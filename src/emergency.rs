@@ -0,0 +1,109 @@
+use std::ptr::NonNull;
+
+use crate::{AllocError, Memory};
+
+/// Pauses the allocations of every process other than `Memory::allocate_critical` until it
+/// is dropped, see `Memory::pause_allocations`.
+pub struct AllocationPause<'a> {
+    memory: &'a Memory,
+}
+
+impl Drop for AllocationPause<'_> {
+    fn drop(&mut self) {
+        self.memory.allocator().resume(false);
+    }
+}
+
+impl Memory {
+    /// Allocates a new block of memory that may take the emergency reserve, see
+    /// `MemoryBuilder::emergency_reserve`, and succeeds while allocations are paused.
+    ///
+    /// Meant for a process recovering the shared state, not for ordinary data: no other
+    /// allocation can take the reserve.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_critical(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        self.observe_with(size, |allocator| {
+            allocator
+                .allocate_critical(size)
+                .ok_or_else(|| allocator.critical_alloc_error(size))
+        })
+    }
+
+    /// Returns the bytes kept free for `allocate_critical`.
+    pub fn emergency_reserve(&self) -> usize {
+        self.inspector().emergency_reserve()
+    }
+
+    /// Changes the bytes kept free for `allocate_critical`, for every process.
+    ///
+    /// Blocks already allocated stay, even if the heap has less free space than `bytes`.
+    pub fn set_emergency_reserve(&self, bytes: usize) {
+        self.allocator().set_emergency_reserve(bytes);
+    }
+
+    /// Makes allocations of every process fail with `AllocError::Paused` until the guard is
+    /// dropped, except those of `allocate_critical`, e.g. while a recovery rebuilds the
+    /// shared structures.
+    ///
+    /// Pauses nest. Blocks that the local cache of a process already holds are still handed
+    /// out. If the pausing process dies, `resume_allocations` ends the pause.
+    pub fn pause_allocations(&self) -> AllocationPause<'_> {
+        self.allocator().pause();
+        AllocationPause { memory: self }
+    }
+
+    /// Ends every pause of `pause_allocations`, including those of processes that died.
+    pub fn resume_allocations(&self) {
+        self.allocator().resume(true);
+    }
+
+    /// Returns true while allocations are paused.
+    pub fn allocations_paused(&self) -> bool {
+        self.inspector().is_paused()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Priority;
+
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_pause() {
+        let memory = Memory::builder("", 8192)
+            .in_process(true)
+            .emergency_reserve(1024)
+            .build()
+            .unwrap();
+        let bulk = memory.allocate(8192).unwrap_err().largest_hole();
+        memory
+            .allocate(bulk)
+            .expect("Bulk data fits next to the reserve");
+        assert!(
+            memory.allocate_with_priority(64, Priority::High).is_err(),
+            "Only critical allocations take the emergency reserve"
+        );
+        assert!(
+            memory.allocate_critical(64).is_ok(),
+            "The reserve is there for critical allocations"
+        );
+
+        memory.set_emergency_reserve(0);
+        let pause = memory.pause_allocations();
+        assert!(memory.allocations_paused(), "Allocations are paused");
+        assert_eq!(
+            memory.allocate(64),
+            Err(AllocError::Paused { requested: 64 }),
+            "Ordinary allocations fail while paused"
+        );
+        assert!(
+            memory.allocate_critical(64).is_ok(),
+            "Critical allocations go on"
+        );
+        drop(pause);
+        assert!(memory.allocate(64).is_ok(), "Allocations resumed");
+    }
+}
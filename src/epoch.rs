@@ -0,0 +1,337 @@
+use crate::{
+    shared::SharedBlock,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+/// Slot states. A pinned slot also holds the epoch it was pinned in, shifted by two.
+const FREE: u64 = 0;
+const REGISTERED: u64 = 1;
+const PINNED: u64 = 2;
+
+/// Retired blocks are collected once this many are pending.
+const COLLECT_EVERY: usize = 64;
+
+#[repr(C)]
+struct EpochHeader {
+    epoch: AtomicU64,
+    /// Set while a process advances the epoch, so only one frees blocks at a time.
+    collecting: AtomicBool,
+    participants: usize,
+    pending: AtomicUsize,
+    /// Offsets of the records of blocks retired in each of the last three epochs.
+    limbo: [AtomicUsize; 3],
+}
+
+#[repr(C)]
+struct Record {
+    next: usize,
+    block: usize,
+}
+
+/// Epoch-based reclamation, which delays freeing blocks until no process can reach them.
+///
+/// Lock-free structures cannot free a node as soon as it is unlinked, because another
+/// process may still be reading it. Instead, every process registers as a participant,
+/// pins the current epoch while it traverses a structure, and retires unlinked nodes rather
+/// than freeing them. The epoch only advances once every pinned participant has seen it,
+/// and a block retired in one epoch is freed two epochs later, when nobody pinned before it
+/// was retired is left.
+///
+/// A participant that dies while pinned stops the epoch from advancing, so retired blocks
+/// pile up until its slot is reused.
+pub struct ShmEpoch<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmEpoch<'a> {
+    /// Creates a domain with room for `participants` participants at once.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, participants: usize) -> Option<Self> {
+        let size = std::mem::size_of::<EpochHeader>() + participants * std::mem::size_of::<u64>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr as *mut EpochHeader)).participants = participants;
+        }
+        Some(Self { block })
+    }
+
+    /// Opens a domain created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a domain that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the domain.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Claims a participant slot for this thread.
+    ///
+    /// Returns None if all slots are taken.
+    pub fn register(&self) -> Option<Participant<'_, 'a>> {
+        let slot = self.slots().iter().find(|slot| {
+            slot.compare_exchange(FREE, REGISTERED, SeqCst, SeqCst)
+                .is_ok()
+        })?;
+        Some(Participant { domain: self, slot })
+    }
+
+    /// Returns the current epoch.
+    pub fn epoch(&self) -> u64 {
+        self.header().epoch.load(SeqCst)
+    }
+
+    /// Returns the number of retired blocks that were not freed yet.
+    pub fn pending(&self) -> usize {
+        self.header().pending.load(SeqCst)
+    }
+
+    /// Advances the epoch if every pinned participant has seen it, and frees the blocks that
+    /// can no longer be reached.
+    ///
+    /// Returns the number of blocks freed.
+    pub fn collect(&self) -> usize {
+        let header = self.header();
+        if header
+            .collecting
+            .compare_exchange(false, true, SeqCst, SeqCst)
+            .is_err()
+        {
+            return 0;
+        }
+
+        let epoch = header.epoch.load(SeqCst);
+        let behind = self.slots().iter().any(|slot| {
+            let state = slot.load(SeqCst);
+            state & PINNED != 0 && state >> 2 != epoch
+        });
+        let mut freed = 0;
+        if !behind {
+            let epoch = epoch + 1;
+            header.epoch.store(epoch, SeqCst);
+            // Everybody pinned is in the previous epoch or later, so blocks retired two
+            // epochs ago are unreachable. Their list is the one the next epoch will use.
+            let list = header.limbo[(epoch as usize + 1) % 3].swap(0, SeqCst);
+            freed = self.free_list(list);
+        }
+
+        header.collecting.store(false, SeqCst);
+        freed
+    }
+
+    /// Frees the domain and every retired block.
+    ///
+    /// No participant may be pinned, and other processes must not use the domain afterwards.
+    pub fn destroy(self) {
+        for list in &self.header().limbo {
+            self.free_list(list.swap(0, SeqCst));
+        }
+        self.block.free();
+    }
+
+    /// Frees the blocks of a list of records, along with the records.
+    fn free_list(&self, mut current: usize) -> usize {
+        let mut freed = 0;
+        while current != 0 {
+            let record = self.pointer(current);
+            // SAFETY: Records were fully written before they were pushed.
+            let Record { next, block } = unsafe { (record as *const Record).read() };
+            self.block.memory.deallocate(self.pointer(block));
+            self.block.memory.deallocate(record);
+            freed += 1;
+            current = next;
+        }
+        self.header().pending.fetch_sub(freed, SeqCst);
+        freed
+    }
+
+    fn header(&self) -> &EpochHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const EpochHeader) }
+    }
+
+    fn slots(&self) -> &[AtomicU64] {
+        // SAFETY: The slots directly follow the header.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.block.ptr.add(std::mem::size_of::<EpochHeader>()) as *const AtomicU64,
+                self.header().participants,
+            )
+        }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Blocks are inside the memory")
+    }
+}
+
+/// A registered participant of a `ShmEpoch`. Dropping it frees its slot.
+pub struct Participant<'d, 'a> {
+    domain: &'d ShmEpoch<'a>,
+    slot: &'d AtomicU64,
+}
+
+impl<'d, 'a> Participant<'d, 'a> {
+    /// Pins the current epoch until the guard is dropped.
+    ///
+    /// Blocks retired by anyone while the guard lives are not freed before it is dropped, so
+    /// nodes reached through a structure stay readable.
+    pub fn pin(&mut self) -> EpochGuard<'_, 'd, 'a> {
+        let header = self.domain.header();
+        loop {
+            let epoch = header.epoch.load(SeqCst);
+            self.slot.store(epoch << 2 | PINNED | REGISTERED, SeqCst);
+            // The epoch may have advanced before the slot was seen, then pin the new one.
+            if header.epoch.load(SeqCst) == epoch {
+                return EpochGuard {
+                    participant: self,
+                    epoch,
+                };
+            }
+        }
+    }
+}
+
+impl<'d, 'a> Drop for Participant<'d, 'a> {
+    fn drop(&mut self) {
+        self.slot.store(FREE, SeqCst);
+    }
+}
+
+/// Keeps an epoch pinned, see `Participant::pin`.
+pub struct EpochGuard<'p, 'd, 'a> {
+    participant: &'p mut Participant<'d, 'a>,
+    epoch: u64,
+}
+
+impl<'p, 'd, 'a> EpochGuard<'p, 'd, 'a> {
+    /// Frees a block once no participant can reach it anymore.
+    ///
+    /// The block must already be unlinked from every structure, so participants that pin
+    /// later cannot find it. Returns false if there was not enough memory to record it, in
+    /// which case the caller still owns the block.
+    pub fn retire(&self, block: *mut u8) -> bool {
+        let domain = self.participant.domain;
+        let Some(block) = domain.block.memory.offset_of(block) else {
+            return false;
+        };
+        let size = std::mem::size_of::<Record>();
+        let Ok(record) = domain.block.memory.allocate_more(size, domain.block.ptr) else {
+            return false;
+        };
+
+        // Participants pinned in the current epoch may still have reached the block, even if
+        // this guard was pinned in the one before. The epoch cannot advance twice while this
+        // guard is pinned, so the list is not collected before they are gone.
+        let header = domain.header();
+        let list = &header.limbo[header.epoch.load(SeqCst) as usize % 3];
+        let offset = domain
+            .block
+            .memory
+            .offset_of(record.as_ptr())
+            .expect("Records are inside the memory");
+        let mut head = list.load(SeqCst);
+        loop {
+            // SAFETY: The record was just allocated and is not in a list yet.
+            unsafe {
+                (record.as_ptr() as *mut Record).write(Record {
+                    next: head,
+                    block: block.0,
+                })
+            };
+            match list.compare_exchange(head, offset.0, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+
+        if (header.pending.fetch_add(1, SeqCst) + 1).is_multiple_of(COLLECT_EVERY) {
+            domain.collect();
+        }
+        true
+    }
+
+    /// Returns the pinned epoch.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
+
+impl<'p, 'd, 'a> Drop for EpochGuard<'p, 'd, 'a> {
+    fn drop(&mut self) {
+        self.participant.slot.store(REGISTERED, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retire_waits_for_pinned() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let domain = ShmEpoch::create(&memory, 2).unwrap();
+        let mut reader = domain.register().unwrap();
+        let mut writer = domain.register().unwrap();
+        assert!(domain.register().is_none(), "All slots are taken");
+
+        let node = memory.allocate(16).unwrap().as_ptr();
+        let pinned = reader.pin();
+        assert!(writer.pin().retire(node), "The record should fit");
+
+        assert_eq!(
+            domain.collect(),
+            0,
+            "The epoch advances, nothing is old enough"
+        );
+        assert_eq!(domain.collect(), 0, "The reader holds back the epoch");
+        assert!(
+            memory.allocation_size(node).is_some(),
+            "The node is still readable"
+        );
+
+        drop(pinned);
+        assert_eq!(domain.collect(), 1, "The node is freed two epochs later");
+        assert!(memory.allocation_size(node).is_none(), "The node was freed");
+        assert_eq!(domain.pending(), 0, "Nothing is pending");
+    }
+
+    #[test]
+    fn test_retire_after_epoch_advanced() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let domain = ShmEpoch::create(&memory, 2).unwrap();
+        let mut reader = domain.register().unwrap();
+        let mut writer = domain.register().unwrap();
+
+        let node = memory.allocate(16).unwrap().as_ptr();
+        let retiring = writer.pin();
+        assert_eq!(domain.collect(), 0, "The epoch advances past the writer");
+        // The reader reaches the node in the new epoch, before the writer unlinks it.
+        let pinned = reader.pin();
+        assert_eq!(pinned.epoch(), 1, "The reader pins the new epoch");
+        assert!(retiring.retire(node), "The record should fit");
+        drop(retiring);
+
+        assert_eq!(domain.collect(), 0, "The reader may still read the node");
+        assert!(
+            memory.allocation_size(node).is_some(),
+            "The node is still readable"
+        );
+
+        drop(pinned);
+        assert_eq!(domain.collect(), 1, "Nobody can reach the node anymore");
+        assert!(memory.allocation_size(node).is_none(), "The node was freed");
+    }
+}
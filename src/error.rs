@@ -0,0 +1,97 @@
+use std::{error::Error, fmt};
+
+/// Why an allocation failed.
+///
+/// Sizes are in bytes. `free` counts all unused bytes of the heap, including the room block
+/// headers would take; `largest_hole` is the largest allocation that would still succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The heap does not have enough free bytes in total. Freeing blocks or using a larger
+    /// memory helps, compacting does not.
+    Exhausted {
+        requested: usize,
+        free: usize,
+        largest_hole: usize,
+    },
+    /// The heap has enough free bytes in total, but no single gap between blocks is large
+    /// enough. Smaller allocations or compacting may help.
+    Fragmented {
+        requested: usize,
+        free: usize,
+        largest_hole: usize,
+    },
+    /// The allocation would take the allocating process or the arena over its quota, see
+    /// `Memory::set_owner_quota` and `ShmArena::set_quota`. The heap itself may have room.
+    QuotaExceeded {
+        requested: usize,
+        quota: usize,
+        used: usize,
+        largest_hole: usize,
+    },
+    /// Allocations are paused for recovery, see `Memory::pause_allocations`. Only
+    /// `Memory::allocate_critical` succeeds until they are resumed.
+    Paused { requested: usize },
+}
+
+impl AllocError {
+    /// Returns the size that was asked for.
+    pub fn requested(&self) -> usize {
+        match *self {
+            Self::Exhausted { requested, .. }
+            | Self::Fragmented { requested, .. }
+            | Self::QuotaExceeded { requested, .. }
+            | Self::Paused { requested } => requested,
+        }
+    }
+
+    /// Returns the size of the largest allocation that would still succeed.
+    pub fn largest_hole(&self) -> usize {
+        match *self {
+            Self::Exhausted { largest_hole, .. }
+            | Self::Fragmented { largest_hole, .. }
+            | Self::QuotaExceeded { largest_hole, .. } => largest_hole,
+            Self::Paused { .. } => 0,
+        }
+    }
+
+    /// Returns true if the heap has enough free bytes, just not in one place.
+    pub fn is_fragmented(&self) -> bool {
+        matches!(self, Self::Fragmented { .. })
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Exhausted {
+                requested, free, ..
+            } => write!(
+                f,
+                "Not enough memory for {requested} bytes, {free} bytes are free"
+            ),
+            Self::Fragmented {
+                requested,
+                largest_hole,
+                ..
+            } => write!(
+                f,
+                "Memory is too fragmented for {requested} bytes, the largest hole has {largest_hole} bytes"
+            ),
+            Self::QuotaExceeded {
+                requested,
+                quota,
+                used,
+                ..
+            } => write!(
+                f,
+                "{requested} bytes would exceed the quota of {quota} bytes, {used} bytes are in use"
+            ),
+            Self::Paused { requested } => write!(
+                f,
+                "Allocations are paused, {requested} bytes were not allocated"
+            ),
+        }
+    }
+}
+
+impl Error for AllocError {}
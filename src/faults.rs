@@ -0,0 +1,97 @@
+//! Failures on demand, so applications can test their out-of-memory and recovery paths.
+//!
+//! Everything here acts on this process only and is meant for tests.
+
+use crate::{mutex::MemoryGuard, Memory};
+
+/// Keeps the heap locked like a process stuck in the middle of an operation, until dropped.
+pub struct HeldLock<'a> {
+    _guard: MemoryGuard<'a>,
+}
+
+impl Memory {
+    /// Makes an allocation of this process fail after `n` more succeed, e.g. zero for the
+    /// next one.
+    ///
+    /// The allocation fails with the error the heap would give for its size, and the failure
+    /// hooks run as usual. Only one failure is pending at a time; a later call replaces it.
+    pub fn fail_allocation_after(&self, n: usize) {
+        self.fail_after.set(Some(n));
+    }
+
+    /// Locks the heap until the returned guard is dropped.
+    ///
+    /// Meanwhile every allocation and deallocation of any thread or process waits, which
+    /// makes timeouts built around them fire deterministically. Using the memory from the
+    /// thread holding the guard deadlocks.
+    pub fn hold_lock(&self) -> HeldLock<'_> {
+        HeldLock {
+            _guard: self.allocator().into_guard(),
+        }
+    }
+
+    /// Frees a block the way a process dying halfway through `deallocate` would.
+    ///
+    /// The block is unlinked but its children stay allocated, and the usage counters keep
+    /// counting it. The heap stays locked by the dead process, so every other operation
+    /// waits until `check_and_repair` takes the lock over and cleans up. Returns false,
+    /// leaving the heap alone, if the block is not allocated.
+    pub fn die_during_deallocate(&self, buffer: *mut u8) -> bool {
+        let allocator = self.allocator();
+        if !allocator.unlink_only(buffer) {
+            return false;
+        }
+        allocator.into_guard().abandon();
+        true
+    }
+
+    /// Returns true if the pending injected failure is due, counting down otherwise.
+    pub(crate) fn take_fault(&self) -> bool {
+        match self.fail_after.get() {
+            Some(0) => {
+                self.fail_after.set(None);
+                true
+            }
+            Some(n) => {
+                self.fail_after.set(Some(n - 1));
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    #[test]
+    fn test_injected_failures() {
+        let memory = Memory::new_in_process(4096).unwrap();
+
+        memory.fail_allocation_after(1);
+        assert!(memory.allocate(8).is_ok(), "The first allocation succeeds");
+        assert!(
+            memory.allocate(8).is_err(),
+            "The second one is made to fail"
+        );
+        assert!(memory.allocate(8).is_ok(), "The failure is not repeated");
+
+        let parent = memory.allocate(8).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+        assert!(
+            memory.die_during_deallocate(parent),
+            "The parent is unlinked"
+        );
+        assert!(memory.is_locked(), "The dead process still holds the lock");
+        let report = memory.check_and_repair();
+        assert!(report.lock_broken, "The lock of the dead process is broken");
+        assert_eq!(report.orphans_freed, 1, "The child is left behind");
+        assert!(!memory.is_locked(), "The repair releases the lock");
+
+        let held = memory.hold_lock();
+        assert!(memory.is_locked(), "The heap is locked");
+        drop(held);
+        assert!(!memory.is_locked(), "The lock is released");
+    }
+}
@@ -0,0 +1,246 @@
+use crate::{allocator, clock, Memory};
+
+/// Version of the layout of the heap and the shared data structures. Changing any of them
+/// must bump it, so binaries built before and after the change refuse to share a memory.
+pub(crate) const LAYOUT_VERSION: u32 = 10;
+
+/// Written in the byte order of the creator, so a process of the other order reads it swapped.
+const BYTE_ORDER: u32 = 0x0102_0304;
+
+/// Width in bytes of the word fields of the heap and block headers.
+const WORD: u32 = if cfg!(feature = "wide-layout") {
+    8
+} else {
+    std::mem::size_of::<usize>() as u32
+};
+
+/// Number of the latest attaching processes the handshake remembers.
+const PEERS: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawPeer {
+    pid: u32,
+    protocol: u32,
+    timestamp: u64,
+    /// The crate version of the process, padded with zeros.
+    version: [u8; 16],
+}
+
+/// What the creator of a memory requires of processes attaching to it, and which ones did.
+///
+/// It comes right after the magic of the heap header, so every future layout finds the
+/// version fields in the same place and can reject the memory.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Handshake {
+    layout: u32,
+    /// Width of the word fields in bytes, 8 for 64-bit processes and the wide layout.
+    word: u32,
+    byte_order: u32,
+    /// See `allocator::layout_hash`.
+    layout_hash: u64,
+    /// Application protocol version of the creator.
+    protocol: u32,
+    /// Number of processes that ever attached, including the creator.
+    attached: u32,
+    peers: [RawPeer; PEERS],
+}
+
+impl Handshake {
+    /// Records the versions of the creator.
+    pub(crate) fn init(&mut self, protocol: u32) {
+        self.layout = LAYOUT_VERSION;
+        self.word = WORD;
+        self.byte_order = BYTE_ORDER;
+        self.layout_hash = allocator::layout_hash();
+        self.protocol = protocol;
+        self.attached = 0;
+        self.register(protocol);
+    }
+
+    /// Returns why a process speaking `protocol` cannot use the memory, if it cannot.
+    pub(crate) fn check(&self, protocol: u32) -> Result<(), String> {
+        // The version comes first in every layout, so check the byte order on it before
+        // trusting any other field.
+        if self.layout.swap_bytes() == LAYOUT_VERSION
+            || self.layout == LAYOUT_VERSION && self.byte_order != BYTE_ORDER
+        {
+            let (theirs, ours) = if cfg!(target_endian = "little") {
+                ("big", "little")
+            } else {
+                ("little", "big")
+            };
+            return Err(format!(
+                "The memory was created by a {theirs}-endian process, this process is {ours}-endian"
+            ));
+        }
+        if self.layout != LAYOUT_VERSION {
+            return Err(format!(
+                "The memory has layout version {}, this process uses {LAYOUT_VERSION}",
+                self.layout
+            ));
+        }
+        if self.word != WORD {
+            return Err(format!(
+                "The memory has {}-bit words, this process uses {}-bit words",
+                self.word * 8,
+                WORD * 8
+            ));
+        }
+        if self.layout_hash != allocator::layout_hash() {
+            return Err(format!(
+                "The memory has heap headers with layout hash {:#x}, this process lays them out as {:#x}",
+                self.layout_hash,
+                allocator::layout_hash()
+            ));
+        }
+        if self.protocol != protocol {
+            return Err(format!(
+                "The memory uses protocol version {}, this process uses {protocol}",
+                self.protocol
+            ));
+        }
+        Ok(())
+    }
+
+    /// Records this process as attached.
+    pub(crate) fn register(&mut self, protocol: u32) {
+        let mut version = [0; 16];
+        let bytes = env!("CARGO_PKG_VERSION").as_bytes();
+        let len = bytes.len().min(version.len());
+        version[..len].copy_from_slice(&bytes[..len]);
+        self.peers[self.attached as usize % PEERS] = RawPeer {
+            pid: std::process::id(),
+            protocol,
+            timestamp: clock::unix_millis(),
+            version,
+        };
+        self.attached = self.attached.wrapping_add(1);
+    }
+
+    pub(crate) fn protocol(&self) -> u32 {
+        self.protocol
+    }
+
+    /// Returns the remembered peers, oldest first.
+    pub(crate) fn peers(&self) -> Vec<Peer> {
+        let attached = self.attached as usize;
+        let kept = attached.min(PEERS);
+        (attached - kept..attached)
+            .map(|index| {
+                let raw = &self.peers[index % PEERS];
+                let len = raw.version.iter().position(|&byte| byte == 0);
+                let version = &raw.version[..len.unwrap_or(raw.version.len())];
+                Peer {
+                    pid: raw.pid,
+                    protocol: raw.protocol,
+                    crate_version: String::from_utf8_lossy(version).into_owned(),
+                    attached_at: raw.timestamp,
+                }
+            })
+            .collect()
+    }
+}
+
+/// A process that attached to a memory, as it recorded itself in the handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub pid: u32,
+    /// The application protocol version it was built with, see
+    /// `MemoryBuilder::protocol_version`.
+    pub protocol: u32,
+    /// The version of this crate it was built with.
+    pub crate_version: String,
+    /// Unix time in milliseconds.
+    pub attached_at: u64,
+}
+
+impl Memory {
+    /// Returns the last processes that created or attached to the memory, oldest first.
+    ///
+    /// Only the latest few are remembered, and processes that detached are not removed.
+    pub fn peers(&self) -> Vec<Peer> {
+        self.inspector().handshake().peers()
+    }
+
+    /// Returns the application protocol version the memory was created with.
+    pub fn protocol_version(&self) -> u32 {
+        self.inspector().handshake().protocol()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_protocol_mismatch() {
+        let memory = Memory::builder("rshmem_test_handshake", 4096)
+            .protocol_version(3)
+            .build()
+            .unwrap();
+        let peer = Memory::builder("rshmem_test_handshake", 4096)
+            .protocol_version(3)
+            .build()
+            .unwrap();
+        assert_eq!(peer.protocol_version(), 3, "The creator chose the version");
+
+        let error = Memory::builder("rshmem_test_handshake", 4096)
+            .protocol_version(4)
+            .build()
+            .err()
+            .unwrap();
+        assert!(
+            error.to_string().contains("protocol version 3"),
+            "The peer is rejected"
+        );
+        assert!(
+            Memory::open("rshmem_test_handshake_missing", 4096, 0).is_err(),
+            "Only existing memories can be opened"
+        );
+
+        let peers = memory.peers();
+        assert_eq!(peers.len(), 2, "The rejected peer is not recorded");
+        assert_eq!(peers[1].pid, std::process::id(), "Peers record their id");
+        assert_eq!(
+            peers[1].crate_version,
+            env!("CARGO_PKG_VERSION"),
+            "Peers record their crate version"
+        );
+    }
+
+    #[test]
+    fn test_abi_mismatch() {
+        // SAFETY: The handshake is plain data, for which zero is valid.
+        let mut handshake: Handshake = unsafe { std::mem::zeroed() };
+        handshake.init(0);
+        assert!(
+            handshake.check(0).is_ok(),
+            "The creator passes its own check"
+        );
+
+        handshake.byte_order = BYTE_ORDER.swap_bytes();
+        let error = handshake.check(0).unwrap_err();
+        assert!(error.contains("endian"), "The byte order is named: {error}");
+
+        handshake.byte_order = BYTE_ORDER;
+        handshake.layout_hash ^= 1;
+        let error = handshake.check(0).unwrap_err();
+        assert!(
+            error.contains("layout hash"),
+            "The layout is named: {error}"
+        );
+
+        handshake.layout = LAYOUT_VERSION.swap_bytes();
+        let error = handshake.check(0).unwrap_err();
+        assert!(
+            error.contains("endian"),
+            "A swapped version is seen: {error}"
+        );
+    }
+}
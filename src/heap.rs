@@ -0,0 +1,215 @@
+use std::marker::PhantomData;
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::{values_start, SharedBlock},
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct QueueHeader {
+    capacity: u32,
+    len: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry<T> {
+    priority: u64,
+    value: T,
+}
+
+/// A fixed capacity priority queue in shared memory, kept as a binary max-heap.
+///
+/// It suits a scheduler process submitting prioritized jobs that several worker processes
+/// pop. Values with the highest priority come out first; values with equal priorities come
+/// out in no particular order.
+pub struct ShmPriorityQueue<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmPriorityQueue<'a, T> {
+    const ENTRIES: usize =
+        values_start::<Entry<T>>(MemoryMutex::SIZE + std::mem::size_of::<QueueHeader>());
+
+    /// Creates an empty queue with room for `capacity` values.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: u32) -> Option<Self> {
+        let size = Self::ENTRIES + capacity as usize * std::mem::size_of::<Entry<T>>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr.add(MemoryMutex::SIZE) as *mut QueueHeader)).capacity = capacity;
+        }
+
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a queue created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a queue of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the queue.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Adds a value with the given priority.
+    ///
+    /// Returns false if the queue is full.
+    pub fn push(&self, priority: u64, value: T) -> bool {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == header.capacity {
+            return false;
+        }
+
+        let entries = self.entries(header.capacity);
+        let mut index = header.len as usize;
+        header.len += 1;
+        // Move parents with a lower priority down until the new entry fits.
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if entries[parent].priority >= priority {
+                break;
+            }
+            entries[index] = entries[parent];
+            index = parent;
+        }
+        entries[index] = Entry { priority, value };
+        true
+    }
+
+    /// Removes the value with the highest priority and returns it with its priority.
+    pub fn pop(&self) -> Option<(u64, T)> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.len == 0 {
+            return None;
+        }
+
+        let entries = self.entries(header.capacity);
+        let top = entries[0];
+        header.len -= 1;
+        let len = header.len as usize;
+        let last = entries[len];
+
+        // Move children with a higher priority up until the last entry fits.
+        let mut index = 0;
+        loop {
+            let mut child = 2 * index + 1;
+            if child >= len {
+                break;
+            }
+            if child + 1 < len && entries[child + 1].priority > entries[child].priority {
+                child += 1;
+            }
+            if entries[child].priority <= last.priority {
+                break;
+            }
+            entries[index] = entries[child];
+            index = child;
+        }
+        entries[index] = last;
+        Some((top.priority, top.value))
+    }
+
+    /// Returns a copy of the value with the highest priority, without removing it.
+    pub fn peek(&self) -> Option<(u64, T)> {
+        let guard = self.lock();
+        let header = header(&guard);
+        let entries = self.entries(header.capacity);
+        let top = entries[..header.len as usize].first()?;
+        Some((top.priority, top.value))
+    }
+
+    /// Returns the number of values in the queue.
+    pub fn len(&self) -> u32 {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of values.
+    pub fn capacity(&self) -> u32 {
+        header(&self.lock()).capacity
+    }
+
+    /// Frees the queue.
+    ///
+    /// Other processes must not use the queue afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::ENTRIES)
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn entries(&self, capacity: u32) -> &mut [Entry<T>] {
+        // SAFETY: The entries directly follow the header.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.block.ptr.add(Self::ENTRIES) as *mut Entry<T>,
+                capacity as usize,
+            )
+        }
+    }
+}
+
+/// Returns the queue header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut QueueHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut QueueHeader) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pop_in_priority_order() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let queue = ShmPriorityQueue::<u32>::create(&memory, 4).unwrap();
+
+        for (priority, value) in [(2, 20), (5, 50), (1, 10), (4, 40)] {
+            assert!(queue.push(priority, value), "The queue has room");
+        }
+        assert!(!queue.push(3, 30), "The queue is full");
+        assert_eq!(
+            queue.peek(),
+            Some((5, 50)),
+            "The highest priority is on top"
+        );
+
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop()).collect();
+        assert_eq!(
+            popped,
+            vec![(5, 50), (4, 40), (2, 20), (1, 10)],
+            "Values come out by priority"
+        );
+        assert!(queue.is_empty(), "The queue is empty");
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    shared::SharedBlock,
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct HistogramHeader {
+    precision: u32,
+    count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+/// A histogram of `u64` values in shared memory, e.g. latencies in microseconds.
+///
+/// Buckets are log-linear like an HDR histogram: every power of two is split into
+/// `2^precision` buckets, so a recorded value is known to within `1 / 2^precision` of itself
+/// whatever its magnitude. Recording is a few atomic additions, so every process can record
+/// into the same histogram, and a collector process can read or merge it with `snapshot`.
+pub struct ShmHistogram<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmHistogram<'a> {
+    /// Creates an empty histogram with `2^precision` buckets per power of two.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, precision: u32) -> Option<Self> {
+        assert!(
+            (1..=10).contains(&precision),
+            "The precision must be from 1 to 10 bits"
+        );
+
+        let size = std::mem::size_of::<HistogramHeader>()
+            + buckets(precision) * std::mem::size_of::<AtomicU64>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            let header = &mut *(block.ptr as *mut HistogramHeader);
+            header.precision = precision;
+            header.min = AtomicU64::new(u64::MAX);
+        }
+        Some(Self { block })
+    }
+
+    /// Opens a histogram created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a histogram that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the histogram.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Records a value.
+    pub fn record(&self, value: u64) {
+        let header = self.header();
+        self.counts()[index(header.precision, value)].fetch_add(1, SeqCst);
+        header.count.fetch_add(1, SeqCst);
+        header.sum.fetch_add(value, SeqCst);
+        header.min.fetch_min(value, SeqCst);
+        header.max.fetch_max(value, SeqCst);
+    }
+
+    /// Adds the values of a snapshot with the same precision, e.g. one taken from another
+    /// histogram.
+    pub fn merge(&self, snapshot: &HistogramSnapshot) {
+        let header = self.header();
+        assert_eq!(
+            header.precision, snapshot.precision,
+            "Histograms of different precisions cannot be merged"
+        );
+        for (count, &other) in self.counts().iter().zip(&snapshot.counts) {
+            if other > 0 {
+                count.fetch_add(other, SeqCst);
+            }
+        }
+        header.count.fetch_add(snapshot.count, SeqCst);
+        header.sum.fetch_add(snapshot.sum, SeqCst);
+        header.min.fetch_min(snapshot.min, SeqCst);
+        header.max.fetch_max(snapshot.max, SeqCst);
+    }
+
+    /// Copies the counts out of the shared memory.
+    ///
+    /// Values recorded during the copy may be counted in some totals but not in others.
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let header = self.header();
+        HistogramSnapshot {
+            precision: header.precision,
+            counts: self
+                .counts()
+                .iter()
+                .map(|count| count.load(SeqCst))
+                .collect(),
+            count: header.count.load(SeqCst),
+            sum: header.sum.load(SeqCst),
+            min: header.min.load(SeqCst),
+            max: header.max.load(SeqCst),
+        }
+    }
+
+    /// Forgets all values.
+    pub fn reset(&self) {
+        let header = self.header();
+        for count in self.counts() {
+            count.store(0, SeqCst);
+        }
+        header.count.store(0, SeqCst);
+        header.sum.store(0, SeqCst);
+        header.min.store(u64::MAX, SeqCst);
+        header.max.store(0, SeqCst);
+    }
+
+    /// Frees the histogram.
+    ///
+    /// Other processes must not use the histogram afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn header(&self) -> &HistogramHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const HistogramHeader) }
+    }
+
+    fn counts(&self) -> &[AtomicU64] {
+        let buckets = buckets(self.header().precision);
+        // SAFETY: The counts directly follow the header.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.block.ptr.add(std::mem::size_of::<HistogramHeader>()) as *const AtomicU64,
+                buckets,
+            )
+        }
+    }
+}
+
+/// A copy of the counts of a `ShmHistogram`, taken with `snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    precision: u32,
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl HistogramSnapshot {
+    /// Returns the number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the smallest recorded value, or None if there are none.
+    pub fn min(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    /// Returns the largest recorded value, or None if there are none.
+    pub fn max(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// Returns the mean of the recorded values, or None if there are none.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// Returns the value below which the fraction `quantile` of the values were recorded,
+    /// e.g. 0.99 for the 99th percentile, or None if there are none.
+    ///
+    /// The value is the upper end of its bucket, capped by the largest recorded value.
+    pub fn value_at_quantile(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(upper_bound(self.precision, index).min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Adds the values of another snapshot with the same precision.
+    pub fn merge(&mut self, other: &HistogramSnapshot) {
+        assert_eq!(
+            self.precision, other.precision,
+            "Histograms of different precisions cannot be merged"
+        );
+        for (count, &other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.count += other.count;
+        self.sum = self.sum.wrapping_add(other.sum);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// Returns the number of buckets: one per value below `2^precision`, then `2^precision` for
+/// each of the remaining powers of two.
+fn buckets(precision: u32) -> usize {
+    (1 << precision) * (65 - precision as usize)
+}
+
+fn index(precision: u32, value: u64) -> usize {
+    let sub_buckets = 1 << precision;
+    if value < sub_buckets {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - precision;
+    let sub_bucket = (value >> shift) - sub_buckets;
+    ((shift as u64 + 1) * sub_buckets + sub_bucket) as usize
+}
+
+/// Returns the largest value counted in a bucket.
+fn upper_bound(precision: u32, index: usize) -> u64 {
+    let sub_buckets = 1 << precision;
+    if index < sub_buckets {
+        return index as u64;
+    }
+    let shift = (index / sub_buckets - 1) as u32;
+    let sub_bucket = (index % sub_buckets) as u64;
+    let lower = (sub_buckets as u64 + sub_bucket) << shift;
+    lower + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_quantiles() {
+        let memory = Memory::new_in_process(65536).unwrap();
+        let histogram = ShmHistogram::create(&memory, 4).unwrap();
+        for value in 1..=1000 {
+            histogram.record(value);
+        }
+        histogram.record(u64::MAX);
+
+        let mut snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count(), 1001, "Every value is counted");
+        assert_eq!(snapshot.min(), Some(1), "The smallest value is kept");
+        assert_eq!(snapshot.max(), Some(u64::MAX), "The largest value is kept");
+        let median = snapshot.value_at_quantile(0.5).unwrap();
+        assert!(
+            (500..=500 + 500 / 16).contains(&median),
+            "The median is within the precision"
+        );
+
+        snapshot.merge(&histogram.snapshot());
+        assert_eq!(snapshot.count(), 2002, "Merging adds the counts");
+        histogram.reset();
+        histogram.merge(&snapshot);
+        assert_eq!(
+            histogram.snapshot(),
+            snapshot,
+            "Merging into a reset histogram copies it"
+        );
+    }
+}
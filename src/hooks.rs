@@ -0,0 +1,110 @@
+use std::{cell::Cell, collections::BTreeMap};
+
+/// How much of the heap is in use, and was at most.
+///
+/// Peaks cover all processes since the memory was created or `reset_peak` was called, which
+/// shows how large a memory has to be for a given load.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// Bytes taken by allocated blocks, counting their headers and padding.
+    pub used: usize,
+    /// Bytes that blocks can take in total.
+    pub capacity: usize,
+    /// Number of allocated blocks.
+    pub blocks: usize,
+    /// Highest `used` seen.
+    pub peak_used: usize,
+    /// Highest `blocks` seen.
+    pub peak_blocks: usize,
+}
+
+impl Usage {
+    /// Returns the used part of the capacity, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        if self.capacity == 0 {
+            return 1.0;
+        }
+        self.used as f64 / self.capacity as f64
+    }
+}
+
+/// Which tags and processes the used bytes of the heap belong to.
+///
+/// Bytes count headers and padding, like `Usage::used`. Blocks allocated without a tag are
+/// under tag zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageBreakdown {
+    /// Bytes by the tag given to `Memory::allocate_tagged`.
+    pub by_tag: BTreeMap<u32, usize>,
+    /// Bytes by the id of the process that allocated them.
+    pub by_owner: BTreeMap<u32, usize>,
+}
+
+type FailureHook = Box<dyn Fn(usize)>;
+type WatermarkHook = Box<dyn Fn(Usage)>;
+
+/// Callbacks of this process, run after its own allocations.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    failure: Vec<FailureHook>,
+    watermarks: Vec<(f64, WatermarkHook)>,
+    /// Bytes in use after the previous allocation of this process.
+    last_used: Cell<usize>,
+}
+
+impl Hooks {
+    pub fn on_failure(&mut self, hook: FailureHook) {
+        self.failure.push(hook);
+    }
+
+    pub fn on_watermark(&mut self, fraction: f64, hook: WatermarkHook) {
+        self.watermarks.push((fraction, hook));
+    }
+
+    /// Runs the hooks for an allocation of `size` bytes, with the memory unlocked.
+    pub fn notify(&self, size: usize, failed: bool, usage: Usage) {
+        if failed {
+            for hook in &self.failure {
+                hook(size);
+            }
+        }
+
+        let last = self.last_used.replace(usage.used);
+        for (fraction, hook) in &self.watermarks {
+            let mark = (usage.capacity as f64 * fraction) as usize;
+            if last < mark && usage.used >= mark {
+                hook(usage);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::Memory;
+
+    #[test]
+    fn test_failure_and_watermark_hooks() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let failures = Rc::new(Cell::new(0));
+        let crossings = Rc::new(Cell::new(0));
+        let (f, c) = (failures.clone(), crossings.clone());
+        memory.on_allocation_failure(move |_| f.set(f.get() + 1));
+        memory.on_watermark(0.5, move |_| c.set(c.get() + 1));
+
+        let capacity = memory.usage().capacity;
+        let big = memory.allocate(capacity / 2).unwrap().as_ptr();
+        assert_eq!(crossings.get(), 1, "The watermark was crossed");
+        assert!(memory.allocate(16).is_ok(), "The block should fit");
+        assert_eq!(crossings.get(), 1, "Staying above does not cross again");
+
+        assert!(memory.allocate(capacity).is_err(), "The heap is too small");
+        assert_eq!(failures.get(), 1, "The failure was reported");
+
+        memory.deallocate(big);
+        assert!(memory.usage().fraction() < 0.5, "Freeing lowers the usage");
+    }
+}
@@ -0,0 +1,134 @@
+use crate::{
+    allocator::High,
+    sync::atomic::{fence, Ordering::SeqCst},
+};
+
+/// Maximum number of word writes a single journal record can hold.
+///
+/// Larger updates have to be committed as several batches that each leave the heap
+/// consistent.
+pub const CAPACITY: usize = 8;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry {
+    address: usize,
+    _address: High,
+    value: usize,
+    _value: High,
+}
+
+/// Write-ahead journal for heap metadata, stored inside the shared buffer.
+///
+/// Word writes are first recorded and marked as committed, then applied. If a process dies
+/// while applying them, the next attacher finds the committed record and applies it again.
+/// Applying a record twice is harmless because every entry stores the final value.
+#[repr(C)]
+pub struct Journal {
+    committed: usize,
+    _committed: High,
+    len: usize,
+    _len: High,
+    entries: [Entry; CAPACITY],
+}
+
+impl Journal {
+    /// Records the batch as a single record, then applies it.
+    ///
+    /// # Panics
+    /// If the batch has more than `CAPACITY` writes.
+    pub fn commit(&mut self, batch: &Batch) {
+        let entries = &batch.entries;
+        assert!(
+            entries.len() <= CAPACITY,
+            "The batch does not fit in a journal record"
+        );
+        self.entries[..entries.len()].copy_from_slice(entries);
+        self.len = entries.len();
+        fence(SeqCst);
+        self.committed = 1;
+        fence(SeqCst);
+        self.apply();
+        fence(SeqCst);
+        self.committed = 0;
+    }
+
+    /// Applies a record left behind by a process that died while committing it.
+    ///
+    /// Returns whether there was anything to replay.
+    pub fn replay(&mut self) -> bool {
+        if self.committed == 0 {
+            return false;
+        }
+        self.apply();
+        fence(SeqCst);
+        self.committed = 0;
+        true
+    }
+
+    fn apply(&mut self) {
+        for entry in &self.entries[..self.len.min(CAPACITY)] {
+            // SAFETY: The addresses were recorded from valid header fields of this heap.
+            unsafe { (entry.address as *mut usize).write(entry.value) };
+        }
+    }
+}
+
+/// A set of word writes that has to be applied as a whole.
+#[derive(Default)]
+pub struct Batch {
+    entries: Vec<Entry>,
+}
+
+impl Batch {
+    /// Schedules `value` to be written to `word`, replacing an earlier write to the same word.
+    pub fn set(&mut self, word: *mut usize, value: usize) {
+        let address = word as usize;
+        match self.entries.iter_mut().find(|e| e.address == address) {
+            Some(entry) => entry.value = value,
+            None => self.entries.push(Entry {
+                address,
+                _address: High::default(),
+                value,
+                _value: High::default(),
+            }),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Applies the writes without journaling them.
+    pub fn apply(&self) {
+        for entry in &self.entries {
+            // SAFETY: The addresses were recorded from valid header fields of this heap.
+            unsafe { (entry.address as *mut usize).write(entry.value) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_committed_record() {
+        let mut words = [0usize; 2];
+        let mut journal: Journal = unsafe { std::mem::zeroed() };
+
+        let mut batch = Batch::default();
+        batch.set(&mut words[0], 1);
+        batch.set(&mut words[1], 2);
+        batch.set(&mut words[0], 3);
+
+        // Simulate a process that recorded the batch but died before applying it.
+        journal.entries[..2].copy_from_slice(&batch.entries);
+        journal.len = 2;
+        journal.committed = 1;
+
+        assert!(journal.replay(), "The committed record should be replayed");
+        assert_eq!(words, [3, 2], "The last write to a word should win");
+        assert!(!journal.replay(), "The record was already replayed");
+    }
+}
@@ -0,0 +1,648 @@
+use std::marker::PhantomData;
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::SharedBlock,
+    EpochGuard, Memory, Offset,
+};
+
+#[repr(C)]
+struct TableHeader {
+    buckets: usize,
+    len: usize,
+}
+
+#[repr(C)]
+struct Entry {
+    /// Offset of the next entry in the bucket, zero for the last one.
+    next: usize,
+    hash: u64,
+    key_len: usize,
+    value_len: usize,
+}
+
+impl Entry {
+    const SIZE: usize = std::mem::size_of::<Entry>();
+
+    fn key(&self) -> &[u8] {
+        // SAFETY: The key bytes directly follow the entry header.
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self as *const u8).add(Self::SIZE),
+                self.key_len,
+            )
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        // SAFETY: The value bytes directly follow the key.
+        unsafe {
+            let start = (self as *const Self as *const u8).add(Self::SIZE + self.key_len);
+            std::slice::from_raw_parts(start, self.value_len)
+        }
+    }
+}
+
+/// A hash map from string keys to byte values stored in shared memory.
+///
+/// The bucket table and every entry are blocks of the memory, so the store can be opened by
+/// any process that knows its offset. Entries are allocated as children of the table, which
+/// lets `destroy` free everything at once. The number of buckets is fixed at creation.
+///
+/// Long-running readers can take a `snapshot`, which sees the store as it was while writers
+/// go on. For that, writers use `insert_retiring` and `remove_retiring`, which never change a
+/// linked entry: they link copies instead and retire the replaced entries through a
+/// `ShmEpoch`, so they are only freed once no snapshot can reach them.
+pub struct ShmKvStore<'a> {
+    table: SharedBlock<'a>,
+}
+
+impl<'a> ShmKvStore<'a> {
+    /// Creates an empty store with the given number of buckets, rounded up to a power of two.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, buckets: usize) -> Option<Self> {
+        let buckets = buckets.max(1).next_power_of_two();
+        let size = Self::table_size(buckets);
+        let table = SharedBlock::create(memory, size)?;
+
+        // SAFETY: The block was just allocated with `size` bytes.
+        unsafe {
+            let header = table.ptr.add(MemoryMutex::SIZE) as *mut TableHeader;
+            (*header).buckets = buckets;
+        }
+        Some(Self { table })
+    }
+
+    /// Opens a store created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a store that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let table = SharedBlock::open(memory, offset)?;
+        Some(Self { table })
+    }
+
+    /// Returns the offset other processes use to open the store.
+    pub fn offset(&self) -> Offset {
+        self.table.offset()
+    }
+
+    /// Inserts a value, replacing the previous value of the key.
+    ///
+    /// Returns false if there was not enough memory for the entry.
+    pub fn insert(&self, key: &str, value: &[u8]) -> bool {
+        let key = key.as_bytes();
+        let hash = hash(key);
+        let Some(data) = self.allocate_entry(key, hash, value) else {
+            return false;
+        };
+
+        let replaced = {
+            let guard = self.lock();
+            let header = header(&guard);
+            let replaced = self.unlink(header, key, hash);
+            let bucket = bucket(header, hash);
+            unsafe { &mut *(data as *mut Entry) }.next = *bucket;
+            *bucket = self.offset_of(data);
+            header.len += 1;
+            replaced
+        };
+
+        if let Some(old) = replaced {
+            self.table.memory.deallocate(old);
+        }
+        true
+    }
+
+    /// Returns a copy of the value of the key.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = key.as_bytes();
+        let guard = self.lock();
+        let entry = self.find(header(&guard), key, hash(key))?;
+        Some(entry.value().to_vec())
+    }
+
+    /// Inserts a value unless the key already has one, as one step for all processes.
+    ///
+    /// Returns a copy of the value of the key afterwards, or None if there was not enough
+    /// memory for the entry.
+    pub fn get_or_insert(&self, key: &str, value: &[u8]) -> Option<Vec<u8>> {
+        if let Some(existing) = self.get(key) {
+            return Some(existing);
+        }
+
+        let key = key.as_bytes();
+        let hash = hash(key);
+        let data = self.allocate_entry(key, hash, value)?;
+        let existing = {
+            let guard = self.lock();
+            let header = header(&guard);
+            let existing = self
+                .find(header, key, hash)
+                .map(|entry| entry.value().to_vec());
+            if existing.is_none() {
+                let bucket = bucket(header, hash);
+                unsafe { &mut *(data as *mut Entry) }.next = *bucket;
+                *bucket = self.offset_of(data);
+                header.len += 1;
+            }
+            existing
+        };
+
+        // Another process inserted the key in the meantime.
+        if existing.is_some() {
+            self.table.memory.deallocate(data);
+        }
+        Some(existing.unwrap_or_else(|| value.to_vec()))
+    }
+
+    /// Inserts a value like `insert`, but retires the replaced entries instead of freeing
+    /// them, so that snapshots keep reading them.
+    ///
+    /// Returns false if there was not enough memory for the entry and the copies.
+    pub fn insert_retiring(&self, guard: &EpochGuard, key: &str, value: &[u8]) -> bool {
+        let key = key.as_bytes();
+        let hash = hash(key);
+        let Some(data) = self.allocate_entry(key, hash, value) else {
+            return false;
+        };
+
+        let retired = {
+            let guard = self.lock();
+            let header = header(&guard);
+            let retired = self.unlink_copying(header, key, hash);
+            if retired.is_some() {
+                let bucket = bucket(header, hash);
+                unsafe { &mut *(data as *mut Entry) }.next = *bucket;
+                *bucket = self.offset_of(data);
+                header.len += 1;
+            }
+            retired
+        };
+
+        match retired {
+            Some(retired) => {
+                retire(guard, &retired);
+                true
+            }
+            None => {
+                self.table.memory.deallocate(data);
+                false
+            }
+        }
+    }
+
+    /// Removes the key like `remove`, but retires its entry instead of freeing it, so that
+    /// snapshots keep reading it.
+    ///
+    /// Returns false if the key was not in the store or there was not enough memory for the
+    /// copies.
+    pub fn remove_retiring(&self, guard: &EpochGuard, key: &str) -> bool {
+        let key = key.as_bytes();
+        let retired = {
+            let guard = self.lock();
+            self.unlink_copying(header(&guard), key, hash(key))
+        };
+        match retired {
+            Some(retired) if !retired.is_empty() => {
+                retire(guard, &retired);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns a view of the store as it is now, which later writes do not change.
+    ///
+    /// Only the bucket heads are copied, under the lock; reads through the view take no lock.
+    /// The guard keeps the entries it reaches from being freed, as long as every writer uses
+    /// `insert_retiring` and `remove_retiring` with the same domain. `insert`, `remove` and
+    /// `get_or_insert` change linked entries in place and must not be used meanwhile.
+    pub fn snapshot<'s>(&'s self, _guard: &'s EpochGuard) -> KvSnapshot<'s, 'a> {
+        let guard = self.lock();
+        let header = header(&guard);
+        // SAFETY: The bucket array holds `buckets` heads, read under the lock.
+        let buckets = unsafe { std::slice::from_raw_parts(buckets(header), header.buckets) };
+        KvSnapshot {
+            store: self,
+            buckets: buckets.to_vec(),
+            len: header.len,
+            _guard: PhantomData,
+        }
+    }
+
+    /// Returns true if the store holds a value for the key.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Removes the key and frees its entry.
+    ///
+    /// Returns false if the key was not in the store.
+    pub fn remove(&self, key: &str) -> bool {
+        let key = key.as_bytes();
+        let removed = {
+            let guard = self.lock();
+            let header = header(&guard);
+            self.unlink(header, key, hash(key))
+        };
+
+        match removed {
+            Some(data) => self.table.memory.deallocate(data),
+            None => false,
+        }
+    }
+
+    /// Returns the number of keys in the store.
+    pub fn len(&self) -> usize {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the store is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns all keys in the store, in no particular order.
+    pub fn keys(&self) -> Vec<String> {
+        let guard = self.lock();
+        let header = header(&guard);
+        let mut keys = Vec::with_capacity(header.len);
+
+        for index in 0..header.buckets {
+            let mut current = unsafe { *buckets(header).add(index) };
+            while current != 0 {
+                let entry = self.entry(current);
+                keys.push(String::from_utf8_lossy(entry.key()).into_owned());
+                current = entry.next;
+            }
+        }
+        keys
+    }
+
+    /// Rewrites the values of all entries in place, as one step for all processes.
+    pub(crate) fn update_values(&self, mut update: impl FnMut(&mut [u8])) {
+        let guard = self.lock();
+        let header = header(&guard);
+        for index in 0..header.buckets {
+            let mut current = unsafe { *buckets(header).add(index) };
+            while current != 0 {
+                let entry = self.entry(current);
+                // SAFETY: The entry is only read under the lock, which this thread holds.
+                let value = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        entry.value().as_ptr() as *mut u8,
+                        entry.value_len,
+                    )
+                };
+                update(value);
+                current = entry.next;
+            }
+        }
+    }
+
+    /// Frees the table and all entries.
+    ///
+    /// Other processes must not use the store afterwards.
+    pub fn destroy(self) {
+        self.table.free();
+    }
+
+    fn table_size(buckets: usize) -> usize {
+        MemoryMutex::SIZE
+            + std::mem::size_of::<TableHeader>()
+            + buckets * std::mem::size_of::<usize>()
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        let size = MemoryMutex::SIZE + std::mem::size_of::<TableHeader>();
+        self.table.lock(size)
+    }
+
+    /// Allocates an entry that is not linked into a bucket yet.
+    fn allocate_entry(&self, key: &[u8], hash: u64, value: &[u8]) -> Option<*mut u8> {
+        let data = self
+            .table
+            .memory
+            .allocate_more(Entry::SIZE + key.len() + value.len(), self.table.ptr)
+            .ok()?
+            .as_ptr();
+
+        // SAFETY: The block was just allocated with room for the header, key and value.
+        unsafe {
+            (data as *mut Entry).write(Entry {
+                next: 0,
+                hash,
+                key_len: key.len(),
+                value_len: value.len(),
+            });
+            let bytes = data.add(Entry::SIZE);
+            bytes.copy_from_nonoverlapping(key.as_ptr(), key.len());
+            bytes
+                .add(key.len())
+                .copy_from_nonoverlapping(value.as_ptr(), value.len());
+        }
+        Some(data)
+    }
+
+    fn find(&self, header: &mut TableHeader, key: &[u8], hash: u64) -> Option<&Entry> {
+        let mut current = *bucket(header, hash);
+        while current != 0 {
+            let entry = self.entry(current);
+            if entry.hash == hash && entry.key() == key {
+                return Some(entry);
+            }
+            current = entry.next;
+        }
+        None
+    }
+
+    /// Removes the entry of the key from its bucket and returns its block.
+    fn unlink(&self, header: &mut TableHeader, key: &[u8], hash: u64) -> Option<*mut u8> {
+        let mut link = bucket(header, hash) as *mut usize;
+        loop {
+            let current = unsafe { *link };
+            if current == 0 {
+                return None;
+            }
+            let entry = self.entry(current);
+            if entry.hash == hash && entry.key() == key {
+                unsafe { *link = entry.next };
+                header.len -= 1;
+                return self.table.memory.pointer(Offset(current));
+            }
+            link = &entry.next as *const usize as *mut usize;
+        }
+    }
+
+    /// Removes the entry of the key from its bucket without changing any linked entry: the
+    /// entries before it are replaced by copies.
+    ///
+    /// Returns the blocks to retire, none if the key was not in the store, or None if there
+    /// was not enough memory for the copies.
+    fn unlink_copying(
+        &self,
+        header: &mut TableHeader,
+        key: &[u8],
+        hash: u64,
+    ) -> Option<Vec<*mut u8>> {
+        let mut prefix = Vec::new();
+        let mut current = *bucket(header, hash);
+        let rest = loop {
+            if current == 0 {
+                return Some(Vec::new());
+            }
+            let entry = self.entry(current);
+            if entry.hash == hash && entry.key() == key {
+                break entry.next;
+            }
+            prefix.push(current);
+            current = entry.next;
+        };
+
+        let copies: Vec<_> = prefix
+            .iter()
+            .map_while(|&offset| {
+                let entry = self.entry(offset);
+                self.allocate_entry(entry.key(), entry.hash, entry.value())
+            })
+            .collect();
+        if copies.len() < prefix.len() {
+            for copy in copies {
+                self.table.memory.deallocate(copy);
+            }
+            return None;
+        }
+
+        let mut next = rest;
+        for &copy in copies.iter().rev() {
+            unsafe { &mut *(copy as *mut Entry) }.next = next;
+            next = self.offset_of(copy);
+        }
+        *bucket(header, hash) = next;
+        header.len -= 1;
+        prefix.push(current);
+        Some(
+            prefix
+                .into_iter()
+                .map(|offset| {
+                    self.table
+                        .memory
+                        .pointer(Offset(offset))
+                        .expect("Entries are inside the memory")
+                })
+                .collect(),
+        )
+    }
+
+    fn entry(&self, offset: usize) -> &Entry {
+        let data = self
+            .table
+            .memory
+            .pointer(Offset(offset))
+            .expect("Entries are inside the memory");
+        unsafe { &*(data as *const Entry) }
+    }
+
+    fn offset_of(&self, data: *mut u8) -> usize {
+        self.table
+            .memory
+            .offset_of(data)
+            .expect("Entries are inside the memory")
+            .0
+    }
+}
+
+/// A view of a `ShmKvStore` as it was when `ShmKvStore::snapshot` was called.
+pub struct KvSnapshot<'s, 'a> {
+    store: &'s ShmKvStore<'a>,
+    buckets: Vec<usize>,
+    len: usize,
+    _guard: PhantomData<&'s ()>,
+}
+
+impl<'s, 'a> KvSnapshot<'s, 'a> {
+    /// Returns a copy of the value the key had.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = key.as_bytes();
+        let hash = hash(key);
+        let mut current = self.buckets[hash as usize & (self.buckets.len() - 1)];
+        while current != 0 {
+            let entry = self.store.entry(current);
+            if entry.hash == hash && entry.key() == key {
+                return Some(entry.value().to_vec());
+            }
+            current = entry.next;
+        }
+        None
+    }
+
+    /// Returns true if the key had a value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Returns the number of keys the store had.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the store was empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the keys the store had, in no particular order.
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::with_capacity(self.len);
+        for &head in &self.buckets {
+            let mut current = head;
+            while current != 0 {
+                let entry = self.store.entry(current);
+                keys.push(String::from_utf8_lossy(entry.key()).into_owned());
+                current = entry.next;
+            }
+        }
+        keys
+    }
+}
+
+/// Retires unlinked entries. One that cannot be recorded for lack of memory stays allocated
+/// as a child of the table until the store is destroyed.
+fn retire(guard: &EpochGuard, blocks: &[*mut u8]) {
+    for &block in blocks {
+        guard.retire(block);
+    }
+}
+
+/// Returns the table header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut TableHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut TableHeader) }
+}
+
+/// Returns the bucket array, which directly follows the header.
+fn buckets(header: &mut TableHeader) -> *mut usize {
+    unsafe { (header as *mut TableHeader).add(1) as *mut usize }
+}
+
+fn bucket(header: &mut TableHeader, hash: u64) -> &mut usize {
+    let index = hash as usize & (header.buckets - 1);
+    unsafe { &mut *buckets(header).add(index) }
+}
+
+/// FNV-1a, which unlike the std hasher is guaranteed to be the same in every process.
+pub(crate) fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_get_remove() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let store = ShmKvStore::create(&memory, 4).unwrap();
+
+        assert!(store.insert("a", b"1"), "The entry should fit");
+        assert!(store.insert("b", b"2"), "The entry should fit");
+        assert!(store.insert("a", b"three"), "The entry should be replaced");
+        assert_eq!(store.len(), 2, "Replacing a value keeps the count");
+        assert_eq!(
+            store.get("a").as_deref(),
+            Some(&b"three"[..]),
+            "The new value wins"
+        );
+
+        for key in ["c", "d", "e", "f"] {
+            assert!(store.insert(key, key.as_bytes()), "The entry should fit");
+        }
+        assert_eq!(store.len(), 6, "Buckets hold more than one entry");
+        assert_eq!(
+            store.get("b").as_deref(),
+            Some(&b"2"[..]),
+            "Colliding keys are kept apart"
+        );
+        assert_eq!(
+            store.get("f").as_deref(),
+            Some(&b"f"[..]),
+            "Colliding keys are kept apart"
+        );
+
+        assert!(store.remove("b"), "The key is in the store");
+        assert!(!store.contains_key("b"), "The key was removed");
+        store.destroy();
+    }
+
+    #[test]
+    fn test_snapshot_sees_old_values() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let store = ShmKvStore::create(&memory, 1).unwrap();
+        let domain = crate::ShmEpoch::create(&memory, 2).unwrap();
+        let mut reader = domain.register().unwrap();
+        let mut writer = domain.register().unwrap();
+
+        assert!(
+            store.insert_retiring(&writer.pin(), "a", b"1"),
+            "The entry should fit"
+        );
+        assert!(
+            store.insert_retiring(&writer.pin(), "b", b"2"),
+            "The entry should fit"
+        );
+        let pinned = reader.pin();
+        let snapshot = store.snapshot(&pinned);
+
+        assert!(
+            store.insert_retiring(&writer.pin(), "a", b"3"),
+            "The entry should fit"
+        );
+        assert!(
+            store.remove_retiring(&writer.pin(), "b"),
+            "The key is in the store"
+        );
+        assert!(
+            store.insert_retiring(&writer.pin(), "c", b"4"),
+            "The entry should fit"
+        );
+        for _ in 0..3 {
+            domain.collect();
+        }
+
+        assert_eq!(
+            store.get("a").as_deref(),
+            Some(&b"3"[..]),
+            "The store changed"
+        );
+        assert!(!store.contains_key("b"), "The key was removed");
+        assert_eq!(
+            snapshot.get("a").as_deref(),
+            Some(&b"1"[..]),
+            "The snapshot did not"
+        );
+        assert_eq!(
+            snapshot.get("b").as_deref(),
+            Some(&b"2"[..]),
+            "The snapshot did not"
+        );
+        assert!(
+            !snapshot.contains_key("c"),
+            "Later keys are not in the snapshot"
+        );
+        assert_eq!(snapshot.len(), 2, "The snapshot keeps its count");
+
+        drop(snapshot);
+        drop(pinned);
+        domain.collect();
+        domain.collect();
+        assert_eq!(domain.pending(), 0, "The replaced entries were freed");
+        assert_eq!(store.len(), 2, "The live entries are kept");
+    }
+}
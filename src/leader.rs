@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use crate::{
+    clock,
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::SharedBlock,
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct LeaseHeader {
+    lease: u64,
+    /// Id of the leader, zero if there is none.
+    leader: u64,
+    /// Incremented whenever leadership changes hands.
+    term: u64,
+    /// Unix time in milliseconds at which the lease runs out.
+    expires: u64,
+}
+
+/// A leadership record in shared memory, held by at most one process at a time.
+///
+/// Processes compete with `try_acquire` using ids of their choice, e.g. their process ids.
+/// The winner holds a lease it must renew with `heartbeat` before it runs out; if the leader
+/// dies or hangs, the lease expires and the next `try_acquire` of another process takes over.
+/// Every change of leader increments the term, which a leader can attach to its work so
+/// that work from an older term is recognized and ignored.
+pub struct ShmLeader<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmLeader<'a> {
+    const SIZE: usize = MemoryMutex::SIZE + std::mem::size_of::<LeaseHeader>();
+
+    /// Creates a record without a leader, whose leases last `lease`.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, lease: Duration) -> Option<Self> {
+        let block = SharedBlock::create(memory, Self::SIZE)?;
+        // SAFETY: The block was just allocated with `SIZE` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr.add(MemoryMutex::SIZE) as *mut LeaseHeader)).lease =
+                lease.as_millis() as u64;
+        }
+        Some(Self { block })
+    }
+
+    /// Opens a record created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a record that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the record.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Becomes the leader if there is none or its lease ran out, or renews the lease if `id`
+    /// already leads.
+    ///
+    /// Returns true if `id` is the leader afterwards.
+    pub fn try_acquire(&self, id: u64) -> bool {
+        assert!(id != 0, "Zero stands for no leader");
+        let guard = self.lock();
+        let header = header(&guard);
+        let now = clock::unix_millis();
+        if header.leader != id && header.leader != 0 && header.expires > now {
+            return false;
+        }
+        if header.leader != id {
+            header.leader = id;
+            header.term += 1;
+        }
+        header.expires = now.saturating_add(header.lease);
+        true
+    }
+
+    /// Renews the lease of the leader.
+    ///
+    /// Returns false if `id` is not the leader, or its lease ran out and it was replaced.
+    pub fn heartbeat(&self, id: u64) -> bool {
+        let guard = self.lock();
+        let header = header(&guard);
+        if id == 0 || header.leader != id {
+            return false;
+        }
+        header.expires = clock::unix_millis().saturating_add(header.lease);
+        true
+    }
+
+    /// Gives up leadership so another process can take over without waiting for the lease.
+    ///
+    /// Returns false if `id` was not the leader.
+    pub fn resign(&self, id: u64) -> bool {
+        let guard = self.lock();
+        let header = header(&guard);
+        if id == 0 || header.leader != id {
+            return false;
+        }
+        header.leader = 0;
+        true
+    }
+
+    /// Returns the id of the leader, or None if there is none or its lease ran out.
+    pub fn leader(&self) -> Option<u64> {
+        let guard = self.lock();
+        let header = header(&guard);
+        (header.leader != 0 && header.expires > clock::unix_millis()).then_some(header.leader)
+    }
+
+    /// Returns true if `id` is the leader and its lease has not run out.
+    pub fn is_leader(&self, id: u64) -> bool {
+        self.leader() == Some(id)
+    }
+
+    /// Returns the number of times leadership changed hands.
+    pub fn term(&self) -> u64 {
+        header(&self.lock()).term
+    }
+
+    /// Frees the record.
+    ///
+    /// Other processes must not use the record afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::SIZE)
+    }
+}
+
+/// Returns the lease header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut LeaseHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut LeaseHeader) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_takeover_after_lease() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let leader = ShmLeader::create(&memory, Duration::from_millis(20)).unwrap();
+
+        assert!(leader.try_acquire(1), "Nobody leads yet");
+        assert!(!leader.try_acquire(2), "The lease is still valid");
+        assert!(leader.heartbeat(1), "The leader renews its lease");
+        assert_eq!(leader.leader(), Some(1), "The first process leads");
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(leader.leader(), None, "The lease ran out");
+        assert!(leader.try_acquire(2), "Another process takes over");
+        assert!(!leader.heartbeat(1), "The old leader was replaced");
+        assert_eq!(leader.term(), 2, "Leadership changed hands twice");
+    }
+}
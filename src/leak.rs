@@ -0,0 +1,73 @@
+use std::{fmt, panic::Location};
+
+use crate::{BlockInfo, Memory};
+
+/// A block this process allocated and did not free, as listed by `Memory::leak_report`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leak {
+    pub block: BlockInfo,
+    /// Where the block was allocated, if sites are tracked.
+    pub site: Option<&'static Location<'static>>,
+}
+
+impl fmt::Display for Leak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes at {:#x} with tag {} were never freed",
+            self.block.size,
+            self.block.offset.get(),
+            self.block.tag
+        )?;
+        match self.site {
+            Some(site) => write!(f, ", allocated at {site}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Memory {
+    /// Lists the blocks this process allocated that are still allocated, in heap order.
+    ///
+    /// Children are listed on their own, even though freeing their parent would free them.
+    /// Blocks are told apart by process id, so other memories of the same process count too.
+    /// With the `debug` feature, the process that created the memory prints the report when
+    /// it drops the memory, to catch leaks during tests.
+    pub fn leak_report(&self) -> Vec<Leak> {
+        let id = std::process::id();
+        self.blocks()
+            .into_iter()
+            .filter(|block| block.owner == id)
+            .map(|block| {
+                let site = self
+                    .pointer(block.offset)
+                    .and_then(|data| self.allocation_site(data));
+                Leak { block, site }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    #[test]
+    fn test_leak_report() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .track_sites(true)
+            .build()
+            .unwrap();
+        let freed = memory.allocate(16).unwrap().as_ptr();
+        let leaked = memory.allocate_tagged(8, 5).unwrap().as_ptr();
+        memory.deallocate(freed);
+
+        let report = memory.leak_report();
+        assert_eq!(report.len(), 1, "Only one block is left");
+        assert_eq!(report[0].block.offset, memory.offset_of(leaked).unwrap());
+        let text = report[0].to_string();
+        assert!(text.contains("tag 5"), "The tag is reported");
+        assert!(text.contains(file!()), "The site is reported");
+    }
+}
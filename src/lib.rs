@@ -1,6 +1,155 @@
+// The derive macro refers to the trait as `::rshmem::ShmSafe`, also in the tests of this crate.
+#[cfg(feature = "derive")]
+extern crate self as rshmem;
+
 mod allocator;
+#[cfg(feature = "rkyv")]
+mod archive;
+mod arena;
+mod bitset;
+mod bloom;
+mod btree;
+#[cfg(feature = "bytes")]
+mod buf;
+mod builder;
+mod bump;
+mod cache;
+mod cancel;
+mod clock;
+mod config;
+#[cfg(feature = "encryption")]
+mod crypt;
+mod cursor;
+mod deferred;
+mod deque;
+mod directory;
+mod dump;
+mod emergency;
+mod epoch;
+mod error;
+#[cfg(feature = "fault-injection")]
+mod faults;
+mod handshake;
+mod heap;
+mod histogram;
+mod hooks;
+mod journal;
+mod kv;
+mod leader;
+mod leak;
+mod lru;
 mod memory;
+mod message;
+mod mpsc;
 mod mutex;
-mod windows;
+mod namespace;
+mod naming;
+mod offset;
+mod oplog;
+#[cfg_attr(windows, path = "windows.rs")]
+#[cfg_attr(unix, path = "unix.rs")]
+mod platform;
+mod pool;
+mod pressure;
+mod priority;
+mod progress;
+mod promise;
+mod protect;
+mod quota;
+mod ratelimit;
+mod ready;
+mod reservation;
+mod restrict;
+mod ring;
+mod sequence;
+#[cfg(feature = "serde")]
+mod serialize;
+mod shared;
+mod shm_safe;
+mod shutdown;
+mod skiplist;
+mod slotmap;
+mod snapshot;
+mod stack;
+mod stream;
+mod stream_copy;
+mod sync;
+#[cfg(all(unix, feature = "sysv"))]
+mod sysv;
+mod table;
+mod tagged;
+#[cfg(feature = "metrics")]
+mod telemetry;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod timecell;
+mod transaction;
+mod trie;
+mod user_header;
+mod view;
+mod work_deque;
 
+pub use allocator::{
+    BlockInfo, RepairReport, ScanReport, BLOCK_HEADER_SIZE, BUMP_TAG, PENDING_TAG, QUARANTINE_TAG,
+};
+#[cfg(feature = "rkyv")]
+pub use archive::{ArchiveSerializer, BlockWriter};
+pub use arena::ShmArena;
+pub use bitset::ShmBitset;
+pub use bloom::ShmBloomFilter;
+pub use btree::ShmBTreeMap;
+#[cfg(feature = "bytes")]
+pub use buf::{ShmBuf, ShmBufMut};
+pub use builder::MemoryBuilder;
+pub use cancel::ShmCancellationToken;
+pub use config::{ConfigSnapshot, ShmConfig};
+#[cfg(feature = "encryption")]
+pub use crypt::EncryptedMemory;
+pub use cursor::ShmCursor;
+pub use deque::ShmDeque;
+pub use emergency::AllocationPause;
+pub use epoch::{EpochGuard, Participant, ShmEpoch};
+pub use error::AllocError;
+#[cfg(feature = "fault-injection")]
+pub use faults::HeldLock;
+pub use handshake::Peer;
+pub use heap::ShmPriorityQueue;
+pub use histogram::{HistogramSnapshot, ShmHistogram};
+pub use hooks::{Usage, UsageBreakdown};
+pub use kv::{KvSnapshot, ShmKvStore};
+pub use leader::ShmLeader;
+pub use leak::Leak;
+pub use lru::ShmLruCache;
 pub use memory::Memory;
+pub use message::{FrameError, Message, MessageConsumer, ShmMessageQueue, WriteGuard};
+pub use mpsc::{Consumer, RecvError, SendError, ShmMpscQueue};
+pub use namespace::{SegmentOptions, ShmNamespace};
+pub use naming::Namespace;
+pub use offset::Offset;
+pub use oplog::{replay_log, LogOp, LogRecord};
+pub use pool::ShmPool;
+pub use pressure::{Pressure, PressureThresholds};
+pub use priority::Priority;
+pub use progress::{ProgressSnapshot, ShmProgress};
+pub use promise::ShmPromise;
+pub use protect::Protection;
+pub use ratelimit::ShmRateLimiter;
+pub use ready::ReadyGuard;
+pub use reservation::Reservation;
+pub use restrict::{Access, RestrictedMemory};
+pub use ring::{FullPolicy, RingConsumer, RingProducer, ShmRing};
+#[cfg(feature = "derive")]
+pub use rshmem_derive::ShmSafe;
+pub use sequence::{CachedSequence, ShmSequence};
+pub use shm_safe::ShmSafe;
+pub use skiplist::{ShmSkipList, SkipListWriter};
+pub use slotmap::{ShmSlotMap, SlotId};
+pub use stack::ShmStack;
+pub use stream::{ShmStream, StreamReader, StreamWriter};
+pub use stream_copy::copy_nontemporal;
+pub use table::{ShmTable, TableRow};
+pub use timecell::{ShmTimeCell, TimeSample};
+pub use transaction::Transaction;
+pub use trie::ShmTrie;
+pub use view::MemoryView;
+pub use work_deque::ShmWorkDeque;
@@ -0,0 +1,370 @@
+use crate::{
+    kv::hash,
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::SharedBlock,
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct CacheHeader {
+    buckets: usize,
+    len: usize,
+    /// Bytes taken by all entries, counting their headers.
+    bytes: usize,
+    budget: usize,
+    /// Offset of the most recently used entry.
+    newest: usize,
+    /// Offset of the least recently used entry, which is evicted first.
+    oldest: usize,
+}
+
+#[repr(C)]
+struct Entry {
+    /// Offset of the next entry in the bucket, zero for the last one.
+    next: usize,
+    /// Offsets of the neighbours in the recency list, zero at either end.
+    newer: usize,
+    older: usize,
+    hash: u64,
+    key_len: usize,
+    value_len: usize,
+}
+
+impl Entry {
+    const SIZE: usize = std::mem::size_of::<Entry>();
+
+    fn key(&self) -> &[u8] {
+        // SAFETY: The key bytes directly follow the entry header.
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Self as *const u8).add(Self::SIZE),
+                self.key_len,
+            )
+        }
+    }
+
+    fn value(&self) -> &[u8] {
+        // SAFETY: The value bytes directly follow the key.
+        unsafe {
+            let start = (self as *const Self as *const u8).add(Self::SIZE + self.key_len);
+            std::slice::from_raw_parts(start, self.value_len)
+        }
+    }
+
+    fn size(&self) -> usize {
+        Self::SIZE + self.key_len + self.value_len
+    }
+}
+
+/// A bounded cache from string keys to byte values stored in shared memory.
+///
+/// Entries are kept in a hash table like `ShmKvStore` and in a list ordered by last use.
+/// When the entries take more than the byte budget, or the memory runs out while inserting,
+/// the least recently used entries are evicted and freed. Reading an entry with `get` counts
+/// as a use.
+pub struct ShmLruCache<'a> {
+    table: SharedBlock<'a>,
+}
+
+impl<'a> ShmLruCache<'a> {
+    /// Creates an empty cache whose entries may take up to `budget` bytes, with the given
+    /// number of buckets rounded up to a power of two.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, buckets: usize, budget: usize) -> Option<Self> {
+        let buckets = buckets.max(1).next_power_of_two();
+        let size = Self::table_size(buckets);
+        let table = SharedBlock::create(memory, size)?;
+
+        // SAFETY: The block was just allocated with `size` bytes.
+        unsafe {
+            let header = &mut *(table.ptr.add(MemoryMutex::SIZE) as *mut CacheHeader);
+            header.buckets = buckets;
+            header.budget = budget;
+        }
+        Some(Self { table })
+    }
+
+    /// Opens a cache created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a cache that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let table = SharedBlock::open(memory, offset)?;
+        Some(Self { table })
+    }
+
+    /// Returns the offset other processes use to open the cache.
+    pub fn offset(&self) -> Offset {
+        self.table.offset()
+    }
+
+    /// Inserts a value as the most recently used entry, replacing the previous value of the
+    /// key and evicting old entries to stay within the budget.
+    ///
+    /// Returns false if the entry alone is over the budget, or there was not enough memory
+    /// even with every other entry evicted.
+    pub fn insert(&self, key: &str, value: &[u8]) -> bool {
+        let key = key.as_bytes();
+        let hash = hash(key);
+        let size = Entry::SIZE + key.len() + value.len();
+        if size > self.budget() {
+            return false;
+        }
+
+        let data = loop {
+            if let Ok(data) = self.table.memory.allocate_more(size, self.table.ptr) {
+                break data.as_ptr();
+            }
+            let evicted = {
+                let guard = self.lock();
+                self.evict(header(&guard))
+            };
+            match evicted {
+                Some(old) => self.table.memory.deallocate(old),
+                None => return false,
+            };
+        };
+
+        // SAFETY: The block was just allocated with room for the header, key and value.
+        unsafe {
+            (data as *mut Entry).write(Entry {
+                next: 0,
+                newer: 0,
+                older: 0,
+                hash,
+                key_len: key.len(),
+                value_len: value.len(),
+            });
+            let bytes = data.add(Entry::SIZE);
+            bytes.copy_from_nonoverlapping(key.as_ptr(), key.len());
+            bytes
+                .add(key.len())
+                .copy_from_nonoverlapping(value.as_ptr(), value.len());
+        }
+
+        let mut freed = Vec::new();
+        {
+            let guard = self.lock();
+            let header = header(&guard);
+            if let Some(old) = self.find(header, key, hash) {
+                freed.push(self.remove_entry(header, old));
+            }
+
+            let offset = self.offset_of(data);
+            let bucket = bucket(header, hash);
+            self.entry(offset).next = *bucket;
+            *bucket = offset;
+            self.attach(header, offset);
+            header.len += 1;
+            header.bytes += size;
+
+            // The new entry is the newest and fits the budget, so it is never evicted here.
+            while header.bytes > header.budget {
+                freed.extend(self.evict(header));
+            }
+        }
+
+        for old in freed {
+            self.table.memory.deallocate(old);
+        }
+        true
+    }
+
+    /// Returns a copy of the value of the key and marks it as the most recently used.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let key = key.as_bytes();
+        let guard = self.lock();
+        let header = header(&guard);
+        let offset = self.find(header, key, hash(key))?;
+        self.detach(header, offset);
+        self.attach(header, offset);
+        Some(self.entry(offset).value().to_vec())
+    }
+
+    /// Returns true if the cache holds a value for the key, without marking it as used.
+    pub fn contains_key(&self, key: &str) -> bool {
+        let key = key.as_bytes();
+        let guard = self.lock();
+        self.find(header(&guard), key, hash(key)).is_some()
+    }
+
+    /// Removes the key and frees its entry.
+    ///
+    /// Returns false if the key was not in the cache.
+    pub fn remove(&self, key: &str) -> bool {
+        let key = key.as_bytes();
+        let removed = {
+            let guard = self.lock();
+            let header = header(&guard);
+            self.find(header, key, hash(key))
+                .map(|offset| self.remove_entry(header, offset))
+        };
+
+        match removed {
+            Some(data) => self.table.memory.deallocate(data),
+            None => false,
+        }
+    }
+
+    /// Returns the number of keys in the cache.
+    pub fn len(&self) -> usize {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the bytes taken by all entries, counting their headers.
+    pub fn bytes(&self) -> usize {
+        header(&self.lock()).bytes
+    }
+
+    /// Returns the most bytes the entries may take.
+    pub fn budget(&self) -> usize {
+        header(&self.lock()).budget
+    }
+
+    /// Frees the table and all entries.
+    ///
+    /// Other processes must not use the cache afterwards.
+    pub fn destroy(self) {
+        self.table.free();
+    }
+
+    fn table_size(buckets: usize) -> usize {
+        MemoryMutex::SIZE
+            + std::mem::size_of::<CacheHeader>()
+            + buckets * std::mem::size_of::<usize>()
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        let size = MemoryMutex::SIZE + std::mem::size_of::<CacheHeader>();
+        self.table.lock(size)
+    }
+
+    fn find(&self, header: &mut CacheHeader, key: &[u8], hash: u64) -> Option<usize> {
+        let mut current = *bucket(header, hash);
+        while current != 0 {
+            let entry = self.entry(current);
+            if entry.hash == hash && entry.key() == key {
+                return Some(current);
+            }
+            current = entry.next;
+        }
+        None
+    }
+
+    /// Removes the least recently used entry and returns its block.
+    fn evict(&self, header: &mut CacheHeader) -> Option<*mut u8> {
+        match header.oldest {
+            0 => None,
+            oldest => Some(self.remove_entry(header, oldest)),
+        }
+    }
+
+    /// Removes an entry from its bucket and the recency list and returns its block.
+    fn remove_entry(&self, header: &mut CacheHeader, offset: usize) -> *mut u8 {
+        let entry = self.entry(offset);
+        let mut link = bucket(header, entry.hash) as *mut usize;
+        while unsafe { *link } != offset {
+            link = &mut self.entry(unsafe { *link }).next;
+        }
+        unsafe { *link = entry.next };
+
+        self.detach(header, offset);
+        header.len -= 1;
+        header.bytes -= entry.size();
+        self.pointer(offset)
+    }
+
+    /// Makes an entry that is not in the recency list the newest one.
+    fn attach(&self, header: &mut CacheHeader, offset: usize) {
+        let entry = self.entry(offset);
+        entry.newer = 0;
+        entry.older = header.newest;
+        match header.newest {
+            0 => header.oldest = offset,
+            newest => self.entry(newest).newer = offset,
+        }
+        header.newest = offset;
+    }
+
+    fn detach(&self, header: &mut CacheHeader, offset: usize) {
+        let entry = self.entry(offset);
+        match entry.newer {
+            0 => header.newest = entry.older,
+            newer => self.entry(newer).older = entry.older,
+        }
+        match entry.older {
+            0 => header.oldest = entry.newer,
+            older => self.entry(older).newer = entry.newer,
+        }
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn entry(&self, offset: usize) -> &mut Entry {
+        unsafe { &mut *(self.pointer(offset) as *mut Entry) }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.table
+            .memory
+            .pointer(Offset(offset))
+            .expect("Entries are inside the memory")
+    }
+
+    fn offset_of(&self, data: *mut u8) -> usize {
+        self.table
+            .memory
+            .offset_of(data)
+            .expect("Entries are inside the memory")
+            .0
+    }
+}
+
+/// Returns the cache header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut CacheHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut CacheHeader) }
+}
+
+fn bucket(header: &mut CacheHeader, hash: u64) -> &mut usize {
+    let index = hash as usize & (header.buckets - 1);
+    // SAFETY: The bucket array directly follows the header.
+    unsafe { &mut *((header as *mut CacheHeader).add(1) as *mut usize).add(index) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let entry = Entry::SIZE + 2;
+        let cache = ShmLruCache::create(&memory, 4, 2 * entry).unwrap();
+
+        assert!(cache.insert("a", b"1"), "The entry should fit");
+        assert!(cache.insert("b", b"2"), "The entry should fit");
+        assert!(cache.get("a").is_some(), "Reading marks the entry as used");
+        assert!(cache.insert("c", b"3"), "The entry should fit");
+
+        assert!(
+            !cache.contains_key("b"),
+            "The least recently used is evicted"
+        );
+        assert!(cache.contains_key("a"), "The read entry stays");
+        assert_eq!(cache.bytes(), 2 * entry, "The budget is used up");
+        assert!(
+            !cache.insert("d", &[0; 256]),
+            "The entry is over the budget"
+        );
+    }
+}
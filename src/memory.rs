@@ -1,13 +1,67 @@
-use std::error::Error;
+use std::{
+    alloc::{self, Layout},
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    error::Error,
+    io::IoSlice,
+    panic::Location,
+    path::Path,
+    ptr::NonNull,
+    time::Duration,
+};
 
-use winapi::ctypes::c_void;
+use crate::{
+    allocator::{self, Allocator, BlockInfo, RepairReport, ScanReport},
+    cache::LocalCache,
+    clock,
+    error::AllocError,
+    hooks::{Hooks, Usage, UsageBreakdown},
+    mutex::{MemoryGuard, MemoryMutex},
+    oplog,
+    platform::{self, c_void},
+    pressure::PressureThresholds,
+    snapshot,
+    transaction::Transaction,
+    MemoryBuilder, Offset,
+};
 
-use crate::{allocator::Allocator, mutex::MemoryMutex, windows};
+#[cfg(all(unix, feature = "sysv"))]
+use crate::sysv;
+#[cfg(feature = "metrics")]
+use crate::telemetry;
+
+/// Where the bytes of a memory come from.
+pub(crate) enum Backend {
+    /// A named file mapping, shared with other processes.
+    Mapping(*mut c_void),
+    /// A heap buffer private to this process.
+    Heap(Layout),
+    /// A System V segment, removed on drop if this handle created it.
+    #[cfg(all(unix, feature = "sysv"))]
+    SysV { id: i32, owner: bool },
+}
+
+/// Alignment of in-process buffers, so they are laid out like a mapped view.
+pub(crate) const PAGE_SIZE: usize = 4096;
 
 pub struct Memory {
-    file: *mut c_void,
+    backend: Backend,
     buffer: *mut c_void,
+    size: usize,
     mutex: MemoryMutex,
+    read_only: bool,
+    /// Whether this process initialized the heap.
+    created: bool,
+    hooks: RefCell<Hooks>,
+    /// Thresholds of `pressure` in this process.
+    pub(crate) pressure: Cell<PressureThresholds>,
+    /// Where this process allocated each of its blocks, by offset, if sites are tracked.
+    pub(crate) sites: Option<RefCell<HashMap<usize, &'static Location<'static>>>>,
+    /// Freed blocks kept for reuse by this process, if it has a cache.
+    pub(crate) cache: Option<RefCell<LocalCache>>,
+    /// Allocations left before an injected failure.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) fail_after: std::cell::Cell<Option<usize>>,
 }
 
 impl Memory {
@@ -15,20 +69,145 @@ impl Memory {
     ///
     /// Name is the file mapping name. Size is the size of the memory in bytes.
     pub fn new(name: &str, size: usize, base_ptr: usize) -> Result<Self, Box<dyn Error>> {
-        if size < MemoryMutex::SIZE + Allocator::MIN_SIZE {
+        Self::builder(name, size).base_address(base_ptr).build()
+    }
+
+    /// Opens a shared memory another process created.
+    ///
+    /// Fails if no process created it yet, or if it was created by a binary with a different
+    /// layout of the heap.
+    pub fn open(name: &str, size: usize, base_ptr: usize) -> Result<Self, Box<dyn Error>> {
+        Self::builder(name, size)
+            .base_address(base_ptr)
+            .create(false)
+            .build()
+    }
+
+    /// Returns a builder for a shared memory with additional options.
+    pub fn builder(name: &str, size: usize) -> MemoryBuilder<'_> {
+        MemoryBuilder::new(name, size)
+    }
+
+    pub(crate) fn from_builder(options: &MemoryBuilder) -> Result<Self, Box<dyn Error>> {
+        let (name, size) = (options.name, options.size);
+        let reserved = oplog::bytes(options.op_log)
+            + allocator::bump_footprint(options.bump_region)
+            + allocator::user_header_footprint(options.user_header);
+        if size < MemoryMutex::SIZE + Allocator::MIN_SIZE + reserved {
+            let name = if options.in_process {
+                "In-process memory"
+            } else {
+                name
+            };
             return Err(format!("{} size is too small", name).into());
         }
+        if options.in_process {
+            let layout = Layout::from_size_align(size, PAGE_SIZE)?;
+            // SAFETY: The layout has a non-zero size.
+            let buffer = unsafe { alloc::alloc_zeroed(layout) };
+            if buffer.is_null() {
+                return Err("Out of heap memory".into());
+            }
+            return Self::with_buffer(Backend::Heap(layout), buffer as *mut _, options);
+        }
+        let base_ptr = options.base_address as *mut _;
+        #[cfg(all(unix, feature = "sysv"))]
+        if let Some(key) = options.sysv_key {
+            let create = options.create && !options.read_only;
+            // SAFETY: Safety is handled within the function.
+            let (id, owner, buffer) =
+                unsafe { sysv::open(key, size, base_ptr, create, !options.read_only)? };
+            return Self::with_buffer(Backend::SysV { id, owner }, buffer, options);
+        }
         // SAFETY: Safety is handled within the function.
-        let (file, buffer) = unsafe { windows::open_memory(name, size, base_ptr as *mut _)? };
+        let (file, buffer) = if options.read_only || !options.create {
+            unsafe { platform::open_existing_memory(name, size, base_ptr, !options.read_only)? }
+        } else {
+            unsafe { platform::open_memory(name, size, base_ptr, options.security)? }
+        };
+        Self::with_buffer(Backend::Mapping(file), buffer, options)
+    }
+
+    /// Creates a memory over a zeroed heap buffer instead of a named mapping.
+    ///
+    /// It has the full API of a shared memory but no OS objects, so no other process can
+    /// open it. This makes it suited for tests, including under Miri. See
+    /// `MemoryBuilder::in_process` to give it options.
+    pub fn new_in_process(size: usize) -> Result<Self, Box<dyn Error>> {
+        Self::builder("", size).in_process(true).build()
+    }
 
+    fn with_buffer(
+        backend: Backend,
+        buffer: *mut c_void,
+        options: &MemoryBuilder,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (name, size) = (options.name, options.size);
         // SAFETY: The buffer is valid pointer, zeroed on first use and long enough.
         let mutex = unsafe { MemoryMutex::new(buffer as *mut _, size) };
-
-        Ok(Self {
-            file,
+        let mut memory = Self {
+            backend,
             buffer,
+            size,
             mutex,
-        })
+            read_only: options.read_only,
+            created: false,
+            hooks: RefCell::default(),
+            pressure: Cell::new(options.pressure_thresholds),
+            sites: options.track_sites.then(RefCell::default),
+            cache: (options.local_cache > 0 && !options.read_only)
+                .then(|| RefCell::new(LocalCache::new(options.local_cache))),
+            #[cfg(feature = "fault-injection")]
+            fail_after: std::cell::Cell::new(None),
+        };
+        if options.read_only {
+            let inspector = memory.inspector();
+            if !inspector.is_attached() {
+                return Err(format!("{} is not initialized", name).into());
+            }
+            inspector.handshake().check(options.protocol)?;
+        } else {
+            // A process that died holding the lock would keep every other one out, and its
+            // journal record from being replayed, so the lock is taken over from it. The queue
+            // of deferred frees is only read once the layout was checked.
+            let (guard, _) = memory.lock_recovering();
+            let created = Allocator::new(guard).attach(options)?;
+            memory.created = created;
+        }
+        Ok(memory)
+    }
+
+    /// Locks the memory for a sequence of allocator operations, first freeing the blocks
+    /// queued by `deallocate_deferred`.
+    pub(crate) fn allocator(&self) -> Allocator<'_> {
+        let allocator = Allocator::new(self.lock());
+        allocator.free_deferred();
+        allocator
+    }
+
+    /// Returns an allocator for reading the heap, which does not lock a read-only memory.
+    pub(crate) fn inspector(&self) -> Allocator<'_> {
+        Allocator::new(self.view())
+    }
+
+    pub(crate) fn lock(&self) -> MemoryGuard<'_> {
+        assert!(!self.read_only, "The memory is read-only");
+        self.mutex.lock()
+    }
+
+    /// Locks the memory, taking the lock over if the process holding it died, see
+    /// `MemoryMutex::lock_recovering`.
+    fn lock_recovering(&self) -> (MemoryGuard<'_>, bool) {
+        assert!(!self.read_only, "The memory is read-only");
+        self.mutex.lock_recovering()
+    }
+
+    fn view(&self) -> MemoryGuard<'_> {
+        if self.read_only {
+            self.mutex.view()
+        } else {
+            self.mutex.lock()
+        }
     }
 
     /// Allocates a new block of memory with the given size.
@@ -36,10 +215,17 @@ impl Memory {
     /// It uses atomic mutex and spin lock to ensure that the memory is not accessed
     /// by multiple threads and processes at the same time.
     ///
-    /// Returns the pointer to the allocated memory. Or None if not enough memory.
-    pub fn allocate(&self, size: usize) -> Option<*mut u8> {
-        let memory = self.mutex.lock();
-        Allocator::new(memory).allocate(size)
+    /// Blocks handed out by the cache of `MemoryBuilder::local_cache` skip the allocation
+    /// hooks, and the pause, the reserve and the quotas are only checked when the cache
+    /// refills from the heap.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        if let Some(data) = self.allocate_cached(size) {
+            return Ok(data);
+        }
+        self.observe(size, |allocator| allocator.allocate(size))
     }
 
     /// Allocates a new block of memory with the given size, linking it to another block.
@@ -47,26 +233,614 @@ impl Memory {
     /// It uses atomic mutex and spin lock to ensure that the memory is not accessed
     /// by multiple threads and processes at the same time.
     ///
-    /// Returns the pointer to the allocated memory. Or None if not enough memory.
-    pub fn allocate_more(&self, size: usize, parent: *mut u8) -> Option<*mut u8> {
-        let memory = self.mutex.lock();
-        Allocator::new(memory).allocate_more(size, parent)
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_more(&self, size: usize, parent: *mut u8) -> Result<NonNull<u8>, AllocError> {
+        self.uncache(parent);
+        self.observe(size, |allocator| allocator.allocate_more(size, parent))
     }
 
-    /// Frees given block of memory and all blocks linked to it.
+    /// Allocates a new block of memory with the given size that expires after `ttl`.
+    ///
+    /// Expired blocks are not freed on their own: they stay valid until some process calls
+    /// `reap_expired`. This bounds the growth of caches whose writers may forget to free.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_with_ttl(&self, size: usize, ttl: Duration) -> Result<NonNull<u8>, AllocError> {
+        let expires = clock::unix_millis().saturating_add(ttl.as_millis() as u64);
+        self.observe(size, |allocator| allocator.allocate_with_ttl(size, expires))
+    }
+
+    /// Allocates a new block of memory with the given size, accounted to `tag`.
+    ///
+    /// Tags are up to the application, e.g. one per subsystem, and show up in
+    /// `usage_breakdown`. Blocks linked to the block with `allocate_more` take its tag.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_tagged(&self, size: usize, tag: u32) -> Result<NonNull<u8>, AllocError> {
+        self.observe(size, |allocator| allocator.allocate_tagged(size, tag))
+    }
+
+    /// Allocates a block whose data starts on a 64-byte cache line and whose size is rounded
+    /// up to whole lines.
+    ///
+    /// Data that different processes write often, like their counters, goes in padded blocks
+    /// so that writes to one block do not slow down the processes using its neighbours.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_padded(&self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        self.observe(size, |allocator| allocator.allocate_padded(size))
+    }
+
+    /// Allocates one block for all of the segments and copies them into it, one after the
+    /// other.
+    ///
+    /// Returns the pointer to the block and the total length of the segments, or why there
+    /// was not enough memory. The segments are copied after the lock is released.
+    #[track_caller]
+    pub fn allocate_vectored(
+        &self,
+        segments: &[IoSlice<'_>],
+    ) -> Result<(NonNull<u8>, usize), AllocError> {
+        let len = segments.iter().map(|segment| segment.len()).sum();
+        // Blocks cannot be empty, so no segments still take a byte.
+        let data = self.allocate(usize::max(len, 1))?;
+        let mut cursor = data.as_ptr();
+        for segment in segments {
+            // SAFETY: The block was just allocated with room for all segments.
+            unsafe {
+                cursor.copy_from_nonoverlapping(segment.as_ptr(), segment.len());
+                cursor = cursor.add(segment.len());
+            }
+        }
+        Ok((data, len))
+    }
+
+    /// Allocates a block for each of the sizes under a single lock, for bursts of messages.
+    ///
+    /// Either all blocks are allocated, in the order of the sizes, or none are, and the error
+    /// tells why the first block that did not fit failed. The hooks run once for the total
+    /// size.
+    #[track_caller]
+    pub fn allocate_many(&self, sizes: &[usize]) -> Result<Vec<NonNull<u8>>, AllocError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("allocate_many", blocks = sizes.len()).entered();
+        let (result, usage) = {
+            let allocator = self.allocator();
+            let mut blocks = Vec::with_capacity(sizes.len());
+            let mut result = Ok(());
+            for &size in sizes {
+                match allocator.allocate(size).and_then(NonNull::new) {
+                    Some(data) => blocks.push((data, allocator.block_size(data.as_ptr()))),
+                    None => {
+                        result = Err(allocator.alloc_error(size));
+                        break;
+                    }
+                }
+            }
+            if result.is_err() {
+                for (data, _) in &blocks {
+                    allocator.deallocate(data.as_ptr());
+                }
+            }
+            (result.map(|_| blocks), allocator.usage())
+        };
+        let result = result.map(|blocks| {
+            blocks
+                .into_iter()
+                .map(|(data, len)| {
+                    // SAFETY: The block was just allocated with `len` bytes.
+                    unsafe { data.as_ptr().write_bytes(0, len) };
+                    data
+                })
+                .collect::<Vec<_>>()
+        });
+        #[cfg(feature = "tracing")]
+        if let Err(error) = &result {
+            tracing::warn!(%error, "allocation failed");
+        }
+        #[cfg(feature = "metrics")]
+        {
+            telemetry::record_allocation(result.is_err());
+            telemetry::record_usage(&usage);
+        }
+        if let (Some(sites), Ok(blocks)) = (&self.sites, &result) {
+            let mut sites = sites.borrow_mut();
+            for data in blocks {
+                let offset = self
+                    .offset_of(data.as_ptr())
+                    .expect("Blocks are inside the memory");
+                sites.insert(offset.0, Location::caller());
+            }
+        }
+        self.hooks
+            .borrow()
+            .notify(sizes.iter().sum(), result.is_err(), usage);
+        result
+    }
+
+    /// Frees every block whose TTL has elapsed and all blocks linked to them.
     ///
     /// It uses atomic mutex and spin lock to ensure that the memory is not accessed
     /// by multiple threads and processes at the same time.
     ///
+    /// Returns the number of blocks that were freed.
+    pub fn reap_expired(&self) -> usize {
+        let now = clock::unix_millis();
+        self.allocator().reap_expired(now)
+    }
+
+    /// Frees given block of memory and all blocks linked to it.
+    ///
+    /// It uses atomic mutex and spin lock to ensure that the memory is not accessed
+    /// by multiple threads and processes at the same time. Only the block headers are
+    /// cleared under the lock; the data is cleared by the next process that allocates it,
+    /// after it released the lock, so new blocks still start out zeroed.
+    ///
     /// Returns boolean indicating whether the block was freed or not.
     pub fn deallocate(&self, buffer: *mut u8) -> bool {
-        let memory = self.mutex.lock();
-        Allocator::new(memory).deallocate(buffer)
+        if self.deallocate_cached(buffer) {
+            return true;
+        }
+        let allocator = self.allocator();
+        let freed = allocator.deallocate(buffer);
+        #[cfg(feature = "metrics")]
+        telemetry::record_usage(&allocator.usage());
+        drop(allocator);
+        if let (Some(sites), Some(offset)) = (&self.sites, self.offset_of(buffer)) {
+            sites.borrow_mut().remove(&offset.0);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            offset = self.offset_of(buffer).map(Offset::get),
+            freed,
+            "deallocated"
+        );
+        freed
+    }
+
+    /// Frees each of the blocks and the blocks linked to them under a single lock.
+    ///
+    /// Returns the number of the given blocks that were freed; blocks that were not
+    /// allocated, or were freed with an earlier block of the list, are skipped.
+    pub fn deallocate_many(&self, buffers: &[*mut u8]) -> usize {
+        self.deallocate_all(buffers)
+            .into_iter()
+            .filter(|&freed| freed)
+            .count()
+    }
+
+    /// Like `deallocate_many`, telling for each of the blocks whether it was freed.
+    ///
+    /// The blocks are freed in one pass over the heap, for teardowns of many blocks.
+    pub fn deallocate_all(&self, buffers: &[*mut u8]) -> Vec<bool> {
+        let mut freed: Vec<_> = buffers
+            .iter()
+            .map(|&buffer| self.deallocate_cached(buffer))
+            .collect();
+        let (indices, rest): (Vec<_>, Vec<_>) = buffers
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| !freed[index])
+            .map(|(index, &buffer)| (index, buffer))
+            .unzip();
+        let allocator = self.allocator();
+        for (index, done) in indices.into_iter().zip(allocator.deallocate_all(&rest)) {
+            freed[index] = done;
+        }
+        #[cfg(feature = "metrics")]
+        telemetry::record_usage(&allocator.usage());
+        drop(allocator);
+        if let Some(sites) = &self.sites {
+            let mut sites = sites.borrow_mut();
+            for offset in rest.iter().filter_map(|&buffer| self.offset_of(buffer)) {
+                sites.remove(&offset.0);
+            }
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            freed = freed.iter().filter(|&&freed| freed).count(),
+            "deallocated many"
+        );
+        freed
+    }
+
+    /// Frees the block with every block linked to it, directly or through other blocks,
+    /// under a single lock. `deallocate` only frees the blocks linked to the block itself.
+    ///
+    /// Returns the number of blocks freed, zero if the block was not allocated.
+    pub fn deallocate_tree(&self, root: *mut u8) -> usize {
+        if self.deallocate_cached(root) {
+            return 1;
+        }
+        let allocator = self.allocator();
+        let freed = allocator.deallocate_tree(root);
+        #[cfg(feature = "metrics")]
+        telemetry::record_usage(&allocator.usage());
+        drop(allocator);
+        if let (Some(sites), Some(offset)) = (&self.sites, self.offset_of(root)) {
+            sites.borrow_mut().remove(&offset.0);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            offset = self.offset_of(root).map(Offset::get),
+            freed,
+            "deallocated tree"
+        );
+        freed
+    }
+
+    /// Trims the block at `buffer` to `size` bytes, rounded up to a word, and gives the rest
+    /// back to the heap, e.g. once a parser that over-allocated knows the exact size.
+    ///
+    /// The block keeps its offset and the data before `size`. Returns false if the block is
+    /// not allocated, is not larger than that, or is tagged `BUMP_TAG` or `QUARANTINE_TAG`.
+    pub fn shrink(&self, buffer: *mut u8, size: usize) -> bool {
+        // The cache hands out blocks by their size, so a trimmed block goes back to the heap.
+        self.uncache(buffer);
+        let allocator = self.allocator();
+        let shrunk = allocator.shrink(buffer, size);
+        #[cfg(feature = "metrics")]
+        telemetry::record_usage(&allocator.usage());
+        shrunk
+    }
+
+    /// Splits the block at `buffer` after `at` bytes, rounded up to a word, into two blocks
+    /// that are freed independently, e.g. to hand out messages of one receive buffer
+    /// without copying them.
+    ///
+    /// The header of the second block takes the `BLOCK_HEADER_SIZE` bytes after the first
+    /// block, overwriting them; the data past them stays. The second block takes the parent,
+    /// tag, owner and expiry of the block, and blocks linked to the block stay with the first
+    /// one.
+    ///
+    /// Returns the second block, or None if the block is not allocated, is tagged `BUMP_TAG`
+    /// or `QUARANTINE_TAG`, or is too small to split there.
+    pub fn split(&self, buffer: *mut u8, at: usize) -> Option<NonNull<u8>> {
+        // The cache hands out blocks by their size, so a split block goes back to the heap.
+        self.uncache(buffer);
+        let second = self.allocator().split(buffer, at)?;
+        NonNull::new(second)
+    }
+
+    /// Returns the size of the allocated block at `buffer`, or None if it is not allocated.
+    pub fn allocation_size(&self, buffer: *mut u8) -> Option<usize> {
+        self.inspector().size_of(buffer)
+    }
+
+    /// Returns how much of the heap is in use, and was at most.
+    pub fn usage(&self) -> Usage {
+        self.inspector().usage()
+    }
+
+    /// Lists all allocated blocks of every process, in the order they lie in the heap.
+    pub fn blocks(&self) -> Vec<BlockInfo> {
+        self.inspector().blocks()
+    }
+
+    /// Lists the `n` largest blocks with their tags and owners, largest first, to tell what
+    /// fills the heap.
+    pub fn top_allocations(&self, n: usize) -> Vec<BlockInfo> {
+        let mut blocks = self.blocks();
+        blocks.sort_by_key(|block| std::cmp::Reverse(block.size));
+        blocks.truncate(n);
+        blocks
+    }
+
+    /// Lists the blocks allocated at least `age` ago, oldest first, e.g. to find buffers a
+    /// consumer never released.
+    ///
+    /// Only memories created with `MemoryBuilder::timestamps` record when blocks were
+    /// allocated; otherwise the list is empty.
+    pub fn allocations_older_than(&self, age: Duration) -> Vec<BlockInfo> {
+        let cutoff = clock::unix_millis().saturating_sub(age.as_millis() as u64);
+        let mut blocks: Vec<_> = self
+            .blocks()
+            .into_iter()
+            .filter(|block| block.created.is_some_and(|created| created <= cutoff))
+            .collect();
+        blocks.sort_by_key(|block| block.created);
+        blocks
+    }
+
+    /// Lists the free ranges between blocks, as their offsets and lengths in bytes.
+    ///
+    /// A block fits into a range if its size plus the block header fits.
+    pub fn gaps(&self) -> Vec<(Offset, usize)> {
+        self.inspector().gaps()
+    }
+
+    /// Returns which tags and processes the used bytes belong to.
+    ///
+    /// It walks every block, so it takes longer than `usage` on a large heap.
+    pub fn usage_breakdown(&self) -> UsageBreakdown {
+        self.inspector().breakdown()
+    }
+
+    /// Starts tracking the peak usage afresh from the current usage, for all processes.
+    pub fn reset_peak(&self) {
+        self.allocator().reset_peak();
+    }
+
+    /// Registers a callback run whenever an allocation of this process fails, with the size
+    /// that was asked for.
+    ///
+    /// Callbacks run after the memory is unlocked, so they may free blocks to shed load.
+    /// They must not register further callbacks.
+    pub fn on_allocation_failure(&self, hook: impl Fn(usize) + 'static) {
+        self.hooks.borrow_mut().on_failure(Box::new(hook));
+    }
+
+    /// Registers a callback run when an allocation of this process brings the used part of
+    /// the heap from below `fraction` to at or above it.
+    ///
+    /// Allocations of other processes are seen at the next allocation of this process.
+    /// Callbacks run after the memory is unlocked and must not register further callbacks.
+    pub fn on_watermark(&self, fraction: f64, hook: impl Fn(Usage) + 'static) {
+        self.hooks
+            .borrow_mut()
+            .on_watermark(fraction, Box::new(hook));
+    }
+
+    /// Returns the source location that allocated the block at `buffer`.
+    ///
+    /// Returns None if sites are not tracked, see `MemoryBuilder::track_sites`, if the block
+    /// is not allocated, or if another process allocated it.
+    pub fn allocation_site(&self, buffer: *mut u8) -> Option<&'static Location<'static>> {
+        let offset = self.offset_of(buffer)?;
+        let sites = self.sites.as_ref()?;
+        let block = self
+            .inspector()
+            .blocks()
+            .into_iter()
+            .find(|b| b.offset == offset)?;
+        if block.owner != std::process::id() {
+            return None;
+        }
+        sites.borrow().get(&offset.0).copied()
+    }
+
+    /// Runs an allocation, then the callbacks.
+    #[track_caller]
+    pub(crate) fn observe(
+        &self,
+        size: usize,
+        allocate: impl FnOnce(&Allocator) -> Option<*mut u8>,
+    ) -> Result<NonNull<u8>, AllocError> {
+        self.observe_with(size, |allocator| {
+            allocate(allocator).ok_or_else(|| allocator.alloc_error(size))
+        })
+    }
+
+    /// Like `observe`, for allocations that explain their own failures.
+    #[track_caller]
+    pub(crate) fn observe_with(
+        &self,
+        size: usize,
+        allocate: impl FnOnce(&Allocator) -> Result<*mut u8, AllocError>,
+    ) -> Result<NonNull<u8>, AllocError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("allocate", size).entered();
+        let (result, usage) = {
+            let allocator = self.allocator();
+            #[cfg(feature = "fault-injection")]
+            let allocate = |allocator: &Allocator| {
+                if self.take_fault() {
+                    Err(allocator.alloc_error(size))
+                } else {
+                    allocate(allocator)
+                }
+            };
+            let result = allocate(&allocator).map(|data| {
+                let data = NonNull::new(data).expect("Blocks are inside the memory");
+                (data, allocator.block_size(data.as_ptr()))
+            });
+            (result, allocator.usage())
+        };
+        // Freed blocks keep their data, which is cleared once the lock is released.
+        let result = result.map(|(data, len)| {
+            // SAFETY: The block was just allocated with `len` bytes.
+            unsafe { data.as_ptr().write_bytes(0, len) };
+            data
+        });
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(data) => tracing::trace!(
+                offset = self.offset_of(data.as_ptr()).map(Offset::get),
+                used = usage.used,
+                "allocated"
+            ),
+            Err(error) => tracing::warn!(%error, "allocation failed"),
+        }
+        #[cfg(feature = "metrics")]
+        {
+            telemetry::record_allocation(result.is_err());
+            telemetry::record_usage(&usage);
+        }
+        if let (Some(sites), Ok(data)) = (&self.sites, &result) {
+            let offset = self
+                .offset_of(data.as_ptr())
+                .expect("Blocks are inside the memory");
+            sites.borrow_mut().insert(offset.0, Location::caller());
+        }
+        self.hooks.borrow().notify(size, result.is_err(), usage);
+        result
+    }
+
+    /// Returns the offset of a pointer into this memory, or None if it points elsewhere.
+    pub fn offset_of(&self, buffer: *mut u8) -> Option<Offset> {
+        let offset = (buffer as usize).checked_sub(self.buffer as usize)?;
+        (offset < self.size).then_some(Offset(offset))
+    }
+
+    /// Returns the pointer for an offset into this memory, or None if it is out of bounds.
+    pub fn pointer(&self, offset: Offset) -> Option<*mut u8> {
+        // SAFETY: The offset is within the mapped memory.
+        (offset.0 < self.size).then(|| unsafe { (self.buffer as *mut u8).add(offset.0) })
+    }
+
+    /// Starts a transaction whose allocations are freed again unless it is committed.
+    pub fn begin(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Slides all blocks towards the start of the heap, so the free space is in one piece again.
+    ///
+    /// Moving a block changes its offset, so everything referring to it has to be updated:
+    /// `relocate` is called with the old and the new offset of every moved block once the
+    /// memory is unlocked. Blocks holding offsets of other blocks, like the nodes of the shared
+    /// data structures, must be fixed up there, and pointers to moved blocks become invalid in
+    /// every process. Blocks registered under a name are found under their new offset.
+    ///
+    /// No other process may use the memory while it is compacted. The moves are not journaled,
+    /// so a process dying midway leaves the heap corrupted.
+    ///
+    /// Returns the number of blocks that were moved.
+    pub fn compact(&self, mut relocate: impl FnMut(Offset, Offset)) -> usize {
+        let moves: Vec<(Offset, Offset)> = {
+            let allocator = self.allocator();
+            // The directory refers to its entries by offset, so it stays where it is.
+            let directory = match allocator.directory() {
+                0 => std::ptr::null_mut(),
+                offset => self
+                    .pointer(Offset(offset))
+                    .expect("The directory is inside the memory"),
+            };
+            allocator
+                .compact(directory)
+                .into_iter()
+                .map(|(old, new)| {
+                    let old = self.offset_of(old).expect("Blocks are inside the memory");
+                    let new = self.offset_of(new).expect("Blocks are inside the memory");
+                    (old, new)
+                })
+                .collect()
+        };
+
+        self.relocate_names(&moves);
+        self.relocate_cache(Some(&moves));
+        for &(old, new) in &moves {
+            relocate(old, new);
+        }
+        moves.len()
+    }
+
+    /// Frees every block at once, returning the memory to the state it was created in.
+    ///
+    /// Names registered in the memory are forgotten too. The data area is zeroed if `zero` is
+    /// set or the memory was created with `MemoryBuilder::secure_wipe`, otherwise it keeps
+    /// its old bytes. Pointers and offsets of blocks become invalid in
+    /// every process, so it suits wiping the shared state between test runs or sessions.
+    pub fn reset(&self, zero: bool) {
+        self.allocator().reset(zero);
+        self.relocate_cache(None);
+    }
+
+    /// Validates all block headers and their canaries to find memory that was written over.
+    ///
+    /// With `quarantine`, each corrupted region is covered by a block tagged `QUARANTINE_TAG`,
+    /// so the rest of the heap stays usable after a misbehaving process scribbled over it.
+    /// The blocks inside the region are lost; their pointers must not be used anymore.
+    ///
+    /// Returns a report of the corrupted regions.
+    pub fn scan(&self, quarantine: bool) -> ScanReport {
+        if quarantine {
+            self.allocator().scan(true)
+        } else {
+            self.inspector().scan(false)
+        }
+    }
+
+    /// Scans all block headers, validates their links and sizes and repairs the heap.
+    ///
+    /// Use it after a process crashed while holding the memory, so the rest of the heap stays
+    /// usable. Blocks behind a broken link are lost.
+    ///
+    /// If the lock is held by a process that is no longer alive, the lock is taken over
+    /// instead of waited for, and the repair replays the journal and cleans up what the
+    /// process left halfway. A lock held by a live process is waited for as usual.
+    ///
+    /// Returns a report of what was fixed.
+    pub fn check_and_repair(&self) -> RepairReport {
+        let (guard, broken) = self.lock_recovering();
+        let allocator = Allocator::new(guard);
+        let mut report = allocator.check_and_repair();
+        report.lock_broken = broken;
+        // The queue is only trusted once the heap was repaired.
+        allocator.free_deferred();
+        report
+    }
+
+    /// Writes a consistent copy of the whole memory to a file.
+    ///
+    /// The memory stays locked while it is copied, so no process can modify it halfway.
+    /// A read-only memory cannot be locked and is copied as it is.
+    pub fn snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let memory = self.view();
+        snapshot::save(&memory, path.as_ref())
+    }
+
+    /// Replaces the blocks of the memory with those of a snapshot written by `snapshot`.
+    ///
+    /// The snapshot must come from a memory of the same size and layout mapped at the same
+    /// address, otherwise the block links would point to the wrong place. The state of the
+    /// attached processes in the heap header, like the handle count, the handshake and the
+    /// lock, stays as it is.
+    ///
+    /// Fails while other handles are attached. Pointers to blocks that are not in the
+    /// snapshot become invalid, and the local cache of this handle is dropped.
+    pub fn restore<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        self.allocator().restore(path.as_ref())?;
+        self.relocate_cache(None);
+        if let Some(sites) = &self.sites {
+            sites.borrow_mut().clear();
+        }
+        Ok(())
+    }
+
+    /// Returns true if some thread or process holds the lock of the heap right now.
+    ///
+    /// A lock that stays held while nothing runs points to a process that died holding it.
+    pub fn is_locked(&self) -> bool {
+        self.mutex.is_locked()
+    }
+
+    /// Returns true if this process initialized the heap, rather than attaching to a heap
+    /// another process created.
+    pub fn is_creator(&self) -> bool {
+        self.created
+    }
+
+    /// Returns true if the memory was opened with `MemoryBuilder::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the offset of the bump region, read without the lock.
+    ///
+    /// # Safety
+    /// The field must not be written concurrently, which it only is while the heap is
+    /// created or reset.
+    pub(crate) unsafe fn heap_bump_offset(&self) -> Option<Offset> {
+        allocator::bump_offset((self.buffer as *mut u8).add(MemoryMutex::SIZE))
+    }
+
+    /// Returns the size of the memory in bytes, as given when it was created.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub(crate) fn backend(&self) -> &Backend {
+        &self.backend
     }
 
     /// Returns the underlying memory buffer.
     ///
-    /// This function is unsafe because modifying the buffer can lead to undefined behavior
+    /// # Safety
+    /// Modifying the buffer outside of allocated blocks can lead to undefined behavior.
     pub unsafe fn buffer(&self) -> *mut u8 {
         self.buffer as *mut u8
     }
@@ -74,7 +848,347 @@ impl Memory {
 
 impl Drop for Memory {
     fn drop(&mut self) {
-        // SAFETY: Both the buffer and the file handle are valid.
-        unsafe { windows::release_memory(self.file, self.buffer) };
+        self.flush_cache();
+        #[cfg(feature = "debug")]
+        if self.created && !self.read_only {
+            for leak in self.leak_report() {
+                eprintln!("rshmem: {leak}");
+            }
+        }
+        if !self.read_only {
+            self.allocator().detach();
+        }
+        match self.backend {
+            // SAFETY: Both the buffer and the file handle are valid.
+            Backend::Mapping(file) => unsafe { platform::release_memory(file, self.buffer) },
+            // SAFETY: The buffer was allocated with this layout.
+            Backend::Heap(layout) => unsafe { alloc::dealloc(self.buffer as *mut u8, layout) },
+            // SAFETY: The segment is attached at the buffer.
+            #[cfg(all(unix, feature = "sysv"))]
+            Backend::SysV { id, owner } => unsafe { sysv::release(id, owner, self.buffer) },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::BLOCK_HEADER_SIZE;
+
+    use super::*;
+
+    #[test]
+    fn test_allocation_site() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .track_sites(true)
+            .build()
+            .unwrap();
+
+        let line = line!() + 1;
+        let data = memory.allocate(16).unwrap().as_ptr();
+        let site = memory.allocation_site(data).unwrap();
+        assert_eq!(site.file(), file!(), "The caller allocated the block");
+        assert_eq!(site.line(), line, "The caller allocated the block");
+
+        memory.deallocate(data);
+        assert!(
+            memory.allocation_site(data).is_none(),
+            "The block was freed"
+        );
+    }
+
+    #[test]
+    fn test_many() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let blocks = memory.allocate_many(&[16, 32, 64]).unwrap();
+        assert_eq!(memory.usage().blocks, 3, "All blocks were allocated");
+        assert_eq!(memory.allocation_size(blocks[1].as_ptr()), Some(32));
+
+        assert!(
+            memory.allocate_many(&[16, 8192]).is_err(),
+            "The second block does not fit"
+        );
+        assert_eq!(memory.usage().blocks, 3, "Nothing was allocated");
+
+        let mut buffers: Vec<_> = blocks.iter().map(|data| data.as_ptr()).collect();
+        buffers.push(buffers[0]);
+        assert_eq!(
+            memory.deallocate_many(&buffers),
+            3,
+            "Each block is freed once"
+        );
+        assert_eq!(memory.usage().blocks, 0, "The heap is empty");
+    }
+
+    #[test]
+    fn test_deallocate_all_and_tree() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let root = memory.allocate(16).unwrap().as_ptr();
+        let child = memory.allocate_more(16, root).unwrap().as_ptr();
+        memory.allocate_more(16, child).unwrap();
+        let other = memory.allocate(16).unwrap().as_ptr();
+        assert_eq!(memory.deallocate_tree(root), 3, "The whole tree is freed");
+        assert_eq!(memory.deallocate_tree(root), 0, "The tree is gone");
+
+        let parent = memory.allocate(16).unwrap().as_ptr();
+        let child = memory.allocate_more(16, parent).unwrap().as_ptr();
+        assert_eq!(
+            memory.deallocate_all(&[parent, child, root, other]),
+            vec![true, false, false, true],
+            "The child went with its parent, the root was freed before"
+        );
+        assert_eq!(memory.usage().blocks, 0, "The heap is empty");
+    }
+
+    #[test]
+    fn test_shrink() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let block = memory.allocate(1024).unwrap().as_ptr();
+        unsafe { block.write_bytes(7, 1024) };
+        let used = memory.usage().used;
+
+        assert!(memory.shrink(block, 100), "The block is trimmed");
+        assert_eq!(
+            memory.allocation_size(block),
+            Some(104),
+            "Sizes are whole words"
+        );
+        assert_eq!(memory.usage().used, used - 920, "The tail is free again");
+        assert_eq!(unsafe { *block.add(99) }, 7, "The data stays");
+        assert!(!memory.shrink(block, 200), "Blocks do not grow");
+
+        let next = memory.allocate(512).unwrap().as_ptr();
+        assert!(
+            (next as usize) < block as usize + 1024,
+            "The tail is reused"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_attach_takes_over_lock_of_dead_process() {
+        let memory = Memory::builder("rshmem_test_dead_holder", 4096)
+            .journal(true)
+            .build()
+            .unwrap();
+        let block = memory.allocate(64).unwrap().as_ptr();
+        memory.lock().abandon();
+        assert!(memory.is_locked(), "The dead process holds the lock");
+
+        let other = Memory::open("rshmem_test_dead_holder", 4096, 0).unwrap();
+        assert!(
+            !other.is_locked(),
+            "Attaching took the lock over and released it"
+        );
+        assert!(other.deallocate(block), "The heap is usable again");
+    }
+
+    #[test]
+    fn test_repair_takes_over_lock_of_dead_process() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let block = memory.allocate(64).unwrap().as_ptr();
+        memory.lock().abandon();
+
+        let report = memory.check_and_repair();
+        assert!(
+            report.lock_broken,
+            "The lock of the dead process was taken over"
+        );
+        assert!(!memory.is_locked(), "The lock is released after the repair");
+        assert!(memory.deallocate(block), "The heap is usable again");
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_restore_keeps_attached_processes() {
+        let memory = Memory::new("rshmem_test_restore", 4096, 0).unwrap();
+        let path = std::env::temp_dir().join("rshmem_test_restore.snap");
+        let kept = memory.allocate(64).unwrap().as_ptr();
+        memory.snapshot(&path).unwrap();
+        let usage = memory.usage();
+
+        let other = Memory::open("rshmem_test_restore", 4096, 0).unwrap();
+        assert!(
+            memory.restore(&path).is_err(),
+            "Other handles would see their blocks change"
+        );
+        drop(other);
+
+        let lost = memory.allocate(128).unwrap().as_ptr();
+        memory.deallocate(kept);
+        memory.restore(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(memory.usage(), usage, "The counters come from the snapshot");
+        assert!(!memory.deallocate(lost), "Later blocks are gone");
+        assert!(memory.deallocate(kept), "Blocks of the snapshot are back");
+        assert!(
+            Memory::open("rshmem_test_restore", 4096, 0).is_ok(),
+            "The handshake and the handle count stayed"
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let block = memory.allocate(256).unwrap().as_ptr();
+        unsafe { block.write_bytes(7, 256) };
+        let (used, blocks) = (memory.usage().used, memory.usage().blocks);
+
+        let second = memory.split(block, 60).unwrap().as_ptr();
+        assert_eq!(
+            second as usize,
+            block as usize + 64 + BLOCK_HEADER_SIZE,
+            "The second block follows the header"
+        );
+        assert_eq!(
+            memory.allocation_size(block),
+            Some(64),
+            "The first block is cut"
+        );
+        assert_eq!(
+            memory.allocation_size(second),
+            Some(256 - 64 - BLOCK_HEADER_SIZE),
+            "The second block has the rest"
+        );
+        assert_eq!(unsafe { *second }, 7, "The data stays");
+        assert_eq!(memory.usage().used, used, "No bytes were added");
+        assert_eq!(memory.usage().blocks, blocks + 1, "There is one more block");
+        assert!(
+            memory.split(second, 200).is_none(),
+            "Too small to split there"
+        );
+
+        assert!(memory.deallocate(block), "The first block is freed alone");
+        assert!(
+            memory.deallocate(second),
+            "The second block is still allocated"
+        );
+    }
+
+    #[test]
+    fn test_top_allocations() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        memory.allocate(16).unwrap();
+        memory.allocate_tagged(512, 7).unwrap();
+        memory.allocate(128).unwrap();
+
+        let top = memory.top_allocations(2);
+        let sizes: Vec<_> = top.iter().map(|block| block.size).collect();
+        assert_eq!(sizes, vec![512, 128], "The largest blocks come first");
+        assert_eq!(top[0].tag, 7, "The tag is reported");
+        assert_eq!(memory.top_allocations(10).len(), 3, "Every block is listed");
+    }
+
+    #[test]
+    fn test_allocations_older_than() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .timestamps(true)
+            .build()
+            .unwrap();
+        let old = memory.allocate(16).unwrap().as_ptr();
+        std::thread::sleep(Duration::from_millis(20));
+        memory.allocate(16).unwrap();
+
+        let stuck = memory.allocations_older_than(Duration::from_millis(10));
+        assert_eq!(stuck.len(), 1, "Only the first block is old");
+        assert_eq!(
+            Some(stuck[0].offset),
+            memory.offset_of(old),
+            "The old block is listed"
+        );
+        assert_eq!(
+            memory.allocations_older_than(Duration::ZERO).len(),
+            2,
+            "Every block is at least this old"
+        );
+
+        let untimed = Memory::new_in_process(4096).unwrap();
+        untimed.allocate(16).unwrap();
+        assert!(
+            untimed.allocations_older_than(Duration::ZERO).is_empty(),
+            "Blocks record no time by default"
+        );
+    }
+
+    #[test]
+    fn test_freed_data_is_cleared() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let data = memory.allocate(16).unwrap().as_ptr();
+        unsafe { data.write_bytes(0xff, 16) };
+        memory.deallocate(data);
+        assert_eq!(
+            unsafe { *data.add(8) },
+            0xff,
+            "The data is left until reuse"
+        );
+
+        let reused = memory.allocate(12).unwrap().as_ptr();
+        assert_eq!(reused, data, "The block is reused");
+        let bytes = unsafe { std::slice::from_raw_parts(reused, 16) };
+        assert!(
+            bytes.iter().all(|&byte| byte == 0),
+            "The whole block is cleared"
+        );
+    }
+
+    #[test]
+    fn test_allocate_vectored() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let segments = [
+            IoSlice::new(b"head"),
+            IoSlice::new(b""),
+            IoSlice::new(b"er"),
+        ];
+        let (data, len) = memory.allocate_vectored(&segments).unwrap();
+        assert_eq!(len, 6, "The lengths add up");
+        let bytes = unsafe { std::slice::from_raw_parts(data.as_ptr(), len) };
+        assert_eq!(bytes, b"header", "The segments follow each other");
+    }
+
+    #[test]
+    fn test_in_process() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        assert!(memory.is_creator(), "The heap is always fresh");
+
+        let parent = memory.allocate(16).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+        assert_eq!(memory.usage().blocks, 2, "Both blocks are counted");
+        memory.deallocate(parent);
+        assert_eq!(memory.usage().blocks, 0, "The child went with its parent");
+
+        assert!(
+            Memory::new_in_process(8).is_err(),
+            "The heap needs room for its header"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_secure_wipe() {
+        let memory = Memory::builder("rshmem_test_secure_wipe", 4096)
+            .secure_wipe(true)
+            .build()
+            .unwrap();
+        let data = memory.allocate(16).unwrap().as_ptr();
+        unsafe { data.write_bytes(0xaa, 16) };
+
+        let peer = Memory::new("rshmem_test_secure_wipe", 4096, 0).unwrap();
+        drop(peer);
+        assert_eq!(unsafe { *data }, 0xaa, "Other handles are still open");
+
+        memory.deallocate(data);
+        memory.reset(false);
+        let wiped = unsafe { std::slice::from_raw_parts(data, 16) };
+        assert!(wiped.iter().all(|&byte| byte == 0), "Reset wipes the heap");
     }
 }
@@ -0,0 +1,511 @@
+use std::{
+    error::Error,
+    fmt,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use crate::{
+    mpsc::Consumer, shared::SharedBlock, Memory, Offset, SendError, ShmMpscQueue, ShmSafe,
+};
+
+#[repr(C)]
+struct MessageHeader {
+    /// Offset of the queue of envelopes.
+    queue: usize,
+    /// Whether messages are framed with their length and checksum.
+    framed: bool,
+}
+
+/// Written before the bytes of a framed message.
+#[repr(C)]
+struct Frame {
+    len: usize,
+    crc: u32,
+}
+
+const FRAME: usize = std::mem::size_of::<Frame>();
+
+/// Where a message is, as passed through the queue.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Envelope {
+    offset: usize,
+    len: usize,
+}
+
+// SAFETY: Two plain numbers, valid for any bytes.
+unsafe impl ShmSafe for Envelope {}
+
+/// A multi-producer, single-consumer queue of byte messages in shared memory, written and
+/// read in place.
+///
+/// A producer reserves a block with `reserve`, fills it, and `commit`s it to the queue. The
+/// consumer only ever sees committed messages, so it cannot read a block that is still being
+/// written. Dropping the guard without committing frees the block instead. Messages are
+/// allocated as children of the queue, which lets `destroy` free everything at once.
+///
+/// A queue created with `create_framed` stores the length and a CRC32 of each message in
+/// front of it and checks both on receive, so a peer writing out of bounds is caught there
+/// rather than by whatever logic consumes the corrupted message.
+pub struct ShmMessageQueue<'a> {
+    block: SharedBlock<'a>,
+    queue: ShmMpscQueue<'a, Envelope>,
+}
+
+impl<'a> ShmMessageQueue<'a> {
+    /// Creates an empty queue.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        Self::create_with(memory, false)
+    }
+
+    /// Creates an empty queue whose messages are checked on receive.
+    ///
+    /// Each message takes a few more bytes for its frame, and both sides hash its bytes.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create_framed(memory: &'a Memory) -> Option<Self> {
+        Self::create_with(memory, true)
+    }
+
+    fn create_with(memory: &'a Memory, framed: bool) -> Option<Self> {
+        let size = std::mem::size_of::<MessageHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        let Some(queue) = ShmMpscQueue::create(memory) else {
+            block.free();
+            return None;
+        };
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (block.ptr as *mut MessageHeader).write(MessageHeader {
+                queue: queue.offset().0,
+                framed,
+            })
+        };
+        Some(Self { block, queue })
+    }
+
+    /// Opens a queue created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a message queue that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        let queue = Offset((*(block.ptr as *const MessageHeader)).queue);
+        Some(Self {
+            block,
+            queue: ShmMpscQueue::open(memory, queue)?,
+        })
+    }
+
+    /// Returns the offset other processes use to open the queue.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the most messages the queue holds, or None if it is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        self.queue.capacity()
+    }
+
+    /// Sets the most messages the queue holds for every process, or makes it unbounded.
+    ///
+    /// See `ShmMpscQueue::set_capacity`.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.queue.set_capacity(capacity);
+    }
+
+    /// Registers a callback run after a send of this process brings the queue to `depth`
+    /// messages.
+    ///
+    /// See `ShmMpscQueue::on_high_watermark`.
+    pub fn on_high_watermark(&self, depth: usize, hook: impl Fn(usize) + 'static) {
+        self.queue.on_high_watermark(depth, hook);
+    }
+
+    /// Returns true if messages are checked on receive.
+    pub fn is_framed(&self) -> bool {
+        self.header().framed
+    }
+
+    /// Returns the number of committed messages not received yet.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true if there are no committed messages.
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Allocates a message of `size` bytes for the caller to fill in place.
+    ///
+    /// Returns None if not enough memory.
+    pub fn reserve(&self, size: usize) -> Option<WriteGuard<'_, 'a>> {
+        let frame = if self.is_framed() { FRAME } else { 0 };
+        let block = self
+            .block
+            .memory
+            .allocate_more((frame + size).max(1), self.queue_block())
+            .ok()?
+            .as_ptr();
+        Some(WriteGuard {
+            queue: self,
+            block,
+            // SAFETY: The frame is inside the block.
+            data: unsafe { block.add(frame) },
+            len: size,
+        })
+    }
+
+    /// Copies a message into the queue.
+    ///
+    /// Returns false if the queue is full or there was not enough memory.
+    pub fn send(&self, message: &[u8]) -> bool {
+        self.try_send(message).is_ok()
+    }
+
+    /// Copies a message into the queue, telling why it could not.
+    pub fn try_send(&self, message: &[u8]) -> Result<(), SendError> {
+        if self
+            .capacity()
+            .is_some_and(|capacity| self.len() >= capacity)
+        {
+            return Err(SendError::Full);
+        }
+        self.fill(message)?.commit()
+    }
+
+    /// Copies a message into the queue, waiting up to `timeout` for the consumer to make
+    /// room.
+    ///
+    /// Returns `SendError::Full` if the queue was still full when the timeout passed.
+    pub fn send_timeout(&self, message: &[u8], timeout: Duration) -> Result<(), SendError> {
+        self.fill(message)?.commit_timeout(timeout)
+    }
+
+    fn fill(&self, message: &[u8]) -> Result<WriteGuard<'_, 'a>, SendError> {
+        let mut guard = self.reserve(message.len()).ok_or(SendError::OutOfMemory)?;
+        guard.copy_from_slice(message);
+        Ok(guard)
+    }
+
+    /// Claims the consumer side of the queue.
+    ///
+    /// Returns None if another thread or process is the consumer.
+    pub fn consumer(&self) -> Option<MessageConsumer<'_, 'a>> {
+        Some(MessageConsumer {
+            queue: self,
+            consumer: self.queue.consumer()?,
+        })
+    }
+
+    /// Frees the queue and all of its messages.
+    ///
+    /// Other processes must not use the queue afterwards.
+    pub fn destroy(self) {
+        self.queue.destroy();
+        self.block.free();
+    }
+
+    fn header(&self) -> &MessageHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const MessageHeader) }
+    }
+
+    fn queue_block(&self) -> *mut u8 {
+        self.block
+            .memory
+            .pointer(self.queue.offset())
+            .expect("The queue is inside the memory")
+    }
+}
+
+/// A reserved message of a `ShmMessageQueue`, not visible to the consumer until committed.
+///
+/// Dropping it without calling `commit` frees the message.
+pub struct WriteGuard<'q, 'a> {
+    queue: &'q ShmMessageQueue<'a>,
+    block: *mut u8,
+    data: *mut u8,
+    len: usize,
+}
+
+impl<'q, 'a> WriteGuard<'q, 'a> {
+    /// Makes the message visible to the consumer.
+    ///
+    /// Frees the message if the queue is full or there was not enough memory for its node.
+    pub fn commit(self) -> Result<(), SendError> {
+        self.push(|queue, envelope| queue.try_push(envelope))
+    }
+
+    /// Makes the message visible to the consumer, waiting up to `timeout` for the consumer to
+    /// make room.
+    ///
+    /// Frees the message if the queue was still full when the timeout passed, or if there was
+    /// not enough memory for its node.
+    pub fn commit_timeout(self, timeout: Duration) -> Result<(), SendError> {
+        self.push(|queue, envelope| queue.push_timeout(envelope, timeout))
+    }
+
+    fn push(
+        self,
+        push: impl FnOnce(&ShmMpscQueue<'a, Envelope>, Envelope) -> Result<(), SendError>,
+    ) -> Result<(), SendError> {
+        let queue = self.queue;
+        if queue.is_framed() {
+            let frame = Frame {
+                len: self.len,
+                crc: crc32(&self),
+            };
+            // SAFETY: The frame is at the start of the block.
+            unsafe { (self.block as *mut Frame).write(frame) };
+        }
+        let (block, len) = (self.block, self.len);
+        std::mem::forget(self);
+        let offset = queue
+            .block
+            .memory
+            .offset_of(block)
+            .expect("Messages are inside the memory");
+        let envelope = Envelope {
+            offset: offset.0,
+            len,
+        };
+        let pushed = push(&queue.queue, envelope);
+        if pushed.is_err() {
+            queue.block.memory.deallocate(block);
+        }
+        pushed
+    }
+}
+
+impl<'q, 'a> Deref for WriteGuard<'q, 'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The block was allocated with `len` bytes and only the guard can reach it.
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl<'q, 'a> DerefMut for WriteGuard<'q, 'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: The block was allocated with `len` bytes and only the guard can reach it.
+        unsafe { std::slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl<'q, 'a> Drop for WriteGuard<'q, 'a> {
+    fn drop(&mut self) {
+        self.queue.block.memory.deallocate(self.block);
+    }
+}
+
+/// The consumer side of a `ShmMessageQueue`. Only one exists at a time.
+pub struct MessageConsumer<'q, 'a> {
+    queue: &'q ShmMessageQueue<'a>,
+    consumer: Consumer<'q, 'a, Envelope>,
+}
+
+impl<'q, 'a> MessageConsumer<'q, 'a> {
+    /// Removes the oldest committed message from the queue.
+    ///
+    /// The message is read in place and freed when the returned value is dropped. Returns
+    /// None if the queue is empty, and an error for a framed message whose frame does not
+    /// match its bytes, which is freed right away.
+    pub fn receive(&mut self) -> Option<Result<Message<'q, 'a>, FrameError>> {
+        let queue = self.queue;
+        let envelope = self.consumer.pop()?;
+        let block = queue
+            .block
+            .memory
+            .pointer(Offset(envelope.offset))
+            .expect("Messages are inside the memory");
+        if !queue.is_framed() {
+            return Some(Ok(Message {
+                queue,
+                block,
+                data: block,
+                len: envelope.len,
+            }));
+        }
+
+        // SAFETY: The frame is at the start of the block.
+        let frame = unsafe { (block as *const Frame).read() };
+        let size = queue.block.memory.allocation_size(block).unwrap_or(0);
+        if frame.len != envelope.len || FRAME + frame.len > size {
+            queue.block.memory.deallocate(block);
+            return Some(Err(FrameError::Length {
+                expected: envelope.len,
+                found: frame.len,
+            }));
+        }
+        let message = Message {
+            queue,
+            block,
+            // SAFETY: The bytes follow the frame inside the block.
+            data: unsafe { block.add(FRAME) },
+            len: frame.len,
+        };
+        let crc = crc32(&message);
+        if crc != frame.crc {
+            return Some(Err(FrameError::Checksum {
+                expected: frame.crc,
+                found: crc,
+            }));
+        }
+        Some(Ok(message))
+    }
+}
+
+/// A received message of a `ShmMessageQueue`, freed when dropped.
+pub struct Message<'q, 'a> {
+    queue: &'q ShmMessageQueue<'a>,
+    block: *mut u8,
+    data: *mut u8,
+    len: usize,
+}
+
+impl<'q, 'a> Deref for Message<'q, 'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: The message was committed with `len` bytes and only the consumer reaches it.
+        unsafe { std::slice::from_raw_parts(self.data, self.len) }
+    }
+}
+
+impl<'q, 'a> Drop for Message<'q, 'a> {
+    fn drop(&mut self) {
+        self.queue.block.memory.deallocate(self.block);
+    }
+}
+
+/// Why a framed message was rejected on receive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The length in the frame is not the committed one, or does not fit the block.
+    Length { expected: usize, found: usize },
+    /// The bytes changed after the message was committed.
+    Checksum { expected: u32, found: u32 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Length { expected, found } => write!(
+                f,
+                "The frame has a length of {found} bytes, the message was committed with {expected}"
+            ),
+            Self::Checksum { expected, found } => write!(
+                f,
+                "The message has a checksum of {found:#010x}, it was committed with {expected:#010x}"
+            ),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+/// CRC-32 (IEEE), as used by zlib and Ethernet.
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut index = 0;
+        while index < 256 {
+            let mut crc = index as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[index] = crc;
+            index += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_commit() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let queue = ShmMessageQueue::create(&memory).unwrap();
+        let mut consumer = queue.consumer().unwrap();
+        let blocks = memory.usage().blocks;
+
+        let mut first = queue.reserve(5).unwrap();
+        first.copy_from_slice(b"first");
+        let mut dropped = queue.reserve(4).unwrap();
+        dropped.copy_from_slice(b"lost");
+        assert!(consumer.receive().is_none(), "Nothing was committed yet");
+
+        drop(dropped);
+        assert!(first.commit().is_ok(), "The node should fit");
+        assert!(queue.send(b"second"), "The message should fit");
+        assert_eq!(queue.len(), 2, "Only committed messages are queued");
+
+        assert_eq!(
+            &*consumer.receive().unwrap().unwrap(),
+            b"first",
+            "In commit order"
+        );
+        assert_eq!(
+            &*consumer.receive().unwrap().unwrap(),
+            b"second",
+            "In commit order"
+        );
+        assert!(consumer.receive().is_none(), "The queue is empty");
+        assert_eq!(
+            memory.usage().blocks,
+            blocks + 2,
+            "Only the reused nodes are left"
+        );
+    }
+
+    #[test]
+    fn test_framed() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926, "The standard check value");
+
+        let memory = Memory::new_in_process(8192).unwrap();
+        let queue = ShmMessageQueue::create_framed(&memory).unwrap();
+        let mut consumer = queue.consumer().unwrap();
+
+        assert!(queue.send(b"intact"), "The message should fit");
+        let mut guard = queue.reserve(7).unwrap();
+        guard.copy_from_slice(b"corrupt");
+        let data = guard.as_mut_ptr();
+        assert!(guard.commit().is_ok(), "The node should fit");
+        // A peer writing past its own block after the commit.
+        unsafe { *data = b'C' };
+
+        assert_eq!(
+            &*consumer.receive().unwrap().unwrap(),
+            b"intact",
+            "Its frame matches"
+        );
+        assert!(
+            matches!(
+                consumer.receive().unwrap(),
+                Err(FrameError::Checksum { .. })
+            ),
+            "The change is detected"
+        );
+        assert!(consumer.receive().is_none(), "The queue is empty");
+    }
+}
@@ -0,0 +1,398 @@
+use std::{cell::RefCell, error::Error, fmt, marker::PhantomData, time::Duration};
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+        hint,
+    },
+    tagged::TaggedStack,
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct QueueHeader {
+    /// Offset of the most recently pushed node.
+    head: AtomicUsize,
+    /// Offset of the node before the oldest message. Only the consumer touches it.
+    tail: AtomicUsize,
+    /// Consumed nodes, kept for reuse by producers.
+    free: AtomicU64,
+    len: AtomicUsize,
+    consumer: AtomicBool,
+    /// Most messages the queue holds, or zero if unbounded.
+    capacity: AtomicUsize,
+}
+
+#[repr(C)]
+struct Node {
+    next: AtomicUsize,
+}
+
+/// A lock-free multi-producer, single-consumer queue in shared memory.
+///
+/// Messages are stored in linked nodes allocated from the memory. Consumed nodes are kept on
+/// a free list and reused by producers, so neither side takes the heap lock once the queue
+/// has warmed up, and the consumer never does.
+///
+/// A queue is unbounded unless given a capacity with `set_capacity`, after which producers
+/// see `SendError::Full` while the consumer falls behind and can back off or wait for
+/// it with `push_timeout`.
+pub struct ShmMpscQueue<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    watermarks: RefCell<Vec<(usize, WatermarkHook)>>,
+    _value: PhantomData<T>,
+}
+
+type WatermarkHook = Box<dyn Fn(usize)>;
+
+impl<'a, T: ShmSafe> ShmMpscQueue<'a, T> {
+    const VALUE: usize = values_start::<T>(std::mem::size_of::<Node>());
+
+    /// Creates an empty queue.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = std::mem::size_of::<QueueHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        let queue = Self {
+            block,
+            watermarks: RefCell::default(),
+            _value: PhantomData,
+        };
+
+        // The list always starts with a node whose message was already consumed.
+        let Some(stub) = queue.allocate_node() else {
+            memory.deallocate(queue.block.ptr);
+            return None;
+        };
+        let stub = queue.offset_of(stub);
+        queue.header().head.store(stub, SeqCst);
+        queue.header().tail.store(stub, SeqCst);
+        Some(queue)
+    }
+
+    /// Opens a queue created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a queue of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            watermarks: RefCell::default(),
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the queue.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Appends a message to the queue.
+    ///
+    /// Returns false if the queue is full, or if there was no free node and not enough memory
+    /// for a new one.
+    pub fn push(&self, value: T) -> bool {
+        self.try_push(value).is_ok()
+    }
+
+    /// Appends a message to the queue, telling why it could not.
+    pub fn try_push(&self, value: T) -> Result<(), SendError> {
+        let header = self.header();
+        let capacity = header.capacity.load(SeqCst);
+        // The message counts from here on, so concurrent producers cannot overfill the queue.
+        let len = header
+            .len
+            .fetch_update(SeqCst, SeqCst, |len| {
+                (capacity == 0 || len < capacity).then_some(len + 1)
+            })
+            .map_err(|_| SendError::Full)?;
+        let Some(node) = self.free_list().pop().or_else(|| self.allocate_node()) else {
+            header.len.fetch_sub(1, SeqCst);
+            return Err(SendError::OutOfMemory);
+        };
+
+        // SAFETY: The node is not linked anywhere, so nobody else accesses it.
+        unsafe {
+            (node.add(Self::VALUE) as *mut T).write(value);
+            (*(node as *const Node)).next.store(0, SeqCst);
+        }
+
+        let node = self.offset_of(node);
+        let prev = header.head.swap(node, SeqCst);
+        // Until this store the consumer sees the queue end at `prev`.
+        self.node(prev).next.store(node, SeqCst);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_queue_depth(self.offset().0, self.len());
+
+        for (depth, hook) in self.watermarks.borrow().iter() {
+            if len + 1 == *depth {
+                hook(len + 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends a message to the queue, waiting up to `timeout` for the consumer to make room.
+    ///
+    /// Returns `SendError::Full` if the queue was still full when the timeout passed.
+    pub fn push_timeout(&self, value: T, timeout: Duration) -> Result<(), SendError> {
+        let started = std::time::Instant::now();
+        loop {
+            match self.try_push(value) {
+                Err(SendError::Full) if started.elapsed() < timeout => {
+                    hint::spin_loop();
+                    std::thread::yield_now();
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Returns the number of messages in the queue, counting the ones still being pushed.
+    pub fn len(&self) -> usize {
+        self.header().len.load(SeqCst)
+    }
+
+    /// Returns the most messages the queue holds, or None if it is unbounded.
+    pub fn capacity(&self) -> Option<usize> {
+        match self.header().capacity.load(SeqCst) {
+            0 => None,
+            capacity => Some(capacity),
+        }
+    }
+
+    /// Sets the most messages the queue holds for every process, or makes it unbounded.
+    ///
+    /// Messages already queued stay if there are more of them.
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        assert_ne!(capacity, Some(0), "A queue holds at least one message");
+        self.header().capacity.store(capacity.unwrap_or(0), SeqCst);
+    }
+
+    /// Registers a callback run after a push of this process brings the queue to `depth`
+    /// messages.
+    ///
+    /// It runs again only after the queue fell below `depth` and reached it again, so a
+    /// producer can start throttling once, e.g. before the queue gets full.
+    pub fn on_high_watermark(&self, depth: usize, hook: impl Fn(usize) + 'static) {
+        self.watermarks.borrow_mut().push((depth, Box::new(hook)));
+    }
+
+    /// Returns true if the queue is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Claims the consumer side of the queue.
+    ///
+    /// Returns None if another thread or process is the consumer.
+    pub fn consumer(&self) -> Option<Consumer<'_, 'a, T>> {
+        let claimed = self
+            .header()
+            .consumer
+            .compare_exchange(false, true, SeqCst, SeqCst);
+        claimed.ok().map(|_| Consumer { queue: self })
+    }
+
+    /// Frees the queue and all of its nodes.
+    ///
+    /// Other processes must not use the queue afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn allocate_node(&self) -> Option<*mut u8> {
+        let size = Self::VALUE + std::mem::size_of::<T>();
+        Some(
+            self.block
+                .memory
+                .allocate_more(size, self.block.ptr)
+                .ok()?
+                .as_ptr(),
+        )
+    }
+
+    fn free_list(&self) -> TaggedStack<'_> {
+        TaggedStack::new(self.block.memory, &self.header().free)
+    }
+
+    fn header(&self) -> &QueueHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const QueueHeader) }
+    }
+
+    fn node(&self, offset: usize) -> &Node {
+        let node = self
+            .block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Nodes are inside the memory");
+        unsafe { &*(node as *const Node) }
+    }
+
+    fn offset_of(&self, node: *mut u8) -> usize {
+        self.block
+            .memory
+            .offset_of(node)
+            .expect("Nodes are inside the memory")
+            .0
+    }
+}
+
+/// Why a message could not be sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The queue holds as many messages as its capacity.
+    Full,
+    /// There was not enough memory for the message.
+    OutOfMemory,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(f, "The queue is full"),
+            Self::OutOfMemory => write!(f, "Not enough memory for the message"),
+        }
+    }
+}
+
+impl Error for SendError {}
+
+/// Why `Consumer::pop_timeout` returned without a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// No message arrived in time.
+    Timeout,
+    /// The queue is empty and a shutdown was requested, see `Memory::request_shutdown`.
+    Shutdown,
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "No message arrived in time"),
+            Self::Shutdown => write!(f, "The memory is shutting down"),
+        }
+    }
+}
+
+impl Error for RecvError {}
+
+/// The consumer side of a `ShmMpscQueue`. Only one exists at a time.
+pub struct Consumer<'q, 'a, T: ShmSafe> {
+    queue: &'q ShmMpscQueue<'a, T>,
+}
+
+impl<'q, 'a, T: ShmSafe> Consumer<'q, 'a, T> {
+    /// Removes the oldest message from the queue.
+    ///
+    /// Returns None if the queue is empty, or if the producer of the next message has not
+    /// finished linking it yet.
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let header = queue.header();
+        let tail = header.tail.load(SeqCst);
+        let next = queue.node(tail).next.load(SeqCst);
+        if next == 0 {
+            return None;
+        }
+
+        let node = queue.node(next) as *const Node as *mut u8;
+        // SAFETY: The node is linked, so its message was fully written.
+        let value = unsafe { (node.add(ShmMpscQueue::<T>::VALUE) as *const T).read() };
+        header.tail.store(next, SeqCst);
+        header.len.fetch_sub(1, SeqCst);
+        #[cfg(feature = "metrics")]
+        crate::telemetry::record_queue_depth(queue.offset().0, queue.len());
+
+        // The old tail is no longer reachable by producers, so it can be reused.
+        let old = queue.node(tail) as *const Node as *mut u8;
+        queue.free_list().push(old);
+        Some(value)
+    }
+
+    /// Removes the oldest message from the queue, waiting up to `timeout` for one.
+    ///
+    /// Messages keep coming out while a shutdown is requested, so the queue is drained
+    /// before the wait ends with `RecvError::Shutdown`.
+    pub fn pop_timeout(&mut self, timeout: Duration) -> Result<T, RecvError> {
+        let started = std::time::Instant::now();
+        loop {
+            if let Some(value) = self.pop() {
+                return Ok(value);
+            }
+            if self.queue.block.memory.is_shutting_down() {
+                return Err(RecvError::Shutdown);
+            }
+            if started.elapsed() >= timeout {
+                return Err(RecvError::Timeout);
+            }
+            hint::spin_loop();
+            std::thread::yield_now();
+        }
+    }
+}
+
+impl<'q, 'a, T: ShmSafe> Drop for Consumer<'q, 'a, T> {
+    fn drop(&mut self) {
+        self.queue.header().consumer.store(false, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let queue = ShmMpscQueue::<u64>::create(&memory).unwrap();
+
+        let mut consumer = queue.consumer().unwrap();
+        assert!(queue.consumer().is_none(), "There is only one consumer");
+        assert_eq!(consumer.pop(), None, "The queue is empty");
+
+        assert!(queue.push(1), "The node should fit");
+        assert!(queue.push(2), "The node should fit");
+        assert_eq!(queue.len(), 2, "Two messages are queued");
+        assert_eq!(consumer.pop(), Some(1), "Messages come out in order");
+
+        assert!(queue.push(3), "The consumed node should be reused");
+        assert_eq!(consumer.pop(), Some(2), "Messages come out in order");
+        assert_eq!(consumer.pop(), Some(3), "Messages come out in order");
+        assert_eq!(consumer.pop(), None, "The queue is empty");
+    }
+
+    #[test]
+    fn test_capacity_and_watermark() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let queue = ShmMpscQueue::<u64>::create(&memory).unwrap();
+        let crossings = std::rc::Rc::new(std::cell::Cell::new(0));
+        let c = crossings.clone();
+        queue.on_high_watermark(2, move |_| c.set(c.get() + 1));
+        queue.set_capacity(Some(2));
+
+        assert_eq!(queue.try_push(1), Ok(()), "The queue has room");
+        assert_eq!(queue.try_push(2), Ok(()), "The queue has room");
+        assert_eq!(crossings.get(), 1, "The watermark was reached");
+        assert_eq!(queue.try_push(3), Err(SendError::Full), "The queue is full");
+        assert_eq!(
+            queue.push_timeout(3, Duration::from_millis(10)),
+            Err(SendError::Full),
+            "Nobody made room"
+        );
+
+        let mut consumer = queue.consumer().unwrap();
+        assert_eq!(consumer.pop(), Some(1), "Messages come out in order");
+        assert_eq!(queue.try_push(3), Ok(()), "Receiving made room");
+        assert_eq!(crossings.get(), 2, "The watermark was reached again");
+        assert_eq!(queue.len(), 2, "The depth is bounded");
+    }
+}
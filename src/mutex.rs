@@ -1,7 +1,21 @@
-use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use crate::{
+    platform,
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        hint,
+    },
+};
+
+/// Spins of `MemoryMutex::lock_recovering` between checks whether the holder is alive.
+const LIVENESS_SPINS: u64 = 1 << 12;
+
+/// Holder that `MemoryGuard::abandon` leaves in the lock word. No process has this id.
+#[cfg(any(test, feature = "fault-injection"))]
+const DEAD_OWNER: u32 = u32::MAX;
 
 pub struct MemoryGuard<'a> {
-    locker: &'a AtomicBool,
+    /// The lock word to release on drop, None for a view that never took the lock.
+    locker: Option<&'a AtomicU32>,
     buffer: *mut u8,
     size: usize,
 }
@@ -14,11 +28,24 @@ impl<'a> MemoryGuard<'a> {
     pub fn size(&self) -> usize {
         self.size
     }
+
+    /// Leaves the lock held as if this process died holding it: the guard is leaked and the
+    /// lock handed to a holder that no process is, so `MemoryMutex::lock_recovering` breaks
+    /// it.
+    #[cfg(any(test, feature = "fault-injection"))]
+    pub fn abandon(self) {
+        if let Some(locker) = self.locker {
+            locker.store(DEAD_OWNER, SeqCst);
+        }
+        std::mem::forget(self);
+    }
 }
 
 impl<'a> Drop for MemoryGuard<'a> {
     fn drop(&mut self) {
-        self.locker.store(false, SeqCst);
+        if let Some(locker) = self.locker {
+            locker.store(0, SeqCst);
+        }
     }
 }
 
@@ -29,7 +56,9 @@ pub struct MemoryMutex {
 
 impl MemoryMutex {
     /// The size in bytes that this Mutex uses in the buffer.
-    pub const SIZE: usize = std::mem::size_of::<AtomicBool>();
+    ///
+    /// It is padded to a full word so that the memory after the lock stays aligned.
+    pub const SIZE: usize = std::mem::size_of::<usize>();
 
     /// Creates a new nutex from the buffer and spin locks until it can acquire it.
     ///
@@ -42,17 +71,52 @@ impl MemoryMutex {
         Self { buffer, size }
     }
 
+    /// Returns a guard over the buffer without taking the lock, for reading memory that
+    /// cannot be written to. What it reads may be changed by lock holders at any time.
+    pub fn view<'a>(&self) -> MemoryGuard<'a> {
+        MemoryGuard {
+            locker: None,
+            size: self.size - Self::SIZE,
+            // SAFETY: Safe as long as the safety rules in the cosntructor are followed.
+            buffer: unsafe { self.buffer.add(Self::SIZE) },
+        }
+    }
+
+    /// Returns true if some thread or process holds the lock.
+    pub fn is_locked(&self) -> bool {
+        self.locker().load(SeqCst) != 0
+    }
+
     /// Locks the mutex and returns a memory guard.
     ///
-    /// The mutex uses spin lock to wait for memory acquire.
+    /// The mutex uses spin lock to wait for memory acquire. With the `tracing` feature, waiting
+    /// for the lock emits an event with the number of spins and the time waited.
     pub fn lock<'a>(&self) -> MemoryGuard<'a> {
+        let locker = self.locker();
+        acquire(locker, false);
+        self.guard(locker)
+    }
+
+    /// Locks the mutex like `lock`, but takes the lock over if the process holding it died.
+    ///
+    /// Returns the guard and whether the lock was taken over, in which case the memory may
+    /// be halfway through an update of the dead process. A holder whose id was reused by a
+    /// new process counts as alive, so the wait goes on.
+    pub fn lock_recovering<'a>(&self) -> (MemoryGuard<'a>, bool) {
+        let locker = self.locker();
+        let broken = acquire(locker, true);
+        (self.guard(locker), broken)
+    }
+
+    /// Returns the lock word, which holds the id of the process holding the lock, or zero.
+    fn locker<'a>(&self) -> &'a AtomicU32 {
         // SAFETY: Safe as long as the safety rules in the cosntructor are followed.
-        let locker = unsafe { &*(self.buffer as *mut AtomicBool) };
-        while let Err(_) = locker.compare_exchange(false, true, SeqCst, SeqCst) {
-            core::hint::spin_loop();
-        }
+        unsafe { &*(self.buffer as *const AtomicU32) }
+    }
+
+    fn guard<'a>(&self, locker: &'a AtomicU32) -> MemoryGuard<'a> {
         MemoryGuard {
-            locker,
+            locker: Some(locker),
             // Exclude the locker size from the total buffer size.
             size: self.size - Self::SIZE,
             // SAFETY: Safe as long as the safety rules in the cosntructor are followed.
@@ -60,3 +124,99 @@ impl MemoryMutex {
         }
     }
 }
+
+/// Spins until the lock is taken. With `recover`, the lock is taken over from a holder that
+/// is no longer alive, which is checked every `LIVENESS_SPINS` spins.
+///
+/// Returns true if the lock was taken over. Waiting for the lock is reported through the
+/// `tracing` and `metrics` features.
+fn acquire(locker: &AtomicU32, recover: bool) -> bool {
+    let owner = std::process::id();
+    if locker.compare_exchange(0, owner, SeqCst, SeqCst).is_ok() {
+        return false;
+    }
+
+    #[cfg(feature = "tracing")]
+    let started = std::time::Instant::now();
+    let mut spins: u64 = 1;
+    let broken = loop {
+        match locker.compare_exchange(0, owner, SeqCst, SeqCst) {
+            Ok(_) => break false,
+            Err(holder)
+                if recover
+                    && spins % LIVENESS_SPINS == 1
+                    && holder != owner
+                    && !platform::process_alive(holder) =>
+            {
+                if locker
+                    .compare_exchange(holder, owner, SeqCst, SeqCst)
+                    .is_ok()
+                {
+                    break true;
+                }
+            }
+            Err(_) => {}
+        }
+        spins += 1;
+        hint::spin_loop();
+    };
+
+    #[cfg(feature = "tracing")]
+    if broken {
+        tracing::warn!(spins, "lock taken over from a process that died holding it");
+    } else {
+        tracing::debug!(
+            spins,
+            waited_us = started.elapsed().as_micros() as u64,
+            "lock contended"
+        );
+    }
+    #[cfg(feature = "metrics")]
+    crate::telemetry::record_contention(spins);
+    #[cfg(not(any(feature = "tracing", feature = "metrics")))]
+    let _ = spins;
+    broken
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use std::ptr;
+
+    use loom::{cell::UnsafeCell, sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_loom_lock_excludes() {
+        loom::model(|| {
+            let locker = Arc::new(AtomicU32::new(0));
+            let counter = Arc::new(UnsafeCell::new(0));
+            let increment = |locker: &AtomicU32, counter: &UnsafeCell<u32>| {
+                acquire(locker, false);
+                let guard = MemoryGuard {
+                    locker: Some(locker),
+                    buffer: ptr::null_mut(),
+                    size: 0,
+                };
+                // SAFETY: The lock is held.
+                counter.with_mut(|value| unsafe { *value += 1 });
+                drop(guard);
+            };
+
+            let other = {
+                let (locker, counter) = (locker.clone(), counter.clone());
+                thread::spawn(move || increment(&locker, &counter))
+            };
+            increment(&locker, &counter);
+            other.join().unwrap();
+
+            // SAFETY: Both threads are done.
+            assert_eq!(
+                counter.with(|value| unsafe { *value }),
+                2,
+                "The increments do not overlap"
+            );
+            assert_eq!(locker.load(SeqCst), 0, "The lock is released");
+        });
+    }
+}
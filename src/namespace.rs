@@ -0,0 +1,186 @@
+use std::error::Error;
+
+use crate::{Memory, MemoryBuilder, ShmKvStore};
+
+/// Options applied to the builder of every segment of a `ShmNamespace`.
+pub type SegmentOptions = fn(MemoryBuilder<'_>) -> MemoryBuilder<'_>;
+
+/// A family of related segments named after one prefix: a control segment `name.control`
+/// and data segments `name.data.N`.
+///
+/// Every segment is built with the same options, and the control segment keeps a directory
+/// of the data segments and their sizes, so processes open them by number without agreeing
+/// on sizes or names by hand.
+pub struct ShmNamespace {
+    name: String,
+    options: SegmentOptions,
+    control: Memory,
+}
+
+impl ShmNamespace {
+    /// Creates or opens the control segment of the namespace, of `control_size` bytes.
+    pub fn create(name: &str, control_size: usize) -> Result<Self, Box<dyn Error>> {
+        Self::with_options(name, control_size, |builder| builder)
+    }
+
+    /// Like `create`, applying `options` to the builder of every segment, e.g. to map them
+    /// read-only or give them a security descriptor.
+    pub fn with_options(
+        name: &str,
+        control_size: usize,
+        options: SegmentOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let control_name = format!("{name}.control");
+        let control = options(Memory::builder(&control_name, control_size)).build()?;
+        Ok(Self {
+            name: name.to_owned(),
+            options,
+            control,
+        })
+    }
+
+    /// Returns the prefix of the names of the segments.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the control segment, for the application's own shared structures.
+    pub fn control(&self) -> &Memory {
+        &self.control
+    }
+
+    /// Returns the name of data segment `index`.
+    pub fn segment_name(&self, index: u32) -> String {
+        format!("{}.data.{index}", self.name)
+    }
+
+    /// Creates data segment `index` of `size` bytes and records it in the directory, or opens
+    /// it if it exists.
+    ///
+    /// Fails if the directory records another size for it.
+    pub fn create_segment(&self, index: u32, size: usize) -> Result<Memory, Box<dyn Error>> {
+        let directory = self.directory()?;
+        let key = index.to_string();
+        let recorded = directory
+            .get_or_insert(&key, &(size as u64).to_le_bytes())
+            .ok_or("The control segment is out of memory")?;
+        let recorded = decode(&recorded);
+        if recorded != size {
+            return Err(format!(
+                "{} was created with {recorded} bytes, not {size}",
+                self.segment_name(index)
+            )
+            .into());
+        }
+        let name = self.segment_name(index);
+        (self.options)(Memory::builder(&name, size)).build()
+    }
+
+    /// Opens data segment `index` with the size recorded in the directory.
+    ///
+    /// Fails if no process created it.
+    pub fn open_segment(&self, index: u32) -> Result<Memory, Box<dyn Error>> {
+        let size = self
+            .directory()?
+            .get(&index.to_string())
+            .map(|value| decode(&value))
+            .ok_or_else(|| format!("{} was never created", self.segment_name(index)))?;
+        let name = self.segment_name(index);
+        (self.options)(Memory::builder(&name, size))
+            .create(false)
+            .build()
+    }
+
+    /// Removes data segment `index` from the directory. The segment itself lives on until
+    /// every process dropped it.
+    ///
+    /// Returns false if it was not recorded.
+    pub fn remove_segment(&self, index: u32) -> bool {
+        self.directory()
+            .map(|directory| directory.remove(&index.to_string()))
+            .unwrap_or(false)
+    }
+
+    /// Returns the numbers and sizes of the recorded data segments, in number order.
+    pub fn segments(&self) -> Vec<(u32, usize)> {
+        let Ok(directory) = self.directory() else {
+            return Vec::new();
+        };
+        let mut segments: Vec<_> = directory
+            .keys()
+            .into_iter()
+            .filter_map(|key| {
+                let size = decode(&directory.get(&key)?);
+                Some((key.parse().ok()?, size))
+            })
+            .collect();
+        segments.sort_unstable();
+        segments
+    }
+
+    fn directory(&self) -> Result<ShmKvStore<'_>, Box<dyn Error>> {
+        let control = &self.control;
+        let block = control
+            .named("namespace", "segments", || {
+                ShmKvStore::create(control, 16).map(|store| {
+                    control
+                        .pointer(store.offset())
+                        .expect("The store is inside the memory")
+                })
+            })
+            .ok_or("The control segment is out of memory")?;
+        let offset = control
+            .offset_of(block)
+            .expect("The directory is inside the memory");
+        // SAFETY: Only the segment directory is registered under this name.
+        unsafe { ShmKvStore::open(control, offset) }.ok_or_else(|| "The directory is gone".into())
+    }
+}
+
+fn decode(value: &[u8]) -> usize {
+    let bytes = value.try_into().expect("Segment sizes are 8 bytes");
+    u64::from_le_bytes(bytes) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_segments_share_options_and_sizes() {
+        let server = ShmNamespace::create("rshmem_test_namespace", 4096).unwrap();
+        let first = server.create_segment(0, 8192).unwrap();
+        let _second = server.create_segment(3, 4096).unwrap();
+        assert!(
+            server.create_segment(0, 4096).is_err(),
+            "The size of a segment is fixed"
+        );
+        assert_eq!(
+            server.segments(),
+            vec![(0, 8192), (3, 4096)],
+            "Both segments are recorded"
+        );
+
+        let client = ShmNamespace::with_options("rshmem_test_namespace", 4096, |builder| {
+            builder.create(false)
+        })
+        .unwrap();
+        let opened = client.open_segment(0).unwrap();
+        assert_eq!(opened.size(), first.size(), "The recorded size is used");
+        assert!(
+            client.open_segment(1).is_err(),
+            "Segments that were not created are not opened"
+        );
+
+        assert!(server.remove_segment(3), "The segment was recorded");
+        assert_eq!(
+            client.segments(),
+            vec![(0, 8192)],
+            "The directory is shared"
+        );
+    }
+}
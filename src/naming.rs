@@ -0,0 +1,62 @@
+/// Where a named mapping can be opened, spelled like the prefixes of .NET's
+/// `MemoryMappedFile` names, so both sides agree on the full name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Namespace {
+    /// Only in the session of the creator. Unprefixed names, of this crate and of
+    /// `MemoryMappedFile`, go here as well.
+    Session,
+    /// In every session, e.g. for a service and a desktop application. Creating a mapping in
+    /// it requires `SeCreateGlobalPrivilege`, which services and administrators have;
+    /// opening one does not.
+    Global,
+}
+
+impl Namespace {
+    /// Returns the prefix of names in the namespace.
+    pub fn prefix(self) -> &'static str {
+        match self {
+            Namespace::Session => "Local\\",
+            Namespace::Global => "Global\\",
+        }
+    }
+
+    /// Returns the full name of the mapping `name` in the namespace, to pass to
+    /// `Memory::builder` here and to `MemoryMappedFile.OpenExisting` in .NET.
+    ///
+    /// A name that already has a prefix is moved to this namespace.
+    pub fn name(self, name: &str) -> String {
+        let name = [Namespace::Session, Namespace::Global]
+            .iter()
+            .find_map(|namespace| name.strip_prefix(namespace.prefix()))
+            .unwrap_or(name);
+        format!("{}{name}", self.prefix())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Memory;
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_names() {
+        assert_eq!(
+            Namespace::Global.name("feed"),
+            "Global\\feed",
+            "The prefix is added"
+        );
+        assert_eq!(
+            Namespace::Session.name("Global\\feed"),
+            "Local\\feed",
+            "The prefix is replaced"
+        );
+
+        let name = Namespace::Session.name("rshmem_test_naming");
+        let memory = Memory::new(&name, 4096, 0).unwrap();
+        assert!(memory.is_creator(), "Prefixed names can be created");
+    }
+}
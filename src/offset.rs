@@ -0,0 +1,18 @@
+/// Position of an allocation relative to the start of the memory.
+///
+/// Unlike a pointer, an offset stays valid in processes that map the memory at a different
+/// address, so it is what should be stored in shared data or sent to other processes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Offset(pub(crate) usize);
+
+impl Offset {
+    /// Creates an offset from a raw value, e.g. one received from another process.
+    pub fn new(value: usize) -> Self {
+        Self(value)
+    }
+
+    /// Returns the raw value of the offset.
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
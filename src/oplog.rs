@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+
+use crate::{clock, Memory, Offset, BLOCK_HEADER_SIZE};
+
+#[repr(C)]
+struct LogHeader {
+    /// Number of records the log holds before it wraps around.
+    capacity: usize,
+    /// Number of records ever appended.
+    appended: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawRecord {
+    op: u32,
+    pid: u32,
+    size: usize,
+    offset: usize,
+    parent: usize,
+    timestamp: u64,
+}
+
+/// Returns the bytes a log of `records` records takes at the end of the memory.
+pub(crate) const fn bytes(records: usize) -> usize {
+    if records == 0 {
+        return 0;
+    }
+    std::mem::size_of::<LogHeader>() + records * std::mem::size_of::<RawRecord>()
+}
+
+/// What a `LogRecord` did to the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogOp {
+    Allocate,
+    Deallocate,
+    /// Every block was freed at once by `Memory::reset`.
+    Reset,
+    /// A process created or opened a writable handle to the memory.
+    Attach,
+    /// A process dropped a writable handle to the memory.
+    Detach,
+    /// A block was split by `Memory::split`. The record is the second block, cut from the
+    /// end of the first.
+    Split,
+    /// A block was trimmed by `Memory::shrink`. The record has the new size of the block.
+    Shrink,
+}
+
+/// One operation of the heap, as recorded in the operation log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogRecord {
+    pub op: LogOp,
+    /// Size of the block. Zero for a reset, attach or detach.
+    pub size: usize,
+    /// Offset of the data of the block. Zero for a reset, attach or detach.
+    pub offset: Offset,
+    /// Offset of the parent of an allocated block.
+    pub parent: Option<Offset>,
+    /// Id of the process that did the operation.
+    pub pid: u32,
+    /// Unix time in milliseconds.
+    pub timestamp: u64,
+}
+
+/// The operation log at the end of a heap, which the allocator appends to under its lock.
+pub(crate) struct OpLog {
+    header: *mut LogHeader,
+}
+
+impl OpLog {
+    /// # Safety
+    /// `start` must be word-aligned and followed by `bytes(capacity)` bytes of the memory.
+    pub(crate) unsafe fn new(start: *mut u8) -> Self {
+        Self {
+            header: start as *mut LogHeader,
+        }
+    }
+
+    /// Empties the log and sets its capacity.
+    pub(crate) fn init(&self, capacity: usize) {
+        let header = self.header();
+        header.capacity = capacity;
+        header.appended = 0;
+    }
+
+    pub(crate) fn append(&self, op: LogOp, size: usize, offset: Offset, parent: Option<Offset>) {
+        let header = self.header();
+        let record = RawRecord {
+            op: op as u32,
+            pid: std::process::id(),
+            size,
+            offset: offset.0,
+            parent: parent.map_or(0, |parent| parent.0),
+            timestamp: clock::unix_millis(),
+        };
+        let index = header.appended % header.capacity;
+        // SAFETY: The index is below the capacity the log was laid out for.
+        unsafe { self.records().add(index).write(record) };
+        header.appended += 1;
+    }
+
+    /// Returns the records still in the log, oldest first.
+    pub(crate) fn read(&self) -> Vec<LogRecord> {
+        let header = self.header();
+        let kept = header.appended.min(header.capacity);
+        (header.appended - kept..header.appended)
+            .map(|index| {
+                // SAFETY: The record was written when it was appended.
+                let raw = unsafe { self.records().add(index % header.capacity).read() };
+                LogRecord {
+                    op: match raw.op {
+                        0 => LogOp::Allocate,
+                        1 => LogOp::Deallocate,
+                        2 => LogOp::Reset,
+                        3 => LogOp::Attach,
+                        4 => LogOp::Detach,
+                        5 => LogOp::Split,
+                        _ => LogOp::Shrink,
+                    },
+                    size: raw.size,
+                    offset: Offset(raw.offset),
+                    parent: (raw.parent != 0).then_some(Offset(raw.parent)),
+                    pid: raw.pid,
+                    timestamp: raw.timestamp,
+                }
+            })
+            .collect()
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn header(&self) -> &mut LogHeader {
+        // SAFETY: The log starts with its header.
+        unsafe { &mut *self.header }
+    }
+
+    fn records(&self) -> *mut RawRecord {
+        // SAFETY: The records directly follow the header.
+        unsafe { self.header.add(1) as *mut RawRecord }
+    }
+}
+
+impl Memory {
+    /// Returns the records of the operation log, oldest first, or an empty list if the memory
+    /// was created without one.
+    ///
+    /// Once the log is full, new records replace the oldest ones. An auditor process can read
+    /// it through a handle opened with `MemoryBuilder::read_only`, which is not recorded
+    /// itself because it cannot write to the memory.
+    pub fn op_log(&self) -> Vec<LogRecord> {
+        self.inspector().op_log()
+    }
+}
+
+/// Rebuilds the allocated blocks from a sequence of log records, for postmortem analysis.
+///
+/// Returns the allocation record of every block still allocated at the end, in heap order.
+/// Freeing a block frees the blocks linked to it, like `Memory::deallocate`. If the log
+/// wrapped around, blocks allocated before its oldest record are missing, and frees of them
+/// are ignored.
+pub fn replay_log(records: &[LogRecord]) -> Vec<LogRecord> {
+    let mut blocks = BTreeMap::new();
+    for record in records {
+        match record.op {
+            LogOp::Allocate => {
+                blocks.insert(record.offset, record.clone());
+            }
+            LogOp::Deallocate => {
+                blocks.remove(&record.offset);
+            }
+            LogOp::Split => {
+                let first = blocks.range_mut(..record.offset).next_back();
+                if let Some((_, first)) = first {
+                    if first.offset.0 + first.size > record.offset.0 {
+                        first.size = record.offset.0 - BLOCK_HEADER_SIZE - first.offset.0;
+                    }
+                }
+                blocks.insert(record.offset, record.clone());
+            }
+            LogOp::Shrink => {
+                if let Some(block) = blocks.get_mut(&record.offset) {
+                    block.size = record.size;
+                }
+            }
+            LogOp::Reset => blocks.clear(),
+            LogOp::Attach | LogOp::Detach => {}
+        }
+    }
+    blocks.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .op_log(4)
+            .build()
+            .unwrap();
+        let parent = memory.allocate(16).unwrap().as_ptr();
+        memory.allocate_more(8, parent).unwrap();
+        let kept = memory.allocate(24).unwrap().as_ptr();
+        memory.deallocate(parent);
+
+        let log = memory.op_log();
+        assert_eq!(log.len(), 4, "The oldest record was replaced");
+        assert_eq!(log[2].op, LogOp::Deallocate, "Both blocks were freed");
+
+        let kept = memory.offset_of(kept).unwrap();
+        let blocks = replay_log(&log);
+        assert_eq!(blocks.len(), 1, "Only one block is left");
+        assert_eq!(blocks[0].offset, kept, "The last block is left");
+        assert_eq!(blocks[0].size, 24, "The size is recorded");
+    }
+
+    #[test]
+    fn test_replay_shrink() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .op_log(4)
+            .build()
+            .unwrap();
+        let block = memory.allocate(256).unwrap().as_ptr();
+        assert!(memory.shrink(block, 16), "The block is trimmed");
+
+        let log = memory.op_log();
+        assert_eq!(log[2].op, LogOp::Shrink, "The shrink is recorded");
+        let blocks = replay_log(&log);
+        assert_eq!(blocks.len(), 1, "The block is still allocated");
+        assert_eq!(
+            Some(blocks[0].size),
+            memory.allocation_size(block),
+            "The log matches the heap"
+        );
+    }
+
+    #[test]
+    fn test_replay_split() {
+        let memory = Memory::builder("", 4096)
+            .in_process(true)
+            .op_log(4)
+            .build()
+            .unwrap();
+        let block = memory.allocate(256).unwrap().as_ptr();
+        let second = memory.split(block, 64).unwrap().as_ptr();
+
+        let log = memory.op_log();
+        assert_eq!(log[2].op, LogOp::Split, "The split is recorded");
+        let blocks = replay_log(&log);
+        assert_eq!(blocks.len(), 2, "The split made two blocks");
+        assert_eq!(blocks[0].size, 64, "The first block was cut");
+        assert_eq!(
+            blocks[1].offset,
+            memory.offset_of(second).unwrap(),
+            "The second block follows"
+        );
+        assert_eq!(
+            blocks[1].size,
+            256 - 64 - BLOCK_HEADER_SIZE,
+            "The second block has the rest"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_attach_and_detach() {
+        let memory = Memory::builder("rshmem_test_oplog_audit", 4096)
+            .op_log(8)
+            .build()
+            .unwrap();
+        drop(Memory::new("rshmem_test_oplog_audit", 4096, 0).unwrap());
+        let auditor = Memory::builder("rshmem_test_oplog_audit", 4096)
+            .read_only(true)
+            .build()
+            .unwrap();
+
+        let ops: Vec<_> = auditor.op_log().iter().map(|record| record.op).collect();
+        assert_eq!(
+            ops,
+            vec![LogOp::Attach, LogOp::Attach, LogOp::Detach],
+            "The creator and the peer are recorded, the auditor is not"
+        );
+        assert!(
+            memory
+                .op_log()
+                .iter()
+                .all(|record| record.pid == std::process::id()),
+            "Records name the process"
+        );
+    }
+}
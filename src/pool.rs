@@ -0,0 +1,215 @@
+use std::marker::PhantomData;
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst},
+    Memory, Offset, ShmSafe,
+};
+
+/// Marks the end of the free list.
+const NIL: u32 = u32::MAX;
+
+#[repr(C)]
+struct PoolHeader {
+    /// Index of the first free slot in the low half, ABA tag in the high half.
+    head: AtomicU64,
+    capacity: u32,
+    available: AtomicU32,
+}
+
+/// A fixed number of `T` slots, pre-allocated in one block and recycled through a lock-free
+/// free list.
+///
+/// Slots are identified by their index, which is what producers hand to consumers. Taking
+/// and returning a slot is a single compare-and-swap on the list head; the head carries a
+/// counter that changes on every update, so a slot that is taken and returned in between
+/// cannot be mistaken for an unchanged list.
+pub struct ShmPool<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmPool<'a, T> {
+    /// Creates a pool with `capacity` free slots.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: u32) -> Option<Self> {
+        assert!(
+            capacity < NIL,
+            "The capacity must leave room for the list end"
+        );
+        let size = Self::slots_start(capacity) + capacity as usize * std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+
+        let pool = Self {
+            block,
+            _value: PhantomData,
+        };
+        for index in 0..capacity {
+            let next = if index + 1 < capacity { index + 1 } else { NIL };
+            pool.next(index).store(next, SeqCst);
+        }
+        // SAFETY: Nobody else can see the pool yet.
+        unsafe { (*(pool.block.ptr as *mut PoolHeader)).capacity = capacity };
+        let header = pool.header();
+        header.available.store(capacity, SeqCst);
+        header
+            .head
+            .store(if capacity > 0 { 0 } else { NIL as u64 }, SeqCst);
+        Some(pool)
+    }
+
+    /// Opens a pool created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a pool of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the pool.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the total number of slots.
+    pub fn capacity(&self) -> u32 {
+        self.header().capacity
+    }
+
+    /// Returns the number of free slots.
+    pub fn available(&self) -> u32 {
+        self.header().available.load(SeqCst)
+    }
+
+    /// Takes a free slot out of the pool and returns its index.
+    ///
+    /// Returns None if all slots are in use.
+    pub fn acquire(&self) -> Option<u32> {
+        let header = self.header();
+        let mut head = header.head.load(SeqCst);
+        loop {
+            let index = head as u32;
+            if index == NIL {
+                return None;
+            }
+            // The slot may be taken by someone else meanwhile, then the tag check fails.
+            let next = self.next(index).load(SeqCst);
+            let new = tagged(head, next);
+            match header.head.compare_exchange(head, new, SeqCst, SeqCst) {
+                Ok(_) => {
+                    header.available.fetch_sub(1, SeqCst);
+                    return Some(index);
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Returns a slot to the pool.
+    ///
+    /// # Safety
+    /// The slot must have been acquired and not released since, by any process.
+    pub unsafe fn release(&self, index: u32) {
+        assert!(index < self.capacity(), "The slot index is out of bounds");
+        let header = self.header();
+        let mut head = header.head.load(SeqCst);
+        loop {
+            self.next(index).store(head as u32, SeqCst);
+            let new = tagged(head, index);
+            match header.head.compare_exchange(head, new, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        header.available.fetch_add(1, SeqCst);
+    }
+
+    /// Takes a free slot and writes the value into it.
+    ///
+    /// Returns the index of the slot, or None if all slots are in use.
+    pub fn insert(&self, value: T) -> Option<u32> {
+        let index = self.acquire()?;
+        // SAFETY: The slot was just acquired, so nobody else uses it.
+        unsafe { self.slot(index).write(value) };
+        Some(index)
+    }
+
+    /// Reads the value of a slot and returns the slot to the pool.
+    ///
+    /// # Safety
+    /// The slot must have been acquired, written and not released since, by any process.
+    pub unsafe fn take(&self, index: u32) -> T {
+        let value = self.slot(index).read();
+        self.release(index);
+        value
+    }
+
+    /// Returns a pointer to the value of a slot.
+    ///
+    /// The pointer is only meaningful while the slot is acquired.
+    pub fn slot(&self, index: u32) -> *mut T {
+        assert!(index < self.capacity(), "The slot index is out of bounds");
+        let start = Self::slots_start(self.capacity());
+        // SAFETY: The index is within the slots of the block.
+        unsafe { (self.block.ptr.add(start) as *mut T).add(index as usize) }
+    }
+
+    /// Frees the pool.
+    ///
+    /// Other processes must not use the pool afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn header(&self) -> &PoolHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const PoolHeader) }
+    }
+
+    fn next(&self, index: u32) -> &AtomicU32 {
+        // SAFETY: The links directly follow the header, one for each slot.
+        unsafe {
+            let links = self.block.ptr.add(std::mem::size_of::<PoolHeader>()) as *const AtomicU32;
+            &*links.add(index as usize)
+        }
+    }
+
+    fn slots_start(capacity: u32) -> usize {
+        let links = std::mem::size_of::<PoolHeader>() + capacity as usize * 4;
+        values_start::<T>(links)
+    }
+}
+
+/// Returns a new list head pointing to `index`, with the tag of `head` incremented.
+fn tagged(head: u64, index: u32) -> u64 {
+    let tag = (head >> 32).wrapping_add(1);
+    (tag << 32) | index as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acquire_and_release() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let pool = ShmPool::<u64>::create(&memory, 2).unwrap();
+
+        let first = pool.insert(1).unwrap();
+        let second = pool.insert(2).unwrap();
+        assert_eq!(pool.acquire(), None, "All slots are in use");
+
+        assert_eq!(unsafe { pool.take(first) }, 1, "The slot keeps its value");
+        assert_eq!(pool.available(), 1, "The slot was returned");
+        assert_eq!(pool.insert(3), Some(first), "Returned slots are reused");
+        assert_eq!(unsafe { pool.take(second) }, 2, "The slot keeps its value");
+    }
+}
@@ -0,0 +1,89 @@
+use crate::{Memory, Usage};
+
+/// How full the heap is, see `Memory::pressure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Pressure {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+/// Used fractions of the capacity, from 0 to 1, at which `Memory::pressure` reaches each
+/// level.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PressureThresholds {
+    pub medium: f64,
+    pub high: f64,
+    pub critical: f64,
+}
+
+impl Default for PressureThresholds {
+    fn default() -> Self {
+        Self {
+            medium: 0.5,
+            high: 0.75,
+            critical: 0.9,
+        }
+    }
+}
+
+impl PressureThresholds {
+    /// Returns the level of a heap whose usage is `usage`.
+    pub fn level(&self, usage: &Usage) -> Pressure {
+        let fraction = usage.fraction();
+        if fraction >= self.critical {
+            Pressure::Critical
+        } else if fraction >= self.high {
+            Pressure::High
+        } else if fraction >= self.medium {
+            Pressure::Medium
+        } else {
+            Pressure::Low
+        }
+    }
+}
+
+impl Memory {
+    /// Returns how full the heap is, by the thresholds of this process, so applications can
+    /// shed load before allocations fail.
+    pub fn pressure(&self) -> Pressure {
+        self.pressure.get().level(&self.usage())
+    }
+
+    /// Returns the thresholds `pressure` uses in this process.
+    pub fn pressure_thresholds(&self) -> PressureThresholds {
+        self.pressure.get()
+    }
+
+    /// Changes the thresholds `pressure` uses in this process. Other processes keep theirs.
+    pub fn set_pressure_thresholds(&self, thresholds: PressureThresholds) {
+        self.pressure.set(thresholds);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        assert_eq!(memory.pressure(), Pressure::Low, "The heap is empty");
+
+        let capacity = memory.usage().capacity;
+        memory.allocate(capacity / 2).unwrap();
+        assert_eq!(memory.pressure(), Pressure::Medium, "Half is used");
+
+        memory.set_pressure_thresholds(PressureThresholds {
+            medium: 0.2,
+            high: 0.4,
+            critical: 0.5,
+        });
+        assert_eq!(
+            memory.pressure(),
+            Pressure::Critical,
+            "The thresholds are configurable"
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::ptr::NonNull;
+
+use crate::{AllocError, Memory};
+
+/// Priority class of an allocation, see `Memory::allocate_with_priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Bulk data. Allocations leave the high-priority reserve free. The other allocation
+    /// methods use this class.
+    #[default]
+    Normal,
+    /// Critical control messages, which may take the high-priority reserve.
+    High,
+}
+
+impl Memory {
+    /// Allocates a new block of memory with the given size and priority class.
+    ///
+    /// Allocations of `Priority::Normal` fail once the free bytes would drop below the
+    /// high-priority reserve, see `MemoryBuilder::high_priority_reserve`, while those of
+    /// `Priority::High` may use it up.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_with_priority(
+        &self,
+        size: usize,
+        priority: Priority,
+    ) -> Result<NonNull<u8>, AllocError> {
+        self.observe_with(size, |allocator| {
+            allocator
+                .allocate_with_priority(size, priority)
+                .ok_or_else(|| allocator.alloc_error_with_priority(size, priority))
+        })
+    }
+
+    /// Returns the bytes kept free for allocations of `Priority::High`.
+    pub fn high_priority_reserve(&self) -> usize {
+        self.inspector().high_priority_reserve()
+    }
+
+    /// Changes the bytes kept free for allocations of `Priority::High`, for every process.
+    ///
+    /// Blocks already allocated stay, even if the heap has less free space than `bytes`.
+    pub fn set_high_priority_reserve(&self, bytes: usize) {
+        self.allocator().set_high_priority_reserve(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_is_kept_for_high_priority() {
+        let memory = Memory::builder("", 8192)
+            .in_process(true)
+            .high_priority_reserve(1024)
+            .build()
+            .unwrap();
+        assert_eq!(memory.high_priority_reserve(), 1024, "The reserve is set");
+
+        let error = memory.allocate(8192).unwrap_err();
+        let bulk = error.largest_hole();
+        memory
+            .allocate(bulk)
+            .expect("Bulk data fits next to the reserve");
+        assert!(
+            memory.allocate_with_priority(64, Priority::Normal).is_err(),
+            "Bulk data cannot take the reserve"
+        );
+        assert!(
+            memory.allocate_with_priority(512, Priority::High).is_ok(),
+            "Control messages take the reserve"
+        );
+
+        memory.set_high_priority_reserve(0);
+        assert!(
+            memory.allocate(64).is_ok(),
+            "Without a reserve, bulk data takes the rest"
+        );
+    }
+}
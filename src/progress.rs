@@ -0,0 +1,208 @@
+use crate::{
+    shared::SharedBlock,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset,
+};
+
+/// Words of the stage slot. Longer stage names are cut at a character boundary.
+const STAGE_WORDS: usize = 8;
+
+const STAGE_LEN: usize = STAGE_WORDS * 8;
+
+#[repr(C)]
+struct Progress {
+    total: AtomicU64,
+    completed: AtomicU64,
+    /// Odd while the stage is being written.
+    seq: AtomicU64,
+    stage_len: AtomicU64,
+    stage: [AtomicU64; STAGE_WORDS],
+}
+
+/// A reading of a `ShmProgress`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    pub total: u64,
+    pub completed: u64,
+    pub stage: String,
+}
+
+impl ProgressSnapshot {
+    /// Returns the completed part of the work from 0 to 1, or None if the total is unknown.
+    pub fn fraction(&self) -> Option<f64> {
+        (self.total != 0).then(|| (self.completed.min(self.total)) as f64 / self.total as f64)
+    }
+}
+
+/// The progress of a job, updated by the worker process doing it and shown by another, e.g.
+/// a GUI.
+///
+/// The total and the completed count are atomic counters. The name of the current stage
+/// lives in a fixed slot of `STAGE_LEN` bytes behind a sequence lock, so readers never see
+/// half of a name.
+pub struct ShmProgress<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmProgress<'a> {
+    /// Bytes a stage name keeps at most.
+    pub const STAGE_LEN: usize = STAGE_LEN;
+
+    /// Creates a progress with nothing done of an unknown total, and no stage.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = std::mem::size_of::<Progress>();
+        let block = SharedBlock::create(memory, size)?;
+        Some(Self { block })
+    }
+
+    /// Opens a progress created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a progress that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the progress.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Sets the amount of work in total, zero if it is unknown.
+    pub fn set_total(&self, total: u64) {
+        self.progress().total.store(total, SeqCst);
+    }
+
+    /// Sets the amount of work done.
+    pub fn set_completed(&self, completed: u64) {
+        self.progress().completed.store(completed, SeqCst);
+    }
+
+    /// Adds to the amount of work done and returns the new amount.
+    pub fn advance(&self, done: u64) -> u64 {
+        self.progress().completed.fetch_add(done, SeqCst) + done
+    }
+
+    /// Sets the name of the current stage, cut to `STAGE_LEN` bytes.
+    ///
+    /// Meant for the worker; concurrent writers take turns.
+    pub fn set_stage(&self, stage: &str) {
+        let mut len = stage.len().min(STAGE_LEN);
+        while !stage.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0u8; STAGE_LEN];
+        bytes[..len].copy_from_slice(&stage.as_bytes()[..len]);
+
+        let progress = self.progress();
+        let mut seq = progress.seq.load(SeqCst);
+        loop {
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                seq = progress.seq.load(SeqCst);
+                continue;
+            }
+            match progress.seq.compare_exchange(seq, seq + 1, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(current) => seq = current,
+            }
+        }
+        progress.stage_len.store(len as u64, SeqCst);
+        for (word, chunk) in progress.stage.iter().zip(bytes.chunks(8)) {
+            word.store(u64::from_le_bytes(chunk.try_into().unwrap()), SeqCst);
+        }
+        progress.seq.store(seq + 2, SeqCst);
+    }
+
+    /// Returns the total, the amount done and the current stage.
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let progress = self.progress();
+        let stage = loop {
+            let seq = progress.seq.load(SeqCst);
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let len = (progress.stage_len.load(SeqCst) as usize).min(STAGE_LEN);
+            let bytes: Vec<_> = progress
+                .stage
+                .iter()
+                .flat_map(|word| word.load(SeqCst).to_le_bytes())
+                .take(len)
+                .collect();
+            if progress.seq.load(SeqCst) == seq {
+                break String::from_utf8_lossy(&bytes).into_owned();
+            }
+        };
+        ProgressSnapshot {
+            total: progress.total.load(SeqCst),
+            completed: progress.completed.load(SeqCst),
+            stage,
+        }
+    }
+
+    /// Frees the progress.
+    ///
+    /// Other processes must not use the progress afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn progress(&self) -> &Progress {
+        // SAFETY: The block holds the progress.
+        unsafe { &*(self.block.ptr as *const Progress) }
+    }
+}
+
+impl Memory {
+    /// Returns the progress registered under `name`, creating it if there is none.
+    ///
+    /// Every process that asks for the same name gets the same progress. Returns None if not
+    /// enough memory.
+    pub fn progress(&self, name: &str) -> Option<ShmProgress<'_>> {
+        let block = self.named("progress", name, || {
+            ShmProgress::create(self).map(|progress| progress.block.ptr)
+        })?;
+        Some(ShmProgress {
+            block: SharedBlock {
+                memory: self,
+                ptr: block,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let worker = memory.progress("import").unwrap();
+        let gui = memory.progress("import").unwrap();
+        assert_eq!(gui.snapshot().fraction(), None, "The total is unknown");
+
+        worker.set_total(200);
+        worker.set_stage("parsing");
+        assert_eq!(worker.advance(50), 50, "The amount done grows");
+        let snapshot = gui.snapshot();
+        assert_eq!(snapshot.stage, "parsing", "The stage is shared");
+        assert_eq!(snapshot.fraction(), Some(0.25), "A quarter is done");
+
+        worker.set_stage(&"é".repeat(40));
+        assert_eq!(
+            gui.snapshot().stage,
+            "é".repeat(32),
+            "Long stages are cut at a character boundary"
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ready::SPINS,
+    shared::{values_start, SharedBlock},
+    sync::{
+        atomic::{AtomicU32, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset, ShmSafe,
+};
+
+/// States of a promise.
+const EMPTY: u32 = 0;
+const WRITING: u32 = 1;
+const FULFILLED: u32 = 2;
+
+/// A one-shot cell in shared memory that one process fulfills with a value and others wait
+/// for, e.g. the response to a request.
+///
+/// The value is set at most once. Waiters poll the state like `Memory::wait_for_peer`,
+/// spinning at first and then sleeping a millisecond between polls.
+pub struct ShmPromise<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmPromise<'a, T> {
+    const VALUE: usize = values_start::<T>(std::mem::size_of::<AtomicU32>());
+
+    /// Creates a promise that is not fulfilled yet.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = Self::VALUE + std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a promise created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a promise of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the promise.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Sets the value, which the waiters see on their next poll.
+    ///
+    /// Returns the value back if the promise was already fulfilled.
+    pub fn fulfill(&self, value: T) -> Result<(), T> {
+        let state = self.state();
+        if state
+            .compare_exchange(EMPTY, WRITING, SeqCst, SeqCst)
+            .is_err()
+        {
+            return Err(value);
+        }
+        // SAFETY: Only the process that moved the state to WRITING writes the value.
+        unsafe { self.value().write(value) };
+        state.store(FULFILLED, SeqCst);
+        Ok(())
+    }
+
+    /// Returns true if the value was set.
+    pub fn is_fulfilled(&self) -> bool {
+        self.state().load(SeqCst) == FULFILLED
+    }
+
+    /// Returns the value, or None if the promise is not fulfilled yet.
+    pub fn try_get(&self) -> Option<T> {
+        // SAFETY: The value was fully written before the state became FULFILLED, and it is
+        // never written again.
+        self.is_fulfilled().then(|| unsafe { self.value().read() })
+    }
+
+    /// Blocks until the promise is fulfilled and returns the value.
+    pub fn wait(&self) -> T {
+        let mut polls = 0;
+        loop {
+            if let Some(value) = self.try_get() {
+                return value;
+            }
+            pause(polls);
+            polls += 1;
+        }
+    }
+
+    /// Blocks until the promise is fulfilled or `timeout` passed.
+    ///
+    /// Returns the value, or None on timeout.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<T> {
+        let started = Instant::now();
+        let mut polls = 0;
+        loop {
+            if let Some(value) = self.try_get() {
+                return Some(value);
+            }
+            if started.elapsed() >= timeout {
+                return None;
+            }
+            pause(polls);
+            polls += 1;
+        }
+    }
+
+    /// Frees the promise.
+    ///
+    /// Other processes must not use the promise afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn state(&self) -> &AtomicU32 {
+        // SAFETY: The block starts with the state.
+        unsafe { &*(self.block.ptr as *const AtomicU32) }
+    }
+
+    fn value(&self) -> *mut T {
+        // SAFETY: The value follows the state, aligned for `T`.
+        unsafe { self.block.ptr.add(Self::VALUE) as *mut T }
+    }
+}
+
+fn pause(polls: u32) {
+    if polls < SPINS {
+        hint::spin_loop();
+    } else {
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fulfill_and_wait() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let promise = ShmPromise::<u64>::create(&memory).unwrap();
+        let waiter = unsafe { ShmPromise::<u64>::open(&memory, promise.offset()) }.unwrap();
+
+        assert_eq!(waiter.try_get(), None, "Nothing was set yet");
+        assert_eq!(
+            waiter.wait_timeout(Duration::from_millis(5)),
+            None,
+            "The wait times out"
+        );
+
+        assert_eq!(promise.fulfill(42), Ok(()), "The first value is set");
+        assert_eq!(promise.fulfill(7), Err(7), "The promise is one-shot");
+        assert_eq!(waiter.wait(), 42, "The waiter gets the value");
+        assert_eq!(
+            waiter.wait_timeout(Duration::from_millis(5)),
+            Some(42),
+            "The value stays"
+        );
+        promise.destroy();
+    }
+}
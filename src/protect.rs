@@ -0,0 +1,75 @@
+use std::{error::Error, ops::Range};
+
+use crate::{memory::PAGE_SIZE, platform, Memory, Offset};
+
+/// What the pages of a region of a memory allow this process to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    ReadOnly,
+    ReadWrite,
+    /// Any access faults.
+    NoAccess,
+}
+
+impl Memory {
+    /// Changes the protection of the pages in `range` for this process, e.g. to freeze a
+    /// published snapshot against accidental writes.
+    ///
+    /// The range must start and end on page boundaries, so no other data shares its pages.
+    /// Writing to a read-only page faults instead of being caught by the API, and so does
+    /// allocating a block over it: protect only regions inside blocks, never the lock or the
+    /// heap header at the start of the memory. Other processes keep their own protection.
+    ///
+    /// Fails if the range is not page-aligned or out of bounds, or if the OS refuses.
+    pub fn protect(
+        &self,
+        range: Range<Offset>,
+        protection: Protection,
+    ) -> Result<(), Box<dyn Error>> {
+        let (start, end) = (range.start.get(), range.end.get());
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 {
+            return Err(format!("The range must be aligned to pages of {PAGE_SIZE} bytes").into());
+        }
+        if start >= end || end > self.size() {
+            return Err("The range is empty or out of bounds".into());
+        }
+        // SAFETY: The pages are inside the memory, and the caller picked what they allow.
+        unsafe { platform::protect(self.buffer().add(start) as *mut _, end - start, protection) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protect_checks_range() {
+        let memory = Memory::new_in_process(4 * PAGE_SIZE).unwrap();
+        let page = |index| Offset::new(index * PAGE_SIZE);
+
+        assert!(
+            memory
+                .protect(page(2)..page(3), Protection::ReadOnly)
+                .is_ok(),
+            "A whole page can be frozen"
+        );
+        assert!(
+            memory
+                .protect(page(2)..page(3), Protection::ReadWrite)
+                .is_ok(),
+            "And thawed again"
+        );
+        assert!(
+            memory
+                .protect(Offset::new(100)..page(1), Protection::ReadOnly)
+                .is_err(),
+            "Partial pages are refused"
+        );
+        assert!(
+            memory
+                .protect(page(3)..page(5), Protection::NoAccess)
+                .is_err(),
+            "The range must be inside the memory"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Byte quotas of the processes sharing a heap, so one misbehaving client cannot starve
+//! the others.
+//!
+//! The quotas live in the heap header and are checked under the lock of the heap by every
+//! allocation of a process that has one, which then walks the blocks to add up its use.
+
+use crate::Memory;
+
+impl Memory {
+    /// Limits the bytes the blocks of process `pid` may take, counting their headers and
+    /// padding like `usage_breakdown`, or lifts the limit if `quota` is None.
+    ///
+    /// Allocations of the process that would go over the quota fail with
+    /// `AllocError::QuotaExceeded`; blocks it already has stay. Up to 16 processes can have a
+    /// quota at once. Returns false if that many others have one already.
+    pub fn set_owner_quota(&self, pid: u32, quota: Option<usize>) -> bool {
+        self.allocator().set_owner_quota(pid, quota)
+    }
+
+    /// Returns the quota of process `pid`, or None if it has none.
+    pub fn owner_quota(&self, pid: u32) -> Option<usize> {
+        self.inspector().owner_quota(pid)
+    }
+
+    /// Returns the bytes taken by the blocks of process `pid`, as checked against its quota.
+    pub fn owner_used(&self, pid: u32) -> usize {
+        self.inspector().owner_used(pid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::AllocError;
+
+    use super::*;
+
+    #[test]
+    fn test_owner_quota() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let pid = std::process::id();
+        let used = memory.owner_used(pid);
+        assert_eq!(memory.owner_quota(pid), None, "No quota by default");
+
+        assert!(
+            memory.set_owner_quota(pid, Some(used + 512)),
+            "A slot is free"
+        );
+        memory.allocate(128).unwrap();
+        let error = memory.allocate(1024).unwrap_err();
+        assert!(
+            matches!(error, AllocError::QuotaExceeded { quota, .. } if quota == used + 512),
+            "The quota is exceeded: {error}"
+        );
+        memory
+            .allocate(error.largest_hole())
+            .expect("The largest hole still fits the quota");
+        assert!(memory.allocate(8).is_err(), "Nothing is left of the quota");
+
+        assert!(memory.set_owner_quota(pid, None), "The quota is lifted");
+        assert!(memory.allocate(1024).is_ok(), "The heap has room");
+    }
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use crate::{
+    clock,
+    shared::SharedBlock,
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct LimiterHeader {
+    /// Nanoseconds it takes to earn one token.
+    interval: u64,
+    burst: u64,
+    /// Unix time in nanoseconds at which the bucket is full again.
+    full_at: AtomicU64,
+}
+
+/// A token-bucket rate limiter in shared memory, so several processes share one budget.
+///
+/// The bucket holds up to `burst` tokens and earns `per_second` tokens each second. Rather
+/// than storing the tokens and the time they were counted, it stores the single time at which
+/// the bucket will be full again, so taking tokens is one compare-and-swap. The time comes
+/// from the wall clock, which all processes on the machine agree on.
+pub struct ShmRateLimiter<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmRateLimiter<'a> {
+    /// Creates a full bucket of `burst` tokens that earns `per_second` tokens each second.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, per_second: u32, burst: u32) -> Option<Self> {
+        assert!(per_second > 0, "The bucket must earn tokens");
+        assert!(burst > 0, "The bucket must hold a token");
+
+        let size = std::mem::size_of::<LimiterHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes.
+        unsafe {
+            (block.ptr as *mut LimiterHeader).write(LimiterHeader {
+                interval: 1_000_000_000 / per_second as u64,
+                burst: burst as u64,
+                full_at: AtomicU64::new(0),
+            })
+        };
+        Some(Self { block })
+    }
+
+    /// Opens a rate limiter created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a rate limiter that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the rate limiter.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Takes `tokens` tokens if the bucket has them.
+    ///
+    /// Returns false, taking nothing, if there are too few.
+    pub fn try_acquire(&self, tokens: u32) -> bool {
+        self.take(tokens).is_ok()
+    }
+
+    /// Takes `tokens` tokens, sleeping until the bucket has earned them.
+    pub fn acquire(&self, tokens: u32) {
+        assert!(
+            tokens as u64 <= self.header().burst,
+            "The bucket never holds that many tokens"
+        );
+        while let Err(wait) = self.take(tokens) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Returns the number of tokens in the bucket.
+    pub fn available(&self) -> u64 {
+        let header = self.header();
+        let now = clock::unix_nanos();
+        let missing = header.full_at.load(SeqCst).saturating_sub(now);
+        header.burst - missing.div_ceil(header.interval).min(header.burst)
+    }
+
+    /// Frees the rate limiter.
+    ///
+    /// Other processes must not use the rate limiter afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    /// Takes the tokens, or returns how long until the bucket has them.
+    fn take(&self, tokens: u32) -> Result<(), Duration> {
+        let header = self.header();
+        let capacity = header.interval * header.burst;
+        let cost = header.interval * tokens as u64;
+        let mut full_at = header.full_at.load(SeqCst);
+        loop {
+            let now = clock::unix_nanos();
+            // Taking tokens moves the time the bucket is full again further away.
+            let new = full_at.max(now) + cost;
+            let missing = new - now;
+            if missing > capacity {
+                return Err(Duration::from_nanos(missing - capacity));
+            }
+            match header
+                .full_at
+                .compare_exchange(full_at, new, SeqCst, SeqCst)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => full_at = current,
+            }
+        }
+    }
+
+    fn header(&self) -> &LimiterHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const LimiterHeader) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst_and_refill() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let limiter = ShmRateLimiter::create(&memory, 100, 5).unwrap();
+        let opened = unsafe { ShmRateLimiter::open(&memory, limiter.offset()) }.unwrap();
+
+        assert!(limiter.try_acquire(3), "The bucket starts full");
+        assert!(opened.try_acquire(2), "The budget is shared");
+        assert!(!limiter.try_acquire(1), "The bucket is empty");
+
+        // One token is earned every 10 milliseconds.
+        limiter.acquire(1);
+        assert!(!limiter.try_acquire(5), "The bucket is not full yet");
+    }
+}
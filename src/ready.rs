@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        hint,
+    },
+    Memory,
+};
+
+/// Polls of a waiter that spin before it starts to sleep between polls.
+pub(crate) const SPINS: u32 = 64;
+
+/// Marks a role as ready while it is alive, see `Memory::mark_ready`.
+pub struct ReadyGuard<'a> {
+    _memory: &'a Memory,
+    gate: &'a AtomicU64,
+}
+
+impl<'a> Drop for ReadyGuard<'a> {
+    fn drop(&mut self) {
+        // Another process may have taken the role over meanwhile.
+        let _ = self
+            .gate
+            .compare_exchange(std::process::id() as u64, 0, SeqCst, SeqCst);
+    }
+}
+
+impl Memory {
+    /// Announces that this process attached and set up its structures as `role`, e.g.
+    /// "consumer", until the guard is dropped.
+    ///
+    /// Processes waiting in `wait_for_peer` for the role return. A later process marking the
+    /// same role takes it over. Returns None if not enough memory.
+    pub fn mark_ready(&self, role: &str) -> Option<ReadyGuard<'_>> {
+        let gate = self.ready_gate(role)?;
+        gate.store(std::process::id() as u64, SeqCst);
+        Some(ReadyGuard {
+            _memory: self,
+            gate,
+        })
+    }
+
+    /// Blocks until a process marked `role` as ready, or `timeout` passed.
+    ///
+    /// Returns the id of the ready process, or None on timeout or if there is not enough
+    /// memory. The wait polls the memory, spinning at first and then sleeping a millisecond
+    /// between polls, so it costs little even for long timeouts. A process that dies without
+    /// dropping its guard leaves the role marked.
+    pub fn wait_for_peer(&self, role: &str, timeout: Duration) -> Option<u32> {
+        let gate = self.ready_gate(role)?;
+        let started = Instant::now();
+        let mut polls = 0;
+        loop {
+            match gate.load(SeqCst) {
+                0 if started.elapsed() >= timeout => return None,
+                0 if polls < SPINS => hint::spin_loop(),
+                0 => std::thread::sleep(Duration::from_millis(1)),
+                pid => return Some(pid as u32),
+            }
+            polls += 1;
+        }
+    }
+
+    fn ready_gate(&self, role: &str) -> Option<&AtomicU64> {
+        let block = self.named("ready", role, || {
+            let block = self.allocate(std::mem::size_of::<AtomicU64>()).ok()?;
+            // SAFETY: The block was just allocated with room for the gate.
+            unsafe { (block.as_ptr() as *mut AtomicU64).write(AtomicU64::new(0)) };
+            Some(block.as_ptr())
+        })?;
+        // SAFETY: Named ready blocks hold a gate, which lives as long as the memory.
+        Some(unsafe { &*(block as *const AtomicU64) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_wait_for_peer() {
+        let memory = Memory::new("rshmem_test_ready", 4096, 0).unwrap();
+        let timeout = Duration::from_millis(5);
+        assert_eq!(
+            memory.wait_for_peer("consumer", timeout),
+            None,
+            "Nobody is ready yet"
+        );
+
+        let peer = Memory::new("rshmem_test_ready", 4096, 0).unwrap();
+        let guard = peer.mark_ready("consumer").unwrap();
+        assert_eq!(
+            memory.wait_for_peer("consumer", timeout),
+            Some(std::process::id()),
+            "The peer is ready"
+        );
+        assert_eq!(
+            memory.wait_for_peer("producer", timeout),
+            None,
+            "Roles are separate"
+        );
+
+        drop(guard);
+        assert_eq!(
+            memory.wait_for_peer("consumer", timeout),
+            None,
+            "Dropping the guard clears the role"
+        );
+    }
+}
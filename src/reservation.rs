@@ -0,0 +1,92 @@
+use std::ptr::NonNull;
+
+use crate::{AllocError, Memory};
+
+/// Room in the heap set aside by `Memory::reserve` for an allocation that must not fail
+/// later.
+///
+/// The reservation is an allocated block, so allocations of other processes in the meantime
+/// cannot take its room. Dropping it without `fill` frees the block.
+pub struct Reservation<'a> {
+    memory: &'a Memory,
+    block: NonNull<u8>,
+    size: usize,
+}
+
+impl<'a> Reservation<'a> {
+    /// Returns the bytes that were reserved.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Turns the reservation into an allocated block holding `data`, followed by zeros.
+    ///
+    /// Returns the reservation back if `data` is larger than it.
+    pub fn fill(self, data: &[u8]) -> Result<NonNull<u8>, Self> {
+        if data.len() > self.size {
+            return Err(self);
+        }
+        // SAFETY: The block has room for `size` bytes, and was zeroed when it was allocated.
+        unsafe {
+            self.block
+                .as_ptr()
+                .copy_from_nonoverlapping(data.as_ptr(), data.len())
+        };
+        let block = self.block;
+        std::mem::forget(self);
+        Ok(block)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.memory.deallocate(self.block.as_ptr());
+    }
+}
+
+impl Memory {
+    /// Sets aside `size` bytes now for an allocation that is filled in later with
+    /// `Reservation::fill`, which cannot fail for lack of memory.
+    ///
+    /// Returns why there was not enough memory.
+    #[track_caller]
+    pub fn reserve(&self, size: usize) -> Result<Reservation<'_>, AllocError> {
+        let block = self.allocate(size)?;
+        Ok(Reservation {
+            memory: self,
+            block,
+            size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_and_fill() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let reservation = memory.reserve(64).unwrap();
+        let rest = memory.allocate(8192).unwrap_err().largest_hole();
+        memory.allocate(rest).unwrap();
+        assert!(memory.allocate(64).is_err(), "The heap is full");
+
+        let reservation = reservation.fill(&[1; 65]).unwrap_err();
+        let Ok(block) = reservation.fill(&[1, 2, 3]) else {
+            panic!("The data fits");
+        };
+        let block = block.as_ptr();
+        let bytes = unsafe { std::slice::from_raw_parts(block, 4) };
+        assert_eq!(bytes, [1, 2, 3, 0], "The data was written");
+
+        memory.deallocate(block);
+        let used = memory.usage().blocks;
+        drop(memory.reserve(16).unwrap());
+        assert_eq!(
+            memory.usage().blocks,
+            used,
+            "An unused reservation is freed"
+        );
+    }
+}
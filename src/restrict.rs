@@ -0,0 +1,195 @@
+use std::{error::Error, ops::Range};
+
+use crate::{memory::Backend, platform, Memory, Offset};
+
+/// What a `RestrictedMemory` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// A handle to part of a memory, for code that should not see or change the rest of it, like
+/// plugins.
+///
+/// It only reaches the bytes in its range, by offset, and cannot allocate or free. A
+/// read-only handle over a shared mapping reads through a view of its own that the OS maps
+/// read-only, so writes through its pointers fault instead of going through. Otherwise the
+/// restriction is only enforced by the API.
+pub struct RestrictedMemory<'a> {
+    memory: &'a Memory,
+    /// Start of the view the handle reads through.
+    base: *mut u8,
+    /// Whether `base` is a view the handle mapped and unmaps when dropped.
+    own_view: bool,
+    range: Range<usize>,
+    access: Access,
+}
+
+impl Memory {
+    /// Returns a handle to the whole memory that only allows `access`.
+    pub fn restrict(&self, access: Access) -> Result<RestrictedMemory<'_>, Box<dyn Error>> {
+        self.restrict_range(Offset(0)..Offset(self.size()), access)
+    }
+
+    /// Returns a handle to the bytes of `range` that only allows `access`.
+    ///
+    /// Fails if the range is out of bounds, if write access is asked of a read-only memory,
+    /// or if the read-only view could not be mapped.
+    pub fn restrict_range(
+        &self,
+        range: Range<Offset>,
+        access: Access,
+    ) -> Result<RestrictedMemory<'_>, Box<dyn Error>> {
+        let range = range.start.0..range.end.0;
+        if range.start > range.end || range.end > self.size() {
+            return Err("The range is out of bounds".into());
+        }
+        if access == Access::ReadWrite && self.is_read_only() {
+            return Err("The memory is read-only".into());
+        }
+        let (base, own_view) = match (self.backend(), access) {
+            (Backend::Mapping(file), Access::ReadOnly) => {
+                // SAFETY: The file handle stays valid while the memory is borrowed.
+                let view = unsafe { platform::map_view(*file, 0, self.size(), false)? };
+                (view as *mut u8, true)
+            }
+            // SAFETY: Only the address is taken.
+            _ => (unsafe { self.buffer() }, false),
+        };
+        Ok(RestrictedMemory {
+            memory: self,
+            base,
+            own_view,
+            range,
+            access,
+        })
+    }
+}
+
+impl<'a> RestrictedMemory<'a> {
+    /// Returns what the handle allows.
+    pub fn access(&self) -> Access {
+        self.access
+    }
+
+    /// Returns the offsets the handle reaches.
+    pub fn range(&self) -> Range<Offset> {
+        Offset(self.range.start)..Offset(self.range.end)
+    }
+
+    /// Returns a handle to a part of this range, with at most this access.
+    ///
+    /// Fails if the range is not inside this one, or if write access is asked of a
+    /// read-only handle.
+    pub fn restrict_range(
+        &self,
+        range: Range<Offset>,
+        access: Access,
+    ) -> Result<RestrictedMemory<'a>, Box<dyn Error>> {
+        if range.start.0 < self.range.start || range.end.0 > self.range.end {
+            return Err("The range is outside the restricted range".into());
+        }
+        if access == Access::ReadWrite && self.access == Access::ReadOnly {
+            return Err("The handle is read-only".into());
+        }
+        self.memory.restrict_range(range, access)
+    }
+
+    /// Copies the bytes at `offset` into `buffer`.
+    ///
+    /// Returns false, copying nothing, if any of them is outside the range.
+    pub fn read(&self, offset: Offset, buffer: &mut [u8]) -> bool {
+        let Some(data) = self.reach(offset, buffer.len()) else {
+            return false;
+        };
+        // SAFETY: The bytes are inside the memory.
+        unsafe { std::ptr::copy_nonoverlapping(data, buffer.as_mut_ptr(), buffer.len()) };
+        true
+    }
+
+    /// Copies `data` to `offset`.
+    ///
+    /// Returns false, copying nothing, if the handle is read-only or any of the bytes is
+    /// outside the range.
+    pub fn write(&self, offset: Offset, data: &[u8]) -> bool {
+        let Some(target) = self.pointer_mut(offset, data.len()) else {
+            return false;
+        };
+        // SAFETY: The bytes are inside the memory and the handle may write them.
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), target, data.len()) };
+        true
+    }
+
+    /// Returns a pointer to `len` bytes at `offset`, or None if they are outside the range.
+    ///
+    /// For a read-only handle over a shared mapping, the pointer is into its read-only view.
+    pub fn pointer(&self, offset: Offset, len: usize) -> Option<*const u8> {
+        self.reach(offset, len).map(|data| data as *const u8)
+    }
+
+    /// Returns a pointer to `len` bytes at `offset` for writing, or None if the handle is
+    /// read-only or they are outside the range.
+    pub fn pointer_mut(&self, offset: Offset, len: usize) -> Option<*mut u8> {
+        if self.access == Access::ReadOnly {
+            return None;
+        }
+        self.reach(offset, len)
+    }
+
+    fn reach(&self, offset: Offset, len: usize) -> Option<*mut u8> {
+        let end = offset.0.checked_add(len)?;
+        if offset.0 < self.range.start || end > self.range.end {
+            return None;
+        }
+        // SAFETY: The offset is inside the view.
+        Some(unsafe { self.base.add(offset.0) })
+    }
+}
+
+impl<'a> Drop for RestrictedMemory<'a> {
+    fn drop(&mut self) {
+        if self.own_view {
+            // SAFETY: The view was mapped by `restrict_range` and nothing else uses it.
+            unsafe { platform::unmap_view(self.base as *mut _) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restricted_range() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let data = memory.allocate(16).unwrap().as_ptr();
+        let offset = memory.offset_of(data).unwrap();
+        let range = offset..Offset::new(offset.get() + 16);
+
+        let writer = memory
+            .restrict_range(range.clone(), Access::ReadWrite)
+            .unwrap();
+        assert!(writer.write(offset, b"plugin"), "The block is writable");
+        assert!(
+            !writer.write(Offset::new(offset.get() + 12), b"spill"),
+            "Writes past the range are refused"
+        );
+
+        let reader = writer.restrict_range(range, Access::ReadOnly).unwrap();
+        let mut text = [0; 6];
+        assert!(reader.read(offset, &mut text), "The block is readable");
+        assert_eq!(&text, b"plugin", "The view shows the memory");
+        assert!(!reader.write(offset, b"x"), "The handle is read-only");
+        assert!(
+            reader
+                .restrict_range(offset..offset, Access::ReadWrite)
+                .is_err(),
+            "Access cannot be widened"
+        );
+        assert!(
+            reader.pointer(Offset::new(0), 1).is_none(),
+            "The heap header is out of reach"
+        );
+    }
+}
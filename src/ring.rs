@@ -0,0 +1,281 @@
+use std::marker::PhantomData;
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct RingHeader {
+    producer: AtomicBool,
+    consumer: AtomicBool,
+    capacity: usize,
+    policy: AtomicUsize,
+    /// Number of values ever pushed. Only the producer writes it.
+    head: AtomicU64,
+    /// Number of values ever popped or overwritten.
+    tail: AtomicU64,
+    /// Number of values overwritten before they were popped.
+    dropped: AtomicU64,
+}
+
+/// What `push` does when the ring is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Wait for the consumer to pop a value, e.g. for command channels.
+    Block,
+    /// Return false without pushing.
+    Fail,
+    /// Replace the oldest value and count it in `dropped`, e.g. for telemetry that only
+    /// cares about the latest values.
+    Overwrite,
+}
+
+/// A fixed-size ring buffer in shared memory for one producer and one consumer.
+///
+/// Neither side takes a lock, and values are copied into and out of slots allocated upfront,
+/// so the ring never touches the heap after it was created. What the producer does when the
+/// ring is full is set by its `FullPolicy`, which any process can change.
+pub struct ShmRing<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmRing<'a, T> {
+    const SLOTS: usize = values_start::<T>(std::mem::size_of::<RingHeader>());
+
+    /// Creates an empty ring holding up to `capacity` values.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: usize, policy: FullPolicy) -> Option<Self> {
+        assert!(capacity > 0, "A ring holds at least one value");
+
+        let size = Self::SLOTS + capacity * std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe { (*(block.ptr as *mut RingHeader)).capacity = capacity };
+        let ring = Self {
+            block,
+            _value: PhantomData,
+        };
+        ring.set_policy(policy);
+        Some(ring)
+    }
+
+    /// Opens a ring created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a ring of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the ring.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the most values the ring holds.
+    pub fn capacity(&self) -> usize {
+        self.header().capacity
+    }
+
+    /// Returns the number of values in the ring.
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        let tail = header.tail.load(SeqCst);
+        header.head.load(SeqCst).saturating_sub(tail) as usize
+    }
+
+    /// Returns true if the ring is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns what pushing to a full ring does.
+    pub fn policy(&self) -> FullPolicy {
+        match self.header().policy.load(SeqCst) {
+            0 => FullPolicy::Block,
+            1 => FullPolicy::Fail,
+            _ => FullPolicy::Overwrite,
+        }
+    }
+
+    /// Sets what pushing to a full ring does, for every process.
+    pub fn set_policy(&self, policy: FullPolicy) {
+        self.header().policy.store(policy as usize, SeqCst);
+    }
+
+    /// Returns the number of values overwritten before the consumer popped them.
+    pub fn dropped(&self) -> u64 {
+        self.header().dropped.load(SeqCst)
+    }
+
+    /// Claims the producer side of the ring.
+    ///
+    /// Returns None if another thread or process is the producer.
+    pub fn producer(&self) -> Option<RingProducer<'_, 'a, T>> {
+        let claimed = self
+            .header()
+            .producer
+            .compare_exchange(false, true, SeqCst, SeqCst);
+        claimed.ok().map(|_| RingProducer { ring: self })
+    }
+
+    /// Claims the consumer side of the ring.
+    ///
+    /// Returns None if another thread or process is the consumer.
+    pub fn consumer(&self) -> Option<RingConsumer<'_, 'a, T>> {
+        let claimed = self
+            .header()
+            .consumer
+            .compare_exchange(false, true, SeqCst, SeqCst);
+        claimed.ok().map(|_| RingConsumer { ring: self })
+    }
+
+    /// Frees the ring.
+    ///
+    /// Other processes must not use the ring afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn header(&self) -> &RingHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const RingHeader) }
+    }
+
+    fn slot(&self, position: u64) -> *mut T {
+        let index = (position % self.capacity() as u64) as usize;
+        // SAFETY: The slots follow the header, `capacity` of them.
+        unsafe { (self.block.ptr.add(Self::SLOTS) as *mut T).add(index) }
+    }
+}
+
+/// The producer side of a `ShmRing`. Only one exists at a time.
+pub struct RingProducer<'r, 'a, T: ShmSafe> {
+    ring: &'r ShmRing<'a, T>,
+}
+
+impl<'r, 'a, T: ShmSafe> RingProducer<'r, 'a, T> {
+    /// Pushes a value, doing what the policy of the ring says if it is full.
+    ///
+    /// Returns false only if the ring is full and its policy is `FullPolicy::Fail`.
+    pub fn push(&mut self, value: T) -> bool {
+        let ring = self.ring;
+        let header = ring.header();
+        let head = header.head.load(SeqCst);
+        loop {
+            let tail = header.tail.load(SeqCst);
+            if head - tail < ring.capacity() as u64 {
+                break;
+            }
+            match ring.policy() {
+                FullPolicy::Block => {
+                    hint::spin_loop();
+                    std::thread::yield_now();
+                }
+                FullPolicy::Fail => return false,
+                FullPolicy::Overwrite => {
+                    // The consumer may be popping the same value, and only one of us wins.
+                    if header
+                        .tail
+                        .compare_exchange(tail, tail + 1, SeqCst, SeqCst)
+                        .is_ok()
+                    {
+                        header.dropped.fetch_add(1, SeqCst);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // SAFETY: The slot is outside the values the consumer may still pop.
+        unsafe { ring.slot(head).write_volatile(value) };
+        header.head.store(head + 1, SeqCst);
+        true
+    }
+}
+
+impl<'r, 'a, T: ShmSafe> Drop for RingProducer<'r, 'a, T> {
+    fn drop(&mut self) {
+        self.ring.header().producer.store(false, SeqCst);
+    }
+}
+
+/// The consumer side of a `ShmRing`. Only one exists at a time.
+pub struct RingConsumer<'r, 'a, T: ShmSafe> {
+    ring: &'r ShmRing<'a, T>,
+}
+
+impl<'r, 'a, T: ShmSafe> RingConsumer<'r, 'a, T> {
+    /// Removes the oldest value from the ring.
+    ///
+    /// Returns None if the ring is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let ring = self.ring;
+        let header = ring.header();
+        loop {
+            let tail = header.tail.load(SeqCst);
+            if tail == header.head.load(SeqCst) {
+                return None;
+            }
+            // SAFETY: The slot was written before the head moved past it. If the producer
+            // overwrites it meanwhile, it moved the tail first and the copy is thrown away.
+            let value = unsafe { ring.slot(tail).read_volatile() };
+            if header
+                .tail
+                .compare_exchange(tail, tail + 1, SeqCst, SeqCst)
+                .is_ok()
+            {
+                return Some(value);
+            }
+        }
+    }
+}
+
+impl<'r, 'a, T: ShmSafe> Drop for RingConsumer<'r, 'a, T> {
+    fn drop(&mut self) {
+        self.ring.header().consumer.store(false, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_full_policies() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let ring = ShmRing::<u32>::create(&memory, 3, FullPolicy::Fail).unwrap();
+        let mut producer = ring.producer().unwrap();
+        let mut consumer = ring.consumer().unwrap();
+        assert!(ring.producer().is_none(), "There is only one producer");
+
+        for value in 0..3 {
+            assert!(producer.push(value), "The ring has room");
+        }
+        assert!(!producer.push(3), "The ring is full");
+        assert_eq!(consumer.pop(), Some(0), "Values come out in order");
+
+        ring.set_policy(FullPolicy::Overwrite);
+        for value in 3..6 {
+            assert!(producer.push(value), "Overwriting never fails");
+        }
+        assert_eq!(ring.dropped(), 2, "The two oldest values were overwritten");
+        assert_eq!(ring.len(), 3, "The ring stays full");
+        let values: Vec<_> = std::iter::from_fn(|| consumer.pop()).collect();
+        assert_eq!(values, vec![3, 4, 5], "Only the latest values are left");
+    }
+}
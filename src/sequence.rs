@@ -0,0 +1,152 @@
+use std::ops::Range;
+
+use crate::{
+    shared::SharedBlock,
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+/// A 64-bit counter in shared memory handing out unique ids to all processes.
+///
+/// Ids start at 1, so zero can stand for no id. Each id is handed out once, in increasing
+/// order, unless ids are reserved in blocks with `cached`.
+pub struct ShmSequence<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmSequence<'a> {
+    /// Creates a sequence that has not handed out any ids.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let block = SharedBlock::create(memory, std::mem::size_of::<AtomicU64>())?;
+        Some(Self { block })
+    }
+
+    /// Opens a sequence created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a sequence that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the sequence.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the next id.
+    pub fn next(&self) -> u64 {
+        self.counter().fetch_add(1, SeqCst) + 1
+    }
+
+    /// Reserves `count` consecutive ids at once.
+    pub fn reserve(&self, count: u64) -> Range<u64> {
+        let start = self.counter().fetch_add(count, SeqCst) + 1;
+        start..start + count
+    }
+
+    /// Returns the id handed out last, zero if there was none.
+    pub fn last(&self) -> u64 {
+        self.counter().load(SeqCst)
+    }
+
+    /// Returns a handle for this process that reserves `block` ids at a time and hands them
+    /// out without touching the shared counter in between.
+    ///
+    /// Ids stay unique, but those of different processes interleave out of order, and ids
+    /// reserved but not handed out before the handle is dropped are skipped.
+    pub fn cached(&self, block: u64) -> CachedSequence<'_, 'a> {
+        assert!(block > 0, "Blocks must hold an id");
+        CachedSequence {
+            sequence: self,
+            block,
+            ids: 0..0,
+        }
+    }
+
+    /// Frees the sequence.
+    ///
+    /// Other processes must not use the sequence afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn counter(&self) -> &AtomicU64 {
+        // SAFETY: The block holds the counter.
+        unsafe { &*(self.block.ptr as *const AtomicU64) }
+    }
+}
+
+/// Hands out ids of a `ShmSequence` from blocks reserved by this process.
+pub struct CachedSequence<'s, 'a> {
+    sequence: &'s ShmSequence<'a>,
+    block: u64,
+    ids: Range<u64>,
+}
+
+impl<'s, 'a> Iterator for CachedSequence<'s, 'a> {
+    type Item = u64;
+
+    /// Returns the next id, reserving a new block when the current one is used up. Never
+    /// returns None.
+    fn next(&mut self) -> Option<u64> {
+        if self.ids.is_empty() {
+            self.ids = self.sequence.reserve(self.block);
+        }
+        self.ids.next()
+    }
+}
+
+impl Memory {
+    /// Returns the sequence registered under `name`, creating it if there is none.
+    ///
+    /// Every process that asks for the same name gets the same sequence. Returns None if not
+    /// enough memory.
+    pub fn sequence(&self, name: &str) -> Option<ShmSequence<'_>> {
+        let block = self.named("sequence", name, || {
+            ShmSequence::create(self).map(|sequence| sequence.block.ptr)
+        })?;
+        Some(ShmSequence {
+            block: SharedBlock {
+                memory: self,
+                ptr: block,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_named_sequences() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let orders = memory.sequence("order_ids").unwrap();
+        assert_eq!(orders.next(), 1, "Ids start at one");
+        assert_eq!(orders.next(), 2, "Ids increase");
+
+        let same = memory.sequence("order_ids").unwrap();
+        assert_eq!(
+            same.offset(),
+            orders.offset(),
+            "Names find the same sequence"
+        );
+        assert_eq!(same.next(), 3, "The counter is shared");
+        assert_eq!(
+            memory.sequence("users").unwrap().next(),
+            1,
+            "Names are separate"
+        );
+
+        let mut cached = orders.cached(10);
+        assert_eq!(cached.next(), Some(4), "The block starts after the last id");
+        assert_eq!(cached.next(), Some(5), "Ids come from the block");
+        assert_eq!(orders.next(), 14, "The whole block is reserved");
+    }
+}
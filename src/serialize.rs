@@ -0,0 +1,71 @@
+use std::error::Error;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Memory, Offset};
+
+impl Memory {
+    /// Serializes a value straight into a new allocation.
+    ///
+    /// The value is encoded with bincode, without an intermediate buffer. The returned offset
+    /// can be passed to other processes, which read the value back with `load`.
+    pub fn store<T: Serialize>(&self, value: &T) -> Result<Offset, Box<dyn Error>> {
+        // Empty blocks are not valid allocations, so values that encode to nothing take a byte.
+        let size = (bincode::serialized_size(value)? as usize).max(1);
+        let data = self.allocate(size)?.as_ptr();
+
+        // SAFETY: The block was just allocated with `size` bytes.
+        let slice = unsafe { std::slice::from_raw_parts_mut(data, size) };
+        if let Err(e) = bincode::serialize_into(slice, value) {
+            self.deallocate(data);
+            return Err(e);
+        }
+
+        Ok(self
+            .offset_of(data)
+            .expect("Allocations are inside the memory"))
+    }
+
+    /// Deserializes a value written by `store`.
+    ///
+    /// The allocation is left as is; free it with `deallocate` once it is no longer needed.
+    pub fn load<T: DeserializeOwned>(&self, offset: Offset) -> Result<T, Box<dyn Error>> {
+        let data = self.pointer(offset).ok_or("Offset is out of bounds")?;
+        let size = self
+            .allocation_size(data)
+            .ok_or("There is no allocation at the offset")?;
+
+        // SAFETY: The block is allocated and has `size` bytes.
+        let slice = unsafe { std::slice::from_raw_parts(data, size) };
+        Ok(bincode::deserialize(slice)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::Memory;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        id: u32,
+        text: String,
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let message = Message {
+            id: 7,
+            text: "hello".to_owned(),
+        };
+
+        let offset = memory.store(&message).unwrap();
+        let loaded: Message = memory.load(offset).unwrap();
+        assert_eq!(
+            loaded, message,
+            "The loaded value should equal the stored one"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! The block behind every shared structure.
+//!
+//! A structure lives in one block that starts with its header, behind a lock word if it is
+//! locked, and other processes open it by the offset of the block.
+
+use crate::{
+    allocator::align_up,
+    mutex::{MemoryGuard, MemoryMutex},
+    shm_safe::check_align,
+    Memory, Offset,
+};
+
+/// The block of a shared structure and the memory it is in.
+pub(crate) struct SharedBlock<'a> {
+    pub memory: &'a Memory,
+    pub ptr: *mut u8,
+}
+
+impl<'a> SharedBlock<'a> {
+    /// Allocates a zeroed block of `size` bytes.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, size: usize) -> Option<Self> {
+        let ptr = memory.allocate(size).ok()?.as_ptr();
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe { ptr.write_bytes(0, size) };
+        Some(Self { memory, ptr })
+    }
+
+    /// Opens the block at `offset`.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a block that has not been freed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let ptr = memory.pointer(offset)?;
+        Some(Self { memory, ptr })
+    }
+
+    /// Returns the offset other processes use to open the block.
+    pub fn offset(&self) -> Offset {
+        self.memory
+            .offset_of(self.ptr)
+            .expect("Structures are inside the memory")
+    }
+
+    /// Locks the lock word at the start of the block. The guard covers the rest of the first
+    /// `size` bytes.
+    pub fn lock(&self, size: usize) -> MemoryGuard<'_> {
+        // SAFETY: Locked structures start with the lock word, zeroed on creation.
+        unsafe { MemoryMutex::new(self.ptr, size) }.lock()
+    }
+
+    /// Frees the block.
+    pub fn free(self) {
+        self.memory.deallocate(self.ptr);
+    }
+}
+
+/// Returns where values of `T` start behind `header` bytes of a block.
+///
+/// Blocks are only word-aligned, so a type that needs more fails the assertion, at compile
+/// time where the result is a constant.
+pub(crate) const fn values_start<T>(header: usize) -> usize {
+    check_align::<T>();
+    align_up(header, std::mem::align_of::<T>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_and_open() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let block = SharedBlock::create(&memory, 64).unwrap();
+        let bytes = unsafe { std::slice::from_raw_parts(block.ptr, 64) };
+        assert!(bytes.iter().all(|&byte| byte == 0), "New blocks are zeroed");
+
+        let opened = unsafe { SharedBlock::open(&memory, block.offset()) }.unwrap();
+        assert_eq!(opened.ptr, block.ptr, "The offset leads to the same block");
+        assert!(
+            unsafe { SharedBlock::open(&memory, Offset(usize::MAX)) }.is_none(),
+            "Offsets out of bounds are refused"
+        );
+
+        let opened_ptr = opened.ptr;
+        block.free();
+        assert_eq!(
+            memory.allocation_size(opened_ptr),
+            None,
+            "Freeing releases the block"
+        );
+    }
+
+    #[test]
+    fn test_values_start() {
+        assert_eq!(values_start::<u8>(3), 3, "Bytes need no alignment");
+        assert_eq!(values_start::<u32>(5), 8, "Values are aligned");
+        assert_eq!(values_start::<u64>(16), 16, "Aligned starts are kept");
+    }
+}
@@ -0,0 +1,134 @@
+use std::marker::PhantomData;
+
+use crate::{error::AllocError, Memory, Offset};
+
+/// Types whose bytes mean the same in every process, so they can be copied into the shared
+/// memory and read back by others.
+///
+/// With the `derive` feature, `#[derive(ShmSafe)]` implements it for `#[repr(C)]` structs
+/// after checking that they hold no references, pointers or heap types, only other
+/// `ShmSafe` fields. Blocks are referred to by `Offset`.
+///
+/// # Safety
+/// The type must have a layout that does not depend on the compiler run, contain nothing
+/// that is only valid in one process, and be valid for any bytes stored by `store`.
+pub unsafe trait ShmSafe: Copy + 'static {
+    /// Copies the value into a new allocation.
+    fn store(&self, memory: &Memory) -> Result<Offset, AllocError> {
+        check_align::<Self>();
+        let data = memory.allocate(std::mem::size_of::<Self>())?.as_ptr();
+        // SAFETY: The block was just allocated with room for the value.
+        unsafe { (data as *mut Self).write(*self) };
+        Ok(memory
+            .offset_of(data)
+            .expect("Allocations are inside the memory"))
+    }
+
+    /// Returns a copy of the value stored at `offset`, or None if there is no allocation
+    /// large enough for it there.
+    fn load(memory: &Memory, offset: Offset) -> Option<Self> {
+        let data = block::<Self>(memory, offset)?;
+        // SAFETY: The block holds a `Self`, which is valid for the bytes it holds.
+        Some(unsafe { (data as *const Self).read_volatile() })
+    }
+
+    /// Returns the value stored at `offset` in place, or None if there is no allocation
+    /// large enough for it there.
+    ///
+    /// # Safety
+    /// The allocation must not be freed or written to while the returned reference is used,
+    /// by this or any other process.
+    unsafe fn view(memory: &Memory, offset: Offset) -> Option<&Self> {
+        let data = block::<Self>(memory, offset)?;
+        Some(&*(data as *const Self))
+    }
+}
+
+pub(crate) const fn check_align<T>() {
+    assert!(
+        std::mem::align_of::<T>() <= std::mem::align_of::<usize>(),
+        "Allocations are only word-aligned"
+    );
+}
+
+fn block<T>(memory: &Memory, offset: Offset) -> Option<*mut u8> {
+    check_align::<T>();
+    let data = memory.pointer(offset)?;
+    let size = memory.allocation_size(data)?;
+    (size >= std::mem::size_of::<T>()).then_some(data)
+}
+
+macro_rules! shm_safe {
+    ($($ty:ty),*) => {
+        $(
+            // SAFETY: Plain numbers are valid for any bytes.
+            unsafe impl ShmSafe for $ty {}
+        )*
+    };
+}
+
+shm_safe!(
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    f32,
+    f64,
+    (),
+    Offset
+);
+
+// SAFETY: Arrays are laid out as their elements.
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
+
+// SAFETY: It has no bytes.
+unsafe impl<T: 'static> ShmSafe for PhantomData<T> {}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use crate::{Memory, Offset, ShmSafe};
+
+    #[derive(Clone, Copy, Debug, PartialEq, ShmSafe)]
+    #[repr(C)]
+    struct Order {
+        id: u64,
+        price: f64,
+        next: Offset,
+        tags: [u16; 4],
+    }
+
+    #[test]
+    fn test_store_and_load() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let order = Order {
+            id: 7,
+            price: 1.5,
+            next: Offset::new(0),
+            tags: [1, 2, 3, 4],
+        };
+        let offset = order.store(&memory).unwrap();
+        assert_eq!(
+            Order::load(&memory, offset),
+            Some(order),
+            "The copy is equal"
+        );
+        assert_eq!(
+            unsafe { Order::view(&memory, offset) }.map(|order| order.id),
+            Some(7),
+            "The value is viewed in place"
+        );
+
+        let small = 1u8.store(&memory).unwrap();
+        assert_eq!(
+            Order::load(&memory, small),
+            None,
+            "Allocations too small for the type are rejected"
+        );
+    }
+}
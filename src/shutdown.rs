@@ -0,0 +1,102 @@
+use crate::{
+    allocator,
+    mutex::MemoryMutex,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    Memory,
+};
+
+impl Memory {
+    /// Asks every process using the memory to drain its work and exit.
+    ///
+    /// The request is a flag in the heap header, which processes poll with
+    /// `is_shutting_down` and which ends `Consumer::pop_timeout` waits once their queue is
+    /// empty. Returns the generation of the shutdown, which stays the same if one was
+    /// already requested.
+    ///
+    /// # Panics
+    /// If the memory is read-only.
+    pub fn request_shutdown(&self) -> u64 {
+        assert!(!self.is_read_only(), "The memory is read-only");
+        let word = self.shutdown_word();
+        let _ = word.fetch_update(SeqCst, SeqCst, |word| (word % 2 == 0).then_some(word + 1));
+        generation(word.load(SeqCst))
+    }
+
+    /// Returns true while a shutdown is requested.
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown_word().load(SeqCst) % 2 == 1
+    }
+
+    /// Returns the number of shutdowns requested since the memory was created, counting
+    /// the current one.
+    ///
+    /// A process that outlives a shutdown, e.g. a supervisor, can tell from it whether its
+    /// peers are from before or after the last one.
+    pub fn shutdown_generation(&self) -> u64 {
+        generation(self.shutdown_word().load(SeqCst))
+    }
+
+    /// Ends a requested shutdown, so the memory can be used again by a new set of
+    /// processes. Does nothing if none is requested.
+    ///
+    /// # Panics
+    /// If the memory is read-only.
+    pub fn clear_shutdown(&self) {
+        assert!(!self.is_read_only(), "The memory is read-only");
+        let _ = self
+            .shutdown_word()
+            .fetch_update(SeqCst, SeqCst, |word| (word % 2 == 1).then_some(word + 1));
+    }
+
+    fn shutdown_word(&self) -> &AtomicUsize {
+        // SAFETY: The heap was attached when the memory was opened and lives as long as it.
+        unsafe { allocator::shutdown_word(self.buffer().add(MemoryMutex::SIZE)) }
+    }
+}
+
+fn generation(word: usize) -> u64 {
+    word.div_ceil(2) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Memory, RecvError, ShmMpscQueue};
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_shutdown_drains_consumers() {
+        let memory = Memory::new("rshmem_test_shutdown", 4096, 0).unwrap();
+        let peer = Memory::new("rshmem_test_shutdown", 4096, 0).unwrap();
+        let queue = ShmMpscQueue::<u64>::create(&memory).unwrap();
+        let mut consumer = queue.consumer().unwrap();
+        let timeout = Duration::from_millis(5);
+        assert_eq!(
+            consumer.pop_timeout(timeout),
+            Err(RecvError::Timeout),
+            "Nothing was sent"
+        );
+
+        queue.push(1);
+        assert_eq!(peer.request_shutdown(), 1, "This is the first shutdown");
+        assert_eq!(peer.request_shutdown(), 1, "Requests are not counted twice");
+        assert!(memory.is_shutting_down(), "Every handle sees the request");
+        assert_eq!(consumer.pop_timeout(timeout), Ok(1), "The queue is drained");
+        assert_eq!(
+            consumer.pop_timeout(timeout),
+            Err(RecvError::Shutdown),
+            "The drained consumer is told to exit"
+        );
+
+        drop(consumer);
+        memory.reset(false);
+        assert!(peer.is_shutting_down(), "A reset keeps the request");
+        memory.clear_shutdown();
+        assert!(!peer.is_shutting_down(), "The shutdown ended");
+        assert_eq!(peer.shutdown_generation(), 1, "The generation stays");
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+    Memory, Offset, ShmSafe,
+};
+
+/// Maximum number of levels a node is linked into.
+const MAX_HEIGHT: usize = 16;
+const WORD: usize = std::mem::size_of::<usize>();
+
+#[repr(C)]
+struct ListHeader {
+    writer: AtomicBool,
+    /// State of the generator picking node heights. Only the writer touches it.
+    seed: AtomicU64,
+    len: AtomicUsize,
+    /// Number of levels in use.
+    height: AtomicUsize,
+    /// Offsets of the first node of each level.
+    head: [AtomicUsize; MAX_HEIGHT],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Entry<K, V> {
+    key: K,
+    value: V,
+}
+
+/// An ordered list in shared memory for one writer and any number of readers.
+///
+/// Readers never take a lock: the writer fills in a node before linking it, level by level
+/// from the bottom, so a reader sees either the old or the new list. It is lighter than
+/// `ShmBTreeMap` for append-mostly data like time-indexed samples, where one process records
+/// and many others query.
+///
+/// Entries are immutable once inserted and cannot be removed. A key inserted twice keeps both
+/// entries, the newer after the older. Nodes are allocated as children of the list, which lets
+/// `destroy` free everything at once.
+///
+/// `K` must order the same way in every process.
+pub struct ShmSkipList<'a, K: ShmSafe + Ord, V: ShmSafe> {
+    block: SharedBlock<'a>,
+    _entry: PhantomData<(K, V)>,
+}
+
+impl<'a, K: ShmSafe + Ord, V: ShmSafe> ShmSkipList<'a, K, V> {
+    /// Creates an empty list.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = std::mem::size_of::<ListHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr as *const ListHeader))
+                .seed
+                .store(0x9e37_79b9_7f4a_7c15, SeqCst);
+        }
+        Some(Self {
+            block,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Opens a list created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a list of the same `K` and `V` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _entry: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the list.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Claims the writer side of the list.
+    ///
+    /// Returns None if another thread or process is the writer.
+    pub fn writer(&self) -> Option<SkipListWriter<'_, 'a, K, V>> {
+        let claimed = self
+            .header()
+            .writer
+            .compare_exchange(false, true, SeqCst, SeqCst);
+        claimed.ok().map(|_| SkipListWriter { list: self })
+    }
+
+    /// Returns a copy of the oldest value of the key.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let next = self.link(self.predecessors(key, false)[0], 0).load(SeqCst);
+        let entry = (next != 0).then(|| self.entry(next))?;
+        (entry.key == *key).then_some(entry.value)
+    }
+
+    /// Returns copies of the entries with keys in the range, in key order.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Vec<(K, V)> {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.predecessors(key, false)[0],
+            Bound::Excluded(key) => self.predecessors(key, true)[0],
+            Bound::Unbounded => 0,
+        };
+
+        let mut entries = Vec::new();
+        let mut current = self.link(start, 0).load(SeqCst);
+        while current != 0 {
+            let entry = self.entry(current);
+            if !range.contains(&entry.key) {
+                break;
+            }
+            entries.push((entry.key, entry.value));
+            current = self.link(current, 0).load(SeqCst);
+        }
+        entries
+    }
+
+    /// Returns a copy of the entry with the greatest key.
+    pub fn last(&self) -> Option<(K, V)> {
+        let mut current = 0;
+        for level in (0..self.header().height.load(SeqCst)).rev() {
+            loop {
+                match self.link(current, level).load(SeqCst) {
+                    0 => break,
+                    next => current = next,
+                }
+            }
+        }
+        let entry = (current != 0).then(|| self.entry(current))?;
+        Some((entry.key, entry.value))
+    }
+
+    /// Returns the number of entries in the list.
+    pub fn len(&self) -> usize {
+        self.header().len.load(SeqCst)
+    }
+
+    /// Returns true if the list is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the list and all of its nodes.
+    ///
+    /// Other processes must not use the list afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    /// Returns the last node of each level before `key`, or zero for the head.
+    ///
+    /// Nodes equal to `key` are skipped too if `after_equal` is set.
+    fn predecessors(&self, key: &K, after_equal: bool) -> [usize; MAX_HEIGHT] {
+        let mut predecessors = [0; MAX_HEIGHT];
+        let mut current = 0;
+        for level in (0..self.header().height.load(SeqCst)).rev() {
+            loop {
+                let next = self.link(current, level).load(SeqCst);
+                if next == 0 {
+                    break;
+                }
+                let next_key = self.entry(next).key;
+                if next_key > *key || (next_key == *key && !after_equal) {
+                    break;
+                }
+                current = next;
+            }
+            predecessors[level] = current;
+        }
+        predecessors
+    }
+
+    fn header(&self) -> &ListHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const ListHeader) }
+    }
+
+    /// Returns the link to the next node on a level, from a node or from the head.
+    fn link(&self, node: usize, level: usize) -> &AtomicUsize {
+        if node == 0 {
+            return &self.header().head[level];
+        }
+        // SAFETY: A node is its height followed by one link per level.
+        unsafe { &*(self.pointer(node).add(WORD) as *const AtomicUsize).add(level) }
+    }
+
+    fn entry(&self, node: usize) -> Entry<K, V> {
+        let node = self.pointer(node);
+        // SAFETY: The entry follows the links and was written before the node was linked.
+        unsafe {
+            let height = *(node as *const usize);
+            (node.add(Self::entry_start(height)) as *const Entry<K, V>).read()
+        }
+    }
+
+    /// Returns where the entry of a node of `height` levels starts.
+    fn entry_start(height: usize) -> usize {
+        values_start::<Entry<K, V>>(WORD * (1 + height))
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Nodes are inside the memory")
+    }
+}
+
+/// The writer side of a `ShmSkipList`. Only one exists at a time.
+pub struct SkipListWriter<'l, 'a, K: ShmSafe + Ord, V: ShmSafe> {
+    list: &'l ShmSkipList<'a, K, V>,
+}
+
+impl<'l, 'a, K: ShmSafe + Ord, V: ShmSafe> SkipListWriter<'l, 'a, K, V> {
+    /// Inserts an entry after all entries with smaller or equal keys.
+    ///
+    /// Returns false if there was not enough memory for the node.
+    pub fn insert(&mut self, key: K, value: V) -> bool {
+        let list = self.list;
+        let height = self.random_height();
+        let size = ShmSkipList::<K, V>::entry_start(height) + std::mem::size_of::<Entry<K, V>>();
+        let Ok(node) = list.block.memory.allocate_more(size, list.block.ptr) else {
+            return false;
+        };
+        let node = node.as_ptr();
+
+        // SAFETY: The block was just allocated with `size` bytes and is not linked yet.
+        unsafe {
+            node.write_bytes(0, size);
+            (node as *mut usize).write(height);
+            (node.add(ShmSkipList::<K, V>::entry_start(height)) as *mut Entry<K, V>)
+                .write(Entry { key, value });
+        }
+
+        let offset = list
+            .block
+            .memory
+            .offset_of(node)
+            .expect("Nodes are inside the memory")
+            .0;
+        let predecessors = list.predecessors(&key, true);
+        for (level, &predecessor) in predecessors.iter().enumerate().take(height) {
+            let next = list.link(predecessor, level).load(SeqCst);
+            list.link(offset, level).store(next, SeqCst);
+        }
+        // Readers may see the node as soon as it is on the bottom level.
+        for (level, &predecessor) in predecessors.iter().enumerate().take(height) {
+            list.link(predecessor, level).store(offset, SeqCst);
+        }
+
+        let header = list.header();
+        header.height.fetch_max(height, SeqCst);
+        header.len.fetch_add(1, SeqCst);
+        true
+    }
+
+    /// Picks a height with a one in four chance of each extra level.
+    fn random_height(&self) -> usize {
+        let seed = &self.list.header().seed;
+        let mut x = seed.load(SeqCst);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        seed.store(x, SeqCst);
+        (1 + x.trailing_zeros() as usize / 2).min(MAX_HEIGHT)
+    }
+}
+
+impl<'l, 'a, K: ShmSafe + Ord, V: ShmSafe> Drop for SkipListWriter<'l, 'a, K, V> {
+    fn drop(&mut self) {
+        self.list.header().writer.store(false, SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_range() {
+        let memory = Memory::new_in_process(65536).unwrap();
+        let list = ShmSkipList::<u64, u32>::create(&memory).unwrap();
+
+        let mut writer = list.writer().unwrap();
+        assert!(list.writer().is_none(), "There is only one writer");
+        for key in (0..100).rev() {
+            assert!(writer.insert(key, key as u32), "The node should fit");
+        }
+        assert!(writer.insert(50, 500), "The node should fit");
+
+        assert_eq!(list.len(), 101, "Duplicate keys are kept");
+        assert_eq!(list.get(&50), Some(50), "The older value comes first");
+        assert_eq!(list.get(&100), None, "The key was never inserted");
+        assert_eq!(
+            list.range(49..=51),
+            vec![(49, 49), (50, 50), (50, 500), (51, 51)],
+            "The range is ordered"
+        );
+        assert_eq!(list.last(), Some((99, 99)), "The greatest key is last");
+    }
+}
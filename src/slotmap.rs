@@ -0,0 +1,259 @@
+use std::marker::PhantomData;
+
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::{values_start, SharedBlock},
+    Memory, Offset, ShmSafe,
+};
+
+/// Marks the end of the free list.
+const NIL: u32 = u32::MAX;
+
+/// Key of a value in a `ShmSlotMap`.
+///
+/// The generation tells apart values that were stored in the same slot over time, so an id
+/// kept after its value was removed never finds a newer value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct SlotId {
+    pub index: u32,
+    pub generation: u32,
+}
+
+#[repr(C)]
+struct MapHeader {
+    capacity: u32,
+    len: u32,
+    free: u32,
+}
+
+#[repr(C)]
+struct Slot {
+    /// Odd while the slot holds a value, even while it is free.
+    generation: u32,
+    next_free: u32,
+}
+
+/// A fixed capacity map with stable generational keys, stored in shared memory.
+///
+/// It suits registries shared between processes, e.g. entities created by a simulation and
+/// drawn by a renderer: the renderer can keep ids around and detects when the entity behind
+/// one is gone, even if its slot was reused.
+pub struct ShmSlotMap<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmSlotMap<'a, T> {
+    const SLOTS: usize = MemoryMutex::SIZE + std::mem::size_of::<MapHeader>();
+
+    /// Creates an empty map with room for `capacity` values.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: u32) -> Option<Self> {
+        assert!(
+            capacity < NIL,
+            "The capacity must leave room for the list end"
+        );
+        let size = Self::values_start(capacity) + capacity as usize * std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            let header = &mut *(block.ptr.add(MemoryMutex::SIZE) as *mut MapHeader);
+            header.capacity = capacity;
+            header.free = if capacity > 0 { 0 } else { NIL };
+            let slots = block.ptr.add(Self::SLOTS) as *mut Slot;
+            for index in 0..capacity {
+                let next = if index + 1 < capacity { index + 1 } else { NIL };
+                (*slots.add(index as usize)).next_free = next;
+            }
+        }
+
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a map created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a map of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the map.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Stores a value in a free slot.
+    ///
+    /// Returns the id of the value, or None if the map is full.
+    pub fn insert(&self, value: T) -> Option<SlotId> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if header.free == NIL {
+            return None;
+        }
+
+        let index = header.free;
+        let slot = self.slot(index);
+        header.free = slot.next_free;
+        header.len += 1;
+        slot.generation = slot.generation.wrapping_add(1);
+        // SAFETY: The slot is free, so nobody else reads the value.
+        unsafe { self.value(index).write(value) };
+
+        Some(SlotId {
+            index,
+            generation: slot.generation,
+        })
+    }
+
+    /// Returns a copy of the value, or None if it was removed.
+    pub fn get(&self, id: SlotId) -> Option<T> {
+        let _guard = self.lock();
+        self.is_live(id)
+            .then(|| unsafe { self.value(id.index).read() })
+    }
+
+    /// Replaces the value.
+    ///
+    /// Returns false if the value was removed.
+    pub fn set(&self, id: SlotId, value: T) -> bool {
+        let _guard = self.lock();
+        if !self.is_live(id) {
+            return false;
+        }
+        unsafe { self.value(id.index).write(value) };
+        true
+    }
+
+    /// Returns true if the id still refers to a value.
+    pub fn contains(&self, id: SlotId) -> bool {
+        let _guard = self.lock();
+        self.is_live(id)
+    }
+
+    /// Removes the value and returns it. The slot is reused with a new generation.
+    ///
+    /// Returns None if the value was already removed.
+    pub fn remove(&self, id: SlotId) -> Option<T> {
+        let guard = self.lock();
+        let header = header(&guard);
+        if !self.is_live(id) {
+            return None;
+        }
+
+        let value = unsafe { self.value(id.index).read() };
+        let slot = self.slot(id.index);
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.next_free = header.free;
+        header.free = id.index;
+        header.len -= 1;
+        Some(value)
+    }
+
+    /// Returns the ids of all values, in slot order.
+    pub fn ids(&self) -> Vec<SlotId> {
+        let guard = self.lock();
+        let header = header(&guard);
+        (0..header.capacity)
+            .map(|index| SlotId {
+                index,
+                generation: self.slot(index).generation,
+            })
+            .filter(|id| id.generation % 2 == 1)
+            .collect()
+    }
+
+    /// Returns the number of values in the map.
+    pub fn len(&self) -> u32 {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the map is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of values.
+    pub fn capacity(&self) -> u32 {
+        header(&self.lock()).capacity
+    }
+
+    /// Frees the map.
+    ///
+    /// Other processes must not use the map afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::SLOTS)
+    }
+
+    /// Must be called with the lock held.
+    fn is_live(&self, id: SlotId) -> bool {
+        let capacity =
+            unsafe { &*(self.block.ptr.add(MemoryMutex::SIZE) as *const MapHeader) }.capacity;
+        id.index < capacity && self.slot(id.index).generation == id.generation
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn slot(&self, index: u32) -> &mut Slot {
+        // SAFETY: The caller checked the index against the capacity.
+        unsafe { &mut *(self.block.ptr.add(Self::SLOTS) as *mut Slot).add(index as usize) }
+    }
+
+    fn value(&self, index: u32) -> *mut T {
+        let capacity =
+            unsafe { &*(self.block.ptr.add(MemoryMutex::SIZE) as *const MapHeader) }.capacity;
+        // SAFETY: The caller checked the index against the capacity.
+        unsafe { (self.block.ptr.add(Self::values_start(capacity)) as *mut T).add(index as usize) }
+    }
+
+    fn values_start(capacity: u32) -> usize {
+        let slots = Self::SLOTS + capacity as usize * std::mem::size_of::<Slot>();
+        values_start::<T>(slots)
+    }
+}
+
+/// Returns the map header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut MapHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut MapHeader) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stale_ids() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let map = ShmSlotMap::<u32>::create(&memory, 1).unwrap();
+
+        let first = map.insert(1).unwrap();
+        assert_eq!(map.insert(2), None, "The map is full");
+        assert_eq!(map.remove(first), Some(1), "The value is in the map");
+
+        let second = map.insert(2).unwrap();
+        assert_eq!(first.index, second.index, "The slot is reused");
+        assert_eq!(map.get(first), None, "Stale ids do not find the new value");
+        assert!(!map.set(first, 3), "Stale ids cannot replace the new value");
+        assert_eq!(map.get(second), Some(2), "The new id finds the new value");
+        assert_eq!(map.ids(), vec![second], "Only the new value is listed");
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::mutex::MemoryGuard;
+
+const MAGIC: &[u8; 8] = b"RSHMSNAP";
+
+/// Writes the locked memory to a file.
+///
+/// The file starts with a small header holding the address and the size of the memory, so that
+/// it can only be restored into a memory whose block links stay valid.
+pub fn save(memory: &MemoryGuard, path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.write_all(&(memory.buffer() as u64).to_le_bytes())?;
+    file.write_all(&(memory.size() as u64).to_le_bytes())?;
+
+    // SAFETY: The guard owns `size` bytes from `buffer` while it is alive.
+    let data = unsafe { std::slice::from_raw_parts(memory.buffer(), memory.size()) };
+    file.write_all(data)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Reads the copy of the locked memory in a file written by `save`, checking that it was
+/// taken of a memory of the same size at the same address.
+pub fn read(memory: &MemoryGuard, path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 8];
+    let mut word = [0u8; 8];
+
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(format!("{} is not a memory snapshot", path.display()).into());
+    }
+
+    file.read_exact(&mut word)?;
+    let address = u64::from_le_bytes(word);
+    file.read_exact(&mut word)?;
+    let size = u64::from_le_bytes(word);
+
+    if address != memory.buffer() as u64 || size != memory.size() as u64 {
+        return Err(format!(
+            "Snapshot of {} bytes at {:#x} does not match memory of {} bytes at {:#x}",
+            size,
+            address,
+            memory.size(),
+            memory.buffer() as usize
+        )
+        .into());
+    }
+
+    // The caller gets a copy, so that a truncated file leaves the memory untouched.
+    let mut data = vec![0u8; memory.size()];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mutex::MemoryMutex;
+    use std::alloc::{alloc_zeroed, Layout};
+
+    #[test]
+    fn test_save_and_read() {
+        let buffer = unsafe { alloc_zeroed(Layout::array::<u8>(64).unwrap()) };
+        let mutex = unsafe { MemoryMutex::new(buffer, 64) };
+        let path = std::env::temp_dir().join("rshmem_test_save_and_read.snap");
+
+        let memory = mutex.lock();
+        unsafe { memory.buffer().write_bytes(7, memory.size()) };
+        save(&memory, &path).unwrap();
+
+        unsafe { memory.buffer().write_bytes(0, memory.size()) };
+        let data = read(&memory, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(data.len(), memory.size(), "The whole memory is read");
+        assert!(
+            data.iter().all(|b| *b == 7),
+            "The snapshot should be read back"
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::marker::PhantomData;
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering::SeqCst},
+    tagged::TaggedStack,
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct StackHeader {
+    top: AtomicU64,
+    /// Popped nodes, kept for reuse by `push`.
+    free: AtomicU64,
+    len: AtomicUsize,
+}
+
+/// A lock-free LIFO stack in shared memory.
+///
+/// This is a Treiber stack linking nodes by offset. The head carries a counter next to the
+/// offset of the top node, which protects against ABA when a node is popped and pushed again
+/// while another process is in the middle of a pop. Popped nodes are kept for reuse rather
+/// than freed, because another process may still be reading their link.
+pub struct ShmStack<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmStack<'a, T> {
+    const VALUE: usize = values_start::<T>(std::mem::size_of::<AtomicUsize>());
+
+    /// Creates an empty stack.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = std::mem::size_of::<StackHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a stack created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a stack of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the stack.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Pushes a value on top of the stack.
+    ///
+    /// Returns false if there was no free node and not enough memory for a new one.
+    pub fn push(&self, value: T) -> bool {
+        let header = self.header();
+        let free = TaggedStack::new(self.block.memory, &header.free);
+        let Some(node) = free.pop().or_else(|| self.allocate_node()) else {
+            return false;
+        };
+
+        // SAFETY: The node is not linked anywhere, so nobody else writes its value.
+        unsafe { (node.add(Self::VALUE) as *mut T).write(value) };
+        TaggedStack::new(self.block.memory, &header.top).push(node);
+        header.len.fetch_add(1, SeqCst);
+        true
+    }
+
+    /// Pops the value on top of the stack.
+    pub fn pop(&self) -> Option<T> {
+        let header = self.header();
+        let node = TaggedStack::new(self.block.memory, &header.top).pop()?;
+        header.len.fetch_sub(1, SeqCst);
+
+        // SAFETY: The node was pushed with a value and is now owned by this call.
+        let value = unsafe { (node.add(Self::VALUE) as *const T).read() };
+        TaggedStack::new(self.block.memory, &header.free).push(node);
+        Some(value)
+    }
+
+    /// Returns the number of values on the stack.
+    pub fn len(&self) -> usize {
+        self.header().len.load(SeqCst)
+    }
+
+    /// Returns true if the stack is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the stack and all of its nodes.
+    ///
+    /// Other processes must not use the stack afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn allocate_node(&self) -> Option<*mut u8> {
+        let size = Self::VALUE + std::mem::size_of::<T>();
+        Some(
+            self.block
+                .memory
+                .allocate_more(size, self.block.ptr)
+                .ok()?
+                .as_ptr(),
+        )
+    }
+
+    fn header(&self) -> &StackHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const StackHeader) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_and_pop() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let stack = ShmStack::<u32>::create(&memory).unwrap();
+
+        assert!(stack.push(1), "The node should fit");
+        assert!(stack.push(2), "The node should fit");
+        assert_eq!(stack.pop(), Some(2), "The last value comes out first");
+
+        assert!(stack.push(3), "The popped node should be reused");
+        assert_eq!(stack.len(), 2, "Two values are on the stack");
+        assert_eq!(stack.pop(), Some(3), "The last value comes out first");
+        assert_eq!(stack.pop(), Some(1), "The last value comes out first");
+        assert_eq!(stack.pop(), None, "The stack is empty");
+    }
+}
@@ -0,0 +1,340 @@
+use std::io::{self, Read, Write};
+
+use crate::{
+    shared::SharedBlock,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct StreamHeader {
+    writer: AtomicBool,
+    /// Number of bytes appended. Bytes below it are never written again.
+    len: AtomicUsize,
+    /// Smallest capacity of a new chunk.
+    chunk: usize,
+    /// Offset of the first chunk, or zero before the first append.
+    first: AtomicUsize,
+    /// Offset of the last chunk. Only the writer touches it.
+    last: AtomicUsize,
+    /// Position of the first byte of the last chunk. Only the writer touches it.
+    last_start: AtomicUsize,
+}
+
+#[repr(C)]
+struct Chunk {
+    /// Offset of the next chunk, or zero for the last one.
+    next: AtomicUsize,
+    capacity: usize,
+}
+
+/// An append-only byte stream in shared memory for one writer and any number of readers.
+///
+/// The bytes are stored in a chain of chunks allocated as the stream grows, so its total size
+/// does not need to be known upfront. Each reader keeps its own position, and readers never
+/// take a lock: the writer fills in bytes before it publishes the new length, so a reader
+/// sees either the old or the new stream. Chunks are allocated as children of the stream,
+/// which lets `destroy` free everything at once.
+pub struct ShmStream<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmStream<'a> {
+    /// Creates an empty stream that grows by chunks of at least `chunk` bytes.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, chunk: usize) -> Option<Self> {
+        let size = std::mem::size_of::<StreamHeader>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr as *mut StreamHeader)).chunk = chunk.max(1);
+        }
+        Some(Self { block })
+    }
+
+    /// Opens a stream created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a stream that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the stream.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the number of bytes appended so far.
+    pub fn len(&self) -> usize {
+        self.header().len.load(SeqCst)
+    }
+
+    /// Returns true if nothing was appended yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Claims the writer side of the stream.
+    ///
+    /// Returns None if another thread or process is the writer.
+    pub fn writer(&self) -> Option<StreamWriter<'_, 'a>> {
+        let claimed = self
+            .header()
+            .writer
+            .compare_exchange(false, true, SeqCst, SeqCst);
+        claimed.ok().map(|_| StreamWriter { stream: self })
+    }
+
+    /// Returns a reader starting at the beginning of the stream.
+    pub fn reader(&self) -> StreamReader<'_, 'a> {
+        self.reader_at(0)
+    }
+
+    /// Returns a reader starting at `position`, e.g. one a consumer saved before it stopped.
+    ///
+    /// A position past the end is clamped to the end.
+    pub fn reader_at(&self, position: usize) -> StreamReader<'_, 'a> {
+        let position = position.min(self.len());
+        let mut reader = StreamReader {
+            stream: self,
+            chunk: self.header().first.load(SeqCst),
+            start: 0,
+            position: 0,
+        };
+        while reader.chunk != 0 {
+            let end = reader.start + self.chunk(reader.chunk).capacity;
+            if end > position {
+                break;
+            }
+            let next = self.chunk(reader.chunk).next.load(SeqCst);
+            if next == 0 {
+                break;
+            }
+            reader.chunk = next;
+            reader.start = end;
+        }
+        reader.position = position;
+        reader
+    }
+
+    /// Frees the stream and all of its chunks.
+    ///
+    /// Other processes must not use the stream afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn header(&self) -> &StreamHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const StreamHeader) }
+    }
+
+    fn chunk(&self, offset: usize) -> &Chunk {
+        // SAFETY: Chunks start with their header.
+        unsafe { &*(self.pointer(offset) as *const Chunk) }
+    }
+
+    /// Returns the first byte of the data of a chunk.
+    fn data(&self, offset: usize) -> *mut u8 {
+        // SAFETY: The data directly follows the chunk header.
+        unsafe { self.pointer(offset).add(std::mem::size_of::<Chunk>()) }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        self.block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Chunks are inside the memory")
+    }
+}
+
+/// The writer side of a `ShmStream`. Only one exists at a time.
+pub struct StreamWriter<'s, 'a> {
+    stream: &'s ShmStream<'a>,
+}
+
+impl<'s, 'a> StreamWriter<'s, 'a> {
+    /// Appends all of `bytes` to the stream.
+    ///
+    /// Returns false, having appended nothing, if there was not enough memory for a new
+    /// chunk.
+    pub fn append(&mut self, bytes: &[u8]) -> bool {
+        let stream = self.stream;
+        let header = stream.header();
+        let len = header.len.load(SeqCst);
+        let mut last = header.last.load(SeqCst);
+        let mut start = header.last_start.load(SeqCst);
+        let space = match last {
+            0 => 0,
+            last => start + stream.chunk(last).capacity - len,
+        };
+
+        // The new chunk is linked before the length covers it, so readers never follow a
+        // link that is not there yet.
+        let mut next = None;
+        if bytes.len() > space {
+            let capacity = header.chunk.max(bytes.len() - space);
+            let size = std::mem::size_of::<Chunk>() + capacity;
+            let Ok(chunk) = stream.block.memory.allocate_more(size, stream.block.ptr) else {
+                return false;
+            };
+            let chunk = chunk.as_ptr();
+            // SAFETY: The block was just allocated with room for the header.
+            unsafe {
+                (chunk as *mut Chunk).write(Chunk {
+                    next: AtomicUsize::new(0),
+                    capacity,
+                })
+            };
+            next = Some(
+                stream
+                    .block
+                    .memory
+                    .offset_of(chunk)
+                    .expect("Chunks are inside the memory")
+                    .0,
+            );
+        }
+
+        let (head, tail) = bytes.split_at(space.min(bytes.len()));
+        if !head.is_empty() {
+            // SAFETY: The last chunk has `space` bytes left after the length.
+            unsafe {
+                let data = stream.data(last).add(len - start);
+                std::ptr::copy_nonoverlapping(head.as_ptr(), data, head.len());
+            }
+        }
+        if let Some(next) = next {
+            // SAFETY: The new chunk has room for at least the rest.
+            unsafe {
+                std::ptr::copy_nonoverlapping(tail.as_ptr(), stream.data(next), tail.len());
+            }
+            match last {
+                0 => header.first.store(next, SeqCst),
+                last => {
+                    start += stream.chunk(last).capacity;
+                    stream.chunk(last).next.store(next, SeqCst);
+                }
+            }
+            last = next;
+            header.last.store(last, SeqCst);
+            header.last_start.store(start, SeqCst);
+        }
+        header.len.store(len + bytes.len(), SeqCst);
+        true
+    }
+}
+
+impl<'s, 'a> Write for StreamWriter<'s, 'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.append(buf) {
+            Ok(buf.len())
+        } else {
+            Err(io::ErrorKind::OutOfMemory.into())
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'s, 'a> Drop for StreamWriter<'s, 'a> {
+    fn drop(&mut self) {
+        self.stream.header().writer.store(false, SeqCst);
+    }
+}
+
+/// A reader of a `ShmStream` with its own position.
+///
+/// Reading stops at the end of what was appended so far: `read` then returns zero bytes, and
+/// later reads return the bytes appended since.
+pub struct StreamReader<'s, 'a> {
+    stream: &'s ShmStream<'a>,
+    /// Offset of the chunk holding the position, or zero before the first chunk exists.
+    chunk: usize,
+    /// Position of the first byte of the chunk.
+    start: usize,
+    position: usize,
+}
+
+impl<'s, 'a> StreamReader<'s, 'a> {
+    /// Returns the position of the next byte to read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the number of bytes appended but not read yet.
+    pub fn available(&self) -> usize {
+        self.stream.len() - self.position
+    }
+}
+
+impl<'s, 'a> Read for StreamReader<'s, 'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stream = self.stream;
+        let len = stream.len();
+        let mut read = 0;
+        while read < buf.len() && self.position < len {
+            if self.chunk == 0 {
+                self.chunk = stream.header().first.load(SeqCst);
+            }
+            let capacity = stream.chunk(self.chunk).capacity;
+            if self.position == self.start + capacity {
+                // The length is past this chunk, so the next one is linked.
+                self.chunk = stream.chunk(self.chunk).next.load(SeqCst);
+                self.start += capacity;
+                continue;
+            }
+            let n = (buf.len() - read)
+                .min(len - self.position)
+                .min(self.start + capacity - self.position);
+            // SAFETY: The bytes are below the length, so they were written and stay unchanged.
+            unsafe {
+                let data = stream.data(self.chunk).add(self.position - self.start);
+                std::ptr::copy_nonoverlapping(data, buf[read..].as_mut_ptr(), n);
+            }
+            read += n;
+            self.position += n;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_and_read() {
+        let memory = Memory::new_in_process(8192).unwrap();
+        let stream = ShmStream::create(&memory, 16).unwrap();
+
+        let mut writer = stream.writer().unwrap();
+        assert!(stream.writer().is_none(), "There is only one writer");
+        let mut early = stream.reader();
+        assert_eq!(early.read(&mut [0; 8]).unwrap(), 0, "The stream is empty");
+
+        writer.write_all(b"hello ").unwrap();
+        writer.write_all(b"shared memory world").unwrap();
+        writer.write_all(b"!").unwrap();
+        assert_eq!(stream.len(), 26, "Every byte is appended");
+
+        let mut text = String::new();
+        early.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello shared memory world!", "Bytes span the chunks");
+
+        let mut late = stream.reader_at(13);
+        let mut text = String::new();
+        late.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "memory world!", "Readers start at their position");
+
+        writer.write_all(b"?").unwrap();
+        assert_eq!(late.available(), 1, "New bytes become available");
+    }
+}
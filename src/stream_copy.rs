@@ -0,0 +1,50 @@
+/// Copies `len` bytes from `src` to `dst` with non-temporal stores, which write around the
+/// cache instead of through it.
+///
+/// Filling or draining a multi-megabyte block with a normal copy evicts the rest of the
+/// working set from the cache, even though the copied bytes are not read again soon. This
+/// is only worth it for large copies; small ones are slower than `copy_nonoverlapping`. On
+/// targets without streaming stores it is a normal copy.
+///
+/// # Safety
+/// The same as for `std::ptr::copy_nonoverlapping`: both ranges must be valid for `len`
+/// bytes and must not overlap.
+pub unsafe fn copy_nontemporal(src: *const u8, dst: *mut u8, len: usize) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{__m128i, _mm_loadu_si128, _mm_sfence, _mm_stream_si128};
+
+        const LANE: usize = std::mem::size_of::<__m128i>();
+        // Streaming stores need an aligned destination, so the bytes before it are copied
+        // normally, as are the bytes after the last full lane.
+        let head = dst.align_offset(LANE).min(len);
+        std::ptr::copy_nonoverlapping(src, dst, head);
+        let lanes = (len - head) / LANE;
+        for lane in 0..lanes {
+            let offset = head + lane * LANE;
+            let value = _mm_loadu_si128(src.add(offset) as *const __m128i);
+            _mm_stream_si128(dst.add(offset) as *mut __m128i, value);
+        }
+        let done = head + lanes * LANE;
+        std::ptr::copy_nonoverlapping(src.add(done), dst.add(done), len - done);
+        // Streaming stores are not ordered with other stores until fenced, and other
+        // processes must see the data before whatever the caller publishes next.
+        _mm_sfence();
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    std::ptr::copy_nonoverlapping(src, dst, len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unaligned_copy() {
+        let src: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut dst = vec![0u8; 1003];
+        unsafe { copy_nontemporal(src.as_ptr(), dst.as_mut_ptr().add(3), src.len()) };
+        assert_eq!(&dst[3..], &src[..], "Every byte is copied");
+        assert_eq!(&dst[..3], &[0, 0, 0], "Nothing is written before");
+    }
+}
@@ -0,0 +1,13 @@
+//! Atomics for the lock and the lock-free structures.
+//!
+//! Under `cfg(loom)` they are loom's types, so the concurrency logic can be model-checked
+//! with `RUSTFLAGS="--cfg loom" cargo test --release --lib test_loom`. Loom tracks its
+//! atomics itself and they are larger than the std ones, so a model must create the atomics
+//! it checks with loom rather than reinterpret bytes of a mapping, as the structures do
+//! outside of loom. The models cover the heap lock and the tagged stack; other tests do not
+//! run under loom.
+
+#[cfg(loom)]
+pub(crate) use loom::{hint, sync::atomic};
+#[cfg(not(loom))]
+pub(crate) use std::{hint, sync::atomic};
@@ -0,0 +1,115 @@
+use std::{error::Error, io, mem::MaybeUninit, ptr};
+
+use libc::key_t;
+
+use crate::platform::c_void;
+
+/// Permissions of the segments this process creates: read and write for the owner only.
+const MODE: i32 = 0o600;
+
+/// Creates or opens the System V segment of `key` and attaches it at `base_address`, or
+/// anywhere if it is null.
+///
+/// Returns the id of the segment, whether this call created it, and where it is attached.
+/// Fails if the segment is smaller than `size`, or if it does not exist and `create` is off.
+pub(crate) unsafe fn open(
+    key: key_t,
+    size: usize,
+    base_address: *mut c_void,
+    create: bool,
+    write: bool,
+) -> Result<(i32, bool, *mut c_void), Box<dyn Error>> {
+    let mut id = -1;
+    let mut created = false;
+    if create {
+        id = libc::shmget(key, size, libc::IPC_CREAT | libc::IPC_EXCL | MODE);
+        let error = io::Error::last_os_error();
+        if id == -1 && error.raw_os_error() != Some(libc::EEXIST) {
+            return Err(format!("Could not create segment {key:#x}: {error}").into());
+        }
+        created = id != -1;
+    }
+    if id == -1 {
+        id = libc::shmget(key, 0, 0);
+    }
+    if id == -1 {
+        let error = io::Error::last_os_error();
+        return Err(format!("Could not open segment {key:#x}: {error}").into());
+    }
+
+    let mut stat = MaybeUninit::<libc::shmid_ds>::zeroed();
+    if libc::shmctl(id, libc::IPC_STAT, stat.as_mut_ptr()) == -1 {
+        let error = io::Error::last_os_error();
+        return Err(format!("Could not query segment {key:#x}: {error}").into());
+    }
+    let segment_size = stat.assume_init().shm_segsz;
+    if segment_size < size {
+        return Err(format!("Segment {key:#x} has {segment_size} bytes, not {size}").into());
+    }
+
+    let buffer = libc::shmat(id, base_address, if write { 0 } else { libc::SHM_RDONLY });
+    if buffer as isize == -1 {
+        let error = io::Error::last_os_error();
+        if created {
+            libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+        }
+        return Err(format!("Could not attach segment {key:#x}: {error}").into());
+    }
+    Ok((id, created, buffer))
+}
+
+/// Detaches the segment, and removes it if this process created it.
+///
+/// A removed segment stays with the processes still attached to it until they detach, but
+/// can no longer be opened by its key.
+pub(crate) unsafe fn release(id: i32, owner: bool, buffer: *mut c_void) {
+    libc::shmdt(buffer);
+    if owner {
+        libc::shmctl(id, libc::IPC_RMID, ptr::null_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Memory;
+
+    #[test]
+    fn test_segment_by_key() {
+        let key = 0x7273_0000 | (std::process::id() & 0xffff) as i32;
+        let memory = Memory::builder("rshmem_test_sysv", 8192)
+            .sysv_key(key)
+            .build()
+            .unwrap();
+        assert!(memory.is_creator(), "The segment was created");
+        let data = memory.allocate(16).unwrap().as_ptr();
+        unsafe { *data = 7 };
+        let offset = memory.offset_of(data).unwrap();
+
+        let peer = Memory::builder("rshmem_test_sysv", 8192)
+            .sysv_key(key)
+            .create(false)
+            .build()
+            .unwrap();
+        assert!(!peer.is_creator(), "The peer attached to the heap");
+        assert_eq!(
+            unsafe { *peer.pointer(offset).unwrap() },
+            7,
+            "The peer sees the block"
+        );
+        assert!(
+            Memory::builder("rshmem_test_sysv", 16384)
+                .sysv_key(key)
+                .build()
+                .is_err(),
+            "The segment is too small"
+        );
+
+        drop(peer);
+        drop(memory);
+        assert_eq!(
+            unsafe { libc::shmget(key, 0, 0) },
+            -1,
+            "The creator removed the segment"
+        );
+    }
+}
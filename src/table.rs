@@ -0,0 +1,379 @@
+use std::marker::PhantomData;
+
+use crate::{
+    shared::SharedBlock,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset, ShmSafe,
+};
+
+const WORD_BITS: usize = usize::BITS as usize;
+
+/// Where the parts of a table of `capacity` rows start in its block, and its size.
+struct Layout {
+    seqs: usize,
+    rows: usize,
+    size: usize,
+}
+
+impl Layout {
+    fn new<T>(capacity: usize) -> Self {
+        let words = capacity.div_ceil(WORD_BITS);
+        let seqs = ((1 + words) * std::mem::size_of::<usize>()).next_multiple_of(8);
+        let rows = (seqs + capacity * std::mem::size_of::<AtomicU64>())
+            .next_multiple_of(std::mem::align_of::<T>());
+        Self {
+            seqs,
+            rows,
+            size: rows + capacity * std::mem::size_of::<T>(),
+        }
+    }
+}
+
+/// A row of a `ShmTable` as it was read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableRow<T> {
+    pub index: usize,
+    /// Number of times the row was written, which only grows, also across claims.
+    pub seq: u64,
+    pub value: T,
+}
+
+/// A fixed number of rows of `T` in shared memory, for status records of a set of workers.
+///
+/// A worker claims a free row, atomically like a bit of a `ShmBitset`, and then writes its
+/// record there as often as it likes. Each row is protected by a sequence lock, so readers
+/// never take the lock of the heap and never see half of a record, and the number of writes
+/// of the row tells them whether it changed since they last looked.
+pub struct ShmTable<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _marker: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmTable<'a, T> {
+    /// Creates a table of `capacity` free rows.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: usize) -> Option<Self> {
+        assert!(
+            std::mem::align_of::<T>() <= std::mem::align_of::<u64>(),
+            "Rows are at most 8-aligned"
+        );
+        let size = Layout::new::<T>(capacity).size;
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (block.ptr as *mut usize).write(capacity);
+        }
+        Some(Self {
+            block,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Opens a table created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a table of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the table.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the number of rows.
+    pub fn capacity(&self) -> usize {
+        // SAFETY: The block starts with the capacity, which never changes.
+        unsafe { *(self.block.ptr as *const usize) }
+    }
+
+    /// Claims the first free row and returns its index, or None if every row is taken.
+    pub fn claim(&self) -> Option<usize> {
+        let capacity = self.capacity();
+        for (index, word) in self.words().iter().enumerate() {
+            let mut value = word.load(SeqCst);
+            loop {
+                let bit = (!value).trailing_zeros() as usize;
+                if bit == WORD_BITS || index * WORD_BITS + bit >= capacity {
+                    break;
+                }
+                let mask = 1 << bit;
+                match word.compare_exchange(value, value | mask, SeqCst, SeqCst) {
+                    Ok(_) => return Some(index * WORD_BITS + bit),
+                    Err(current) => value = current,
+                }
+            }
+        }
+        None
+    }
+
+    /// Claims a given row, e.g. the one of a worker number.
+    ///
+    /// Returns false if it is taken.
+    pub fn claim_row(&self, row: usize) -> bool {
+        let (word, mask) = self.locate(row);
+        word.fetch_or(mask, SeqCst) & mask == 0
+    }
+
+    /// Frees a row for the next claim. Its last record stays until then.
+    ///
+    /// Returns false if it was free.
+    pub fn release(&self, row: usize) -> bool {
+        let (word, mask) = self.locate(row);
+        word.fetch_and(!mask, SeqCst) & mask != 0
+    }
+
+    /// Returns true if the row is claimed.
+    pub fn is_occupied(&self, row: usize) -> bool {
+        let (word, mask) = self.locate(row);
+        word.load(SeqCst) & mask != 0
+    }
+
+    /// Writes the record of a row and returns its new number of writes.
+    ///
+    /// Meant for the process that claimed the row; concurrent writers take turns.
+    pub fn write(&self, row: usize, value: &T) -> u64 {
+        let seq = self.seq(row);
+        let mut current = seq.load(SeqCst);
+        loop {
+            if current % 2 == 1 {
+                hint::spin_loop();
+                current = seq.load(SeqCst);
+                continue;
+            }
+            match seq.compare_exchange(current, current + 1, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // SAFETY: The row is inside the block, and readers discard what they read while the
+        // sequence is odd.
+        unsafe { self.row(row).write_volatile(*value) };
+        seq.store(current + 2, SeqCst);
+        current / 2 + 1
+    }
+
+    /// Returns the record of a row, or None if the row is free or was never written.
+    pub fn read(&self, row: usize) -> Option<TableRow<T>> {
+        if !self.is_occupied(row) {
+            return None;
+        }
+        let record = self.read_versioned(row);
+        (record.seq != 0).then_some(record)
+    }
+
+    /// Returns the record of a row with its version, for `compare_and_swap_record`.
+    ///
+    /// A row that was never written reads as zeroed bytes at version 0.
+    pub fn read_versioned(&self, row: usize) -> TableRow<T> {
+        let seq = self.seq(row);
+        loop {
+            let before = seq.load(SeqCst);
+            if before % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            // SAFETY: The row is inside the block, and `T` is valid for any bytes, so a torn
+            // read is harmless and retried below.
+            let value = unsafe { self.row(row).read_volatile() };
+            if seq.load(SeqCst) == before {
+                return TableRow {
+                    index: row,
+                    seq: before / 2,
+                    value,
+                };
+            }
+        }
+    }
+
+    /// Writes the record of a row only if it is still at version `expected`, so that two
+    /// processes updating the same record notice each other instead of one overwriting the
+    /// other.
+    ///
+    /// Returns the new version, or the current one if the row was written in between, in
+    /// which case the caller reads it again and retries.
+    pub fn compare_and_swap_record(
+        &self,
+        row: usize,
+        expected: u64,
+        value: &T,
+    ) -> Result<u64, u64> {
+        let seq = self.seq(row);
+        let mut current = seq.load(SeqCst);
+        loop {
+            if current % 2 == 1 {
+                hint::spin_loop();
+                current = seq.load(SeqCst);
+                continue;
+            }
+            if current != expected * 2 {
+                return Err(current / 2);
+            }
+            match seq.compare_exchange(current, current + 1, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        // SAFETY: As in `write`.
+        unsafe { self.row(row).write_volatile(*value) };
+        seq.store(current + 2, SeqCst);
+        Ok(expected + 1)
+    }
+
+    /// Returns the number of times a row was written.
+    pub fn sequence(&self, row: usize) -> u64 {
+        self.seq(row).load(SeqCst) / 2
+    }
+
+    /// Returns the records of the claimed rows that were written, in row order.
+    pub fn rows(&self) -> Vec<TableRow<T>> {
+        (0..self.capacity())
+            .filter_map(|row| self.read(row))
+            .collect()
+    }
+
+    /// Returns the records of the claimed rows that were written as of one moment, in row
+    /// order.
+    ///
+    /// Unlike `rows`, where each row may be read at a different time, no row is claimed,
+    /// freed or written between the reads of the first and the last row. Writers are never
+    /// held up: the rows are read again until no write came in between.
+    pub fn snapshot(&self) -> Vec<TableRow<T>> {
+        let capacity = self.capacity();
+        loop {
+            let words: Vec<_> = self.words().iter().map(|word| word.load(SeqCst)).collect();
+            let rows: Vec<_> = (0..capacity).map(|row| self.read_versioned(row)).collect();
+            let unchanged = words
+                .iter()
+                .zip(self.words())
+                .all(|(&value, word)| word.load(SeqCst) == value)
+                && rows.iter().all(|row| self.sequence(row.index) == row.seq);
+            if unchanged {
+                return rows
+                    .into_iter()
+                    .filter(|row| {
+                        row.seq != 0
+                            && words[row.index / WORD_BITS] & 1 << (row.index % WORD_BITS) != 0
+                    })
+                    .collect();
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Frees the table.
+    ///
+    /// Other processes must not use the table afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn words(&self) -> &[AtomicUsize] {
+        let words = self.capacity().div_ceil(WORD_BITS);
+        // SAFETY: The occupancy words directly follow the capacity.
+        unsafe { std::slice::from_raw_parts((self.block.ptr as *const AtomicUsize).add(1), words) }
+    }
+
+    fn locate(&self, row: usize) -> (&AtomicUsize, usize) {
+        assert!(row < self.capacity(), "The row index is out of bounds");
+        (&self.words()[row / WORD_BITS], 1 << (row % WORD_BITS))
+    }
+
+    fn seq(&self, row: usize) -> &AtomicU64 {
+        assert!(row < self.capacity(), "The row index is out of bounds");
+        let layout = Layout::new::<T>(self.capacity());
+        // SAFETY: The sequences start 8-aligned after the occupancy words, one per row.
+        unsafe { &*(self.block.ptr.add(layout.seqs) as *const AtomicU64).add(row) }
+    }
+
+    fn row(&self, row: usize) -> *mut T {
+        let layout = Layout::new::<T>(self.capacity());
+        // SAFETY: The rows follow the sequences, aligned for `T`.
+        unsafe { (self.block.ptr.add(layout.rows) as *mut T).add(row) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_write_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let table = ShmTable::<[u32; 3]>::create(&memory, 3).unwrap();
+
+        assert_eq!(table.claim(), Some(0), "The first row is free");
+        assert!(table.claim_row(2), "The last row is free");
+        assert!(!table.claim_row(2), "The last row is taken");
+        assert_eq!(table.read(0), None, "The row was not written yet");
+
+        assert_eq!(table.write(0, &[1, 2, 3]), 1, "First write");
+        assert_eq!(table.write(0, &[4, 5, 6]), 2, "Second write");
+        table.write(2, &[7, 8, 9]);
+        table.write(1, &[0, 0, 0]);
+
+        assert_eq!(
+            table.rows(),
+            vec![
+                TableRow {
+                    index: 0,
+                    seq: 2,
+                    value: [4, 5, 6]
+                },
+                TableRow {
+                    index: 2,
+                    seq: 1,
+                    value: [7, 8, 9]
+                },
+            ],
+            "Only claimed rows are listed"
+        );
+
+        assert_eq!(table.snapshot(), table.rows(), "Nothing changed in between");
+        assert!(table.release(0), "The row was claimed");
+        assert_eq!(table.read(0), None, "A free row has no record");
+        assert_eq!(table.claim(), Some(0), "The row is claimed again");
+        assert_eq!(table.sequence(0), 2, "The sequence carries over");
+        table.destroy();
+    }
+
+    #[test]
+    fn test_compare_and_swap_record() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let table = ShmTable::<u64>::create(&memory, 1).unwrap();
+
+        let first = table.read_versioned(0);
+        let second = table.read_versioned(0);
+        assert_eq!(first.seq, 0, "The row was never written");
+        assert_eq!(
+            table.compare_and_swap_record(0, first.seq, &(first.value + 1)),
+            Ok(1),
+            "The first update wins"
+        );
+        assert_eq!(
+            table.compare_and_swap_record(0, second.seq, &(second.value + 10)),
+            Err(1),
+            "The second update sees the conflict"
+        );
+
+        let retry = table.read_versioned(0);
+        assert_eq!(
+            table.compare_and_swap_record(0, retry.seq, &(retry.value + 10)),
+            Ok(2),
+            "The retry applies on top"
+        );
+        assert_eq!(table.read_versioned(0).value, 11, "Both updates are kept");
+    }
+}
@@ -0,0 +1,154 @@
+//! Offset-based lock-free list heads with ABA protection.
+//!
+//! A head packs the offset of the first node and a counter into one 64-bit word. Every
+//! update increments the counter, so a compare-and-swap fails if the head was changed and
+//! changed back in the meantime, even though the offset looks the same.
+
+use crate::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering::SeqCst},
+    Memory, Offset,
+};
+
+/// Number of low bits holding the offset, in units of 8 bytes. This covers 8 TiB.
+const OFFSET_BITS: u32 = 40;
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+
+/// Packs a word-aligned offset and a tag into a head word.
+pub fn pack(offset: usize, tag: u64) -> u64 {
+    debug_assert!(offset.is_multiple_of(8), "Nodes are word-aligned");
+    ((offset as u64 >> 3) & OFFSET_MASK) | (tag << OFFSET_BITS)
+}
+
+/// Returns the offset packed into a head word, zero for an empty list.
+pub fn offset(word: u64) -> usize {
+    ((word & OFFSET_MASK) << 3) as usize
+}
+
+/// Returns the tag packed into a head word.
+pub fn tag(word: u64) -> u64 {
+    word >> OFFSET_BITS
+}
+
+/// A lock-free stack of nodes, linked through an atomic `next` word at the start of each node.
+pub struct TaggedStack<'a> {
+    /// Start of the memory that offsets count from, and its size.
+    base: *mut u8,
+    size: usize,
+    head: &'a AtomicU64,
+}
+
+impl<'a> TaggedStack<'a> {
+    pub fn new(memory: &'a Memory, head: &'a AtomicU64) -> Self {
+        let base = memory.pointer(Offset(0)).expect("The memory is not empty");
+        Self {
+            base,
+            size: memory.size(),
+            head,
+        }
+    }
+
+    /// Pushes a node, which must start with an `AtomicUsize` link.
+    pub fn push(&self, node: *mut u8) {
+        let node_offset = self.offset_of(node);
+        let link = unsafe { &*(node as *const AtomicUsize) };
+        let mut head = self.head.load(SeqCst);
+        loop {
+            link.store(offset(head), SeqCst);
+            let new = pack(node_offset, tag(head).wrapping_add(1));
+            match self.head.compare_exchange(head, new, SeqCst, SeqCst) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Pops the most recently pushed node.
+    pub fn pop(&self) -> Option<*mut u8> {
+        let mut head = self.head.load(SeqCst);
+        loop {
+            let first = offset(head);
+            if first == 0 {
+                return None;
+            }
+            let node = self.pointer(first);
+            // The node may be popped and reused meanwhile, then the tag check fails.
+            let next = unsafe { &*(node as *const AtomicUsize) }.load(SeqCst);
+            let new = pack(next, tag(head).wrapping_add(1));
+            match self.head.compare_exchange(head, new, SeqCst, SeqCst) {
+                Ok(_) => return Some(node),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn pointer(&self, offset: usize) -> *mut u8 {
+        assert!(offset < self.size, "Nodes are inside the memory");
+        // SAFETY: The offset is within the memory.
+        unsafe { self.base.add(offset) }
+    }
+
+    fn offset_of(&self, node: *mut u8) -> usize {
+        (node as usize)
+            .checked_sub(self.base as usize)
+            .filter(|&offset| offset < self.size)
+            .expect("Nodes are inside the memory")
+    }
+}
+
+#[cfg(all(test, loom))]
+mod tests {
+    use loom::{sync::Arc, thread};
+
+    use super::*;
+
+    /// A node made of a loom atomic, which the stack reads its link from.
+    #[repr(C, align(8))]
+    struct Node {
+        link: AtomicUsize,
+    }
+
+    fn stack<'a>(nodes: &[Node], head: &'a AtomicU64) -> TaggedStack<'a> {
+        TaggedStack {
+            base: nodes.as_ptr() as *mut u8,
+            size: std::mem::size_of_val(nodes),
+            head,
+        }
+    }
+
+    fn node(nodes: &[Node], i: usize) -> *mut u8 {
+        &nodes[i] as *const Node as *mut u8
+    }
+
+    #[test]
+    fn test_loom_push_and_pop() {
+        loom::model(|| {
+            // The first node stands for the start of the memory, as offset zero ends the list.
+            let nodes: Arc<Vec<Node>> = Arc::new(
+                (0..3)
+                    .map(|_| Node {
+                        link: AtomicUsize::new(0),
+                    })
+                    .collect(),
+            );
+            let head = Arc::new(AtomicU64::new(0));
+            stack(&nodes, &head).push(node(&nodes, 1));
+
+            let pusher = {
+                let (nodes, head) = (nodes.clone(), head.clone());
+                thread::spawn(move || stack(&nodes, &head).push(node(&nodes, 2)))
+            };
+            let mut popped: Vec<_> = stack(&nodes, &head).pop().into_iter().collect();
+            pusher.join().unwrap();
+
+            while let Some(node) = stack(&nodes, &head).pop() {
+                popped.push(node);
+            }
+            popped.sort();
+            assert_eq!(
+                popped,
+                [node(&nodes, 1), node(&nodes, 2)],
+                "Every node is popped exactly once"
+            );
+        });
+    }
+}
@@ -0,0 +1,35 @@
+//! Gauges and counters published through the `metrics` facade.
+//!
+//! Whatever recorder the application installs, e.g. a Prometheus exporter, picks them up. The
+//! values are those seen by this process: usage after its own allocations and frees, and the
+//! queue depths after its own pushes and pops.
+
+use metrics::{counter, gauge};
+
+use crate::hooks::Usage;
+
+/// Publishes the usage of the heap after an allocation or a free.
+pub fn record_usage(usage: &Usage) {
+    gauge!("rshmem_used_bytes").set(usage.used as f64);
+    gauge!("rshmem_capacity_bytes").set(usage.capacity as f64);
+    gauge!("rshmem_blocks").set(usage.blocks as f64);
+}
+
+/// Counts an allocation and whether it failed.
+pub fn record_allocation(failed: bool) {
+    counter!("rshmem_allocations_total").increment(1);
+    if failed {
+        counter!("rshmem_allocation_failures_total").increment(1);
+    }
+}
+
+/// Counts a lock that was held by someone else, and how often it was tried until it was free.
+pub fn record_contention(spins: u64) {
+    counter!("rshmem_lock_contended_total").increment(1);
+    counter!("rshmem_lock_spins_total").increment(spins);
+}
+
+/// Publishes the number of messages in the queue at `offset`.
+pub fn record_queue_depth(offset: usize, depth: usize) {
+    gauge!("rshmem_queue_depth", "queue" => offset.to_string()).set(depth as f64);
+}
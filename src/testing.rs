@@ -0,0 +1,296 @@
+//! A shadow model of the heap, for checking allocator changes against random operations.
+//!
+//! `Harness` applies alloc and free operations to an in-process memory and to a plain list
+//! of the blocks that should exist, and after each operation compares the two and checks the
+//! invariants of the heap. Sequences come from `ops` for a fixed seed, or from the proptest
+//! and quickcheck generators.
+
+use std::fmt;
+
+use proptest::{prelude::*, strategy::Strategy};
+use quickcheck::{Arbitrary, Gen};
+
+use crate::{Memory, Offset};
+
+/// Largest size the generators allocate.
+const MAX_SIZE: usize = 256;
+
+/// One step of a sequence.
+///
+/// Indices pick a live block modulo the number of live blocks, so any index is valid. Steps
+/// that pick a block while none is live do nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// `Memory::allocate` of the size.
+    Allocate(usize),
+    /// `Memory::allocate_more` of the size, with the picked block as parent.
+    AllocateMore(usize, usize),
+    /// `Memory::deallocate` of the picked block, which frees the blocks linked to it too.
+    Deallocate(usize),
+}
+
+/// An invariant that did not hold after a step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// Index of the step after which it was found.
+    pub step: usize,
+    pub op: Op,
+    pub message: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "after step {} ({:?}): {}",
+            self.step, self.op, self.message
+        )
+    }
+}
+
+impl std::error::Error for Violation {}
+
+struct Shadow {
+    data: *mut u8,
+    offset: Offset,
+    size: usize,
+    parent: Option<Offset>,
+    fill: u8,
+}
+
+/// Applies operations to a memory and to a model of it, checking both agree.
+pub struct Harness {
+    memory: Memory,
+    live: Vec<Shadow>,
+    steps: usize,
+}
+
+impl Harness {
+    /// Creates a harness over a fresh in-process memory of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        Self {
+            memory: Memory::new_in_process(size).expect("The heap is large enough"),
+            live: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    /// Returns the memory under test.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Returns the number of blocks the model expects.
+    pub fn live(&self) -> usize {
+        self.live.len()
+    }
+
+    /// Applies every operation in turn, stopping at the first violation.
+    pub fn run(&mut self, ops: &[Op]) -> Result<(), Violation> {
+        ops.iter().try_for_each(|&op| self.apply(op))
+    }
+
+    /// Applies one operation and checks the invariants afterwards.
+    ///
+    /// An allocation that fails for lack of memory is not a violation; the model then expects
+    /// no new block.
+    pub fn apply(&mut self, op: Op) -> Result<(), Violation> {
+        let step = self.steps;
+        self.steps += 1;
+        match op {
+            Op::Allocate(size) => self.allocate(size, None),
+            Op::AllocateMore(size, index) => match self.pick(index) {
+                Some(index) => self.allocate(size, Some(index)),
+                None => self.allocate(size, None),
+            },
+            Op::Deallocate(index) => {
+                if let Some(index) = self.pick(index) {
+                    let data = self.live[index].data;
+                    self.memory.deallocate(data);
+                    self.forget(self.live[index].offset);
+                }
+            }
+        }
+        self.check()
+            .map_err(|message| Violation { step, op, message })
+    }
+
+    /// Compares the heap with the model and checks the heap is consistent.
+    pub fn check(&self) -> Result<(), String> {
+        let mut expected: Vec<_> = self
+            .live
+            .iter()
+            .map(|block| (block.offset, block.size, block.parent))
+            .collect();
+        expected.sort();
+        let actual: Vec<_> = self
+            .memory
+            .blocks()
+            .iter()
+            .map(|block| (block.offset, block.size, block.parent))
+            .collect();
+        if actual != expected {
+            return Err(format!(
+                "the heap has blocks {actual:?}, expected {expected:?}"
+            ));
+        }
+
+        if let Some(pair) = actual
+            .windows(2)
+            .find(|pair| pair[0].0.get() + pair[0].1 > pair[1].0.get())
+        {
+            return Err(format!(
+                "blocks at {:?} and {:?} overlap",
+                pair[0].0, pair[1].0
+            ));
+        }
+
+        for block in &self.live {
+            // SAFETY: The block is allocated with `size` bytes, which the model filled.
+            let data = unsafe { std::slice::from_raw_parts(block.data, block.size) };
+            if data.iter().any(|&byte| byte != block.fill) {
+                return Err(format!(
+                    "the data of the block at {:?} changed",
+                    block.offset
+                ));
+            }
+        }
+
+        let usage = self.memory.usage();
+        if usage.blocks != self.live.len() {
+            return Err(format!(
+                "the heap counts {} blocks, expected {}",
+                usage.blocks,
+                self.live.len()
+            ));
+        }
+        if usage.used > usage.capacity {
+            return Err(format!(
+                "{} of {} bytes are used",
+                usage.used, usage.capacity
+            ));
+        }
+
+        let report = self.memory.scan(false);
+        if !report.corrupted.is_empty() {
+            return Err(format!("the heap is corrupted at {:?}", report.corrupted));
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, size: usize, parent: Option<usize>) {
+        let result = match parent {
+            Some(index) => self.memory.allocate_more(size, self.live[index].data),
+            None => self.memory.allocate(size),
+        };
+        let Ok(data) = result else {
+            return;
+        };
+        let data = data.as_ptr();
+        // Blocks may be rounded up, and the whole block is the caller's to write.
+        let size = self
+            .memory
+            .allocation_size(data)
+            .filter(|&actual| actual >= size)
+            .expect("The block is at least as large as requested");
+        let fill = (self.steps % 255) as u8 + 1;
+        // SAFETY: The block was just allocated with `size` bytes.
+        unsafe { data.write_bytes(fill, size) };
+        self.live.push(Shadow {
+            data,
+            offset: self
+                .memory
+                .offset_of(data)
+                .expect("Blocks are inside the memory"),
+            size,
+            parent: parent.map(|index| self.live[index].offset),
+            fill,
+        });
+    }
+
+    /// Removes a block and the blocks linked to it from the model.
+    ///
+    /// Like `deallocate`, this only reaches the direct children; their own children stay.
+    fn forget(&mut self, offset: Offset) {
+        self.live
+            .retain(|block| block.offset != offset && block.parent != Some(offset));
+    }
+
+    fn pick(&self, index: usize) -> Option<usize> {
+        (!self.live.is_empty()).then(|| index % self.live.len())
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        // The blocks left over are not leaks of the application, so do not report them.
+        self.memory.reset(false);
+    }
+}
+
+/// Returns `len` operations generated from `seed`, the same ones for the same seed.
+pub fn ops(seed: u64, len: usize) -> Vec<Op> {
+    let mut x = seed | 1;
+    let mut next = move || {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        x as usize
+    };
+    (0..len)
+        .map(|_| match next() % 3 {
+            0 => Op::Allocate(1 + next() % MAX_SIZE),
+            1 => Op::AllocateMore(1 + next() % MAX_SIZE, next()),
+            _ => Op::Deallocate(next()),
+        })
+        .collect()
+}
+
+/// Generates single operations for proptest.
+pub fn op_strategy() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        (1..=MAX_SIZE).prop_map(Op::Allocate),
+        (1..=MAX_SIZE, any::<usize>()).prop_map(|(size, index)| Op::AllocateMore(size, index)),
+        any::<usize>().prop_map(Op::Deallocate),
+    ]
+}
+
+/// Generates sequences of up to `max_len` operations for proptest.
+pub fn ops_strategy(max_len: usize) -> impl Strategy<Value = Vec<Op>> {
+    proptest::collection::vec(op_strategy(), 0..=max_len)
+}
+
+impl Arbitrary for Op {
+    fn arbitrary(g: &mut Gen) -> Self {
+        let size = 1 + usize::arbitrary(g) % MAX_SIZE;
+        match u8::arbitrary(g) % 3 {
+            0 => Op::Allocate(size),
+            1 => Op::AllocateMore(size, usize::arbitrary(g)),
+            _ => Op::Deallocate(usize::arbitrary(g)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_ops() {
+        assert_eq!(ops(7, 100), ops(7, 100), "Sequences are deterministic");
+
+        let mut harness = Harness::new(16384);
+        harness.run(&ops(7, 500)).unwrap();
+        harness.check().unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn test_generated_ops(ops in ops_strategy(64)) {
+            let mut harness = Harness::new(4096);
+            if let Err(violation) = harness.run(&ops) {
+                panic!("{violation}");
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::{
+    clock,
+    shared::SharedBlock,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset,
+};
+
+#[repr(C)]
+struct Cell {
+    /// Odd while a sample is being written, and zero until the first one is.
+    seq: AtomicU64,
+    ticks: AtomicU64,
+    rate: AtomicU64,
+    published_at: AtomicU64,
+}
+
+/// A reading of the clock of the publisher of a `ShmTimeCell`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSample {
+    /// The monotonic clock of the publisher, e.g. `QueryPerformanceCounter`.
+    pub ticks: u64,
+    /// Ticks per second.
+    pub rate: u64,
+    /// Unix time in nanoseconds when the sample was published.
+    pub published_at: u64,
+}
+
+impl TimeSample {
+    /// Returns the nanoseconds between two tick counts of the clock, zero if `later` is
+    /// before `earlier`.
+    pub fn nanos_between(&self, earlier: u64, later: u64) -> u64 {
+        let ticks = later.saturating_sub(earlier) as u128;
+        (ticks * 1_000_000_000 / self.rate.max(1) as u128) as u64
+    }
+
+    /// Returns the ticks the clock is estimated to show at unix time `unix_nanos`, from the
+    /// wall-clock time that passed since the sample was published.
+    pub fn ticks_at(&self, unix_nanos: u64) -> u64 {
+        let nanos = unix_nanos.saturating_sub(self.published_at) as u128;
+        self.ticks + (nanos * self.rate as u128 / 1_000_000_000) as u64
+    }
+}
+
+/// A cell through which one process publishes readings of its clock, so that all processes
+/// measure latencies against the same time source.
+///
+/// The samples are protected by a sequence lock: readers never wait for the lock of the
+/// heap and never see half of a sample, but retry while a sample is written. Publishing is
+/// meant for a single process at a time; concurrent publishers take turns.
+pub struct ShmTimeCell<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmTimeCell<'a> {
+    /// Creates a cell that holds no sample yet.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let size = std::mem::size_of::<Cell>();
+        let block = SharedBlock::create(memory, size)?;
+        Some(Self { block })
+    }
+
+    /// Opens a cell created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a cell that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the cell.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Publishes a reading of `ticks` of a clock running at `rate` ticks per second.
+    pub fn publish(&self, ticks: u64, rate: u64) {
+        let cell = self.cell();
+        let mut seq = cell.seq.load(SeqCst);
+        loop {
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                seq = cell.seq.load(SeqCst);
+                continue;
+            }
+            match cell.seq.compare_exchange(seq, seq + 1, SeqCst, SeqCst) {
+                Ok(_) => break,
+                Err(current) => seq = current,
+            }
+        }
+        cell.ticks.store(ticks, SeqCst);
+        cell.rate.store(rate, SeqCst);
+        cell.published_at.store(clock::unix_nanos(), SeqCst);
+        cell.seq.store(seq + 2, SeqCst);
+    }
+
+    /// Returns the latest sample, or None if none was published yet.
+    pub fn read(&self) -> Option<TimeSample> {
+        let cell = self.cell();
+        loop {
+            let seq = cell.seq.load(SeqCst);
+            if seq == 0 {
+                return None;
+            }
+            if seq % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let sample = TimeSample {
+                ticks: cell.ticks.load(SeqCst),
+                rate: cell.rate.load(SeqCst),
+                published_at: cell.published_at.load(SeqCst),
+            };
+            if cell.seq.load(SeqCst) == seq {
+                return Some(sample);
+            }
+        }
+    }
+
+    /// Frees the cell.
+    ///
+    /// Other processes must not use the cell afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn cell(&self) -> &Cell {
+        // SAFETY: The block holds the cell.
+        unsafe { &*(self.block.ptr as *const Cell) }
+    }
+}
+
+impl Memory {
+    /// Returns the time cell registered under `name`, creating it if there is none.
+    ///
+    /// Every process that asks for the same name gets the same cell. Returns None if not
+    /// enough memory.
+    pub fn time_cell(&self, name: &str) -> Option<ShmTimeCell<'_>> {
+        let block = self.named("time_cell", name, || {
+            ShmTimeCell::create(self).map(|cell| cell.block.ptr)
+        })?;
+        Some(ShmTimeCell {
+            block: SharedBlock {
+                memory: self,
+                ptr: block,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_and_read() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let cell = memory.time_cell("clock").unwrap();
+        assert_eq!(cell.read(), None, "Nothing was published yet");
+
+        cell.publish(5_000, 1_000);
+        let reader = unsafe { ShmTimeCell::open(&memory, cell.offset()) }.unwrap();
+        let sample = reader.read().unwrap();
+        assert_eq!(
+            (sample.ticks, sample.rate),
+            (5_000, 1_000),
+            "Readers see the sample"
+        );
+        assert_eq!(
+            sample.nanos_between(5_000, 5_002),
+            2_000_000,
+            "Ticks convert to nanoseconds"
+        );
+        assert_eq!(
+            sample.ticks_at(sample.published_at + 3_000_000),
+            5_003,
+            "Ticks are estimated from the wall clock"
+        );
+        assert_eq!(
+            memory.time_cell("clock").unwrap().offset(),
+            cell.offset(),
+            "The name finds the same cell"
+        );
+    }
+}
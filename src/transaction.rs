@@ -0,0 +1,120 @@
+use std::ptr::{self, NonNull};
+
+use crate::{error::AllocError, Memory};
+
+/// A group of allocations that is either kept as a whole or freed as a whole.
+///
+/// Allocations made through the transaction are regular blocks, but they are freed again
+/// unless `commit` is called. Dropping the transaction rolls it back, so an error returned
+/// halfway through building a message does not leak the blocks allocated so far.
+///
+/// Until the commit, the blocks are tagged `PENDING_TAG`, so processes walking the heap can
+/// tell them from the blocks of finished operations. A process that dies halfway leaves
+/// them tagged.
+pub struct Transaction<'a> {
+    memory: &'a Memory,
+    allocations: Vec<*mut u8>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(memory: &'a Memory) -> Self {
+        Self {
+            memory,
+            allocations: Vec::new(),
+        }
+    }
+
+    /// Allocates a new block of memory with the given size as part of the transaction.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate(&mut self, size: usize) -> Result<NonNull<u8>, AllocError> {
+        let data = self.memory.observe(size, |allocator| {
+            allocator.allocate_pending(size, ptr::null_mut())
+        })?;
+        self.allocations.push(data.as_ptr());
+        Ok(data)
+    }
+
+    /// Allocates a new block of memory linked to another block as part of the transaction.
+    ///
+    /// Returns the pointer to the allocated memory, or why there was not enough memory.
+    #[track_caller]
+    pub fn allocate_more(
+        &mut self,
+        size: usize,
+        parent: *mut u8,
+    ) -> Result<NonNull<u8>, AllocError> {
+        self.memory.uncache(parent);
+        let data = self
+            .memory
+            .observe(size, |allocator| allocator.allocate_pending(size, parent))?;
+        self.allocations.push(data.as_ptr());
+        Ok(data)
+    }
+
+    /// Keeps all allocations made by the transaction, giving them the tags they would have
+    /// had without it.
+    pub fn commit(mut self) {
+        if !self.allocations.is_empty() {
+            self.memory.allocator().publish(&self.allocations);
+        }
+        self.allocations.clear();
+    }
+
+    /// Frees all allocations made by the transaction.
+    pub fn rollback(self) {}
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        // Children are freed with their parents, so freeing them again simply fails.
+        for data in self.allocations.drain(..).rev() {
+            self.memory.deallocate(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Memory, PENDING_TAG};
+
+    #[test]
+    fn test_rollback_and_commit() {
+        let memory = Memory::new_in_process(4096).unwrap();
+
+        let mut tx = memory.begin();
+        let first = tx.allocate(4).unwrap().as_ptr();
+        let second = tx.allocate_more(4, first).unwrap().as_ptr();
+        assert!(
+            memory.blocks().iter().all(|block| block.tag == PENDING_TAG),
+            "The blocks are not published before the commit"
+        );
+        tx.rollback();
+        assert!(
+            !memory.deallocate(first),
+            "The rollback should free the blocks"
+        );
+        assert!(
+            !memory.deallocate(second),
+            "The rollback should free the blocks"
+        );
+        assert!(
+            memory.leak_report().is_empty(),
+            "Rolled back blocks are not reported as leaks"
+        );
+
+        let parent = memory.allocate_tagged(4, 3).unwrap().as_ptr();
+        let mut tx = memory.begin();
+        let kept = tx.allocate(4).unwrap().as_ptr();
+        let child = tx.allocate_more(4, parent).unwrap().as_ptr();
+        tx.commit();
+        let tags: Vec<_> = memory.blocks().iter().map(|block| block.tag).collect();
+        assert_eq!(tags, [3, 0, 3], "The commit gives the blocks their tags");
+        assert!(memory.deallocate(kept), "The commit should keep the blocks");
+        assert!(
+            memory.deallocate(child),
+            "The commit should keep the blocks"
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{
+    mutex::{MemoryGuard, MemoryMutex},
+    shared::SharedBlock,
+    Memory, Offset,
+};
+
+/// Each byte of a key takes two levels of the trie, one per nibble.
+const FANOUT: usize = 16;
+
+#[repr(C)]
+struct TrieHeader {
+    /// Offset of the root node, zero until the first insert.
+    root: usize,
+    len: usize,
+}
+
+#[repr(C)]
+struct Node {
+    children: [usize; FANOUT],
+    /// Offset plus one of the value of the key ending here, zero for none.
+    value: usize,
+}
+
+/// A prefix tree in shared memory from byte strings to offsets, e.g. from topic names to the
+/// queues of their subscribers.
+///
+/// A router process and its subscribers share the mapping directly, without serializing it,
+/// and can look up the longest registered prefix of a topic or list every key under a
+/// prefix. Keys are split into nibbles, so a node has sixteen children. Nodes are allocated
+/// as children of the trie, which lets `destroy` free everything at once; removing keys does
+/// not free nodes.
+pub struct ShmTrie<'a> {
+    block: SharedBlock<'a>,
+}
+
+impl<'a> ShmTrie<'a> {
+    const SIZE: usize = MemoryMutex::SIZE + std::mem::size_of::<TrieHeader>();
+
+    /// Creates an empty trie.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory) -> Option<Self> {
+        let block = SharedBlock::create(memory, Self::SIZE)?;
+        Some(Self { block })
+    }
+
+    /// Opens a trie created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a trie that has not been destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self { block })
+    }
+
+    /// Returns the offset other processes use to open the trie.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Maps a key to an offset, replacing the previous offset of the key.
+    ///
+    /// Returns false if there was not enough memory for the nodes.
+    pub fn insert(&self, key: &[u8], value: Offset) -> bool {
+        let guard = self.lock();
+        let header = header(&guard);
+        let mut link = &mut header.root as *mut usize;
+        for nibble in nibbles(key) {
+            // SAFETY: Links point into the header or into live nodes, under the lock.
+            let Some(node) = (unsafe { self.child(link) }) else {
+                return false;
+            };
+            link = &mut node.children[nibble];
+        }
+        // SAFETY: As above.
+        let Some(node) = (unsafe { self.child(link) }) else {
+            return false;
+        };
+        if node.value == 0 {
+            header.len += 1;
+        }
+        node.value = value.0 + 1;
+        true
+    }
+
+    /// Returns the offset of the key.
+    pub fn get(&self, key: &[u8]) -> Option<Offset> {
+        let guard = self.lock();
+        let node = self.find(header(&guard).root, nibbles(key))?;
+        value(node)
+    }
+
+    /// Returns true if the trie holds the key.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Removes the key and returns its offset.
+    pub fn remove(&self, key: &[u8]) -> Option<Offset> {
+        let guard = self.lock();
+        let header = header(&guard);
+        let node = self.find(header.root, nibbles(key))?;
+        let removed = value(node)?;
+        node.value = 0;
+        header.len -= 1;
+        Some(removed)
+    }
+
+    /// Returns the length and the offset of the longest key that is a prefix of `key`, e.g. to
+    /// route a topic to the most specific registered queue.
+    pub fn longest_prefix(&self, key: &[u8]) -> Option<(usize, Offset)> {
+        let guard = self.lock();
+        let mut current = header(&guard).root;
+        let mut found = None;
+        for (index, nibble) in nibbles(key).enumerate() {
+            if current == 0 {
+                break;
+            }
+            let node = self.node(current);
+            // Keys end on whole bytes, so only every second level can hold a value.
+            if index % 2 == 0 {
+                found = value(node).map(|value| (index / 2, value)).or(found);
+            }
+            current = node.children[nibble];
+        }
+        if current != 0 {
+            found = value(self.node(current))
+                .map(|value| (key.len(), value))
+                .or(found);
+        }
+        found
+    }
+
+    /// Returns every key that starts with `prefix` and its offset, in key order.
+    pub fn with_prefix(&self, prefix: &[u8]) -> Vec<(Vec<u8>, Offset)> {
+        let guard = self.lock();
+        let mut entries = Vec::new();
+        if let Some(node) = self.find(header(&guard).root, nibbles(prefix)) {
+            let mut key = nibbles(prefix).collect();
+            self.collect(node, &mut key, &mut entries);
+        }
+        entries
+    }
+
+    /// Returns the number of keys in the trie.
+    pub fn len(&self) -> usize {
+        header(&self.lock()).len
+    }
+
+    /// Returns true if the trie is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Frees the trie and all of its nodes.
+    ///
+    /// Other processes must not use the trie afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn lock(&self) -> MemoryGuard<'_> {
+        self.block.lock(Self::SIZE)
+    }
+
+    /// Returns the node a link points to, allocating it if the link is empty.
+    ///
+    /// # Safety
+    /// The link must be valid for writes, with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn child(&self, link: *mut usize) -> Option<&mut Node> {
+        if *link == 0 {
+            let size = std::mem::size_of::<Node>();
+            let data = self
+                .block
+                .memory
+                .allocate_more(size, self.block.ptr)
+                .ok()?
+                .as_ptr();
+            data.write_bytes(0, size);
+            *link = self
+                .block
+                .memory
+                .offset_of(data)
+                .expect("Nodes are inside the memory")
+                .0;
+        }
+        Some(self.node(*link))
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn find(&self, root: usize, path: impl Iterator<Item = usize>) -> Option<&mut Node> {
+        let mut current = root;
+        for nibble in path {
+            if current == 0 {
+                return None;
+            }
+            current = self.node(current).children[nibble];
+        }
+        (current != 0).then(|| self.node(current))
+    }
+
+    /// Must be called with the lock held.
+    fn collect(&self, node: &Node, key: &mut Vec<usize>, entries: &mut Vec<(Vec<u8>, Offset)>) {
+        if let Some(value) = value(node) {
+            let bytes = key
+                .chunks(2)
+                .map(|pair| (pair[0] << 4 | pair[1]) as u8)
+                .collect();
+            entries.push((bytes, value));
+        }
+        for (nibble, &child) in node.children.iter().enumerate() {
+            if child != 0 {
+                key.push(nibble);
+                self.collect(self.node(child), key, entries);
+                key.pop();
+            }
+        }
+    }
+
+    /// Must be called with the lock held.
+    #[allow(clippy::mut_from_ref)]
+    fn node(&self, offset: usize) -> &mut Node {
+        let data = self
+            .block
+            .memory
+            .pointer(Offset(offset))
+            .expect("Nodes are inside the memory");
+        // SAFETY: Nodes are only linked once they are zeroed, and only changed under the lock.
+        unsafe { &mut *(data as *mut Node) }
+    }
+}
+
+/// Returns the trie header, which the guard protects.
+#[allow(clippy::mut_from_ref)]
+fn header<'g>(guard: &'g MemoryGuard) -> &'g mut TrieHeader {
+    // SAFETY: The header directly follows the lock word.
+    unsafe { &mut *(guard.buffer() as *mut TrieHeader) }
+}
+
+fn nibbles(key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    key.iter()
+        .flat_map(|&byte| [(byte >> 4) as usize, (byte & 0xf) as usize])
+}
+
+fn value(node: &Node) -> Option<Offset> {
+    node.value.checked_sub(1).map(Offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_route_and_list() {
+        let memory = Memory::new_in_process(16384).unwrap();
+        let trie = ShmTrie::create(&memory).unwrap();
+
+        assert!(trie.insert(b"orders", Offset(100)), "The nodes should fit");
+        assert!(
+            trie.insert(b"orders.eu", Offset(200)),
+            "The nodes should fit"
+        );
+        assert!(trie.insert(b"prices", Offset(300)), "The nodes should fit");
+        assert!(trie.insert(b"orders", Offset(101)), "The key is replaced");
+        assert_eq!(trie.len(), 3, "Replacing a key keeps the count");
+
+        assert_eq!(
+            trie.get(b"orders"),
+            Some(Offset(101)),
+            "The new offset wins"
+        );
+        assert_eq!(trie.get(b"order"), None, "Prefixes are not keys");
+        assert_eq!(
+            trie.longest_prefix(b"orders.eu.de"),
+            Some((9, Offset(200))),
+            "The most specific key wins"
+        );
+        assert_eq!(
+            trie.longest_prefix(b"orders.us"),
+            Some((6, Offset(101))),
+            "Shorter keys are found too"
+        );
+        assert_eq!(trie.longest_prefix(b"trades"), None, "No key matches");
+        assert_eq!(
+            trie.with_prefix(b"orders"),
+            vec![
+                (b"orders".to_vec(), Offset(101)),
+                (b"orders.eu".to_vec(), Offset(200))
+            ],
+            "Keys under the prefix are listed in order"
+        );
+
+        assert_eq!(
+            trie.remove(b"orders"),
+            Some(Offset(101)),
+            "The key was there"
+        );
+        assert_eq!(trie.remove(b"orders"), None, "The key is gone");
+        assert_eq!(
+            trie.longest_prefix(b"orders.eu"),
+            Some((9, Offset(200))),
+            "Longer keys stay"
+        );
+        trie.destroy();
+    }
+}
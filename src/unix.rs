@@ -0,0 +1,95 @@
+use std::{error::Error, io, time::Duration};
+
+pub use std::ffi::c_void;
+
+use crate::Protection;
+
+/// Named file mappings are a Windows object. Elsewhere memories are backed by a System V
+/// segment, see `MemoryBuilder::sysv_key`, or by the heap of the process.
+const UNSUPPORTED: &str = "Named file mappings are only available on Windows";
+
+pub unsafe fn open_memory(
+    _name: &str,
+    _size: usize,
+    _base_address: *mut c_void,
+    _sddl: Option<&str>,
+) -> Result<(*mut c_void, *mut c_void), Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+pub unsafe fn open_existing_memory(
+    _name: &str,
+    _size: usize,
+    _base_address: *mut c_void,
+    _write: bool,
+) -> Result<(*mut c_void, *mut c_void), Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+/// Offsets of views are multiples of this.
+pub const ALLOCATION_GRANULARITY: usize = 4096;
+
+pub unsafe fn open_mapping(_name: &str, _write: bool) -> Result<*mut c_void, Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+pub unsafe fn close_mapping(_file: *mut c_void) {}
+
+pub unsafe fn map_view(
+    _file: *mut c_void,
+    _offset: usize,
+    _size: usize,
+    _write: bool,
+) -> Result<*mut c_void, Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+pub unsafe fn unmap_view(_buffer: *mut c_void) {}
+
+/// Changes the protection of the pages at `address` for this process.
+pub unsafe fn protect(
+    address: *mut c_void,
+    len: usize,
+    protection: Protection,
+) -> Result<(), Box<dyn Error>> {
+    let flags = match protection {
+        Protection::ReadOnly => libc::PROT_READ,
+        Protection::ReadWrite => libc::PROT_READ | libc::PROT_WRITE,
+        Protection::NoAccess => libc::PROT_NONE,
+    };
+    if libc::mprotect(address, len, flags) != 0 {
+        return Err(format!("Could not protect: {}", io::Error::last_os_error()).into());
+    }
+    Ok(())
+}
+
+pub unsafe fn release_memory(_file: *mut c_void, _buffer: *mut c_void) {}
+
+/// Returns true if a process with the id `pid` exists.
+pub fn process_alive(pid: u32) -> bool {
+    // Zero and negative ids would signal process groups.
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    if pid <= 0 {
+        return false;
+    }
+    // SAFETY: Signal zero only checks that the process exists.
+    unsafe {
+        libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+pub unsafe fn open_event(_name: &str) -> Result<*mut c_void, Box<dyn Error>> {
+    Err("Named events are only available on Windows".into())
+}
+
+pub unsafe fn set_event(_event: *mut c_void) {}
+
+pub unsafe fn reset_event(_event: *mut c_void) {}
+
+pub unsafe fn wait_event(_event: *mut c_void, _timeout: Duration) -> bool {
+    false
+}
+
+pub unsafe fn close_event(_event: *mut c_void) {}
@@ -0,0 +1,98 @@
+use crate::{Memory, ShmSafe};
+
+impl Memory {
+    /// Returns the bytes reserved for the application header with
+    /// `MemoryBuilder::user_header`, zero if there is none.
+    pub fn user_header_len(&self) -> usize {
+        self.inspector().user_header().1
+    }
+
+    /// Returns a copy of the start of the application header as a `T`, read under the lock
+    /// of the heap, or None if the header is smaller than `T`.
+    ///
+    /// A header nobody wrote yet is all zeros.
+    pub fn read_user_header<T: ShmSafe>(&self) -> Option<T> {
+        let inspector = self.inspector();
+        let (start, len) = inspector.user_header();
+        check_fits::<T>(len)?;
+        // SAFETY: The header holds at least a `T` and the lock keeps writers out.
+        Some(unsafe { (start as *const T).read_volatile() })
+    }
+
+    /// Writes `value` to the start of the application header under the lock of the heap.
+    ///
+    /// Returns false if the header is smaller than `T`. Panics if the memory is read-only.
+    pub fn write_user_header<T: ShmSafe>(&self, value: &T) -> bool {
+        let allocator = self.allocator();
+        let (start, len) = allocator.user_header();
+        if check_fits::<T>(len).is_none() {
+            return false;
+        }
+        // SAFETY: The header holds at least a `T` and the lock keeps others out.
+        unsafe { (start as *mut T).write_volatile(*value) };
+        true
+    }
+}
+
+fn check_fits<T>(len: usize) -> Option<()> {
+    assert!(
+        std::mem::align_of::<T>() <= std::mem::align_of::<usize>(),
+        "The header is only word-aligned"
+    );
+    (std::mem::size_of::<T>() <= len).then_some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Memory, ShmSafe};
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct AppHeader {
+        version: u32,
+        queue: usize,
+    }
+
+    // SAFETY: Plain numbers, valid for any bytes.
+    unsafe impl ShmSafe for AppHeader {}
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_user_header() {
+        let memory = Memory::builder("rshmem_test_user_header", 4096)
+            .user_header(12)
+            .build()
+            .unwrap();
+        assert_eq!(memory.user_header_len(), 16, "The length is rounded up");
+        assert_eq!(
+            memory.read_user_header::<AppHeader>(),
+            Some(AppHeader {
+                version: 0,
+                queue: 0
+            }),
+            "The header starts zeroed"
+        );
+
+        let header = AppHeader {
+            version: 2,
+            queue: 640,
+        };
+        assert!(memory.write_user_header(&header), "The header fits");
+        let block = memory.allocate(16).unwrap().as_ptr();
+        unsafe { block.write_bytes(0xff, 16) };
+        memory.reset(false);
+        let peer = Memory::new("rshmem_test_user_header", 4096, 0).unwrap();
+        assert_eq!(
+            peer.read_user_header(),
+            Some(header),
+            "Peers see the header, which survives resets"
+        );
+        assert!(
+            !peer.write_user_header(&[0u64; 4]),
+            "Larger values do not fit"
+        );
+    }
+}
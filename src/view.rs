@@ -0,0 +1,183 @@
+use std::{error::Error, marker::PhantomData, ops::Range};
+
+use crate::{
+    memory::Backend,
+    platform::{self, c_void},
+    Memory, Offset,
+};
+
+/// A view of part of the section of a memory, mapped on its own.
+///
+/// A process that cannot fit a multi-gigabyte memory into its address space, like a 32-bit
+/// peer, maps only the window it works with, and a process may map several windows of the
+/// same section at once. Pointers of a view are only valid inside it, so blocks are found by
+/// their offsets in the whole memory, which are the same in every view.
+///
+/// A view does not see the heap unless its window covers it, so it cannot allocate or free;
+/// that is left to processes that map the whole memory.
+pub struct MemoryView<'a> {
+    /// Start of the mapped view.
+    base: *mut u8,
+    /// Offset in the memory that `base` is at, which is aligned down from the window.
+    base_offset: usize,
+    range: Range<usize>,
+    write: bool,
+    /// Whether `base` is a view this one mapped and unmaps when dropped.
+    own_view: bool,
+    /// The mapping handle the view opened itself, closed when dropped.
+    file: Option<*mut c_void>,
+    _memory: PhantomData<&'a Memory>,
+}
+
+impl Memory {
+    /// Maps another view of the `len` bytes from `offset` of the memory.
+    ///
+    /// The view is writable unless the memory is read-only. Only a named file mapping has a
+    /// section to map; views of other memories point into the memory itself.
+    ///
+    /// Fails if the range is out of bounds or the view could not be mapped.
+    pub fn map_range(&self, offset: Offset, len: usize) -> Result<MemoryView<'_>, Box<dyn Error>> {
+        let end = offset.0.checked_add(len).filter(|&end| end <= self.size());
+        if end.is_none() {
+            return Err("The range is out of bounds".into());
+        }
+        let write = !self.is_read_only();
+        match self.backend() {
+            // SAFETY: The file handle stays valid while the memory is borrowed.
+            Backend::Mapping(file) => unsafe { MemoryView::map(*file, offset.0, len, write, None) },
+            _ => Ok(MemoryView {
+                // SAFETY: Only the address is taken.
+                base: unsafe { self.buffer() },
+                base_offset: 0,
+                range: offset.0..offset.0 + len,
+                write,
+                own_view: false,
+                file: None,
+                _memory: PhantomData,
+            }),
+        }
+    }
+}
+
+impl MemoryView<'static> {
+    /// Maps the `len` bytes from `offset` of the memory another process created under `name`,
+    /// without mapping the rest of it.
+    ///
+    /// Fails if there is no such memory or the range is outside of it.
+    pub fn open(
+        name: &str,
+        offset: Offset,
+        len: usize,
+        write: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        // SAFETY: The handle is closed by the view, or right away if mapping fails.
+        unsafe {
+            let file = platform::open_mapping(name, write)?;
+            Self::map(file, offset.0, len, write, Some(file)).inspect_err(|_| {
+                platform::close_mapping(file);
+            })
+        }
+    }
+}
+
+impl<'a> MemoryView<'a> {
+    unsafe fn map(
+        file: *mut c_void,
+        offset: usize,
+        len: usize,
+        write: bool,
+        owned: Option<*mut c_void>,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Views start on the allocation granularity, so the window may be preceded by a bit.
+        let base_offset = offset - offset % platform::ALLOCATION_GRANULARITY;
+        let base = platform::map_view(file, base_offset, offset + len - base_offset, write)?;
+        Ok(Self {
+            base: base as *mut u8,
+            base_offset,
+            range: offset..offset + len,
+            write,
+            own_view: true,
+            file: owned,
+            _memory: PhantomData,
+        })
+    }
+
+    /// Returns the offsets of the memory the view covers.
+    pub fn range(&self) -> Range<Offset> {
+        Offset(self.range.start)..Offset(self.range.end)
+    }
+
+    /// Returns true if the view may be written to.
+    pub fn is_writable(&self) -> bool {
+        self.write
+    }
+
+    /// Returns the pointer in this view for an offset of the memory, or None if the view
+    /// does not cover it.
+    pub fn pointer(&self, offset: Offset) -> Option<*mut u8> {
+        // SAFETY: The offset is inside the view.
+        self.range
+            .contains(&offset.0)
+            .then(|| unsafe { self.base.add(offset.0 - self.base_offset) })
+    }
+
+    /// Returns the offset in the memory of a pointer into this view, or None if it is not
+    /// inside the window.
+    pub fn offset_of(&self, pointer: *mut u8) -> Option<Offset> {
+        let offset = (pointer as usize).checked_sub(self.base as usize)? + self.base_offset;
+        self.range.contains(&offset).then_some(Offset(offset))
+    }
+}
+
+impl<'a> Drop for MemoryView<'a> {
+    fn drop(&mut self) {
+        // SAFETY: The view and the handle were opened by this view and nothing else uses them.
+        unsafe {
+            if self.own_view {
+                platform::unmap_view(self.base as *mut _);
+            }
+            if let Some(file) = self.file {
+                platform::close_mapping(file);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg_attr(
+        not(windows),
+        ignore = "Named file mappings are only available on Windows"
+    )]
+    fn test_windows_share_the_section() {
+        let memory = Memory::new("rshmem_test_view", 4096, 0).unwrap();
+        let data = memory.allocate(16).unwrap().as_ptr();
+        let offset = memory.offset_of(data).unwrap();
+
+        let view = memory.map_range(offset, 16).unwrap();
+        let window = MemoryView::open("rshmem_test_view", offset, 16, false).unwrap();
+        assert!(!window.is_writable(), "The window was opened read-only");
+        unsafe { *view.pointer(offset).unwrap() = 42 };
+        assert_eq!(unsafe { *data }, 42, "The views map the same bytes");
+        assert_eq!(
+            unsafe { *window.pointer(offset).unwrap() },
+            42,
+            "The views map the same bytes"
+        );
+
+        let end = Offset::new(offset.get() + 16);
+        assert!(view.pointer(end).is_none(), "The end is outside the window");
+        assert_eq!(
+            window.offset_of(window.pointer(offset).unwrap()),
+            Some(offset),
+            "Pointers map back to offsets"
+        );
+        assert!(
+            memory.map_range(offset, 4096).is_err(),
+            "Windows stay inside the memory"
+        );
+    }
+}
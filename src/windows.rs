@@ -1,54 +1,105 @@
 use std::{
     error::Error,
     ffi::{CStr, CString},
+    sync::OnceLock,
+    time::Duration,
 };
 
+pub use winapi::ctypes::c_void;
 use winapi::{
-    ctypes::c_void,
+    shared::{
+        sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorA, SDDL_REVISION_1},
+        winerror::{ERROR_ACCESS_DENIED, WAIT_TIMEOUT},
+    },
     um::{
         errhandlingapi::GetLastError,
         handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
-        memoryapi::{MapViewOfFileEx, UnmapViewOfFile, FILE_MAP_ALL_ACCESS},
+        libloaderapi::{GetModuleHandleA, GetProcAddress},
+        memoryapi::{
+            MapViewOfFileEx, UnmapViewOfFile, VirtualFree, VirtualProtect, FILE_MAP_ALL_ACCESS,
+            FILE_MAP_READ,
+        },
+        minwinbase::SECURITY_ATTRIBUTES,
+        processthreadsapi::OpenProcess,
+        synchapi::{CreateEventA, ResetEvent, SetEvent, WaitForSingleObject},
         winbase::{
-            CreateFileMappingA, FormatMessageA, LocalFree, FORMAT_MESSAGE_ALLOCATE_BUFFER,
-            FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+            CreateFileMappingA, FormatMessageA, LocalFree, OpenFileMappingA,
+            FORMAT_MESSAGE_ALLOCATE_BUFFER, FORMAT_MESSAGE_FROM_SYSTEM,
+            FORMAT_MESSAGE_IGNORE_INSERTS, WAIT_OBJECT_0,
+        },
+        winnt::{
+            MEM_RELEASE, MEM_RESERVE, PAGE_NOACCESS, PAGE_READONLY, PAGE_READWRITE, SYNCHRONIZE,
         },
-        winnt::PAGE_READWRITE,
     },
 };
 
+use crate::Protection;
+
+/// Security descriptor that lets AppContainer and low-integrity processes open a mapping: full
+/// access for the system, administrators and the owner, read/write for all application
+/// packages, and a low mandatory label.
+pub const SANDBOX_SDDL: &str =
+    "D:(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)(A;;GRGW;;;AC)S:(ML;;NW;;;LW)";
+
+/// Security descriptor that lets the processes of every signed-in user open a mapping: full
+/// access for the system, administrators and the owner, read/write for authenticated users.
+pub const USERS_SDDL: &str = "D:(A;;GA;;;SY)(A;;GA;;;BA)(A;;GA;;;OW)(A;;GRGW;;;AU)";
+
 /// Creates or opens a named file mapping object for a specified file with mapped view of the file.
+///
+/// A new mapping gets the security descriptor in `sddl` if there is one, otherwise the
+/// default security of the process.
 pub unsafe fn open_memory(
     name: &str,
     size: usize,
     base_address: *mut c_void,
+    sddl: Option<&str>,
 ) -> Result<(*mut c_void, *mut c_void), Box<dyn Error>> {
     let high_size: u32 = ((size as u64 & 0xFFFF_FFFF_0000_0000_u64) >> 32) as u32;
     let low_size: u32 = (size as u64 & 0xFFFF_FFFF_u64) as u32;
     let name = CString::new(name)?;
 
+    let mut attributes = SECURITY_ATTRIBUTES {
+        nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: std::ptr::null_mut(),
+        bInheritHandle: 0,
+    };
+    if let Some(sddl) = sddl {
+        let sddl = CString::new(sddl)?;
+        let converted = ConvertStringSecurityDescriptorToSecurityDescriptorA(
+            sddl.as_ptr(),
+            SDDL_REVISION_1 as u32,
+            &mut attributes.lpSecurityDescriptor,
+            std::ptr::null_mut(),
+        );
+        if converted == 0 {
+            let error = get_last_error_as_string();
+            return Err(format!("Invalid security descriptor: {}", error).into());
+        }
+    }
+
     let file = CreateFileMappingA(
         INVALID_HANDLE_VALUE, // use paging file
-        std::ptr::null_mut(), // default security
-        PAGE_READWRITE,       // read/write access
-        high_size,            // maximum object size (high-order DWORD)
-        low_size,             // maximum object size (low-order DWORD)
+        if sddl.is_some() {
+            &mut attributes
+        } else {
+            std::ptr::null_mut() // default security
+        },
+        PAGE_READWRITE, // read/write access
+        high_size,      // maximum object size (high-order DWORD)
+        low_size,       // maximum object size (low-order DWORD)
         name.as_ptr(),
     );
+    if !attributes.lpSecurityDescriptor.is_null() {
+        LocalFree(attributes.lpSecurityDescriptor);
+    }
 
     if file.is_null() {
         let error = get_last_error_as_string();
         return Err(format!("Could not create file mapping object: {}", error).into());
     }
 
-    let buffer = MapViewOfFileEx(
-        file,                // handle to map object
-        FILE_MAP_ALL_ACCESS, // read/write permission
-        0,
-        0,
-        size,
-        base_address,
-    );
+    let buffer = map_view_at(file, FILE_MAP_ALL_ACCESS, size, base_address);
 
     if buffer.is_null() {
         CloseHandle(file);
@@ -60,6 +111,273 @@ pub unsafe fn open_memory(
     Ok((file, buffer))
 }
 
+/// Opens an existing named file mapping object with a view of the file, read-only unless
+/// `write` is set.
+pub unsafe fn open_existing_memory(
+    name: &str,
+    size: usize,
+    base_address: *mut c_void,
+    write: bool,
+) -> Result<(*mut c_void, *mut c_void), Box<dyn Error>> {
+    let name = CString::new(name)?;
+    let access = if write {
+        FILE_MAP_ALL_ACCESS
+    } else {
+        FILE_MAP_READ
+    };
+    let file = OpenFileMappingA(access, 0, name.as_ptr());
+
+    if file.is_null() {
+        let error = get_last_error_as_string();
+        return Err(format!("Could not open file mapping object: {}", error).into());
+    }
+
+    let buffer = map_view_at(file, access, size, base_address);
+
+    if buffer.is_null() {
+        CloseHandle(file);
+
+        let error = get_last_error_as_string();
+        return Err(format!("Could not map view of file: {:?}", error).into());
+    }
+
+    Ok((file, buffer))
+}
+
+/// Granularity of the offsets views can start at.
+pub const ALLOCATION_GRANULARITY: usize = 65536;
+
+/// Opens an existing named file mapping object without mapping a view of it.
+pub unsafe fn open_mapping(name: &str, write: bool) -> Result<*mut c_void, Box<dyn Error>> {
+    let name = CString::new(name)?;
+    let access = if write {
+        FILE_MAP_ALL_ACCESS
+    } else {
+        FILE_MAP_READ
+    };
+    let file = OpenFileMappingA(access, 0, name.as_ptr());
+
+    if file.is_null() {
+        let error = get_last_error_as_string();
+        return Err(format!("Could not open file mapping object: {}", error).into());
+    }
+
+    Ok(file)
+}
+
+/// Closes a handle of `open_mapping`.
+pub unsafe fn close_mapping(file: *mut c_void) {
+    CloseHandle(file);
+}
+
+/// Maps another view of `size` bytes of a file mapping object from `offset`, e.g. a
+/// read-only one next to a writable one.
+///
+/// The offset must be a multiple of `ALLOCATION_GRANULARITY`.
+pub unsafe fn map_view(
+    file: *mut c_void,
+    offset: usize,
+    size: usize,
+    write: bool,
+) -> Result<*mut c_void, Box<dyn Error>> {
+    let access = if write {
+        FILE_MAP_ALL_ACCESS
+    } else {
+        FILE_MAP_READ
+    };
+    let high = (offset as u64 >> 32) as u32;
+    let low = offset as u32;
+    let buffer = MapViewOfFileEx(file, access, high, low, size, std::ptr::null_mut());
+
+    if buffer.is_null() {
+        let error = get_last_error_as_string();
+        return Err(format!("Could not map view of file: {:?}", error).into());
+    }
+
+    Ok(buffer)
+}
+
+/// Flags of `VirtualAlloc2` and `MapViewOfFile3`, which winapi does not define.
+const MEM_RESERVE_PLACEHOLDER: u32 = 0x0004_0000;
+const MEM_REPLACE_PLACEHOLDER: u32 = 0x0000_4000;
+
+type VirtualAlloc2 = unsafe extern "system" fn(
+    process: *mut c_void,
+    base_address: *mut c_void,
+    size: usize,
+    allocation_type: u32,
+    page_protection: u32,
+    extended_parameters: *mut c_void,
+    parameter_count: u32,
+) -> *mut c_void;
+
+type MapViewOfFile3 = unsafe extern "system" fn(
+    file: *mut c_void,
+    process: *mut c_void,
+    base_address: *mut c_void,
+    offset: u64,
+    view_size: usize,
+    allocation_type: u32,
+    page_protection: u32,
+    extended_parameters: *mut c_void,
+    parameter_count: u32,
+) -> *mut c_void;
+
+/// Returns the placeholder APIs, which only Windows 10 1803 and later have.
+fn placeholder_api() -> Option<(VirtualAlloc2, MapViewOfFile3)> {
+    static API: OnceLock<Option<(usize, usize)>> = OnceLock::new();
+    let (alloc, map) = (*API.get_or_init(|| unsafe {
+        let module = GetModuleHandleA(c"kernelbase.dll".as_ptr());
+        if module.is_null() {
+            return None;
+        }
+        let alloc = GetProcAddress(module, c"VirtualAlloc2".as_ptr());
+        let map = GetProcAddress(module, c"MapViewOfFile3".as_ptr());
+        (!alloc.is_null() && !map.is_null()).then_some((alloc as usize, map as usize))
+    }))?;
+    // SAFETY: The addresses are of the functions of these names, which have these signatures.
+    unsafe {
+        Some((
+            std::mem::transmute::<usize, VirtualAlloc2>(alloc),
+            std::mem::transmute::<usize, MapViewOfFile3>(map),
+        ))
+    }
+}
+
+/// Maps a view of the whole mapping at `base_address`, or anywhere if it is null.
+///
+/// A fixed address is first reserved as a placeholder, which the view then replaces, so no
+/// other allocation of the process can take the range in between and a failure says whether
+/// the range was taken or the view could not be mapped. Without the placeholder APIs, or for
+/// sizes that are not whole pages, the view is mapped with `MapViewOfFileEx` directly.
+unsafe fn map_view_at(
+    file: *mut c_void,
+    access: u32,
+    size: usize,
+    base_address: *mut c_void,
+) -> *mut c_void {
+    if let (false, true, Some((alloc, map))) = (
+        base_address.is_null(),
+        size.is_multiple_of(4096),
+        placeholder_api(),
+    ) {
+        let placeholder = alloc(
+            std::ptr::null_mut(),
+            base_address,
+            size,
+            MEM_RESERVE | MEM_RESERVE_PLACEHOLDER,
+            PAGE_NOACCESS,
+            std::ptr::null_mut(),
+            0,
+        );
+        if !placeholder.is_null() {
+            let protection = if access == FILE_MAP_READ {
+                PAGE_READONLY
+            } else {
+                PAGE_READWRITE
+            };
+            let view = map(
+                file,
+                std::ptr::null_mut(),
+                placeholder,
+                0,
+                size,
+                MEM_REPLACE_PLACEHOLDER,
+                protection,
+                std::ptr::null_mut(),
+                0,
+            );
+            if !view.is_null() {
+                return view;
+            }
+            VirtualFree(placeholder, 0, MEM_RELEASE);
+        }
+    }
+
+    MapViewOfFileEx(
+        file,   // handle to map object
+        access, // read or read/write permission
+        0,
+        0,
+        size,
+        base_address,
+    )
+}
+
+/// Unmaps a view mapped with `map_view`, leaving the file handle open.
+pub unsafe fn unmap_view(buffer: *mut c_void) {
+    UnmapViewOfFile(buffer as *mut _);
+}
+
+/// Changes the protection of the pages of a view for this process.
+pub unsafe fn protect(
+    address: *mut c_void,
+    len: usize,
+    protection: Protection,
+) -> Result<(), Box<dyn Error>> {
+    let flags = match protection {
+        Protection::ReadOnly => PAGE_READONLY,
+        Protection::ReadWrite => PAGE_READWRITE,
+        Protection::NoAccess => PAGE_NOACCESS,
+    };
+    let mut old = 0;
+    if VirtualProtect(address, len, flags, &mut old) == 0 {
+        let error = get_last_error_as_string();
+        return Err(format!("Could not change the page protection: {}", error).into());
+    }
+    Ok(())
+}
+
+/// Creates or opens a named manual-reset event, initially not signaled.
+pub unsafe fn open_event(name: &str) -> Result<*mut c_void, Box<dyn Error>> {
+    let name = CString::new(name)?;
+    let event = CreateEventA(std::ptr::null_mut(), 1, 0, name.as_ptr());
+
+    if event.is_null() {
+        let error = get_last_error_as_string();
+        return Err(format!("Could not create event: {}", error).into());
+    }
+
+    Ok(event)
+}
+
+/// Signals an event of `open_event`, waking every waiter until it is reset.
+pub unsafe fn set_event(event: *mut c_void) {
+    SetEvent(event);
+}
+
+pub unsafe fn reset_event(event: *mut c_void) {
+    ResetEvent(event);
+}
+
+/// Waits until an event of `open_event` is signaled or `timeout` passed.
+///
+/// Returns true if it was signaled.
+pub unsafe fn wait_event(event: *mut c_void, timeout: Duration) -> bool {
+    // A timeout of u32::MAX milliseconds would mean INFINITE.
+    let millis = timeout.as_millis().min(u32::MAX as u128 - 1) as u32;
+    WaitForSingleObject(event, millis) == WAIT_OBJECT_0
+}
+
+pub unsafe fn close_event(event: *mut c_void) {
+    CloseHandle(event);
+}
+
+/// Returns true if a process with the id `pid` is running.
+pub fn process_alive(pid: u32) -> bool {
+    // SAFETY: The handle is only used here and closed before returning.
+    unsafe {
+        let process = OpenProcess(SYNCHRONIZE, 0, pid);
+        if process.is_null() {
+            // Processes of other users cannot be opened, but they exist.
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+        let alive = WaitForSingleObject(process, 0) == WAIT_TIMEOUT;
+        CloseHandle(process);
+        alive
+    }
+}
+
 // Releases file handle and file view.
 pub unsafe fn release_memory(file: *mut c_void, buffer: *mut c_void) {
     UnmapViewOfFile(buffer as *mut _);
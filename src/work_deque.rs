@@ -0,0 +1,203 @@
+use std::marker::PhantomData;
+
+use crate::{
+    shared::{values_start, SharedBlock},
+    sync::{
+        atomic::{AtomicI64, Ordering::SeqCst},
+        hint,
+    },
+    Memory, Offset, ShmSafe,
+};
+
+#[repr(C)]
+struct DequeHeader {
+    /// Index of the oldest value, which thieves take.
+    top: AtomicI64,
+    /// Index after the newest value, which only the owner moves.
+    bottom: AtomicI64,
+    capacity: usize,
+}
+
+/// A Chase–Lev work-stealing deque in shared memory.
+///
+/// One owner process pushes and pops tasks at the bottom without contention, while any
+/// number of other processes steal the oldest tasks from the top, so idle workers of a job
+/// system can take work from busy ones. Only the last task makes the owner race with
+/// thieves, which is settled with a compare-and-swap on the top.
+///
+/// The capacity is fixed at creation; `push` fails once it is reached, as the buffer cannot
+/// grow while thieves may be reading it.
+pub struct ShmWorkDeque<'a, T: ShmSafe> {
+    block: SharedBlock<'a>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: ShmSafe> ShmWorkDeque<'a, T> {
+    const SLOTS: usize = values_start::<T>(std::mem::size_of::<DequeHeader>());
+
+    /// Creates an empty deque for `capacity` tasks, rounded up to a power of two.
+    ///
+    /// Returns None if not enough memory.
+    pub fn create(memory: &'a Memory, capacity: usize) -> Option<Self> {
+        let capacity = capacity.max(1).next_power_of_two();
+        let size = Self::SLOTS + capacity * std::mem::size_of::<T>();
+        let block = SharedBlock::create(memory, size)?;
+        // SAFETY: The block was just allocated with `size` bytes and nobody else can see it.
+        unsafe {
+            (*(block.ptr as *mut DequeHeader)).capacity = capacity;
+        }
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Opens a deque created by another process.
+    ///
+    /// Returns None if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// The offset must come from `offset` of a deque of the same `T` that has not been
+    /// destroyed.
+    pub unsafe fn open(memory: &'a Memory, offset: Offset) -> Option<Self> {
+        let block = SharedBlock::open(memory, offset)?;
+        Some(Self {
+            block,
+            _value: PhantomData,
+        })
+    }
+
+    /// Returns the offset other processes use to open the deque.
+    pub fn offset(&self) -> Offset {
+        self.block.offset()
+    }
+
+    /// Returns the number of tasks the deque holds at most.
+    pub fn capacity(&self) -> usize {
+        self.header().capacity
+    }
+
+    /// Returns the number of tasks in the deque, which may be stale by the time it returns.
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        let bottom = header.bottom.load(SeqCst);
+        let top = header.top.load(SeqCst);
+        (bottom - top).max(0) as usize
+    }
+
+    /// Returns true if the deque is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pushes a task at the bottom. Only the owner may call this.
+    ///
+    /// Returns the task back if the deque is full.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let header = self.header();
+        let bottom = header.bottom.load(SeqCst);
+        let top = header.top.load(SeqCst);
+        if bottom - top >= header.capacity as i64 {
+            return Err(value);
+        }
+        // SAFETY: The slot is free, as thieves only read slots between the top and the bottom.
+        unsafe { self.slot(bottom).write_volatile(value) };
+        header.bottom.store(bottom + 1, SeqCst);
+        Ok(())
+    }
+
+    /// Pops the newest task from the bottom. Only the owner may call this.
+    pub fn pop(&self) -> Option<T> {
+        let header = self.header();
+        let bottom = header.bottom.load(SeqCst) - 1;
+        header.bottom.store(bottom, SeqCst);
+        let top = header.top.load(SeqCst);
+        if top > bottom {
+            header.bottom.store(bottom + 1, SeqCst);
+            return None;
+        }
+
+        // SAFETY: The slot holds a task that thieves can no longer reach, except the last one.
+        let value = unsafe { self.slot(bottom).read_volatile() };
+        if top < bottom {
+            return Some(value);
+        }
+        // The last task: whoever moves the top past it gets it.
+        let won = header
+            .top
+            .compare_exchange(top, top + 1, SeqCst, SeqCst)
+            .is_ok();
+        header.bottom.store(bottom + 1, SeqCst);
+        won.then_some(value)
+    }
+
+    /// Steals the oldest task from the top. Any process may call this.
+    ///
+    /// Returns None if the deque is empty.
+    pub fn steal(&self) -> Option<T> {
+        let header = self.header();
+        loop {
+            let top = header.top.load(SeqCst);
+            let bottom = header.bottom.load(SeqCst);
+            if top >= bottom {
+                return None;
+            }
+            // SAFETY: `T` is valid for any bytes, and the value is only kept if the top did not
+            // move, in which case the owner did not reuse the slot.
+            let value = unsafe { self.slot(top).read_volatile() };
+            if header
+                .top
+                .compare_exchange(top, top + 1, SeqCst, SeqCst)
+                .is_ok()
+            {
+                return Some(value);
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Frees the deque.
+    ///
+    /// Other processes must not use the deque afterwards.
+    pub fn destroy(self) {
+        self.block.free();
+    }
+
+    fn slot(&self, index: i64) -> *mut T {
+        let index = index as usize & (self.capacity() - 1);
+        // SAFETY: The slots follow the header, one per task of the capacity.
+        unsafe { (self.block.ptr.add(Self::SLOTS) as *mut T).add(index) }
+    }
+
+    fn header(&self) -> &DequeHeader {
+        // SAFETY: The block starts with the header.
+        unsafe { &*(self.block.ptr as *const DequeHeader) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_pop_and_steal() {
+        let memory = Memory::new_in_process(4096).unwrap();
+        let deque = ShmWorkDeque::<u64>::create(&memory, 3).unwrap();
+        let thief = unsafe { ShmWorkDeque::<u64>::open(&memory, deque.offset()) }.unwrap();
+
+        for task in 1..=4 {
+            assert_eq!(deque.push(task), Ok(()), "The deque has room");
+        }
+        assert_eq!(deque.push(5), Err(5), "The deque is full");
+        assert_eq!(thief.steal(), Some(1), "Thieves take the oldest task");
+        assert_eq!(deque.pop(), Some(4), "The owner takes the newest task");
+        assert_eq!(deque.push(6), Ok(()), "The stolen slot is reused");
+        assert_eq!(deque.len(), 3, "Three tasks are left");
+
+        assert_eq!(deque.pop(), Some(6), "The owner takes the newest task");
+        assert_eq!(thief.steal(), Some(2), "Thieves take the oldest task");
+        assert_eq!(deque.pop(), Some(3), "The last task goes to the owner");
+        assert_eq!(deque.pop(), None, "The deque is empty");
+        assert_eq!(thief.steal(), None, "The deque is empty");
+    }
+}